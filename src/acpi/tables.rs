@@ -6,57 +6,135 @@
 
 extern crate alloc;
 
+use crate::config::LaunchConfig;
+use crate::cpu::topology::CpuTopology;
 use crate::fw_cfg::FwCfg;
+use crate::locking::SpinLock;
+use crate::mm::ptguards::{map_phys, MapError, MappingFlags};
 use crate::string::FixedString;
-use alloc::alloc::{alloc, dealloc, handle_alloc_error};
+use crate::types::{PhysAddr, MAX_CPUS};
 use alloc::vec::Vec;
-use core::alloc::Layout;
 use core::mem;
 use core::ptr;
 use log;
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AcpiError {
+    // The table is shorter than its header or its length field says
+    InvalidLength,
+    // The table does not have the expected signature
+    BadSignature,
+    // The bytes of the table do not sum up to zero
+    BadChecksum,
+    // A structure following the fixed part of the table is truncated or has
+    // a bad length
+    InvalidEntry,
+    // Reading the tables from fw_cfg failed
+    FwCfg,
+    // No table with the requested signature exists
+    NotFound,
+    // The MADT lists more CPUs than the SVSM supports
+    TooManyCpus,
+    // Mapping a table from guest memory failed
+    Map(MapError),
+}
+
+const RSDP_V1_SIZE: usize = 20;
+const RSDP_V2_SIZE: usize = 36;
+
+// All bytes of an ACPI table, including the checksum field, sum up to zero
+pub fn verify_checksum(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) == 0
+}
+
+// The RSDP checksum only covers the ACPI 1.0 fields. From revision 2 on the
+// extended checksum covers the whole structure as given by its length field.
+pub fn verify_rsdp_checksum(bytes: &[u8]) -> bool {
+    if bytes.len() < RSDP_V1_SIZE || !verify_checksum(&bytes[..RSDP_V1_SIZE]) {
+        return false;
+    }
+
+    if bytes[15] < 2 {
+        return true;
+    }
+
+    if bytes.len() < RSDP_V2_SIZE {
+        return false;
+    }
+
+    let len = u32::from_le_bytes([bytes[20], bytes[21], bytes[22], bytes[23]]) as usize;
+
+    (RSDP_V2_SIZE..=bytes.len()).contains(&len) && verify_checksum(&bytes[..len])
+}
+
 #[repr(C, packed)]
+#[derive(Clone, Copy)]
 pub struct RSDPDesc {
     pub sig: [u8; 8],
     pub chksum: u8,
     pub oem_id: [u8; 6],
     pub rev: u8,
     pub rsdt_addr: u32,
+    // Revision 2 fields
+    pub len: u32,
+    pub xsdt_addr: u64,
+    pub ext_chksum: u8,
+    pub reserved: [u8; 3],
 }
 
 impl RSDPDesc {
-    pub const fn new() -> Self {
-        RSDPDesc {
-            sig: [0; 8],
-            chksum: 0,
-            oem_id: [0; 6],
-            rev: 0,
-            rsdt_addr: 0,
+    // A revision 1 RSDP ends after rsdt_addr, the remaining fields are zero
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, AcpiError> {
+        if bytes.len() < RSDP_V1_SIZE {
+            return Err(AcpiError::InvalidLength);
+        }
+        if &bytes[..8] != b"RSD PTR " {
+            return Err(AcpiError::BadSignature);
+        }
+        if !verify_rsdp_checksum(bytes) {
+            return Err(AcpiError::BadChecksum);
         }
-    }
 
-    pub fn load(&mut self, fw_cfg: &FwCfg) -> Result<(), ()> {
-        let file = fw_cfg.file_selector("etc/acpi/rsdp")?;
-        let size = file.size() as usize;
+        let len = if bytes[15] < 2 {
+            RSDP_V1_SIZE
+        } else {
+            RSDP_V2_SIZE
+        };
+        let mut buf = [0u8; RSDP_V2_SIZE];
+        buf[..len].copy_from_slice(&bytes[..len]);
 
-        if file.size() as usize != mem::size_of::<RSDPDesc>() {
-            return Err(());
-        }
+        Ok(unsafe { ptr::read_unaligned(buf.as_ptr().cast::<RSDPDesc>()) })
+    }
 
-        fw_cfg.select(file.selector());
+    pub fn load(fw_cfg: &FwCfg) -> Result<Self, AcpiError> {
+        let bytes = fw_cfg_file(fw_cfg, "etc/acpi/rsdp")?;
 
-        unsafe {
-            let ptr = ptr::NonNull::new(self).unwrap();
-            let buf_ptr = ptr.as_ptr().cast::<u8>();
+        RSDPDesc::from_bytes(&bytes)
+    }
 
-            for i in 0..size {
-                let byte: u8 = fw_cfg.read_le();
-                buf_ptr.add(i).write(byte);
-            }
+    // The XSDT supersedes the RSDT when present. Returns the address and
+    // signature of the root table and the size of its entries.
+    fn root_table(&self) -> (u64, &'static [u8; 4], usize) {
+        let xsdt_addr = self.xsdt_addr;
+
+        if self.rev >= 2 && xsdt_addr != 0 {
+            (xsdt_addr, b"XSDT", 8)
+        } else {
+            (self.rsdt_addr as u64, b"RSDT", 4)
         }
+    }
+}
+
+fn fw_cfg_file(fw_cfg: &FwCfg, name: &str) -> Result<Vec<u8>, AcpiError> {
+    let file = fw_cfg.file_selector(name).map_err(|_| AcpiError::FwCfg)?;
+    let mut bytes: Vec<u8> = Vec::with_capacity(file.size() as usize);
 
-        Ok(())
+    fw_cfg.select(file.selector());
+    for _ in 0..file.size() {
+        bytes.push(fw_cfg.read_le());
     }
+
+    Ok(bytes)
 }
 
 #[derive(Copy, Clone)]
@@ -101,7 +179,6 @@ impl ACPITableHeader {
         }
     }
 
-    #[allow(dead_code)]
     pub fn print_summary(&self) {
         let sig = FixedString::from(self.sig);
         let oem_id = FixedString::from(self.oem_id);
@@ -122,179 +199,156 @@ impl ACPITableHeader {
     }
 }
 
-struct ACPITable {
-    header: ACPITableHeader,
-    ptr: ptr::NonNull<u8>,
-    size: usize,
-}
-
-impl ACPITable {
-    fn new(ptr: *const u8) -> Result<Self, ()> {
-        unsafe {
-            let raw_header = ptr.cast::<RawACPITableHeader>();
-            let size = (*raw_header).len as usize;
-
-            let layout = Layout::array::<u8>(size).unwrap();
-            let buf = ptr::NonNull::new(alloc(layout)).unwrap();
-
-            ptr::copy(ptr, buf.as_ptr(), size);
-
-            Ok(ACPITable {
-                header: ACPITableHeader::new(*raw_header),
-                ptr: buf,
-                size: size,
-            })
-        }
+// A table is read in two steps, first the header to get the length and then
+// the whole table. The checksum is verified on the final copy.
+fn read_table<F>(read: &F, addr: u64) -> Result<Vec<u8>, AcpiError>
+where
+    F: Fn(u64, usize) -> Result<Vec<u8>, AcpiError>,
+{
+    let header_size = mem::size_of::<RawACPITableHeader>();
+    let header = read(addr, header_size)?;
+    let raw_header = unsafe { ptr::read_unaligned(header.as_ptr().cast::<RawACPITableHeader>()) };
+    let len = raw_header.len as usize;
+
+    if len < header_size {
+        return Err(AcpiError::InvalidLength);
     }
 
-    #[allow(dead_code)]
-    pub fn signature(&self) -> FixedString<4> {
-        FixedString::from(self.header.sig)
+    let table = read(addr, len)?;
+    if !verify_checksum(&table) {
+        return Err(AcpiError::BadChecksum);
     }
 
-    pub fn content_length(&self) -> usize {
-        if self.size <= mem::size_of::<RawACPITableHeader>() {
-            0
-        } else {
-            self.size - mem::size_of::<RawACPITableHeader>()
-        }
-    }
+    ACPITableHeader::new(raw_header).print_summary();
 
-    pub fn content(&mut self) -> *mut u8 {
-        let offset = mem::size_of::<RawACPITableHeader>();
-
-        unsafe { self.ptr.as_ptr().add(offset) }
-    }
+    Ok(table)
 }
 
-struct ACPITableMeta {
-    pub sig: FixedString<4>,
-    pub offset: usize,
+// Copies of all tables listed in the RSDT or XSDT. Taking copies makes sure
+// that the tables can't change after they were checked.
+pub struct AcpiTables {
+    tables: Vec<Vec<u8>>,
 }
 
-impl ACPITableMeta {
-    pub fn new(header: &RawACPITableHeader, offset: usize) -> Self {
-        let sig = FixedString::from(header.sig);
-
-        ACPITableMeta {
-            sig: sig,
-            offset: offset,
+impl AcpiTables {
+    // read returns len bytes at the given table address
+    fn load<F>(rsdp: &RSDPDesc, read: F) -> Result<Self, AcpiError>
+    where
+        F: Fn(u64, usize) -> Result<Vec<u8>, AcpiError>,
+    {
+        let (root_addr, root_sig, entry_size) = rsdp.root_table();
+        let root = read_table(&read, root_addr)?;
+        let root = check_table(&root, root_sig, 0)?;
+        let mut tables: Vec<Vec<u8>> = Vec::new();
+
+        for entry in root[mem::size_of::<RawACPITableHeader>()..].chunks_exact(entry_size) {
+            let addr = entry
+                .iter()
+                .rev()
+                .fold(0u64, |addr, b| (addr << 8) | u64::from(*b));
+            tables.push(read_table(&read, addr)?);
         }
-    }
-}
 
-struct ACPITableBuffer {
-    ptr: ptr::NonNull<u8>,
-    size: usize,
-    tables: Vec<ACPITableMeta>,
-}
-
-impl ACPITableBuffer {
-    pub const fn new() -> Self {
-        ACPITableBuffer {
-            ptr: ptr::NonNull::dangling(),
-            size: 0,
-            tables: Vec::new(),
-        }
+        Ok(AcpiTables { tables })
     }
 
-    fn load_tables(&mut self, fw_cfg: &FwCfg) -> Result<(), ()> {
-        let mut desc: RSDPDesc = RSDPDesc::new();
-
-        desc.load(fw_cfg)?;
-
-        let mut rsdt = self.acpi_table_from_offset(desc.rsdt_addr as usize)?;
-        let len = rsdt.content_length();
-
-        if len == 0 {
-            return Err(());
+    // Reads the tables from guest memory, starting at the RSDP at the given
+    // physical address
+    pub fn from_rsdp(rsdp_addr: PhysAddr) -> Result<Self, AcpiError> {
+        let addr = rsdp_addr.as_usize() as u64;
+        let mut rsdp = read_phys(addr, RSDP_V1_SIZE)?;
+        if rsdp[15] >= 2 {
+            rsdp = read_phys(addr, RSDP_V2_SIZE)?;
         }
 
-        let entries = len / 4;
-
-        let content = rsdt.content().cast::<u32>();
+        AcpiTables::load(&RSDPDesc::from_bytes(&rsdp)?, read_phys)
+    }
 
-        for i in 0..entries {
-            unsafe {
-                let entry_ptr = content.add(i);
-                let offset = (*entry_ptr) as usize;
+    // QEMU provides the tables as one blob via fw_cfg. The addresses in the
+    // RSDP and the RSDT are offsets into the blob.
+    pub fn from_fw_cfg(fw_cfg: &FwCfg) -> Result<Self, AcpiError> {
+        let rsdp = RSDPDesc::load(fw_cfg)?;
+        let blob = fw_cfg_file(fw_cfg, "etc/acpi/tables")?;
+
+        AcpiTables::load(&rsdp, |addr, len| {
+            let start = addr as usize;
+            let end = start.checked_add(len).ok_or(AcpiError::InvalidLength)?;
+            blob.get(start..end)
+                .map(|bytes| bytes.to_vec())
+                .ok_or(AcpiError::InvalidLength)
+        })
+    }
 
-                if offset + mem::size_of::<RawACPITableHeader>() >= self.size {
-                    return Err(());
-                }
+    // The first table with the given signature, including its header
+    pub fn find(&self, sig: &[u8; 4]) -> Option<&[u8]> {
+        self.tables
+            .iter()
+            .find(|t| &t[..4] == sig)
+            .map(|t| t.as_slice())
+    }
+}
 
-                let raw_header = self.ptr.as_ptr().add(offset).cast::<RawACPITableHeader>();
-                let meta = ACPITableMeta::new(raw_header.as_ref().unwrap(), offset);
+fn read_phys(addr: u64, len: usize) -> Result<Vec<u8>, AcpiError> {
+    let mapping =
+        map_phys(PhysAddr::from(addr), len, MappingFlags::empty()).map_err(AcpiError::Map)?;
 
-                self.tables.push(meta);
-            }
-        }
+    Ok(mapping.to_vec())
+}
 
-        Ok(())
+// Checks the header of the table at the start of bytes and returns the
+// table. fixed_size is the size of the table specific fields following the
+// header.
+fn check_table<'a>(
+    bytes: &'a [u8],
+    sig: &[u8; 4],
+    fixed_size: usize,
+) -> Result<&'a [u8], AcpiError> {
+    let min_len = mem::size_of::<RawACPITableHeader>() + fixed_size;
+
+    if bytes.len() < min_len {
+        return Err(AcpiError::InvalidLength);
     }
 
-    pub fn load_from_fwcfg(&mut self, fw_cfg: &FwCfg) -> Result<(), ()> {
-        if self.size != 0 {
-            return Err(());
-        }
-
-        let file = fw_cfg.file_selector("etc/acpi/tables")?;
-        let size = file.size() as usize;
+    let raw_header = unsafe { ptr::read_unaligned(bytes.as_ptr().cast::<RawACPITableHeader>()) };
+    let len = raw_header.len as usize;
 
-        unsafe {
-            let layout = Layout::array::<u8>(size).unwrap();
-            let ptr = match ptr::NonNull::new(alloc(layout)) {
-                Some(p) => p,
-                None => handle_alloc_error(layout),
-            };
+    if len < min_len || len > bytes.len() {
+        return Err(AcpiError::InvalidLength);
+    }
+    if &raw_header.sig != sig {
+        return Err(AcpiError::BadSignature);
+    }
 
-            self.ptr = ptr::NonNull::new(ptr.as_ptr()).unwrap();
-            self.size = size;
+    let table = &bytes[..len];
+    if !verify_checksum(table) {
+        return Err(AcpiError::BadChecksum);
+    }
 
-            fw_cfg.select(file.selector());
-            for i in 0..size {
-                let byte: u8 = fw_cfg.read_le();
-                ptr.as_ptr().add(i).write(byte);
-            }
-        }
+    Ok(table)
+}
 
-        self.load_tables(fw_cfg)
-            .expect("Loading ACPI tables failed");
+// Splits the structures following the fixed part of a table, which all
+// start with a type and a length byte
+fn subtables(table: &[u8], fixed_size: usize) -> Result<Vec<&[u8]>, AcpiError> {
+    let len = table.len();
+    let mut offset = mem::size_of::<RawACPITableHeader>() + fixed_size;
+    let mut entries: Vec<&[u8]> = Vec::new();
 
-        Ok(())
-    }
-
-    fn acpi_table_from_offset(&self, offset: usize) -> Result<ACPITable, ()> {
-        if offset + mem::size_of::<RawACPITableHeader>() >= self.size {
-            return Err(());
+    while offset < len {
+        if len - offset < 2 {
+            return Err(AcpiError::InvalidEntry);
         }
 
-        unsafe {
-            let ptr = self.ptr.as_ptr().add(offset);
-            ACPITable::new(ptr)
+        let entry_len = table[offset + 1] as usize;
+        if entry_len < 2 || entry_len > len - offset {
+            return Err(AcpiError::InvalidEntry);
         }
-    }
-
-    pub fn acp_table_by_sig(&self, sig: &str) -> Option<ACPITable> {
-        let offset = self
-            .tables
-            .iter()
-            .find(|entry| entry.sig == sig)
-            .map(|entry| entry.offset)?;
 
-        self.acpi_table_from_offset(offset).ok()
+        entries.push(&table[offset..offset + entry_len]);
+        offset += entry_len;
     }
-}
 
-impl Drop for ACPITableBuffer {
-    fn drop(&mut self) {
-        if self.size != 0 {
-            unsafe {
-                let layout = Layout::array::<u8>(self.size).unwrap();
-                dealloc(self.ptr.as_ptr(), layout);
-            }
-        }
-    }
+    Ok(entries)
 }
 
 pub const MADT_HEADER_SIZE: usize = 8;
@@ -328,52 +382,454 @@ struct RawMADTEntryLocalX2Apic {
 pub struct ACPICPUInfo {
     pub apic_id: u32,
     pub enabled: bool,
+    pub topology: CpuTopology,
 }
 
-pub fn load_acpi_cpu_info(fw_cfg: &FwCfg) -> Result<Vec<ACPICPUInfo>, ()> {
-    let mut buffer = ACPITableBuffer::new();
+// A malformed MADT may list an APIC-ID more than once. Only the first entry
+// is kept, starting the same CPU twice would corrupt its per-cpu state.
+// Returns the number of remaining entries.
+pub fn remove_duplicate_cpus(cpus: &mut Vec<ACPICPUInfo>) -> usize {
+    let mut seen: Vec<u32> = Vec::with_capacity(cpus.len());
+
+    cpus.retain(|c| {
+        if seen.contains(&c.apic_id) {
+            log::warn!("ACPI: Ignoring duplicate APIC-ID {}", c.apic_id);
+            false
+        } else {
+            seen.push(c.apic_id);
+            true
+        }
+    });
 
-    buffer.load_from_fwcfg(fw_cfg)?;
+    cpus.len()
+}
 
-    let mut apic_table = buffer
-        .acp_table_by_sig("APIC")
-        .expect("MADT ACPI table not found");
-    let len = apic_table.content_length();
+const MADT_TYPE_LOCAL_APIC: u8 = 0;
+const MADT_TYPE_LOCAL_X2APIC: u8 = 9;
 
-    if len == 0 {
-        return Err(());
-    }
+const MADT_CPU_ENABLED: u32 = 1;
 
-    let content = apic_table.content();
+pub fn parse_madt(bytes: &[u8]) -> Result<Vec<ACPICPUInfo>, AcpiError> {
+    parse_madt_with_topology(bytes, CpuTopology::from_apic_id)
+}
 
+// Decoding the topology needs the CPUID table, so it is passed in to keep
+// the parser itself usable without one.
+fn parse_madt_with_topology<F>(bytes: &[u8], topology: F) -> Result<Vec<ACPICPUInfo>, AcpiError>
+where
+    F: Fn(u32) -> CpuTopology,
+{
+    let table = check_table(bytes, b"APIC", MADT_HEADER_SIZE)?;
     let mut cpus: Vec<ACPICPUInfo> = Vec::new();
 
-    unsafe {
-        let mut offset = MADT_HEADER_SIZE;
-        while offset < len {
-            let entry_ptr = content.add(offset).cast::<RawMADTEntryHeader>();
-            let t: u8 = (*entry_ptr).entry_type;
-            let l: u8 = (*entry_ptr).entry_len;
-            offset += l as usize;
-            if t == 0 {
-                let lapic_ptr = entry_ptr.cast::<RawMADTEntryLocalApic>();
-                let apic_id: u32 = (*lapic_ptr).apic_id as u32;
-                let flags: u32 = (*lapic_ptr).flags;
-                cpus.push(ACPICPUInfo {
-                    apic_id: apic_id,
-                    enabled: (flags & 1) == 1,
+    for entry in subtables(table, MADT_HEADER_SIZE)? {
+        let entry_type = entry[0];
+        let entry_len = entry.len();
+
+        let (apic_id, flags) = match entry_type {
+            MADT_TYPE_LOCAL_APIC => {
+                if entry_len < mem::size_of::<RawMADTEntryLocalApic>() {
+                    return Err(AcpiError::InvalidEntry);
+                }
+                let lapic =
+                    unsafe { ptr::read_unaligned(entry.as_ptr().cast::<RawMADTEntryLocalApic>()) };
+                (lapic.apic_id as u32, lapic.flags)
+            }
+            MADT_TYPE_LOCAL_X2APIC => {
+                if entry_len < mem::size_of::<RawMADTEntryLocalX2Apic>() {
+                    return Err(AcpiError::InvalidEntry);
+                }
+                let x2apic = unsafe {
+                    ptr::read_unaligned(entry.as_ptr().cast::<RawMADTEntryLocalX2Apic>())
+                };
+                (x2apic.apic_id, x2apic.flags)
+            }
+            _ => continue,
+        };
+
+        cpus.push(ACPICPUInfo {
+            apic_id,
+            enabled: (flags & MADT_CPU_ENABLED) != 0,
+            topology: topology(apic_id),
+        });
+    }
+
+    Ok(cpus)
+}
+
+const SRAT_HEADER_SIZE: usize = 12;
+
+const SRAT_TYPE_LOCAL_APIC: u8 = 0;
+const SRAT_TYPE_MEMORY: u8 = 1;
+const SRAT_TYPE_LOCAL_X2APIC: u8 = 2;
+
+const SRAT_ENTRY_ENABLED: u32 = 1;
+
+#[allow(dead_code)]
+#[repr(C, packed)]
+struct RawSRATEntryLocalApic {
+    entry_type: u8,
+    entry_len: u8,
+    proximity_domain_lo: u8,
+    apic_id: u8,
+    flags: u32,
+    sapic_eid: u8,
+    proximity_domain_hi: [u8; 3],
+    clock_domain: u32,
+}
+
+#[allow(dead_code)]
+#[repr(C, packed)]
+struct RawSRATEntryMemory {
+    entry_type: u8,
+    entry_len: u8,
+    proximity_domain: u32,
+    reserved1: u16,
+    base: u64,
+    length: u64,
+    reserved2: u32,
+    flags: u32,
+    reserved3: u64,
+}
+
+#[allow(dead_code)]
+#[repr(C, packed)]
+struct RawSRATEntryLocalX2Apic {
+    entry_type: u8,
+    entry_len: u8,
+    reserved1: u16,
+    proximity_domain: u32,
+    apic_id: u32,
+    flags: u32,
+    clock_domain: u32,
+    reserved2: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SratCpuAffinity {
+    pub apic_id: u32,
+    pub proximity_domain: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SratMemAffinity {
+    pub start: PhysAddr,
+    pub end: PhysAddr,
+    pub proximity_domain: u32,
+}
+
+#[derive(Debug, Default)]
+pub struct SratInfo {
+    pub cpus: Vec<SratCpuAffinity>,
+    pub memory: Vec<SratMemAffinity>,
+}
+
+impl SratInfo {
+    pub const fn new() -> Self {
+        SratInfo {
+            cpus: Vec::new(),
+            memory: Vec::new(),
+        }
+    }
+
+    pub fn proximity_domain(&self, apic_id: u32) -> Option<u32> {
+        self.cpus
+            .iter()
+            .find(|c| c.apic_id == apic_id)
+            .map(|c| c.proximity_domain)
+    }
+}
+
+// Disabled entries are skipped
+pub fn parse_srat(bytes: &[u8]) -> Result<SratInfo, AcpiError> {
+    let table = check_table(bytes, b"SRAT", SRAT_HEADER_SIZE)?;
+    let mut info = SratInfo::new();
+
+    for entry in subtables(table, SRAT_HEADER_SIZE)? {
+        match entry[0] {
+            SRAT_TYPE_LOCAL_APIC => {
+                if entry.len() < mem::size_of::<RawSRATEntryLocalApic>() {
+                    return Err(AcpiError::InvalidEntry);
+                }
+                let lapic =
+                    unsafe { ptr::read_unaligned(entry.as_ptr().cast::<RawSRATEntryLocalApic>()) };
+                if (lapic.flags & SRAT_ENTRY_ENABLED) == 0 {
+                    continue;
+                }
+                let hi = lapic.proximity_domain_hi;
+                info.cpus.push(SratCpuAffinity {
+                    apic_id: lapic.apic_id as u32,
+                    proximity_domain: u32::from_le_bytes([
+                        lapic.proximity_domain_lo,
+                        hi[0],
+                        hi[1],
+                        hi[2],
+                    ]),
+                });
+            }
+            SRAT_TYPE_LOCAL_X2APIC => {
+                if entry.len() < mem::size_of::<RawSRATEntryLocalX2Apic>() {
+                    return Err(AcpiError::InvalidEntry);
+                }
+                let x2apic = unsafe {
+                    ptr::read_unaligned(entry.as_ptr().cast::<RawSRATEntryLocalX2Apic>())
+                };
+                if (x2apic.flags & SRAT_ENTRY_ENABLED) == 0 {
+                    continue;
+                }
+                info.cpus.push(SratCpuAffinity {
+                    apic_id: x2apic.apic_id,
+                    proximity_domain: x2apic.proximity_domain,
                 });
-            } else if t == 9 {
-                let x2apic_ptr = entry_ptr.cast::<RawMADTEntryLocalX2Apic>();
-                let apic_id: u32 = (*x2apic_ptr).apic_id as u32;
-                let flags: u32 = (*x2apic_ptr).flags;
-                cpus.push(ACPICPUInfo {
-                    apic_id: apic_id,
-                    enabled: (flags & 1) == 1,
+            }
+            SRAT_TYPE_MEMORY => {
+                if entry.len() < mem::size_of::<RawSRATEntryMemory>() {
+                    return Err(AcpiError::InvalidEntry);
+                }
+                let mem =
+                    unsafe { ptr::read_unaligned(entry.as_ptr().cast::<RawSRATEntryMemory>()) };
+                if (mem.flags & SRAT_ENTRY_ENABLED) == 0 || mem.length == 0 {
+                    continue;
+                }
+                let base = mem.base;
+                let end = base
+                    .checked_add(mem.length)
+                    .ok_or(AcpiError::InvalidEntry)?;
+                info.memory.push(SratMemAffinity {
+                    start: PhysAddr::from(base),
+                    end: PhysAddr::from(end),
+                    proximity_domain: mem.proximity_domain,
                 });
             }
+            _ => {}
         }
     }
 
+    Ok(info)
+}
+
+// Filled from the SRAT, if the firmware provides one
+static SRAT_INFO: SpinLock<SratInfo> = SpinLock::new(SratInfo::new());
+
+pub fn srat_proximity_domain(apic_id: u32) -> Option<u32> {
+    SRAT_INFO.lock().proximity_domain(apic_id)
+}
+
+pub fn acpi_cpu_info(tables: &AcpiTables) -> Result<Vec<ACPICPUInfo>, AcpiError> {
+    let madt = tables.find(b"APIC").ok_or(AcpiError::NotFound)?;
+    let mut cpus = parse_madt(madt)?;
+
+    // Without a usable SRAT all CPUs are treated as being in one domain
+    if let Some(srat) = tables.find(b"SRAT") {
+        match parse_srat(srat) {
+            Ok(info) => *SRAT_INFO.lock() = info,
+            Err(e) => log::warn!("ACPI: Ignoring SRAT: {:?}", e),
+        }
+    }
+
+    if remove_duplicate_cpus(&mut cpus) > MAX_CPUS {
+        log::error!("ACPI: {} CPUs exceed maximum of {}", cpus.len(), MAX_CPUS);
+        return Err(AcpiError::TooManyCpus);
+    }
+
     Ok(cpus)
 }
+
+pub fn find_rsdp(config: &LaunchConfig) -> Option<PhysAddr> {
+    config.acpi_rsdp
+}
+
+// Falls back to fw_cfg when the launch configuration has no RSDP address
+pub fn load_acpi_tables(config: &LaunchConfig, fw_cfg: &FwCfg) -> Result<AcpiTables, AcpiError> {
+    match find_rsdp(config) {
+        Some(rsdp) => AcpiTables::from_rsdp(rsdp),
+        None => AcpiTables::from_fw_cfg(fw_cfg),
+    }
+}
+
+#[test]
+fn test_remove_duplicate_cpus() {
+    let mut cpus: Vec<ACPICPUInfo> = [0, 1, 1, 2, 0, 3]
+        .iter()
+        .map(|&apic_id| ACPICPUInfo {
+            apic_id,
+            enabled: apic_id != 2,
+            topology: CpuTopology::decode(apic_id, 0, 0),
+        })
+        .collect();
+
+    assert_eq!(remove_duplicate_cpus(&mut cpus), 4);
+
+    let ids: Vec<u32> = cpus.iter().map(|c| c.apic_id).collect();
+    assert_eq!(ids, [0, 1, 2, 3]);
+}
+
+#[cfg(test)]
+fn test_table(sig: &[u8; 4], parts: &[&[u8]]) -> Vec<u8> {
+    let mut table: Vec<u8> = Vec::new();
+
+    table.extend_from_slice(sig);
+    table.extend_from_slice(&[0; 32]);
+    for part in parts {
+        table.extend_from_slice(part);
+    }
+
+    let len = table.len() as u32;
+    table[4..8].copy_from_slice(&len.to_le_bytes());
+    let sum = table.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+    table[9] = 0u8.wrapping_sub(sum);
+
+    table
+}
+
+#[cfg(test)]
+fn test_madt(entries: &[&[u8]]) -> Vec<u8> {
+    // Local APIC address and flags
+    let mut parts: Vec<&[u8]> = Vec::from([&[0x00, 0x00, 0xe0, 0xfe, 0x01, 0x00, 0x00, 0x00][..]]);
+    parts.extend_from_slice(entries);
+
+    test_table(b"APIC", &parts)
+}
+
+#[test]
+fn test_parse_madt() {
+    let table = test_madt(&[
+        &[0, 8, 0, 0, 1, 0, 0, 0],
+        &[0, 8, 1, 2, 0, 0, 0, 0],
+        // I/O APIC entries are skipped
+        &[1, 12, 0, 0, 0, 0, 0xc0, 0xfe, 0, 0, 0, 0],
+        &[9, 16, 0, 0, 0x00, 0x01, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0],
+    ]);
+    let parse = |t: &[u8]| parse_madt_with_topology(t, |id| CpuTopology::decode(id, 0, 0));
+
+    let cpus = parse(&table).unwrap();
+    let ids: Vec<(u32, bool)> = cpus.iter().map(|c| (c.apic_id, c.enabled)).collect();
+    assert_eq!(ids, [(0, true), (2, false), (0x100, true)]);
+    assert_eq!(cpus[2].topology.package_id, 0x100);
+
+    let mut bad = table.clone();
+    bad[40] ^= 1;
+    assert_eq!(parse(&bad).err(), Some(AcpiError::BadChecksum));
+
+    assert_eq!(
+        parse(&table[..table.len() - 1]).err(),
+        Some(AcpiError::InvalidLength)
+    );
+    assert_eq!(
+        parse(&test_madt(&[&[0, 0]])).err(),
+        Some(AcpiError::InvalidEntry)
+    );
+    assert_eq!(
+        parse(&test_madt(&[&[9, 8, 0, 0, 0, 0, 0, 0]])).err(),
+        Some(AcpiError::InvalidEntry)
+    );
+}
+
+#[test]
+fn test_verify_rsdp_checksum() {
+    let mut rsdp = [0u8; RSDP_V2_SIZE];
+
+    rsdp[..8].copy_from_slice(b"RSD PTR ");
+    rsdp[8] = 0u8.wrapping_sub(
+        rsdp[..RSDP_V1_SIZE]
+            .iter()
+            .fold(0u8, |s, b| s.wrapping_add(*b)),
+    );
+    assert!(verify_rsdp_checksum(&rsdp[..RSDP_V1_SIZE]));
+
+    // Revision 2 adds the extended checksum over the length given
+    rsdp[15] = 2;
+    rsdp[8] = rsdp[8].wrapping_sub(2);
+    rsdp[20] = RSDP_V2_SIZE as u8;
+    assert!(verify_checksum(&rsdp[..RSDP_V1_SIZE]));
+    assert!(!verify_rsdp_checksum(&rsdp));
+    rsdp[32] = 0u8.wrapping_sub(rsdp.iter().fold(0u8, |s, b| s.wrapping_add(*b)));
+    assert!(verify_rsdp_checksum(&rsdp));
+    assert!(!verify_rsdp_checksum(&rsdp[..RSDP_V1_SIZE]));
+}
+
+#[test]
+fn test_parse_srat() {
+    let mut table: Vec<u8> = Vec::new();
+
+    table.extend_from_slice(b"SRAT");
+    table.extend_from_slice(&[0; 32]);
+    table.extend_from_slice(&[1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    // Local APIC 1 in domain 0x0201, a disabled one and x2APIC 0x100 in domain 3
+    table.extend_from_slice(&[0, 16, 0x01, 1, 1, 0, 0, 0, 0, 0x02, 0, 0, 0, 0, 0, 0]);
+    table.extend_from_slice(&[0, 16, 0x00, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    table.extend_from_slice(&[
+        2, 24, 0, 0, 3, 0, 0, 0, 0x00, 0x01, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    ]);
+    // 1GiB of memory at 4GiB in domain 3
+    let mut mem = [0u8; 40];
+    mem[0] = 1;
+    mem[1] = 40;
+    mem[2] = 3;
+    mem[8..16].copy_from_slice(&0x1_0000_0000u64.to_le_bytes());
+    mem[16..24].copy_from_slice(&0x4000_0000u64.to_le_bytes());
+    mem[28] = 1;
+    table.extend_from_slice(&mem);
+
+    let len = table.len() as u32;
+    table[4..8].copy_from_slice(&len.to_le_bytes());
+    let sum = table.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+    table[9] = 0u8.wrapping_sub(sum);
+
+    let info = parse_srat(&table).unwrap();
+    assert_eq!(info.cpus.len(), 2);
+    assert_eq!(info.proximity_domain(1), Some(0x0201));
+    assert_eq!(info.proximity_domain(2), None);
+    assert_eq!(info.proximity_domain(0x100), Some(3));
+    assert_eq!(
+        info.memory,
+        [SratMemAffinity {
+            start: PhysAddr::from(0x1_0000_0000u64),
+            end: PhysAddr::from(0x1_4000_0000u64),
+            proximity_domain: 3,
+        }]
+    );
+
+    table[9] ^= 1;
+    assert_eq!(parse_srat(&table).err(), Some(AcpiError::BadChecksum));
+}
+
+#[test]
+fn test_acpi_tables_load() {
+    let madt = test_madt(&[&[0, 8, 0, 0, 1, 0, 0, 0]]);
+    let dsdt = test_table(b"DSDT", &[]);
+    // XSDT at 0x100 listing the MADT at 0x200 and the DSDT at 0x300
+    let xsdt = test_table(b"XSDT", &[&0x200u64.to_le_bytes(), &0x300u64.to_le_bytes()]);
+
+    let mut mem = [0u8; 0x400];
+    mem[0x100..0x100 + xsdt.len()].copy_from_slice(&xsdt);
+    mem[0x200..0x200 + madt.len()].copy_from_slice(&madt);
+    mem[0x300..0x300 + dsdt.len()].copy_from_slice(&dsdt);
+
+    let mut rsdp = [0u8; RSDP_V2_SIZE];
+    rsdp[..8].copy_from_slice(b"RSD PTR ");
+    rsdp[15] = 2;
+    rsdp[20] = RSDP_V2_SIZE as u8;
+    rsdp[25] = 0x01;
+    let sum = |bytes: &[u8]| bytes.iter().fold(0u8, |s, b| s.wrapping_add(*b));
+    rsdp[8] = 0u8.wrapping_sub(sum(&rsdp[..RSDP_V1_SIZE]));
+    rsdp[32] = 0u8.wrapping_sub(sum(&rsdp));
+    let rsdp = RSDPDesc::from_bytes(&rsdp).unwrap();
+
+    let read = |mem: &[u8], addr: u64, len: usize| {
+        mem.get(addr as usize..addr as usize + len)
+            .map(|bytes| bytes.to_vec())
+            .ok_or(AcpiError::InvalidLength)
+    };
+
+    let tables = AcpiTables::load(&rsdp, |addr, len| read(&mem, addr, len)).unwrap();
+    assert_eq!(tables.find(b"APIC"), Some(&madt[..]));
+    assert_eq!(tables.find(b"DSDT"), Some(&dsdt[..]));
+    assert_eq!(tables.find(b"SRAT"), None);
+
+    mem[0x210] ^= 1;
+    assert_eq!(
+        AcpiTables::load(&rsdp, |addr, len| read(&mem, addr, len)).err(),
+        Some(AcpiError::BadChecksum)
+    );
+}
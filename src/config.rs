@@ -0,0 +1,81 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2026 SUSE LLC
+//
+// Author: agent <agent@local>
+
+extern crate alloc;
+
+use crate::fw_cfg::{FwCfg, E820_TYPE_RAM};
+use crate::fw_meta::SevFWMetaData;
+use crate::kernel_launch::KernelLaunchInfo;
+use crate::mm::memory::{MemoryKind, MemoryMap};
+use crate::types::PhysAddr;
+use crate::utils::immut_after_init::ImmutAfterInitRef;
+use alloc::boxed::Box;
+
+// Everything the SVSM learns about its environment at launch. It is put
+// together once on the BSP and read-only afterwards.
+#[derive(Debug)]
+pub struct LaunchConfig {
+    // Physical range of the SVSM image
+    pub svsm_region: (PhysAddr, PhysAddr),
+    // Guest memory as reported by the firmware, with the SVSM image carved
+    // out
+    pub memory_map: MemoryMap,
+    // Physical address of the ACPI RSDP from the launch information, None
+    // when the tables are read from fw_cfg
+    pub acpi_rsdp: Option<PhysAddr>,
+    // Reset vector of the guest firmware, from its SEV meta-data
+    pub guest_entry: Option<PhysAddr>,
+}
+
+impl LaunchConfig {
+    pub fn parse(
+        launch_info: &KernelLaunchInfo,
+        fw_cfg: &FwCfg,
+        fw_meta: &SevFWMetaData,
+    ) -> Result<Self, ()> {
+        let svsm_region = (
+            PhysAddr::from(launch_info.kernel_start),
+            PhysAddr::from(launch_info.kernel_end),
+        );
+        let mut memory_map = MemoryMap::new();
+
+        // Usable memory goes first, so that reserved ranges overlapping it
+        // take precedence
+        let mut e820 = fw_cfg.get_e820_map()?;
+        e820.sort_by_key(|(_, t)| *t != E820_TYPE_RAM);
+        for (region, t) in e820 {
+            let kind = if t == E820_TYPE_RAM {
+                MemoryKind::Usable
+            } else {
+                MemoryKind::Reserved
+            };
+            let size = region.end.checked_sub(region.start).ok_or(())?;
+            memory_map.insert(PhysAddr::from(region.start), size as usize, kind);
+        }
+
+        memory_map.carve_out_svsm(svsm_region.0, svsm_region.1);
+
+        Ok(LaunchConfig {
+            svsm_region,
+            memory_map,
+            acpi_rsdp: (launch_info.acpi_rsdp != 0).then(|| PhysAddr::from(launch_info.acpi_rsdp)),
+            guest_entry: fw_meta.reset_ip,
+        })
+    }
+}
+
+static LAUNCH_CONFIG: ImmutAfterInitRef<'static, LaunchConfig> = ImmutAfterInitRef::uninit();
+
+// Must only be called once, on the BSP and before any other CPU runs
+pub fn init_launch_config(config: LaunchConfig) {
+    let config: &'static LaunchConfig = Box::leak(Box::new(config));
+
+    unsafe { LAUNCH_CONFIG.init_from_ref(config) };
+}
+
+pub fn launch_config() -> &'static LaunchConfig {
+    LAUNCH_CONFIG.get()
+}
@@ -5,9 +5,11 @@
 // Author: Joerg Roedel <jroedel@suse.de>
 
 use crate::locking::SpinLock;
+use crate::log_buffer::LOG_BUFFER;
 use crate::serial::DEFAULT_SERIAL_PORT;
 use crate::utils::immut_after_init::ImmutAfterInitCell;
 use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
 use log;
 
 pub trait ConsoleWriter {
@@ -51,13 +53,55 @@ pub fn init_console() {
     unsafe { CONSOLE_INITIALIZED.reinit(&true) };
 }
 
+// Set by console_enter_unlocked(), after which logging never waits for a
+// lock
+static CONSOLE_UNLOCKED: AtomicBool = AtomicBool::new(false);
+
+// For the panic handler once the other CPUs are stopped, as they might have
+// been stopped while holding the WRITER or LOG_BUFFER lock. Output is then
+// written even when the WRITER is locked, and records are dropped from the
+// log buffer when it is locked.
+pub fn console_enter_unlocked() {
+    CONSOLE_UNLOCKED.store(true, Ordering::Release);
+}
+
+fn console_unlocked() -> bool {
+    CONSOLE_UNLOCKED.load(Ordering::Acquire)
+}
+
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
     if !*CONSOLE_INITIALIZED {
         return;
     }
-    WRITER.lock().write_fmt(args).unwrap();
+
+    if console_unlocked() {
+        _print_unlocked(args);
+    } else {
+        WRITER.lock().write_fmt(args).unwrap();
+    }
+}
+
+// Writes to the console without waiting for the WRITER lock. When the lock
+// is taken the output might interleave with that of the holder.
+#[doc(hidden)]
+pub fn _print_unlocked(args: fmt::Arguments) {
+    use core::fmt::Write;
+    if !*CONSOLE_INITIALIZED {
+        return;
+    }
+
+    match WRITER.try_lock() {
+        Ok(mut writer) => {
+            let _ = writer.write_fmt(args);
+        }
+        Err(()) => {
+            // SAFETY: the writer is only replaced during initialization, a
+            // lock holder just writes bytes through it as well
+            let _ = unsafe { (*WRITER.data_ptr()).write_fmt(args) };
+        }
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -88,6 +132,13 @@ impl log::Log for ConsoleLogger {
             return;
         }
 
+        // Keep a copy for the guest, also before the console is working
+        if console_unlocked() {
+            LOG_BUFFER.try_log(record);
+        } else {
+            LOG_BUFFER.log(record);
+        }
+
         // The logger being uninitialized is impossible, as that would mean it
         // wouldn't have been registered with the log library.
         let component: &'static str = &self.component.name;
@@ -0,0 +1,104 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2026 SUSE LLC
+//
+// Author: agent <agent@local>
+
+use crate::cpu::features::cpu_has_x2apic;
+use crate::cpu::percpu::this_cpu_mut;
+use crate::types::PhysAddr;
+
+// APIC MSRs are intercepted for SEV-SNP guests, so all accesses go to the
+// hypervisor via the GHCB MSR protocol.
+pub const MSR_APIC_BASE: u32 = 0x1b;
+pub const MSR_X2APIC_ID: u32 = 0x802;
+pub const MSR_X2APIC_EOI: u32 = 0x80b;
+pub const MSR_X2APIC_ICR: u32 = 0x830;
+
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+const XAPIC_ID_OFFSET: usize = 0x20;
+
+fn decode_apic_base(val: u64) -> (PhysAddr, bool) {
+    let base = PhysAddr::from(val & APIC_BASE_ADDR_MASK);
+    let x2apic =
+        (val & (APIC_BASE_ENABLE | APIC_BASE_X2APIC)) == (APIC_BASE_ENABLE | APIC_BASE_X2APIC);
+
+    (base, x2apic)
+}
+
+fn read_apic_base() -> u64 {
+    this_cpu_mut()
+        .ghcb()
+        .rdmsr(MSR_APIC_BASE)
+        .expect("Failed to read APIC base MSR")
+}
+
+// Returns the xAPIC MMIO base address and whether the APIC of the current CPU
+// runs in x2APIC mode
+pub fn apic_base() -> (PhysAddr, bool) {
+    decode_apic_base(read_apic_base())
+}
+
+pub fn x2apic_enabled() -> bool {
+    apic_base().1
+}
+
+// Switch the APIC of the current CPU to x2APIC mode. Going from xAPIC to
+// x2APIC mode only needs the EXTD bit set, a disabled APIC is enabled in the
+// same write.
+pub fn enable_x2apic() -> Result<(), ()> {
+    if !cpu_has_x2apic() {
+        return Err(());
+    }
+
+    let val = read_apic_base();
+    if decode_apic_base(val).1 {
+        return Ok(());
+    }
+
+    this_cpu_mut()
+        .ghcb()
+        .wrmsr(MSR_APIC_BASE, val | APIC_BASE_ENABLE | APIC_BASE_X2APIC)
+        .map_err(|_| ())?;
+
+    if x2apic_enabled() {
+        Ok(())
+    } else {
+        Err(())
+    }
+}
+
+// The APIC-ID of the current CPU as reported by its local APIC
+pub fn local_apic_id() -> u32 {
+    let (base, x2apic) = apic_base();
+
+    if x2apic {
+        this_cpu_mut()
+            .ghcb()
+            .rdmsr(MSR_X2APIC_ID)
+            .expect("Failed to read x2APIC ID") as u32
+    } else {
+        let id = this_cpu_mut()
+            .ghcb()
+            .mmio_read_u32(base + XAPIC_ID_OFFSET)
+            .expect("Failed to read xAPIC ID");
+        id >> 24
+    }
+}
+
+#[test]
+fn test_decode_apic_base() {
+    let (base, x2apic) = decode_apic_base(0xfee0_0900);
+    assert_eq!(base, PhysAddr::from(0xfee0_0000u64));
+    assert!(!x2apic);
+
+    let (base, x2apic) = decode_apic_base(0xfee0_0d00);
+    assert_eq!(base, PhysAddr::from(0xfee0_0000u64));
+    assert!(x2apic);
+
+    // EXTD without EN is an invalid state, not x2APIC mode
+    assert!(!decode_apic_base(0xfee0_0500).1);
+}
@@ -0,0 +1,74 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2026 SUSE LLC
+//
+// Author: agent <agent@local>
+
+use crate::cpu::cpuid::cpuid_table;
+use crate::types::VirtAddr;
+use core::arch::asm;
+
+const CLFLUSH_LINE_SIZE_DEFAULT: usize = 64;
+
+// CPUID Fn0000_0001 EBX[15:8] gives the CLFLUSH line size in quadwords
+fn clflush_line_size_from(ebx: u32) -> usize {
+    match ((ebx >> 8) & 0xff) as usize {
+        0 => CLFLUSH_LINE_SIZE_DEFAULT,
+        qwords => qwords * 8,
+    }
+}
+
+fn clflush_line_size() -> usize {
+    match cpuid_table(0x00000001) {
+        None => CLFLUSH_LINE_SIZE_DEFAULT,
+        Some(c) => clflush_line_size_from(c.ebx),
+    }
+}
+
+// Writes back and invalidates all caches of this CPU and waits for the
+// external caches to do the same. This takes milliseconds on large caches
+// and stalls the whole core, so callers which know the affected memory
+// should use clflush_range() instead.
+pub fn wbinvd() {
+    unsafe {
+        asm!("wbinvd", options(att_syntax, nostack));
+    }
+}
+
+// Writes back and invalidates the cache line containing va in all caches of
+// the coherency domain. va must be mapped.
+pub fn clflush(va: VirtAddr) {
+    unsafe {
+        asm!("clflush (%rax)",
+             in("rax") va.as_usize(),
+             options(att_syntax, nostack));
+    }
+}
+
+// Flushes all cache lines overlapping [start, start + len). CLFLUSH is only
+// ordered against writes by fences, so the flushes are complete on return.
+pub fn clflush_range(start: VirtAddr, len: usize) {
+    let line = clflush_line_size();
+    let end = start + len;
+    let mut va = VirtAddr::from(start.as_usize() & !(line - 1));
+
+    unsafe {
+        asm!("mfence", options(att_syntax, nostack));
+    }
+
+    while va < end {
+        clflush(va);
+        va += line;
+    }
+
+    unsafe {
+        asm!("mfence", options(att_syntax, nostack));
+    }
+}
+
+#[test]
+fn test_clflush_line_size() {
+    assert_eq!(clflush_line_size_from(0x0008_0800), 64);
+    assert_eq!(clflush_line_size_from(0x0000_1000), 128);
+    assert_eq!(clflush_line_size_from(0), CLFLUSH_LINE_SIZE_DEFAULT);
+}
@@ -4,18 +4,28 @@
 //
 // Author: Joerg Roedel <jroedel@suse.de>
 
-use super::features::cpu_has_pge;
+use super::features::{
+    cpu_has_cet_ss, cpu_has_fsgsbase, cpu_has_pcid, cpu_has_pge, cpu_has_pku, cpu_has_smap,
+    cpu_has_smep, cpu_has_umip, cpu_has_xsave,
+};
+use super::msr::{write_msr, MSR_GS_BASE};
+use crate::types::PhysAddr;
 use bitflags::bitflags;
 use core::arch::asm;
+use core::sync::atomic::{AtomicBool, Ordering};
 
-pub fn cr0_init() {
-    let mut cr0 = read_cr0();
-
+// The CR0 value cr0_init() writes, kept apart from the register access so it
+// can be tested on the host
+fn cr0_init_flags(mut cr0: CR0Flags) -> CR0Flags {
     cr0.insert(CR0Flags::WP); // Enable Write Protection
     cr0.remove(CR0Flags::NW); // Enable caches ...
     cr0.remove(CR0Flags::CD); // ... if not already happened
 
-    write_cr0(cr0);
+    cr0
+}
+
+pub fn cr0_init() {
+    write_cr0(cr0_init_flags(read_cr0()));
 }
 
 pub fn cr4_init() {
@@ -27,7 +37,102 @@ pub fn cr4_init() {
         cr4.insert(CR4Flags::PGE); // Enable Global Pages
     }
 
+    // Setting any of the following bits on a CPU without the feature raises
+    // #GP, so only enable what CPUID reports.
+    if cpu_has_smep() {
+        cr4.insert(CR4Flags::SMEP);
+    }
+
+    if cpu_has_smap() {
+        cr4.insert(CR4Flags::SMAP);
+    }
+
+    if cpu_has_umip() {
+        cr4.insert(CR4Flags::UMIP);
+    }
+
+    if cpu_has_fsgsbase() {
+        cr4.insert(CR4Flags::FSGSBASE);
+    }
+
+    // PCIDE can only be turned on while CR3[11:0] is zero
+    if cpu_has_pcid() && read_cr3().pcid() == 0 {
+        cr4.insert(CR4Flags::PCIDE);
+    }
+
+    write_cr4(cr4);
+
+    if cr4.contains(CR4Flags::SMAP) {
+        SMAP_ENABLED.store(true, Ordering::Relaxed);
+    }
+}
+
+pub fn enable_smep() -> bool {
+    if !cpu_has_smep() {
+        return false;
+    }
+
+    let mut cr4 = read_cr4();
+    cr4.insert(CR4Flags::SMEP);
+    write_cr4(cr4);
+
+    true
+}
+
+// Set once SMAP got enabled on any CPU. All CPUs are expected to run with
+// the same CR4 setup, so STAC/CLAC are either needed everywhere or nowhere.
+static SMAP_ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn enable_smap() -> bool {
+    if !cpu_has_smap() {
+        return false;
+    }
+
+    let mut cr4 = read_cr4();
+    cr4.insert(CR4Flags::SMAP);
     write_cr4(cr4);
+
+    SMAP_ENABLED.store(true, Ordering::Relaxed);
+
+    true
+}
+
+// Allow supervisor accesses to user pages. This is a no-op when SMAP was
+// never enabled, because STAC/CLAC raise #UD on CPUs without SMAP support.
+#[inline(always)]
+pub fn stac() {
+    if SMAP_ENABLED.load(Ordering::Relaxed) {
+        unsafe {
+            asm!("stac", options(att_syntax, nostack));
+        }
+    }
+}
+
+// Forbid supervisor accesses to user pages again. No-op without SMAP.
+#[inline(always)]
+pub fn clac() {
+    if SMAP_ENABLED.load(Ordering::Relaxed) {
+        unsafe {
+            asm!("clac", options(att_syntax, nostack));
+        }
+    }
+}
+
+// Permits user-memory accesses while alive, like stac()/clac() this does
+// nothing when SMAP is not enabled.
+pub struct SmapGuard {}
+
+impl SmapGuard {
+    pub fn enter() -> Self {
+        stac();
+        SmapGuard {}
+    }
+}
+
+impl Drop for SmapGuard {
+    fn drop(&mut self) {
+        clac();
+    }
 }
 
 bitflags! {
@@ -68,6 +173,115 @@ pub fn write_cr0(cr0: CR0Flags) {
     }
 }
 
+#[test]
+fn test_cr0_flags() {
+    assert_eq!(CR0Flags::PE.bits(), 0x1);
+    assert_eq!(CR0Flags::WP.bits(), 0x1_0000);
+    assert_eq!(CR0Flags::PG.bits(), 0x8000_0000);
+
+    // Reserved bits are dropped, ET is hardwired to 1 on modern CPUs
+    let cr0 = CR0Flags::from_bits_truncate(0xffff_ffff_8005_0033);
+    assert_eq!(
+        cr0,
+        CR0Flags::PE
+            | CR0Flags::MP
+            | CR0Flags::ET
+            | CR0Flags::NE
+            | CR0Flags::WP
+            | CR0Flags::AM
+            | CR0Flags::PG
+    );
+    assert!(CR0Flags::from_bits(0x8005_0033 | 1 << 32).is_none());
+
+    let cr0 = cr0_init_flags(CR0Flags::PE | CR0Flags::CD | CR0Flags::NW | CR0Flags::PG);
+    assert_eq!(cr0, CR0Flags::PE | CR0Flags::WP | CR0Flags::PG);
+}
+
+const RFLAGS_IF: u64 = 1 << 9;
+
+pub fn read_rflags() -> u64 {
+    let rflags: u64;
+
+    unsafe {
+        asm!("pushfq
+              popq %rax",
+             out("rax") rflags,
+             options(att_syntax));
+    }
+
+    rflags
+}
+
+pub fn irqs_enabled() -> bool {
+    (read_rflags() & RFLAGS_IF) != 0
+}
+
+// Clears CR0.WP while alive, allowing writes to read-only mappings. This must
+// only be used with interrupts disabled, so that no other code ever runs with
+// write protection turned off.
+pub struct WpGuard {
+    restore: bool,
+}
+
+impl WpGuard {
+    pub fn disable() -> Self {
+        debug_assert!(!irqs_enabled());
+
+        let mut cr0 = read_cr0();
+        let restore = cr0.contains(CR0Flags::WP);
+
+        if restore {
+            cr0.remove(CR0Flags::WP);
+            write_cr0(cr0);
+        }
+
+        WpGuard { restore }
+    }
+}
+
+impl Drop for WpGuard {
+    fn drop(&mut self) {
+        if self.restore {
+            let mut cr0 = read_cr0();
+            cr0.insert(CR0Flags::WP);
+            write_cr0(cr0);
+        }
+    }
+}
+
+// Run f() with CR0.WP cleared. Interrupts must be disabled by the caller.
+pub fn write_protection_disabled<T>(f: impl FnOnce() -> T) -> T {
+    let _guard = WpGuard::disable();
+    f()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CtrlRegError {
+    // Bits set which are reserved in the register
+    ReservedBits(u64),
+    // Feature bits set which the CPU does not support
+    Unsupported(u64),
+    // Combination of bits which raises #GP when written
+    Inconsistent,
+}
+
+pub fn try_write_cr0(cr0: CR0Flags) -> Result<(), CtrlRegError> {
+    let reserved = cr0.bits() & !CR0Flags::all().bits();
+    if reserved != 0 {
+        return Err(CtrlRegError::ReservedBits(reserved));
+    }
+
+    // Paging without protection and NW without CD both #GP
+    if (cr0.contains(CR0Flags::PG) && !cr0.contains(CR0Flags::PE))
+        || (cr0.contains(CR0Flags::NW) && !cr0.contains(CR0Flags::CD))
+    {
+        return Err(CtrlRegError::Inconsistent);
+    }
+
+    write_cr0(cr0);
+    Ok(())
+}
+
 pub fn read_cr2() -> usize {
     let ret: usize;
     unsafe {
@@ -78,6 +292,57 @@ pub fn read_cr2() -> usize {
     ret
 }
 
+// Page-fault error code bits
+const PF_ERROR_PRESENT: u64 = 1 << 0;
+const PF_ERROR_WRITE: u64 = 1 << 1;
+const PF_ERROR_USER: u64 = 1 << 2;
+const PF_ERROR_RESERVED: u64 = 1 << 3;
+const PF_ERROR_INSTR: u64 = 1 << 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PageFaultInfo {
+    pub addr: usize,
+    pub error_code: u64,
+    // Protection violation on a present page, otherwise not-present fault
+    pub present: bool,
+    pub write: bool,
+    pub user: bool,
+    pub reserved: bool,
+    pub instr_fetch: bool,
+}
+
+impl PageFaultInfo {
+    pub fn decode(addr: usize, error_code: u64) -> Self {
+        PageFaultInfo {
+            addr,
+            error_code,
+            present: (error_code & PF_ERROR_PRESENT) != 0,
+            write: (error_code & PF_ERROR_WRITE) != 0,
+            user: (error_code & PF_ERROR_USER) != 0,
+            reserved: (error_code & PF_ERROR_RESERVED) != 0,
+            instr_fetch: (error_code & PF_ERROR_INSTR) != 0,
+        }
+    }
+}
+
+// Must be called from the #PF handler before anything can fault again and
+// overwrite CR2.
+pub fn page_fault_info(error_code: u64) -> PageFaultInfo {
+    PageFaultInfo::decode(read_cr2(), error_code)
+}
+
+#[test]
+fn test_page_fault_info_decode() {
+    let info = PageFaultInfo::decode(0xffff_8000_0000_1000, 0x3);
+    assert_eq!(info.addr, 0xffff_8000_0000_1000);
+    assert!(info.present && info.write);
+    assert!(!info.user && !info.reserved && !info.instr_fetch);
+
+    let info = PageFaultInfo::decode(0x1000, 0x14);
+    assert!(!info.present && !info.write);
+    assert!(info.user && info.instr_fetch);
+}
+
 pub fn write_cr2(cr2: usize) {
     unsafe {
         asm!("mov %rax, %cr2",
@@ -86,24 +351,85 @@ pub fn write_cr2(cr2: usize) {
     }
 }
 
-pub fn read_cr3() -> usize {
-    let ret: usize;
+const CR3_PCID_MASK: u64 = 0xfff;
+const CR3_ROOT_MASK: u64 = 0x000f_ffff_ffff_f000;
+const CR3_NO_FLUSH: u64 = 1 << 63;
+
+// CR3 value. With CR4.PCIDE set bits 11:0 hold the PCID and bit 63 keeps the
+// TLB entries of the PCID on a write. Without PCIDE the low bits are PWT/PCD,
+// which the SVSM leaves clear. The root includes the C-bit, if set.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cr3(u64);
+
+impl Cr3 {
+    pub const fn new(root: PhysAddr, pcid: u16, no_flush: bool) -> Self {
+        assert!(pcid as u64 <= CR3_PCID_MASK, "PCID out of range");
+
+        let mut bits = (root.as_usize() as u64 & CR3_ROOT_MASK) | pcid as u64;
+        if no_flush {
+            bits |= CR3_NO_FLUSH;
+        }
+        Cr3(bits)
+    }
+
+    pub const fn from_bits(bits: u64) -> Self {
+        Cr3(bits)
+    }
+
+    pub const fn bits(&self) -> u64 {
+        self.0
+    }
+
+    pub fn root_phys(&self) -> PhysAddr {
+        PhysAddr::from(self.0 & CR3_ROOT_MASK)
+    }
+
+    pub fn pcid(&self) -> u16 {
+        (self.0 & CR3_PCID_MASK) as u16
+    }
+
+    pub fn no_flush(&self) -> bool {
+        (self.0 & CR3_NO_FLUSH) != 0
+    }
+}
+
+pub fn read_cr3() -> Cr3 {
+    let ret: u64;
     unsafe {
         asm!("mov %cr3, %rax",
              out("rax") ret,
              options(att_syntax));
     }
-    ret
+    Cr3(ret)
 }
 
-pub fn write_cr3(cr3: usize) {
+pub fn write_cr3(cr3: Cr3) {
     unsafe {
         asm!("mov %rax, %cr3",
-             in("rax") cr3,
+             in("rax") cr3.bits(),
              options(att_syntax));
     }
 }
 
+// The PCID and the no-flush bit must only be used with CR4.PCIDE set
+pub fn write_cr3_root(root: PhysAddr, pcid: u16, no_flush: bool) {
+    debug_assert!((pcid == 0 && !no_flush) || read_cr4().contains(CR4Flags::PCIDE));
+    write_cr3(Cr3::new(root, pcid, no_flush));
+}
+
+#[test]
+fn test_cr3_decode() {
+    let cr3 = Cr3::from_bits(0x8008_0000_0123_4005);
+    assert_eq!(cr3.root_phys(), PhysAddr::from(0x0008_0000_0123_4000u64));
+    assert_eq!(cr3.pcid(), 5);
+    assert!(cr3.no_flush());
+
+    let cr3 = Cr3::new(PhysAddr::from(0x1234_5678usize), 0xfff, false);
+    assert_eq!(cr3.bits(), 0x1234_5fff);
+    assert_eq!(cr3.root_phys(), PhysAddr::from(0x1234_5000usize));
+    assert!(!cr3.no_flush());
+}
+
 bitflags! {
     pub struct CR4Flags: u64 {
         const VME       = 1 << 0;  // Virtual-8086 Mode Extensions
@@ -129,6 +455,21 @@ bitflags! {
     }
 }
 
+#[test]
+fn test_cr4_flags() {
+    assert_eq!(CR4Flags::PAE.bits(), 0x20);
+    assert_eq!(CR4Flags::FSGSBASE.bits(), 0x1_0000);
+    assert_eq!(CR4Flags::SMEP.bits(), 0x10_0000);
+    assert_eq!(CR4Flags::SMAP.bits(), 0x20_0000);
+    assert_eq!(CR4Flags::CET.bits(), 0x80_0000);
+    assert_eq!(CR4Flags::all().bits(), 0xf7_0fff);
+
+    // Bits 12-15 and 19 have no flag here and are dropped
+    let cr4 = CR4Flags::from_bits_truncate(0x000a_f0a0);
+    assert_eq!(cr4, CR4Flags::PAE | CR4Flags::PGE | CR4Flags::PCIDE);
+    assert!(CR4Flags::from_bits(0x000a_f0a0).is_none());
+}
+
 pub fn read_cr4() -> CR4Flags {
     let cr4: u64;
 
@@ -150,3 +491,247 @@ pub fn write_cr4(cr4: CR4Flags) {
              options(att_syntax));
     }
 }
+
+fn cr4_unsupported(cr4: CR4Flags) -> CR4Flags {
+    let checks = [
+        (CR4Flags::PGE, cpu_has_pge as fn() -> bool),
+        (CR4Flags::UMIP, cpu_has_umip),
+        (CR4Flags::FSGSBASE, cpu_has_fsgsbase),
+        (CR4Flags::PCIDE, cpu_has_pcid),
+        (CR4Flags::OSXSAVE, cpu_has_xsave),
+        (CR4Flags::SMEP, cpu_has_smep),
+        (CR4Flags::SMAP, cpu_has_smap),
+        (CR4Flags::PKE, cpu_has_pku),
+        (CR4Flags::CET, cpu_has_cet_ss),
+    ];
+    let mut unsupported = CR4Flags::empty();
+
+    for (flag, supported) in checks {
+        if cr4.contains(flag) && !supported() {
+            unsupported.insert(flag);
+        }
+    }
+
+    unsupported
+}
+
+pub fn try_write_cr4(cr4: CR4Flags) -> Result<(), CtrlRegError> {
+    let reserved = cr4.bits() & !CR4Flags::all().bits();
+    if reserved != 0 {
+        return Err(CtrlRegError::ReservedBits(reserved));
+    }
+
+    let unsupported = cr4_unsupported(cr4);
+    if !unsupported.is_empty() {
+        return Err(CtrlRegError::Unsupported(unsupported.bits()));
+    }
+
+    // Long mode requires PAE, CET requires CR0.WP. PCIDE can only be
+    // set while CR3[11:0] is zero.
+    if !cr4.contains(CR4Flags::PAE)
+        || (cr4.contains(CR4Flags::CET) && !read_cr0().contains(CR0Flags::WP))
+        || (cr4.contains(CR4Flags::PCIDE)
+            && !read_cr4().contains(CR4Flags::PCIDE)
+            && read_cr3().pcid() != 0)
+    {
+        return Err(CtrlRegError::Inconsistent);
+    }
+
+    write_cr4(cr4);
+    Ok(())
+}
+
+pub fn read_cr8() -> u8 {
+    let cr8: u64;
+
+    unsafe {
+        asm!("mov %cr8, %rax",
+             out("rax") cr8,
+             options(att_syntax));
+    }
+
+    (cr8 & 0xf) as u8
+}
+
+pub fn write_cr8(tpr: u8) {
+    let reg = (tpr & 0xf) as u64;
+
+    unsafe {
+        asm!("mov %rax, %cr8",
+             in("rax") reg,
+             options(att_syntax));
+    }
+}
+
+// Run f() with the task priority raised to at least level and restore the
+// previous TPR afterwards. The TPR is never lowered by this function.
+pub fn with_tpr_raised<T>(level: u8, f: impl FnOnce() -> T) -> T {
+    let old = read_cr8();
+    let new = level & 0xf;
+
+    if new > old {
+        write_cr8(new);
+    }
+
+    let ret = f();
+
+    if new > old {
+        write_cr8(old);
+    }
+
+    ret
+}
+
+bitflags! {
+    pub struct XCR0Flags: u64 {
+        const X87       = 1 << 0;  // x87 FPU state
+        const SSE       = 1 << 1;  // SSE state (XMM registers, MXCSR)
+        const AVX       = 1 << 2;  // AVX state (upper halves of YMM registers)
+        const BNDREG    = 1 << 3;  // MPX bound registers
+        const BNDCSR    = 1 << 4;  // MPX bound configuration and status
+        const OPMASK    = 1 << 5;  // AVX-512 opmask registers
+        const ZMM_HI256 = 1 << 6;  // AVX-512 upper halves of ZMM0-15
+        const HI16_ZMM  = 1 << 7;  // AVX-512 ZMM16-31
+        const PKRU      = 1 << 9;  // Protection Key Rights register
+    }
+}
+
+// Architectural constraints on XCR0 values, violating any of them makes
+// XSETBV raise #GP. Supervisor state components like CET are managed
+// through IA32_XSS, their bits are reserved in XCR0.
+pub fn xcr0_valid(xcr0: XCR0Flags) -> bool {
+    let avx512 = XCR0Flags::OPMASK | XCR0Flags::ZMM_HI256 | XCR0Flags::HI16_ZMM;
+    let mpx = XCR0Flags::BNDREG | XCR0Flags::BNDCSR;
+
+    if xcr0.bits() & !XCR0Flags::all().bits() != 0 {
+        return false;
+    }
+
+    if !xcr0.contains(XCR0Flags::X87) {
+        return false;
+    }
+
+    if xcr0.contains(XCR0Flags::AVX) && !xcr0.contains(XCR0Flags::SSE) {
+        return false;
+    }
+
+    if xcr0.intersects(avx512) && !xcr0.contains(avx512 | XCR0Flags::AVX) {
+        return false;
+    }
+
+    if xcr0.intersects(mpx) && !xcr0.contains(mpx) {
+        return false;
+    }
+
+    true
+}
+
+pub fn read_xcr0() -> XCR0Flags {
+    let eax: u32;
+    let edx: u32;
+
+    unsafe {
+        asm!("xgetbv",
+             in("ecx") 0,
+             out("eax") eax,
+             out("edx") edx,
+             options(att_syntax));
+    }
+
+    XCR0Flags::from_bits_truncate((eax as u64) | ((edx as u64) << 32))
+}
+
+pub fn write_xcr0(xcr0: XCR0Flags) -> Result<(), ()> {
+    // XSETBV raises #UD with CR4.OSXSAVE cleared
+    if !read_cr4().contains(CR4Flags::OSXSAVE) || !xcr0_valid(xcr0) {
+        return Err(());
+    }
+
+    let reg = xcr0.bits();
+
+    unsafe {
+        asm!("xsetbv",
+             in("ecx") 0,
+             in("eax") (reg & 0xffffffff) as u32,
+             in("edx") (reg >> 32) as u32,
+             options(att_syntax));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_xcr0_x87_sse_roundtrip() {
+    let xcr0 = XCR0Flags::X87 | XCR0Flags::SSE;
+    let reg = xcr0.bits();
+
+    assert_eq!(reg, 0x3);
+    assert_eq!(XCR0Flags::from_bits_truncate(reg), xcr0);
+    assert!(xcr0_valid(xcr0));
+    assert!(xcr0_valid(xcr0 | XCR0Flags::AVX));
+    assert!(!xcr0_valid(XCR0Flags::SSE));
+    assert!(!xcr0_valid(XCR0Flags::X87 | XCR0Flags::AVX));
+    // CET user state is an IA32_XSS component
+    assert!(!xcr0_valid(unsafe {
+        XCR0Flags::from_bits_unchecked(reg | 1 << 11)
+    }));
+}
+
+// The FS/GS base instructions raise #UD unless CR4.FSGSBASE is set, see
+// write_gs_base() for a version which falls back to MSR_GS_BASE.
+pub fn rdfsbase() -> u64 {
+    let ret: u64;
+
+    debug_assert!(read_cr4().contains(CR4Flags::FSGSBASE));
+
+    unsafe {
+        asm!("rdfsbase %rax",
+             out("rax") ret,
+             options(att_syntax, nostack));
+    }
+
+    ret
+}
+
+pub fn rdgsbase() -> u64 {
+    let ret: u64;
+
+    debug_assert!(read_cr4().contains(CR4Flags::FSGSBASE));
+
+    unsafe {
+        asm!("rdgsbase %rax",
+             out("rax") ret,
+             options(att_syntax, nostack));
+    }
+
+    ret
+}
+
+pub fn wrfsbase(val: u64) {
+    debug_assert!(read_cr4().contains(CR4Flags::FSGSBASE));
+
+    unsafe {
+        asm!("wrfsbase %rax",
+             in("rax") val,
+             options(att_syntax, nostack));
+    }
+}
+
+pub fn wrgsbase(val: u64) {
+    debug_assert!(read_cr4().contains(CR4Flags::FSGSBASE));
+
+    unsafe {
+        asm!("wrgsbase %rax",
+             in("rax") val,
+             options(att_syntax, nostack));
+    }
+}
+
+// GS base for code running before cr4_init() or on CPUs without FSGSBASE
+pub fn write_gs_base(val: u64) {
+    if read_cr4().contains(CR4Flags::FSGSBASE) {
+        wrgsbase(val);
+    } else {
+        write_msr(MSR_GS_BASE, val);
+    }
+}
@@ -0,0 +1,176 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2026 SUSE LLC
+//
+// Author: agent <agent@local>
+
+use crate::types::VirtAddr;
+use bitflags::bitflags;
+use core::arch::asm;
+
+bitflags! {
+    pub struct DR7Flags: u64 {
+        const L0    = 1 << 0;  // Local enable breakpoint 0
+        const G0    = 1 << 1;  // Global enable breakpoint 0
+        const L1    = 1 << 2;  // Local enable breakpoint 1
+        const G1    = 1 << 3;  // Global enable breakpoint 1
+        const L2    = 1 << 4;  // Local enable breakpoint 2
+        const G2    = 1 << 5;  // Global enable breakpoint 2
+        const L3    = 1 << 6;  // Local enable breakpoint 3
+        const G3    = 1 << 7;  // Global enable breakpoint 3
+        const LE    = 1 << 8;  // Local exact breakpoint enable
+        const GE    = 1 << 9;  // Global exact breakpoint enable
+        const GD    = 1 << 13; // General detect enable
+        const RW0   = 3 << 16; // Breakpoint 0 condition
+        const LEN0  = 3 << 18; // Breakpoint 0 length
+        const RW1   = 3 << 20; // Breakpoint 1 condition
+        const LEN1  = 3 << 22; // Breakpoint 1 length
+        const RW2   = 3 << 24; // Breakpoint 2 condition
+        const LEN2  = 3 << 26; // Breakpoint 2 length
+        const RW3   = 3 << 28; // Breakpoint 3 condition
+        const LEN3  = 3 << 30; // Breakpoint 3 length
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BreakKind {
+    Execute,
+    Write,
+    ReadWrite,
+}
+
+impl BreakKind {
+    fn bits(self) -> u64 {
+        match self {
+            BreakKind::Execute => 0b00,
+            BreakKind::Write => 0b01,
+            BreakKind::ReadWrite => 0b11,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BreakLen {
+    Len1,
+    Len2,
+    Len4,
+    Len8,
+}
+
+impl BreakLen {
+    fn bits(self) -> u64 {
+        match self {
+            BreakLen::Len1 => 0b00,
+            BreakLen::Len2 => 0b01,
+            BreakLen::Len8 => 0b10,
+            BreakLen::Len4 => 0b11,
+        }
+    }
+
+    fn size(self) -> usize {
+        match self {
+            BreakLen::Len1 => 1,
+            BreakLen::Len2 => 2,
+            BreakLen::Len4 => 4,
+            BreakLen::Len8 => 8,
+        }
+    }
+}
+
+pub fn read_dr7() -> DR7Flags {
+    let dr7: u64;
+
+    unsafe {
+        asm!("mov %dr7, %rax",
+             out("rax") dr7,
+             options(att_syntax));
+    }
+
+    DR7Flags::from_bits_truncate(dr7)
+}
+
+pub fn write_dr7(dr7: DR7Flags) {
+    let reg = dr7.bits();
+
+    unsafe {
+        asm!("mov %rax, %dr7",
+             in("rax") reg,
+             options(att_syntax));
+    }
+}
+
+pub fn read_dr6() -> u64 {
+    let ret: u64;
+
+    unsafe {
+        asm!("mov %dr6, %rax",
+             out("rax") ret,
+             options(att_syntax));
+    }
+
+    ret
+}
+
+pub fn write_dr6(dr6: u64) {
+    unsafe {
+        asm!("mov %rax, %dr6",
+             in("rax") dr6,
+             options(att_syntax));
+    }
+}
+
+fn write_dr_addr(index: u8, addr: VirtAddr) {
+    unsafe {
+        match index {
+            0 => asm!("mov %rax, %dr0", in("rax") addr.as_usize(), options(att_syntax)),
+            1 => asm!("mov %rax, %dr1", in("rax") addr.as_usize(), options(att_syntax)),
+            2 => asm!("mov %rax, %dr2", in("rax") addr.as_usize(), options(att_syntax)),
+            3 => asm!("mov %rax, %dr3", in("rax") addr.as_usize(), options(att_syntax)),
+            _ => unreachable!(),
+        }
+    }
+}
+
+pub fn set_hw_breakpoint(
+    index: u8,
+    addr: VirtAddr,
+    kind: BreakKind,
+    len: BreakLen,
+) -> Result<(), ()> {
+    if index >= 4 {
+        return Err(());
+    }
+
+    // Instruction breakpoints must use a length of one byte, data
+    // breakpoints must be naturally aligned to their length.
+    if (kind == BreakKind::Execute && len != BreakLen::Len1) || !addr.is_aligned(len.size()) {
+        return Err(());
+    }
+
+    let shift = 16 + 4 * index as u64;
+    let field = (kind.bits() | (len.bits() << 2)) << shift;
+    let enable = 1u64 << (2 * index as u64);
+
+    let mut dr7 = read_dr7().bits();
+    dr7 &= !((0xfu64 << shift) | enable);
+
+    write_dr_addr(index, addr);
+    write_dr7(DR7Flags::from_bits_truncate(dr7 | field | enable));
+
+    Ok(())
+}
+
+pub fn clear_hw_breakpoint(index: u8) -> Result<(), ()> {
+    if index >= 4 {
+        return Err(());
+    }
+
+    let shift = 16 + 4 * index as u64;
+    let enable = 3u64 << (2 * index as u64);
+
+    let dr7 = read_dr7().bits() & !((0xfu64 << shift) | enable);
+    write_dr7(DR7Flags::from_bits_truncate(dr7));
+    write_dr_addr(index, VirtAddr::null());
+
+    Ok(())
+}
@@ -21,12 +21,12 @@ struct ExceptionTableEntry {
 
 fn check_exception_table(rip: usize) -> usize {
     unsafe {
-        let ex_table_start: VirtAddr = (&exception_table_start as *const u8) as VirtAddr;
-        let ex_table_end: VirtAddr = (&exception_table_end as *const u8) as VirtAddr;
+        let ex_table_start: VirtAddr = VirtAddr::from_ptr(&exception_table_start as *const u8);
+        let ex_table_end: VirtAddr = VirtAddr::from_ptr(&exception_table_end as *const u8);
         let mut current = ex_table_start;
 
         loop {
-            let addr = current as *const ExceptionTableEntry;
+            let addr = current.as_ptr::<ExceptionTableEntry>();
 
             let start = (*addr).start;
             let end = (*addr).end;
@@ -47,12 +47,12 @@ fn check_exception_table(rip: usize) -> usize {
 
 pub fn dump_exception_table() {
     unsafe {
-        let ex_table_start: VirtAddr = (&exception_table_start as *const u8) as VirtAddr;
-        let ex_table_end: VirtAddr = (&exception_table_end as *const u8) as VirtAddr;
+        let ex_table_start: VirtAddr = VirtAddr::from_ptr(&exception_table_start as *const u8);
+        let ex_table_end: VirtAddr = VirtAddr::from_ptr(&exception_table_end as *const u8);
         let mut current = ex_table_start;
 
         loop {
-            let addr = current as *const ExceptionTableEntry;
+            let addr = current.as_ptr::<ExceptionTableEntry>();
 
             let start = (*addr).start;
             let end = (*addr).end;
@@ -5,24 +5,237 @@
 // Author: Joerg Roedel <jroedel@suse.de>
 
 use super::cpuid::cpuid_table;
+use crate::sev::msr_protocol::{
+    ghcb_terminate, GHCB_TERM_SET_GENERAL, GHCB_TERM_UNSUPPORTED_FEATURES,
+};
+use crate::utils::immut_after_init::ImmutAfterInitCell;
 
+// CPUID Fn8000_0001 EDX
 const X86_FEATURE_NX: u32 = 20;
+const X86_FEATURE_GBPAGES: u32 = 26;
+
+// CPUID Fn0000_0001 EDX
 const X86_FEATURE_PGE: u32 = 13;
 
-pub fn cpu_has_nx() -> bool {
-    let ret = cpuid_table(0x80000001);
+// CPUID Fn0000_0001 ECX
+const X86_FEATURE_PCID: u32 = 17;
+const X86_FEATURE_X2APIC: u32 = 21;
+const X86_FEATURE_XSAVE: u32 = 26;
+
+// CPUID Fn0000_0007_x0 EBX
+const X86_FEATURE_FSGSBASE: u32 = 0;
+const X86_FEATURE_SMEP: u32 = 7;
+const X86_FEATURE_SMAP: u32 = 20;
+
+// CPUID Fn0000_0007_x0 ECX
+const X86_FEATURE_UMIP: u32 = 2;
+const X86_FEATURE_PKU: u32 = 3;
+const X86_FEATURE_CET_SS: u32 = 7;
+
+// Snapshot of the CPUID feature words the SVSM cares about. It is taken once
+// on the BSP, all feature checks go through it afterwards.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CpuFeatures {
+    fn1_ecx: u32,
+    fn1_edx: u32,
+    fn7_ebx: u32,
+    fn7_ecx: u32,
+    ext1_edx: u32,
+}
+
+fn bit(word: u32, bit: u32) -> bool {
+    (word >> bit) & 1 == 1
+}
+
+// Features the SVSM does not run without: NX keeps its data mappings
+// non-executable and PGE keeps its own mappings across CR3 switches
+const REQUIRED_CPU_FEATURES: CpuFeatures = CpuFeatures {
+    fn1_ecx: 0,
+    fn1_edx: 1 << X86_FEATURE_PGE,
+    fn7_ebx: 0,
+    fn7_ecx: 0,
+    ext1_edx: 1 << X86_FEATURE_NX,
+};
+
+impl CpuFeatures {
+    pub fn read() -> Self {
+        let fn1 = cpuid_table(0x00000001);
+        let fn7 = cpuid_table(0x00000007);
+        let ext1 = cpuid_table(0x80000001);
+
+        CpuFeatures {
+            fn1_ecx: fn1.as_ref().map_or(0, |c| c.ecx),
+            fn1_edx: fn1.as_ref().map_or(0, |c| c.edx),
+            fn7_ebx: fn7.as_ref().map_or(0, |c| c.ebx),
+            fn7_ecx: fn7.as_ref().map_or(0, |c| c.ecx),
+            ext1_edx: ext1.as_ref().map_or(0, |c| c.edx),
+        }
+    }
+
+    // The features set in required but not in self
+    pub fn missing(&self, required: &CpuFeatures) -> CpuFeatures {
+        CpuFeatures {
+            fn1_ecx: required.fn1_ecx & !self.fn1_ecx,
+            fn1_edx: required.fn1_edx & !self.fn1_edx,
+            fn7_ebx: required.fn7_ebx & !self.fn7_ebx,
+            fn7_ecx: required.fn7_ecx & !self.fn7_ecx,
+            ext1_edx: required.ext1_edx & !self.ext1_edx,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == CpuFeatures::default()
+    }
+
+    pub fn has_nx(&self) -> bool {
+        bit(self.ext1_edx, X86_FEATURE_NX)
+    }
+
+    pub fn has_gbpages(&self) -> bool {
+        bit(self.ext1_edx, X86_FEATURE_GBPAGES)
+    }
 
-    match ret {
-        None => false,
-        Some(c) => (c.edx >> X86_FEATURE_NX) & 1 == 1,
+    pub fn has_pge(&self) -> bool {
+        bit(self.fn1_edx, X86_FEATURE_PGE)
+    }
+
+    pub fn has_pcid(&self) -> bool {
+        bit(self.fn1_ecx, X86_FEATURE_PCID)
+    }
+
+    pub fn has_x2apic(&self) -> bool {
+        bit(self.fn1_ecx, X86_FEATURE_X2APIC)
+    }
+
+    pub fn has_xsave(&self) -> bool {
+        bit(self.fn1_ecx, X86_FEATURE_XSAVE)
+    }
+
+    pub fn has_fsgsbase(&self) -> bool {
+        bit(self.fn7_ebx, X86_FEATURE_FSGSBASE)
+    }
+
+    pub fn has_smep(&self) -> bool {
+        bit(self.fn7_ebx, X86_FEATURE_SMEP)
+    }
+
+    pub fn has_smap(&self) -> bool {
+        bit(self.fn7_ebx, X86_FEATURE_SMAP)
+    }
+
+    pub fn has_umip(&self) -> bool {
+        bit(self.fn7_ecx, X86_FEATURE_UMIP)
+    }
+
+    pub fn has_pku(&self) -> bool {
+        bit(self.fn7_ecx, X86_FEATURE_PKU)
+    }
+
+    pub fn has_cet_ss(&self) -> bool {
+        bit(self.fn7_ecx, X86_FEATURE_CET_SS)
     }
 }
 
-pub fn cpu_has_pge() -> bool {
-    let ret = cpuid_table(0x00000001);
+static CPU_FEATURES: ImmutAfterInitCell<CpuFeatures> = ImmutAfterInitCell::uninit();
+
+// Must be called on the BSP once the CPUID table is registered and before
+// any of the cpu_has_*() functions is used. There might be no console yet,
+// so a CPU lacking required features terminates the guest right away.
+pub fn init_cpu_features() {
+    let features = CpuFeatures::read();
 
-    match ret {
-        None => false,
-        Some(c) => (c.edx >> X86_FEATURE_PGE) & 1 == 1,
+    if verify_cpu_features(&features).is_err() {
+        ghcb_terminate(GHCB_TERM_SET_GENERAL, GHCB_TERM_UNSUPPORTED_FEATURES);
     }
+
+    unsafe { CPU_FEATURES.init(&features) };
+}
+
+pub fn cpu_features() -> &'static CpuFeatures {
+    &CPU_FEATURES
+}
+
+// Returns the required features the given CPUID values lack
+pub fn verify_cpu_features(features: &CpuFeatures) -> Result<(), CpuFeatures> {
+    let missing = features.missing(&REQUIRED_CPU_FEATURES);
+
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(missing)
+    }
+}
+
+pub fn cpu_has_nx() -> bool {
+    cpu_features().has_nx()
+}
+
+pub fn cpu_has_gbpages() -> bool {
+    cpu_features().has_gbpages()
+}
+
+pub fn cpu_has_pge() -> bool {
+    cpu_features().has_pge()
+}
+
+pub fn cpu_has_pcid() -> bool {
+    cpu_features().has_pcid()
+}
+
+pub fn cpu_has_x2apic() -> bool {
+    cpu_features().has_x2apic()
+}
+
+pub fn cpu_has_xsave() -> bool {
+    cpu_features().has_xsave()
+}
+
+pub fn cpu_has_fsgsbase() -> bool {
+    cpu_features().has_fsgsbase()
+}
+
+pub fn cpu_has_smep() -> bool {
+    cpu_features().has_smep()
+}
+
+pub fn cpu_has_smap() -> bool {
+    cpu_features().has_smap()
+}
+
+pub fn cpu_has_umip() -> bool {
+    cpu_features().has_umip()
+}
+
+pub fn cpu_has_pku() -> bool {
+    cpu_features().has_pku()
+}
+
+pub fn cpu_has_cet_ss() -> bool {
+    cpu_features().has_cet_ss()
+}
+
+#[test]
+fn test_cpu_features_bits() {
+    let features = CpuFeatures {
+        fn1_ecx: 1 << X86_FEATURE_PCID,
+        fn1_edx: 1 << X86_FEATURE_PGE,
+        fn7_ebx: 1 << X86_FEATURE_SMEP,
+        fn7_ecx: 0,
+        ext1_edx: (1 << X86_FEATURE_NX) | (1 << X86_FEATURE_GBPAGES),
+    };
+
+    assert!(features.has_pcid() && features.has_pge() && features.has_smep());
+    assert!(features.has_nx() && features.has_gbpages());
+    assert!(!features.has_xsave() && !features.has_smap() && !features.has_pku());
+}
+
+#[test]
+fn test_verify_cpu_features() {
+    let mut features = REQUIRED_CPU_FEATURES;
+    features.fn1_ecx = 1 << X86_FEATURE_PCID;
+    assert_eq!(verify_cpu_features(&features), Ok(()));
+
+    features.ext1_edx = 0;
+    let missing = verify_cpu_features(&features).unwrap_err();
+    assert!(missing.has_nx() && !missing.has_pge() && !missing.has_pcid());
 }
@@ -5,7 +5,10 @@
 // Author: Joerg Roedel <jroedel@suse.de>
 
 use super::tss::{X86Tss, TSS_LIMIT};
-use crate::types::{VirtAddr, SVSM_CS, SVSM_DS, SVSM_TSS};
+use crate::types::{
+    VirtAddr, SVSM_CS, SVSM_CS_FLAGS, SVSM_DS, SVSM_DS_FLAGS, SVSM_TR_FLAGS, SVSM_TSS,
+    SVSM_USER_CS, SVSM_USER_CS_FLAGS, SVSM_USER_DS, SVSM_USER_DS_FLAGS,
+};
 use core::arch::asm;
 use core::mem;
 
@@ -17,40 +20,65 @@ pub struct GdtDesc {
 
 const GDT_SIZE: u16 = 8;
 
-static mut GDT: [u64; GDT_SIZE as usize] = [
-    0,
-    0x00af9a000000ffff, // 64-bit code segment
-    0x00cf92000000ffff, // 64-bit data segment
-    0,                  // Reserved for User code
-    0,                  // Reserver for User data
-    0,                  // Reverved
-    0,                  // TSS
-    0,                  // TSS continued
-];
+const SEGMENT_LIMIT: u64 = 0xfffff;
 
-pub fn load_tss(tss: &X86Tss) {
-    let addr = (tss as *const X86Tss) as u64;
+const fn gdt_index(selector: u16) -> usize {
+    (selector / 8) as usize
+}
+
+// The *_FLAGS values use the VMSA segment attribute format: bits 0-7 are
+// the descriptor access byte and bits 8-11 the G, D/B, L and AVL nibble.
+const fn segment_descriptor(flags: u16, base: u64, limit: u64) -> u64 {
+    let flags = flags as u64;
+
+    (limit & 0xffff)
+        | ((base & 0x00ff_ffff) << 16)
+        | ((flags & 0xff) << 40)
+        | (((limit >> 16) & 0xf) << 48)
+        | (((flags >> 8) & 0xf) << 52)
+        | ((base & 0xff00_0000) << 32)
+}
+
+const fn descriptor_flags(desc: u64) -> u16 {
+    (((desc >> 40) & 0xff) | (((desc >> 52) & 0xf) << 8)) as u16
+}
 
-    let mut desc0: u64 = 0;
-    let mut desc1: u64 = 0;
+const fn build_gdt() -> [u64; GDT_SIZE as usize] {
+    let mut gdt = [0u64; GDT_SIZE as usize];
 
-    // Limit
-    desc0 |= TSS_LIMIT & 0xffffu64;
-    desc0 |= ((TSS_LIMIT >> 16) & 0xfu64) << 48;
+    gdt[gdt_index(SVSM_CS)] = segment_descriptor(SVSM_CS_FLAGS, 0, SEGMENT_LIMIT);
+    gdt[gdt_index(SVSM_DS)] = segment_descriptor(SVSM_DS_FLAGS, 0, SEGMENT_LIMIT);
+    gdt[gdt_index(SVSM_USER_CS)] = segment_descriptor(SVSM_USER_CS_FLAGS, 0, SEGMENT_LIMIT);
+    gdt[gdt_index(SVSM_USER_DS)] = segment_descriptor(SVSM_USER_DS_FLAGS, 0, SEGMENT_LIMIT);
+    // The TSS descriptor is filled in by load_tss()
 
-    // Address
-    desc0 |= (addr & 0x00ff_ffffu64) << 16;
-    desc0 |= (addr & 0xff00_0000u64) << 32;
-    desc1 |= (addr >> 32) as u64;
+    gdt
+}
 
-    // Present
-    desc0 |= 1u64 << 47;
+// Catch selectors and flags in types.rs which don't match the GDT layout
+const _: () = {
+    let gdt = build_gdt();
 
-    // Type
-    desc0 |= 0x9u64 << 40;
+    assert!(gdt[0] == 0);
+    assert!(descriptor_flags(gdt[gdt_index(SVSM_CS)]) == SVSM_CS_FLAGS);
+    assert!(descriptor_flags(gdt[gdt_index(SVSM_DS)]) == SVSM_DS_FLAGS);
+    assert!(descriptor_flags(gdt[gdt_index(SVSM_USER_CS)]) == SVSM_USER_CS_FLAGS);
+    assert!(descriptor_flags(gdt[gdt_index(SVSM_USER_DS)]) == SVSM_USER_DS_FLAGS);
+    // The 16 byte TSS descriptor must fit and not overlap the others
+    assert!(gdt_index(SVSM_TSS) + 1 < GDT_SIZE as usize);
+    assert!(gdt[gdt_index(SVSM_TSS)] == 0 && gdt[gdt_index(SVSM_TSS) + 1] == 0);
+};
+
+static mut GDT: [u64; GDT_SIZE as usize] = build_gdt();
+
+pub fn load_tss(tss: &X86Tss) {
+    let addr = (tss as *const X86Tss) as u64;
+
+    let desc0 = segment_descriptor(SVSM_TR_FLAGS, addr, TSS_LIMIT);
+    let desc1 = addr >> 32;
 
     unsafe {
-        let idx = (SVSM_TSS / 8) as usize;
+        let idx = gdt_index(SVSM_TSS);
         GDT[idx + 0] = desc0;
         GDT[idx + 1] = desc1;
 
@@ -58,7 +86,10 @@ pub fn load_tss(tss: &X86Tss) {
     }
 }
 
-static mut GDT_DESC: GdtDesc = GdtDesc { size: 0, addr: 0 };
+static mut GDT_DESC: GdtDesc = GdtDesc {
+    size: 0,
+    addr: VirtAddr::null(),
+};
 
 pub fn gdt_base_limit() -> (u64, u32) {
     unsafe {
@@ -71,7 +102,7 @@ pub fn gdt_base_limit() -> (u64, u32) {
 
 pub fn load_gdt() {
     unsafe {
-        let vaddr = GDT.as_ptr() as VirtAddr;
+        let vaddr = VirtAddr::from_ptr(GDT.as_ptr());
 
         GDT_DESC.addr = vaddr;
         GDT_DESC.size = (GDT_SIZE * 8) - 1;
@@ -99,3 +130,20 @@ pub fn load_gdt() {
             options(att_syntax));
     }
 }
+
+#[test]
+fn test_segment_descriptor() {
+    assert_eq!(
+        segment_descriptor(SVSM_DS_FLAGS, 0, SEGMENT_LIMIT),
+        0x00cf_9300_0000_ffff
+    );
+    assert_eq!(
+        segment_descriptor(SVSM_CS_FLAGS, 0, SEGMENT_LIMIT),
+        0x002f_9b00_0000_ffff
+    );
+
+    let desc = segment_descriptor(SVSM_TR_FLAGS, 0xffff_8000_1234_5678, TSS_LIMIT);
+    assert_eq!(descriptor_flags(desc), SVSM_TR_FLAGS);
+    assert_eq!((desc >> 16) & 0xff_ffff, 0x34_5678);
+    assert_eq!(desc >> 56, 0x12);
+}
@@ -4,17 +4,22 @@
 //
 // Author: Joerg Roedel <jroedel@suse.de>
 
-use super::control_regs::read_cr2;
-use super::tss::IST_DF;
+use super::control_regs::{page_fault_info, read_cr2};
+use super::ipi::{cpus_stopping, handle_wakeup_ipi, IPI_WAKEUP_VECTOR};
+use super::tss::{IST_DF, IST_VC};
 use super::vc::handle_vc_exception;
 use crate::cpu::extable::handle_exception_table;
+use crate::cpu::percpu::{this_cpu, this_cpu_mut};
+use crate::mm::stack::stack_guard_hit;
 use crate::types::{VirtAddr, SVSM_CS};
+use crate::utils::halt;
 use core::arch::{asm, global_asm};
 use core::mem;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 pub const _DE_VECTOR: usize = 0;
 pub const _DB_VECTOR: usize = 1;
-pub const _NMI_VECTOR: usize = 2;
+pub const NMI_VECTOR: usize = 2;
 pub const _BP_VECTOR: usize = 3;
 pub const _OF_VECTOR: usize = 4;
 pub const _BR_VECTOR: usize = 5;
@@ -84,15 +89,20 @@ const IDT_CS_SHIFT: u64 = 16;
 const IDT_IST_MASK: u64 = 0x7;
 const IDT_IST_SHIFT: u64 = 32;
 
+const IDT_DPL_MASK: u64 = 0x3;
+const IDT_DPL_SHIFT: u64 = 45;
+
 impl IdtEntry {
-    const fn create(target: VirtAddr, cs: u16, ist: u8) -> Self {
-        let vaddr = target as u64;
+    const fn create(target: VirtAddr, cs: u16, ist: u8, dpl: u8) -> Self {
+        let vaddr = target.as_usize() as u64;
         let cs_mask = (cs as u64) << IDT_CS_SHIFT;
         let ist_mask = ((ist as u64) & IDT_IST_MASK) << IDT_IST_SHIFT;
+        let dpl_mask = ((dpl as u64) & IDT_DPL_MASK) << IDT_DPL_SHIFT;
         let low = (vaddr & IDT_TARGET_MASK_1) << IDT_TARGET_MASK_1_SHIFT
             | (vaddr & IDT_TARGET_MASK_2) << IDT_TARGET_MASK_2_SHIFT
             | IDT_TYPE_MASK
             | IDT_PRESENT_MASK
+            | dpl_mask
             | cs_mask
             | ist_mask;
         let high = (vaddr & IDT_TARGET_MASK_3) >> IDT_TARGET_MASK_3_SHIFT;
@@ -103,14 +113,6 @@ impl IdtEntry {
         }
     }
 
-    pub const fn entry(target: VirtAddr) -> Self {
-        IdtEntry::create(target, SVSM_CS, 0)
-    }
-
-    pub const fn ist_entry(target: VirtAddr, ist: u8) -> Self {
-        IdtEntry::create(target, SVSM_CS, ist)
-    }
-
     pub const fn no_handler() -> Self {
         IdtEntry { low: 0, high: 0 }
     }
@@ -121,109 +123,252 @@ const IDT_ENTRIES: usize = 256;
 #[repr(C, packed)]
 struct IdtDesc {
     size: u16,
-    address: usize,
+    address: VirtAddr,
 }
 
 extern "C" {
     static idt_handler_array: u8;
 }
 
-type Idt = [IdtEntry; IDT_ENTRIES];
+// Entry stub for vector, each stub in idt_handler_array is 32 bytes in size
+fn default_entry(vector: usize) -> VirtAddr {
+    VirtAddr::from_ptr(unsafe { &idt_handler_array } as *const u8) + (32 * vector)
+}
+
+#[repr(C)]
+pub struct Idt {
+    entries: [IdtEntry; IDT_ENTRIES],
+}
 
-static mut GLOBAL_IDT: Idt = [IdtEntry::no_handler(); IDT_ENTRIES];
+impl Idt {
+    pub const fn new() -> Self {
+        Idt {
+            entries: [IdtEntry::no_handler(); IDT_ENTRIES],
+        }
+    }
 
-pub fn idt_base_limit() -> (u64, u32) {
-    unsafe {
-        let base = (&GLOBAL_IDT as *const Idt) as u64;
+    // Installs an interrupt gate for vector targeting handler in the SVSM
+    // code segment. An ist_index of 0 keeps the current stack.
+    pub fn set_handler(
+        &mut self,
+        vector: usize,
+        handler: VirtAddr,
+        ist_index: u8,
+        dpl: u8,
+    ) -> &mut Self {
+        self.entries[vector] = IdtEntry::create(handler, SVSM_CS, ist_index, dpl);
+        self
+    }
+
+    // Points all vectors to the generic entry stubs, which dispatch to
+    // generic_idt_handler()
+    pub fn set_default_handlers(&mut self) -> &mut Self {
+        for vector in 0..IDT_ENTRIES {
+            self.set_handler(vector, default_entry(vector), 0, 0);
+        }
+        self
+    }
+
+    // #DF and #VC get dedicated stacks, which requires the TSS to be set up
+    pub fn set_ist_handlers(&mut self) -> &mut Self {
+        // The #VC handler must not issue instructions raising another #VC, as
+        // a nested #VC would start over at the top of the same IST stack
+        self.set_handler(DF_VECTOR, default_entry(DF_VECTOR), IST_DF as u8, 0)
+            .set_handler(VC_VECTOR, default_entry(VC_VECTOR), IST_VC as u8, 0)
+    }
+
+    pub fn base_limit(&self) -> (u64, u32) {
+        let base = (self as *const Idt) as u64;
         let limit = (IDT_ENTRIES * mem::size_of::<IdtEntry>()) as u32;
         (base, limit)
     }
-}
 
-fn init_idt(idt: &mut Idt) {
-    // Set IDT handlers
-    for i in 0..IDT_ENTRIES {
+    pub fn load(&self) {
+        let desc: IdtDesc = IdtDesc {
+            size: (IDT_ENTRIES * 16) as u16,
+            address: VirtAddr::from_ptr(self.entries.as_ptr()),
+        };
+
         unsafe {
-            let handler = ((&idt_handler_array as *const u8) as VirtAddr) + (32 * i);
-            idt[i] = IdtEntry::entry(handler);
+            asm!("lidt (%rax)", in("rax") &desc, options(att_syntax));
         }
     }
 }
 
-unsafe fn init_ist_vectors(idt: &mut Idt) {
-    let handler = ((&idt_handler_array as *const u8) as VirtAddr) + (32 * DF_VECTOR);
-    idt[DF_VECTOR] = IdtEntry::ist_entry(handler, IST_DF.try_into().unwrap());
+impl Default for Idt {
+    fn default() -> Self {
+        Idt::new()
+    }
 }
 
-fn load_idt(idt: &Idt) {
-    let desc: IdtDesc = IdtDesc {
-        size: (IDT_ENTRIES * 16) as u16,
-        address: idt.as_ptr() as VirtAddr,
-    };
+static mut GLOBAL_IDT: Idt = Idt::new();
 
-    unsafe {
-        asm!("lidt (%rax)", in("rax") &desc, options(att_syntax));
-    }
+pub fn idt_base_limit() -> (u64, u32) {
+    unsafe { GLOBAL_IDT.base_limit() }
 }
 
 pub fn early_idt_init() {
     unsafe {
-        init_idt(&mut GLOBAL_IDT);
-        load_idt(&GLOBAL_IDT);
+        GLOBAL_IDT.set_default_handlers().load();
     }
 }
 
 pub fn idt_init() {
     // Set IST vectors
     unsafe {
-        init_ist_vectors(&mut GLOBAL_IDT);
+        GLOBAL_IDT.set_ist_handlers();
     }
 }
 
-#[no_mangle]
-fn generic_idt_handler(regs: &mut X86Regs) {
-    if regs.vector == DF_VECTOR {
-        let cr2 = read_cr2();
-        let rip = regs.rip;
-        let rsp = regs.rsp;
+// Loads the SVSM IDT on the current CPU
+pub fn load_idt() {
+    unsafe {
+        GLOBAL_IDT.load();
+    }
+}
+
+pub type ExceptionHandler = fn(&mut X86Regs);
+
+// Handlers registered per vector take precedence over the built-in ones.
+// Function pointers are stored as usize, 0 means no handler.
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_HANDLER: AtomicUsize = AtomicUsize::new(0);
+static EXCEPTION_HANDLERS: [AtomicUsize; IDT_ENTRIES] = [NO_HANDLER; IDT_ENTRIES];
+
+pub fn register_exception_handler(vector: usize, handler: ExceptionHandler) -> Result<(), ()> {
+    let slot = EXCEPTION_HANDLERS.get(vector).ok_or(())?;
+
+    slot.compare_exchange(0, handler as usize, Ordering::AcqRel, Ordering::Acquire)
+        .map(|_| ())
+        .map_err(|_| ())
+}
+
+pub fn unregister_exception_handler(vector: usize) {
+    if let Some(slot) = EXCEPTION_HANDLERS.get(vector) {
+        slot.store(0, Ordering::Release);
+    }
+}
+
+fn registered_handler(vector: usize) -> Option<ExceptionHandler> {
+    let addr = EXCEPTION_HANDLERS.get(vector)?.load(Ordering::Acquire);
+
+    if addr == 0 {
+        None
+    } else {
+        // Only ever set from an ExceptionHandler in register_exception_handler()
+        Some(unsafe { mem::transmute::<usize, ExceptionHandler>(addr) })
+    }
+}
+
+fn handle_double_fault(regs: &mut X86Regs) {
+    let cr2 = read_cr2();
+    let rip = regs.rip;
+    let rsp = regs.rsp;
+
+    // A #PF on the stack guard escalates to #DF since the exception
+    // frame can not be pushed to the overflowed stack.
+    if stack_guard_hit(VirtAddr::from(cr2)) {
         panic!(
-            "Double-Fault at RIP {:#018x} RSP: {:#018x} CR2: {:#018x}",
-            rip, rsp, cr2
+            "Stack overflow on CPU {} at RIP {:#018x} RSP: {:#018x} CR2: {:#018x}",
+            this_cpu().get_apic_id(),
+            rip,
+            rsp,
+            cr2
+        );
+    }
+
+    panic!(
+        "Double-Fault at RIP {:#018x} RSP: {:#018x} CR2: {:#018x}",
+        rip, rsp, cr2
+    );
+}
+
+fn handle_general_protection(regs: &mut X86Regs) {
+    let rip = regs.rip;
+    let err = regs.error_code;
+
+    if !handle_exception_table(regs) {
+        panic!(
+            "Unhandled General-Protection-Fault at RIP {:#018x} error code: {:#018x}",
+            rip, err
         );
-    } else if regs.vector == GP_VECTOR {
-        let rip = regs.rip;
-        let err = regs.error_code;
-
-        if !handle_exception_table(regs) {
-            panic!(
-                "Unhandled General-Protection-Fault at RIP {:#018x} error code: {:#018x}",
-                rip, err
-            );
-        }
-    } else if regs.vector == PF_VECTOR {
-        let cr2 = read_cr2();
-        let rip = regs.rip;
-        let err = regs.error_code;
-
-        if !handle_exception_table(regs) {
-            panic!(
-                "Unhandled Page-Fault at RIP {:#018x} CR2: {:#018x} error code: {:#018x}",
-                rip, cr2, err
-            );
-        }
-    } else if regs.vector == VC_VECTOR {
-        handle_vc_exception(regs);
-    } else {
-        let err = regs.error_code;
-        let vec = regs.vector;
-        let rip = regs.rip;
-
-        if !handle_exception_table(regs) {
-            panic!(
-                "Unhandled exception {} RIP {:#018x} error code: {:#018x}",
-                vec, rip, err
-            );
-        }
+    }
+}
+
+fn handle_page_fault(regs: &mut X86Regs) {
+    let info = page_fault_info(regs.error_code as u64);
+    let rip = regs.rip;
+
+    if stack_guard_hit(VirtAddr::from(info.addr)) {
+        panic!(
+            "Stack overflow on CPU {} at RIP {:#018x} CR2: {:#018x}",
+            this_cpu().get_apic_id(),
+            rip,
+            info.addr
+        );
+    }
+
+    if !handle_exception_table(regs) {
+        panic!(
+            "Unhandled Page-Fault at RIP {:#018x} CR2: {:#018x} error code: {:#018x} ({}{}{}{}{})",
+            rip,
+            info.addr,
+            info.error_code,
+            if info.present {
+                "protection"
+            } else {
+                "not-present"
+            },
+            if info.write { " write" } else { " read" },
+            if info.user { " user" } else { "" },
+            if info.reserved { " reserved-bit" } else { "" },
+            if info.instr_fetch { " fetch" } else { "" }
+        );
+    }
+}
+
+fn handle_unknown_exception(regs: &mut X86Regs) {
+    let err = regs.error_code;
+    let vec = regs.vector;
+    let rip = regs.rip;
+
+    if !handle_exception_table(regs) {
+        panic!(
+            "Unhandled exception {} RIP {:#018x} error code: {:#018x}",
+            vec, rip, err
+        );
+    }
+}
+
+// Another CPU panicked or shuts down the SVSM. The NMI can interrupt any
+// code, including code holding the console locks, so nothing here may log
+// or take a lock. The CPU keeps its state for inspection. Going offline
+// acknowledges the stop to stop_all_other_cpus().
+fn handle_stop_nmi() -> ! {
+    this_cpu_mut().set_offline();
+
+    loop {
+        halt();
+    }
+}
+
+#[no_mangle]
+fn generic_idt_handler(regs: &mut X86Regs) {
+    let vector = regs.vector;
+
+    if let Some(handler) = registered_handler(vector) {
+        handler(regs);
+        return;
+    }
+
+    match vector {
+        NMI_VECTOR if cpus_stopping() => handle_stop_nmi(),
+        DF_VECTOR => handle_double_fault(regs),
+        GP_VECTOR => handle_general_protection(regs),
+        PF_VECTOR => handle_page_fault(regs),
+        VC_VECTOR => handle_vc_exception(regs),
+        v if v == IPI_WAKEUP_VECTOR as usize => handle_wakeup_ipi(),
+        _ => handle_unknown_exception(regs),
     }
 }
 
@@ -234,7 +379,7 @@ extern "C" {
 
 #[cfg(feature = "enable-stacktrace")]
 pub fn is_exception_handler_return_site(rip: VirtAddr) -> bool {
-    rip == unsafe { &generic_idt_handler_return } as *const u8 as VirtAddr
+    rip == VirtAddr::from_ptr(unsafe { &generic_idt_handler_return } as *const u8)
 }
 
 // Entry Code
@@ -289,9 +434,9 @@ global_asm!(
         .globl idt_handler_array
     idt_handler_array:
         i = 0
-        .rept 32
+        .rept 256
         .align 32
-        .if ((0x20027d00 >> i) & 1) == 0
+        .if i >= 32 || ((0x20027d00 >> i) & 1) == 0
         pushq   $0
         .endif
         pushq   $i  /* Vector Number */
@@ -301,3 +446,24 @@ global_asm!(
         "#,
     options(att_syntax)
 );
+
+#[test]
+fn test_register_exception_handler() {
+    fn handler(regs: &mut X86Regs) {
+        regs.rax = 0x42;
+    }
+
+    // Vector 200 is not used by the SVSM
+    assert!(registered_handler(200).is_none());
+    register_exception_handler(200, handler).unwrap();
+    assert!(register_exception_handler(200, handler).is_err());
+
+    let mut regs: X86Regs = unsafe { mem::zeroed() };
+    registered_handler(200).unwrap()(&mut regs);
+    let rax = regs.rax;
+    assert_eq!(rax, 0x42);
+
+    unregister_exception_handler(200);
+    assert!(registered_handler(200).is_none());
+    assert!(register_exception_handler(IDT_ENTRIES, handler).is_err());
+}
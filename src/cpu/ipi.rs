@@ -0,0 +1,113 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2026 SUSE LLC
+//
+// Author: agent <agent@local>
+
+use crate::cpu::apic::{x2apic_enabled, MSR_X2APIC_EOI, MSR_X2APIC_ICR};
+use crate::cpu::percpu::{this_cpu, this_cpu_mut, PERCPU_AREAS};
+use crate::utils::idle_halt;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+// Sent by ring_doorbell() to a CPU waiting in wait_for_doorbell()
+pub const IPI_WAKEUP_VECTOR: u8 = 0xf0;
+
+// Set before the stop NMI goes out, NMIs are unexpected otherwise
+static CPUS_STOPPING: AtomicBool = AtomicBool::new(false);
+
+const ICR_DM_NMI: u64 = 4 << 8;
+const ICR_LEVEL_ASSERT: u64 = 1 << 14;
+const ICR_DEST_SELF: u64 = 1 << 18;
+const ICR_DEST_ALL_BUT_SELF: u64 = 3 << 18;
+
+// IPIs are sent through the x2APIC interrupt command register, the
+// destination is the x2APIC ID as returned by local_apic_id() on the target.
+fn write_icr(icr: u64) -> Result<(), ()> {
+    if !x2apic_enabled() {
+        return Err(());
+    }
+
+    this_cpu_mut()
+        .ghcb()
+        .wrmsr(MSR_X2APIC_ICR, icr)
+        .map_err(|_| ())
+}
+
+fn icr_fixed(vector: u8) -> u64 {
+    // Fixed delivery mode, physical destination mode
+    (vector as u64) | ICR_LEVEL_ASSERT
+}
+
+// Send a fixed IPI to the CPU with the given APIC-ID. Fails if no such CPU
+// has been set up by the SVSM.
+pub fn send_ipi(apic_id: u32, vector: u8) -> Result<(), ()> {
+    if PERCPU_AREAS.get(apic_id).is_none() {
+        return Err(());
+    }
+
+    write_icr(icr_fixed(vector) | ((apic_id as u64) << 32))
+}
+
+// Send a fixed IPI to all CPUs but the current one
+pub fn broadcast_ipi(vector: u8) -> Result<(), ()> {
+    write_icr(icr_fixed(vector) | ICR_DEST_ALL_BUT_SELF)
+}
+
+pub fn send_ipi_self(vector: u8) -> Result<(), ()> {
+    write_icr(icr_fixed(vector) | ICR_DEST_SELF)
+}
+
+// Halts all other CPUs for good. The stop is sent as NMI, so that it also
+// reaches CPUs running with interrupts disabled, which is everywhere but in
+// idle_halt().
+pub fn stop_other_cpus() -> Result<(), ()> {
+    CPUS_STOPPING.store(true, Ordering::Release);
+    write_icr(ICR_DM_NMI | ICR_LEVEL_ASSERT | ICR_DEST_ALL_BUT_SELF)
+}
+
+pub fn cpus_stopping() -> bool {
+    CPUS_STOPPING.load(Ordering::Acquire)
+}
+
+pub fn send_wakeup_ipi(apic_id: u32) -> Result<(), ()> {
+    send_ipi(apic_id, IPI_WAKEUP_VECTOR)
+}
+
+// Tells the CPU with the given APIC-ID to look for new work. A CPU halted in
+// wait_for_doorbell() is woken up, a CPU about to halt returns right away.
+pub fn ring_doorbell(apic_id: u32) -> Result<(), ()> {
+    let cpu = PERCPU_AREAS.get(apic_id).ok_or(())?;
+
+    cpu.set_doorbell();
+
+    if apic_id == this_cpu().get_apic_id() {
+        return Ok(());
+    }
+
+    send_wakeup_ipi(apic_id)
+}
+
+// Halts until the doorbell of this CPU rings or any other interrupt arrives.
+// Must be called with interrupts disabled, a doorbell rung after the caller
+// checked for work is not lost.
+pub fn wait_for_doorbell() {
+    if !this_cpu().take_doorbell() {
+        idle_halt();
+    }
+}
+
+// The doorbell was rung by the sender already. Clear it, the woken CPU
+// rechecks for work anyway.
+pub fn handle_wakeup_ipi() {
+    this_cpu().take_doorbell();
+    ack_ipi();
+}
+
+// Signal end-of-interrupt. Only safe to call from an interrupt handler which
+// interrupted idle_halt(), since the GHCB must not be in use.
+pub fn ack_ipi() {
+    this_cpu_mut()
+        .ghcb()
+        .wrmsr(MSR_X2APIC_EOI, 0)
+        .expect("Failed to write x2APIC EOI");
+}
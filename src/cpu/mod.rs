@@ -4,17 +4,24 @@
 //
 // Author: Joerg Roedel <jroedel@suse.de>
 
+pub mod apic;
+pub mod cache;
 pub mod control_regs;
 pub mod cpuid;
+pub mod debug_regs;
 pub mod efer;
 pub mod extable;
 pub mod features;
 pub mod gdt;
 pub mod idt;
+pub mod ipi;
 pub mod msr;
 pub mod percpu;
 pub mod smp;
+pub mod stats;
 pub mod tlb;
+pub mod topology;
+pub mod tsc;
 pub mod tss;
 pub mod vc;
 pub mod vmsa;
@@ -6,28 +6,41 @@
 
 extern crate alloc;
 
-use super::gdt::load_tss;
-use super::tss::{X86Tss, IST_DF};
+use super::apic::local_apic_id;
+use super::control_regs::write_gs_base;
+use super::gdt::{load_gdt, load_tss};
+use super::idt::load_idt;
+use super::stats::{CpuStats, CpuStatsSnapshot};
+use super::tlb::invlpg;
+use super::topology::CpuTopology;
+#[cfg(feature = "boot_timing")]
+use super::tsc::rdtsc;
+use super::tss::{X86Tss, IST_DF, IST_VC};
+use crate::acpi::tables::srat_proximity_domain;
 use crate::cpu::tss::TSS_LIMIT;
-use crate::cpu::vmsa::init_guest_vmsa;
+use crate::cpu::vmsa::{init_guest_vmsa, VmsaBuilder};
 use crate::locking::{LockGuard, RWLock, SpinLock};
-use crate::mm::alloc::{allocate_page, allocate_zeroed_page};
+use crate::mm::alloc::{allocate_zeroed_page, free_page};
+use crate::mm::early_alloc::{buddy_allocator_active, EarlyAllocator};
 use crate::mm::pagetable::{get_init_pgtable_locked, PageTable, PageTableRef};
-use crate::mm::stack::{allocate_stack_addr, stack_base_pointer};
+use crate::mm::stack::{allocate_stack_addr, free_stack_addr, stack_base_pointer};
 use crate::mm::{
-    virt_to_phys, SVSM_PERCPU_BASE, SVSM_PERCPU_CAA_BASE, SVSM_PERCPU_VMSA_BASE,
-    SVSM_STACKS_INIT_TASK, SVSM_STACK_IST_DF_BASE,
+    phys_to_virt, virt_to_phys, PerCPUPageMappingGuard, SVSM_PERCPU_BASE, SVSM_PERCPU_CAA_BASE,
+    SVSM_PERCPU_TEMP_2M_SLOTS, SVSM_PERCPU_TEMP_4K_SLOTS, SVSM_PERCPU_VMSA_BASE,
+    SVSM_STACKS_INIT_TASK, SVSM_STACK_IST_DF_BASE, SVSM_STACK_IST_VC_BASE,
 };
-use crate::sev::ghcb::GHCB;
-use crate::sev::utils::RMPFlags;
-use crate::sev::vmsa::{allocate_new_vmsa, VMSASegment, VMSA};
-use crate::types::{PhysAddr, VirtAddr};
+use crate::sev::caa::CallingArea;
+use crate::sev::ghcb::{allocate_ghcb, release_ghcb, GhcbError, GHCB};
+use crate::sev::msr_protocol::request_termination_msr;
+use crate::sev::vmsa::{
+    allocate_new_vmsa, free_vmsa, VMSASegment, VmsaBusy, VmsaGuard, VMPL_MAX, VMSA,
+};
+use crate::types::{PhysAddr, VirtAddr, PAGE_SIZE};
 use crate::types::{SVSM_TR_FLAGS, SVSM_TSS};
-use crate::utils::{page_align, page_offset};
 use alloc::vec::Vec;
-use core::cell::SyncUnsafeCell;
+use core::fmt;
 use core::ptr;
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 
 struct PerCpuInfo {
     apic_id: u32,
@@ -46,41 +59,241 @@ impl PerCpuInfo {
 // PERCPU areas virtual addresses into shared memory
 pub static PERCPU_AREAS: PerCpuAreas = PerCpuAreas::new();
 
-// We use a SyncUnsafeCell to allow for a static with interior
-// mutability. It is like an UnsafeCell except it implements Sync,
-// which allows the compiler to know that it is going to be accessed
-// from multiple threads, but synchronization is left to the user. In
-// our case we do not use any synchronization because writes to the
-// structure only occur at initialization, from CPU 0, and reads
-// should only occur after all writes are done.
+// Upper limit for cpu_index(), given by the size of CpuOnlineMask
+pub const MAX_CPUS: usize = 512;
+
+const CPU_MASK_WORDS: usize = MAX_CPUS / 64;
+
+// Set of online CPUs, indexed by cpu_index(). Allows to snapshot the online
+// CPUs without walking the per-cpu registry.
+pub struct CpuOnlineMask {
+    words: [AtomicU64; CPU_MASK_WORDS],
+}
+
+impl CpuOnlineMask {
+    pub const fn new() -> Self {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const EMPTY: AtomicU64 = AtomicU64::new(0);
+        CpuOnlineMask {
+            words: [EMPTY; CPU_MASK_WORDS],
+        }
+    }
+
+    pub fn set(&self, cpu_index: usize) {
+        self.words[cpu_index / 64].fetch_or(1u64 << (cpu_index % 64), Ordering::Release);
+    }
+
+    pub fn clear(&self, cpu_index: usize) {
+        self.words[cpu_index / 64].fetch_and(!(1u64 << (cpu_index % 64)), Ordering::Release);
+    }
+
+    pub fn is_set(&self, cpu_index: usize) -> bool {
+        (self.words[cpu_index / 64].load(Ordering::Acquire) >> (cpu_index % 64)) & 1 == 1
+    }
+
+    pub fn online_count(&self) -> usize {
+        self.words
+            .iter()
+            .map(|w| w.load(Ordering::Acquire).count_ones() as usize)
+            .sum()
+    }
+
+    // Calls f with the cpu_index() of each online CPU. Every word is read
+    // once, CPUs changing state meanwhile may or may not be reported.
+    pub fn for_each_online(&self, mut f: impl FnMut(usize)) {
+        for (i, word) in self.words.iter().enumerate() {
+            let mut bits = word.load(Ordering::Acquire);
+
+            while bits != 0 {
+                let bit = bits.trailing_zeros() as usize;
+                f(i * 64 + bit);
+                bits &= bits - 1;
+            }
+        }
+    }
+}
+
+// Prints the online CPUs as a list of ranges, e.g. "0-3,8"
+impl fmt::Display for CpuOnlineMask {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut sep = "";
+        let mut cpu = 0;
+
+        while cpu < MAX_CPUS {
+            if !self.is_set(cpu) {
+                cpu += 1;
+                continue;
+            }
+
+            let start = cpu;
+            while cpu + 1 < MAX_CPUS && self.is_set(cpu + 1) {
+                cpu += 1;
+            }
+
+            if start == cpu {
+                write!(f, "{}{}", sep, start)?;
+            } else {
+                write!(f, "{}{}-{}", sep, start, cpu)?;
+            }
+            sep = ",";
+            cpu += 1;
+        }
+
+        if sep.is_empty() {
+            write!(f, "none")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for CpuOnlineMask {
+    fn default() -> Self {
+        CpuOnlineMask::new()
+    }
+}
+
+pub static CPU_ONLINE_MASK: CpuOnlineMask = CpuOnlineMask::new();
+
+// Besides the list of areas in allocation order, an index keyed on the
+// APIC-ID makes lookups O(1). Both grow on demand, so their size follows
+// the number of CPUs actually present.
+struct PerCpuRegistry {
+    areas: Vec<PerCpuInfo>,
+    index: Vec<Option<usize>>,
+}
+
+// The registry is only written while CPUs are brought up, but looked up
+// from all CPUs, hence the RWLock. Only PerCpu areas of CPUs which never
+// ran are freed again, see PerCpu::free(), so references to them stay valid
+// after the lock is dropped.
 pub struct PerCpuAreas {
-    areas: SyncUnsafeCell<Vec<PerCpuInfo>>,
+    registry: RWLock<PerCpuRegistry>,
 }
 
 impl PerCpuAreas {
     const fn new() -> Self {
         Self {
-            areas: SyncUnsafeCell::new(Vec::new()),
+            registry: RWLock::new(PerCpuRegistry {
+                areas: Vec::new(),
+                index: Vec::new(),
+            }),
+        }
+    }
+
+    // Pre-size the registry for nr_cpus CPUs with APIC-IDs up to
+    // max_apic_id to avoid re-allocations during AP bring-up.
+    pub fn reserve(&self, nr_cpus: usize, max_apic_id: u32) -> Result<(), ()> {
+        let mut registry = self.registry.lock_write();
+        let index_len = max_apic_id as usize + 1;
+        let areas_len = registry.areas.len();
+        let index_cur = registry.index.len();
+
+        registry
+            .areas
+            .try_reserve(nr_cpus.saturating_sub(areas_len))
+            .map_err(|_| ())?;
+        registry
+            .index
+            .try_reserve(index_len.saturating_sub(index_cur))
+            .map_err(|_| ())?;
+
+        Ok(())
+    }
+
+    // Returns the index of the new area, which is the number of areas
+    // registered before it.
+    fn push(&self, info: PerCpuInfo) -> Result<usize, ()> {
+        let mut registry = self.registry.lock_write();
+        let slot = info.apic_id as usize;
+
+        if registry.areas.len() >= MAX_CPUS || registry.index.get(slot).is_some_and(|e| e.is_some())
+        {
+            return Err(());
+        }
+
+        if slot >= registry.index.len() {
+            let grow = slot + 1 - registry.index.len();
+            registry.index.try_reserve(grow).map_err(|_| ())?;
+            registry.index.resize(slot + 1, None);
+        }
+        registry.areas.try_reserve(1).map_err(|_| ())?;
+
+        let cpu_index = registry.areas.len();
+        registry.index[slot] = Some(cpu_index);
+        registry.areas.push(info);
+
+        Ok(cpu_index)
+    }
+
+    // Only the area registered last can be removed, so that the cpu_index()
+    // of all others stays the same
+    fn remove_last(&self, apic_id: u32) -> Result<(), ()> {
+        let mut registry = self.registry.lock_write();
+        let slot = apic_id as usize;
+
+        match registry.areas.last() {
+            Some(info) if info.apic_id == apic_id => {}
+            _ => return Err(()),
         }
+
+        registry.areas.pop();
+        registry.index[slot] = None;
+
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.registry.lock_read().areas.len()
     }
 
-    unsafe fn push(&self, info: PerCpuInfo) {
-        let ptr = self.areas.get().as_mut().unwrap();
-        ptr.push(info);
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Calls f for all PerCpu areas in allocation order, i.e. by
+    // cpu_index(). The registry is read-locked meanwhile, so f must not
+    // allocate new PerCpu areas.
+    pub fn for_each(&self, mut f: impl FnMut(&'static PerCpu)) {
+        let registry = self.registry.lock_read();
+
+        for info in registry.areas.iter() {
+            f(unsafe { info.addr.as_ptr::<PerCpu>().as_ref().unwrap() });
+        }
+    }
+
+    fn lookup(&self, apic_id: u32) -> Option<VirtAddr> {
+        let registry = self.registry.lock_read();
+
+        registry
+            .index
+            .get(apic_id as usize)
+            .copied()
+            .flatten()
+            .map(|i| registry.areas[i].addr)
     }
 
     // Fails if no such area exists or its address is NULL
     pub fn get(&self, apic_id: u32) -> Option<&'static PerCpu> {
-        // For this to not produce UB the only invariant we must
-        // uphold is that there are no mutations or mutable aliases
-        // going on when casting via as_ref(). This only happens via
-        // Self::push(), which is intentionally unsafe and private.
-        let ptr = unsafe { self.areas.get().as_ref().unwrap() };
-        ptr.iter().find(|info| info.apic_id == apic_id).map(|info| {
-            let ptr = info.addr as *const PerCpu;
+        self.lookup(apic_id).map(|addr| {
+            let ptr = addr.as_ptr::<PerCpu>();
             unsafe { ptr.as_ref().unwrap() }
         })
     }
+
+    /// Mutable access to the PerCpu structure of another CPU.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that no other reference to the PerCpu of
+    /// that CPU is alive while the returned one is used. This holds while
+    /// the target CPU is offline or has not been started yet.
+    pub unsafe fn get_mut(&self, apic_id: u32) -> Option<&'static mut PerCpu> {
+        self.lookup(apic_id).map(|addr| {
+            let ptr = addr.as_mut_ptr::<PerCpu>();
+            ptr.as_mut().unwrap()
+        })
+    }
 }
 
 #[derive(Copy, Clone)]
@@ -100,26 +313,36 @@ impl VmsaRef {
     }
 
     pub fn vmsa(&self) -> &mut VMSA {
-        let ptr: *mut VMSA = self.vaddr as *mut VMSA;
+        let ptr: *mut VMSA = self.vaddr.as_mut_ptr::<VMSA>();
         unsafe { ptr.as_mut().unwrap() }
     }
+
+    pub fn try_acquire(&self) -> Result<VmsaGuard, VmsaBusy> {
+        VMSA::try_acquire(self.vaddr)
+    }
 }
 
 struct IstStacks {
     double_fault_stack: Option<VirtAddr>,
+    vc_stack: Option<VirtAddr>,
 }
 
 impl IstStacks {
     const fn new() -> Self {
         IstStacks {
             double_fault_stack: None,
+            vc_stack: None,
         }
     }
 }
 
+// VMPL of the CAA announced in the secrets page
+pub const DEFAULT_CAA_VMPL: usize = 1;
+
 pub struct GuestVmsaRef {
     vmsa: Option<PhysAddr>,
-    caa: Option<PhysAddr>,
+    // One calling area per VMPL of the guest
+    caas: [Option<PhysAddr>; VMPL_MAX],
     generation: u64,
     gen_in_use: u64,
 }
@@ -128,7 +351,7 @@ impl GuestVmsaRef {
     pub const fn new() -> Self {
         GuestVmsaRef {
             vmsa: None,
-            caa: None,
+            caas: [None; VMPL_MAX],
             generation: 1,
             gen_in_use: 0,
         }
@@ -144,13 +367,18 @@ impl GuestVmsaRef {
     }
 
     pub fn update_caa(&mut self, paddr: Option<PhysAddr>) {
-        self.caa = paddr;
+        self.update_vmpl_caa(DEFAULT_CAA_VMPL, paddr);
+    }
+
+    pub fn update_vmpl_caa(&mut self, vmpl: usize, paddr: Option<PhysAddr>) {
+        assert!(vmpl > 0 && vmpl < VMPL_MAX);
+        self.caas[vmpl] = paddr;
         self.generation += 1;
     }
 
     pub fn update_vmsa_caa(&mut self, vmsa: Option<PhysAddr>, caa: Option<PhysAddr>) {
         self.vmsa = vmsa;
-        self.caa = caa;
+        self.caas[DEFAULT_CAA_VMPL] = caa;
         self.generation += 1;
     }
 
@@ -163,62 +391,262 @@ impl GuestVmsaRef {
     }
 
     pub fn caa_phys(&self) -> Option<PhysAddr> {
-        self.caa
+        self.vmpl_caa_phys(DEFAULT_CAA_VMPL)
+    }
+
+    pub fn vmpl_caa_phys(&self, vmpl: usize) -> Option<PhysAddr> {
+        self.caas.get(vmpl).copied().flatten()
     }
 }
 
+// Scratch slots used by with_temp_map(). They are taken from the top of the
+// per-cpu temporary 4k mapping area, the low slots are still used directly.
+const TEMP_MAP_SLOTS: usize = 8;
+const TEMP_MAP_SLOT_BASE: usize = SVSM_PERCPU_TEMP_4K_SLOTS - TEMP_MAP_SLOTS;
+
+// 2M slots handed out to map_phys(), taken from the top of the per-cpu
+// temporary 2M mapping area. Each bit in map_phys_slots tracks one slot.
+const MAP_PHYS_SLOTS: usize = 8;
+const MAP_PHYS_SLOT_BASE: usize = SVSM_PERCPU_TEMP_2M_SLOTS - MAP_PHYS_SLOTS;
+
+// ap_entry loads the AP stack pointer from offset 0, so the layout is fixed
+// and ap_stack_top must stay the first field.
+#[repr(C)]
 pub struct PerCpu {
+    ap_stack_top: u64,
     online: AtomicBool,
+    offline_requested: AtomicBool,
+    // Set by ring_doorbell(), tells the CPU to look for new work before it
+    // halts
+    doorbell: AtomicBool,
     apic_id: u32,
+    cpu_index: usize,
+    topology: CpuTopology,
+    // NUMA proximity domain as given in the SRAT
+    proximity_domain: Option<u32>,
     pgtbl: SpinLock<PageTableRef>,
+    // Pages for setting up this CPU before the buddy allocator is up
+    early_alloc: SpinLock<EarlyAllocator>,
     ghcb: *mut GHCB,
+    // Set by teardown_on_cpu() until the next bind_ghcb()
+    ghcb_torn_down: bool,
     init_stack: Option<VirtAddr>,
     ist: IstStacks,
     tss: X86Tss,
     svsm_vmsa: Option<VmsaRef>,
     guest_vmsa: SpinLock<GuestVmsaRef>,
+    // VMSAs allocated for the lower privileged VMPLs, indexed by VMPL
+    guest_vmsas: [Option<VmsaRef>; VMPL_MAX],
     reset_ip: u64,
+    temp_map_depth: AtomicUsize,
+    map_phys_slots: AtomicU64,
+    // PCIDs switch_address_space() loaded on this CPU, one bit per PCID
+    loaded_pcids: AtomicU64,
+    stats: CpuStats,
+    // TSC values at AP_CREATE and when the CPU reported online
+    #[cfg(feature = "boot_timing")]
+    ap_create_tsc: AtomicU64,
+    #[cfg(feature = "boot_timing")]
+    online_tsc: AtomicU64,
 }
 
 impl PerCpu {
     pub const fn new() -> Self {
         PerCpu {
+            ap_stack_top: 0,
             online: AtomicBool::new(false),
+            offline_requested: AtomicBool::new(false),
+            doorbell: AtomicBool::new(false),
             apic_id: 0,
+            cpu_index: 0,
+            topology: CpuTopology {
+                package_id: 0,
+                core_id: 0,
+                thread_id: 0,
+            },
+            proximity_domain: None,
             pgtbl: SpinLock::<PageTableRef>::new(PageTableRef::unset()),
+            early_alloc: SpinLock::new(EarlyAllocator::new()),
             ghcb: ptr::null_mut(),
+            ghcb_torn_down: false,
             init_stack: None,
             ist: IstStacks::new(),
             tss: X86Tss::new(),
             svsm_vmsa: None,
             guest_vmsa: SpinLock::new(GuestVmsaRef::new()),
+            guest_vmsas: [None; VMPL_MAX],
             reset_ip: 0xffff_fff0u64,
+            temp_map_depth: AtomicUsize::new(0),
+            map_phys_slots: AtomicU64::new(0),
+            loaded_pcids: AtomicU64::new(0),
+            stats: CpuStats::new(),
+            #[cfg(feature = "boot_timing")]
+            ap_create_tsc: AtomicU64::new(0),
+            #[cfg(feature = "boot_timing")]
+            online_tsc: AtomicU64::new(0),
         }
     }
 
     pub fn alloc(apic_id: u32) -> Result<*mut PerCpu, ()> {
         let vaddr = allocate_zeroed_page()?;
         unsafe {
-            let percpu = vaddr as *mut PerCpu;
+            let percpu = vaddr.as_mut_ptr::<PerCpu>();
             (*percpu) = PerCpu::new();
             (*percpu).apic_id = apic_id;
-            PERCPU_AREAS.push(PerCpuInfo::new(apic_id, vaddr));
+            (*percpu).proximity_domain = srat_proximity_domain(apic_id);
+            match PERCPU_AREAS.push(PerCpuInfo::new(apic_id, vaddr)) {
+                Ok(cpu_index) => (*percpu).cpu_index = cpu_index,
+                Err(()) => {
+                    free_page(vaddr);
+                    return Err(());
+                }
+            }
             Ok(percpu)
         }
     }
 
+    /// Gives back a PerCpu area from alloc() together with everything
+    /// setup() and alloc_svsm_vmsa() allocated for it.
+    ///
+    /// # Safety
+    ///
+    /// The CPU must never have run with the area, and no references to it
+    /// may be alive. percpu is invalid afterwards.
+    pub unsafe fn free(percpu: *mut PerCpu) {
+        let cpu = &mut *percpu;
+
+        cpu.free_svsm_vmsa();
+
+        let stacks = [
+            cpu.init_stack.take(),
+            cpu.ist.double_fault_stack.take(),
+            cpu.ist.vc_stack.take(),
+        ];
+        let mut pgtable = cpu.get_pgtable();
+        for stack in stacks.into_iter().flatten() {
+            free_stack_addr(stack, &mut pgtable);
+        }
+        pgtable.free_shared_clone();
+        drop(pgtable);
+
+        let vaddr = VirtAddr::from_ptr(percpu as *const PerCpu);
+        PERCPU_AREAS
+            .remove_last(cpu.apic_id)
+            .expect("Freed PerCpu is not the last one registered");
+        free_page(vaddr);
+    }
+
     pub fn set_online(&mut self) {
+        #[cfg(feature = "boot_timing")]
+        self.online_tsc.store(rdtsc(), Ordering::Relaxed);
         self.online.store(true, Ordering::Relaxed);
+        CPU_ONLINE_MASK.set(self.cpu_index);
+    }
+
+    pub fn set_offline(&mut self) {
+        self.offline_requested.store(false, Ordering::Relaxed);
+        CPU_ONLINE_MASK.clear(self.cpu_index);
+        self.online.store(false, Ordering::Release);
     }
 
     pub fn is_online(&self) -> bool {
         self.online.load(Ordering::Acquire)
     }
 
+    #[cfg(feature = "boot_timing")]
+    pub fn record_ap_create(&self) {
+        self.ap_create_tsc.store(rdtsc(), Ordering::Relaxed);
+    }
+
+    // TSC ticks from AP_CREATE until the CPU reported online, None while it
+    // is not online
+    #[cfg(feature = "boot_timing")]
+    pub fn bringup_ticks(&self) -> Option<u64> {
+        let create = self.ap_create_tsc.load(Ordering::Relaxed);
+
+        if !self.is_online() || create == 0 {
+            return None;
+        }
+
+        Some(
+            self.online_tsc
+                .load(Ordering::Relaxed)
+                .saturating_sub(create),
+        )
+    }
+
+    // Ask the CPU to leave its request loop. The CPU notices the request the
+    // next time it returns to the request loop.
+    pub fn request_offline(&self) {
+        self.offline_requested.store(true, Ordering::Release);
+    }
+
+    pub fn offline_requested(&self) -> bool {
+        self.offline_requested.load(Ordering::Acquire)
+    }
+
+    pub fn set_doorbell(&self) {
+        self.doorbell.store(true, Ordering::Release);
+    }
+
+    // Clears the doorbell, returns whether it was rung
+    pub fn take_doorbell(&self) -> bool {
+        self.doorbell.swap(false, Ordering::AcqRel)
+    }
+
     pub const fn get_apic_id(&self) -> u32 {
         self.apic_id
     }
 
+    // Dense index in allocation order: 0 for the BSP, then the APs in the
+    // order they were set up.
+    pub const fn cpu_index(&self) -> usize {
+        self.cpu_index
+    }
+
+    pub fn stats(&self) -> CpuStatsSnapshot {
+        self.stats.snapshot()
+    }
+
+    pub fn counters(&self) -> &CpuStats {
+        &self.stats
+    }
+
+    pub fn set_topology(&mut self, topology: CpuTopology) {
+        self.topology = topology;
+    }
+
+    pub const fn topology(&self) -> CpuTopology {
+        self.topology
+    }
+
+    pub fn set_proximity_domain(&mut self, domain: Option<u32>) {
+        self.proximity_domain = domain;
+    }
+
+    pub const fn proximity_domain(&self) -> Option<u32> {
+        self.proximity_domain
+    }
+
+    // The region is used for pages this CPU needs before
+    // promote_to_buddy_allocator()
+    pub fn seed_early_allocator(&self, start: PhysAddr, end: PhysAddr) {
+        self.early_alloc
+            .lock()
+            .seed(phys_to_virt(start), phys_to_virt(end));
+    }
+
+    pub fn freeze_early_allocator(&self) -> Option<(VirtAddr, VirtAddr)> {
+        self.early_alloc.lock().freeze()
+    }
+
+    // Pages needed during CPU setup come from the early allocator until
+    // promote_to_buddy_allocator() was called
+    fn allocate_early_page(&self) -> Result<VirtAddr, ()> {
+        assert!(!buddy_allocator_active());
+        self.early_alloc.lock().allocate_zeroed_page()
+    }
+
     fn allocate_page_table(&mut self) -> Result<(), ()> {
         let pgtable_ref = get_init_pgtable_locked().clone_shared()?;
         self.set_pgtable(pgtable_ref);
@@ -230,18 +658,22 @@ impl PerCpu {
         *my_pgtable = pgtable;
     }
 
+    // The stacks are recorded before they are allocated, so that free()
+    // also finds partially allocated ones
     fn allocate_init_stack(&mut self) -> Result<(), ()> {
-        allocate_stack_addr(SVSM_STACKS_INIT_TASK, &mut self.get_pgtable())
-            .expect("Failed to allocate per-cpu init stack");
         self.init_stack = Some(SVSM_STACKS_INIT_TASK);
+        allocate_stack_addr(SVSM_STACKS_INIT_TASK, &mut self.get_pgtable())?;
+        self.ap_stack_top = u64::from(self.get_top_of_stack());
         Ok(())
     }
 
     fn allocate_ist_stacks(&mut self) -> Result<(), ()> {
-        allocate_stack_addr(SVSM_STACK_IST_DF_BASE, &mut self.get_pgtable())
-            .expect("Failed to allocate percpu double-fault stack");
-
         self.ist.double_fault_stack = Some(SVSM_STACK_IST_DF_BASE);
+        allocate_stack_addr(SVSM_STACK_IST_DF_BASE, &mut self.get_pgtable())?;
+
+        self.ist.vc_stack = Some(SVSM_STACK_IST_VC_BASE);
+        allocate_stack_addr(SVSM_STACK_IST_VC_BASE, &mut self.get_pgtable())?;
+
         Ok(())
     }
 
@@ -249,14 +681,114 @@ impl PerCpu {
         self.pgtbl.lock()
     }
 
+    // Maps the page containing paddr into a scratch slot of this CPU, calls
+    // f with the virtual address of paddr and unmaps the page again. Slots
+    // are used as a stack, so with_temp_map() can be nested up to
+    // TEMP_MAP_SLOTS levels. Must only be called on the current CPU's PerCpu.
+    pub fn with_temp_map<T>(
+        &self,
+        paddr: PhysAddr,
+        f: impl FnOnce(VirtAddr) -> T,
+    ) -> Result<T, ()> {
+        let depth = self.temp_map_depth.load(Ordering::Relaxed);
+        if depth >= TEMP_MAP_SLOTS {
+            return Err(());
+        }
+        self.temp_map_depth.store(depth + 1, Ordering::Relaxed);
+
+        let ret = PerCPUPageMappingGuard::create(
+            paddr.page_align_down(),
+            TEMP_MAP_SLOT_BASE + depth,
+            false,
+        )
+        .map(|guard| f(guard.virt_addr() + paddr.page_offset()));
+
+        self.temp_map_depth.store(depth, Ordering::Relaxed);
+
+        ret
+    }
+
+    // Returns a free 2M temporary slot for map_phys() or None when all of
+    // them are in use
+    pub fn alloc_map_phys_slot(&self) -> Option<usize> {
+        let used = self.map_phys_slots.load(Ordering::Relaxed);
+        let idx = (!used).trailing_zeros() as usize;
+        if idx >= MAP_PHYS_SLOTS {
+            return None;
+        }
+        self.map_phys_slots.fetch_or(1 << idx, Ordering::Relaxed);
+        Some(MAP_PHYS_SLOT_BASE + idx)
+    }
+
+    pub fn free_map_phys_slot(&self, slot: usize) {
+        assert!((MAP_PHYS_SLOT_BASE..MAP_PHYS_SLOT_BASE + MAP_PHYS_SLOTS).contains(&slot));
+        let mask = 1u64 << (slot - MAP_PHYS_SLOT_BASE);
+        let old = self.map_phys_slots.fetch_and(!mask, Ordering::Relaxed);
+        assert!(old & mask != 0);
+    }
+
+    // Marks pcid as loaded, returns whether it was loaded before
+    pub fn mark_pcid_loaded(&self, pcid: u16) -> bool {
+        assert!(pcid < 64);
+        let mask = 1u64 << pcid;
+        self.loaded_pcids.fetch_or(mask, Ordering::Relaxed) & mask != 0
+    }
+
+    // The next load of each PCID flushes its TLB entries
+    pub fn clear_loaded_pcids(&self) {
+        self.loaded_pcids.store(0, Ordering::Relaxed);
+    }
+
+    // Allocates a GHCB page, does nothing when the CPU already has one
     pub fn setup_ghcb(&mut self) -> Result<(), ()> {
-        let ghcb_page = allocate_page().expect("Failed to allocate GHCB page");
-        self.ghcb = ghcb_page as *mut GHCB;
-        unsafe { (*self.ghcb).init() }
+        if !self.ghcb.is_null() {
+            return Ok(());
+        }
+
+        if buddy_allocator_active() {
+            self.ghcb = allocate_ghcb()?;
+            return Ok(());
+        }
+
+        let ghcb = self.allocate_early_page()?.as_mut_ptr::<GHCB>();
+        unsafe { (*ghcb).init()? };
+        self.ghcb = ghcb;
+
+        Ok(())
     }
 
+    // Makes sure the CPU has a GHCB and registers it with the hypervisor.
+    // Must run on the target CPU, every time a GHCB is bound to it.
+    pub fn bind_ghcb(&mut self) -> Result<(), ()> {
+        self.setup_ghcb()?;
+        self.register_ghcb()?;
+        self.ghcb_torn_down = false;
+        Ok(())
+    }
+
+    // Unregisters the GHCB on the current CPU and gives it back, so that an
+    // offline CPU does not keep a shared page
+    fn release_ghcb(&mut self) -> Result<(), ()> {
+        if self.ghcb.is_null() {
+            return Ok(());
+        }
+
+        let ghcb = self.ghcb;
+        self.ghcb = ptr::null_mut();
+        release_ghcb(unsafe { &mut *ghcb })
+    }
+
+    // A GHCB GPA mismatch means the hypervisor would use a different page
+    // than the SVSM for all further exits, terminate right away.
     pub fn register_ghcb(&self) -> Result<(), ()> {
-        unsafe { self.ghcb.as_ref().unwrap().register() }
+        match unsafe { self.ghcb.as_ref().unwrap().register() } {
+            Ok(()) => Ok(()),
+            Err(GhcbError::GpaMismatch(_)) => {
+                request_termination_msr();
+                Err(())
+            }
+            Err(_) => Err(()),
+        }
     }
 
     pub fn get_top_of_stack(&self) -> VirtAddr {
@@ -265,10 +797,11 @@ impl PerCpu {
 
     fn setup_tss(&mut self) {
         self.tss.ist_stacks[IST_DF] = stack_base_pointer(self.ist.double_fault_stack.unwrap());
+        self.tss.ist_stacks[IST_VC] = stack_base_pointer(self.ist.vc_stack.unwrap());
     }
 
     pub fn map_self(&mut self) -> Result<(), ()> {
-        let vaddr = (self as *const PerCpu) as VirtAddr;
+        let vaddr = VirtAddr::from_ptr(self as *const PerCpu);
         let paddr = virt_to_phys(vaddr);
         let flags = PageTable::data_flags();
 
@@ -282,9 +815,6 @@ impl PerCpu {
         // Map PerCpu data in own page-table
         self.map_self()?;
 
-        // Setup GHCB
-        self.setup_ghcb()?;
-
         // Allocate per-cpu init stack
         self.allocate_init_stack()?;
 
@@ -297,9 +827,44 @@ impl PerCpu {
         Ok(())
     }
 
-    // Setup code which needs to run on the target CPU
-    pub fn setup_on_cpu(&self) -> Result<(), ()> {
-        self.register_ghcb()
+    // Setup code which needs to run on the target CPU. The GHCB is allocated
+    // here, on the CPU it gets registered on.
+    pub fn setup_on_cpu(&mut self) -> Result<(), ()> {
+        load_gdt();
+        load_idt();
+        // GS base points to the PerCpu of the CPU
+        write_gs_base(u64::from(VirtAddr::from_ptr(self as *const PerCpu)));
+        self.bind_ghcb()?;
+
+        let apic_id = local_apic_id();
+        if apic_id != self.apic_id {
+            log::warn!(
+                "Local APIC-ID {} does not match expected APIC-ID {}",
+                apic_id,
+                self.apic_id
+            );
+        }
+
+        Ok(())
+    }
+
+    // Teardown code which needs to run on the target CPU before it goes
+    // offline. The GHCB is unregistered and, unless disabled with
+    // set_release_ghcb_on_offline(), released, so nothing relying on it
+    // (including logging) must run on this CPU afterwards. The SVSM VMSA
+    // is still in use at that point and is released by free_svsm_vmsa()
+    // from another CPU once the AP has been destroyed.
+    pub fn teardown_on_cpu(&mut self) -> Result<(), ()> {
+        if RELEASE_GHCB_ON_OFFLINE.load(Ordering::Relaxed) {
+            self.release_ghcb()?;
+        } else if !self.ghcb.is_null() {
+            unsafe { (*self.ghcb).unregister()? };
+        }
+
+        self.ghcb_torn_down = true;
+        self.set_offline();
+
+        Ok(())
     }
 
     pub fn load_pgtable(&mut self) {
@@ -313,6 +878,7 @@ impl PerCpu {
     pub fn load(&mut self) {
         self.load_pgtable();
         self.load_tss();
+        set_percpu_ready();
     }
 
     pub fn shutdown(&mut self) -> Result<(), ()> {
@@ -327,7 +893,16 @@ impl PerCpu {
         self.reset_ip = reset_ip;
     }
 
+    // Binds a GHCB on first use, so must only be called on the current
+    // CPU's PerCpu. After teardown_on_cpu() the GHCB is gone or no longer
+    // registered, using it then is a bug.
     pub fn ghcb(&mut self) -> &'static mut GHCB {
+        assert!(!self.ghcb_torn_down, "GHCB used after CPU teardown");
+
+        if self.ghcb.is_null() {
+            self.bind_ghcb().expect("Failed to bind GHCB");
+        }
+
         unsafe { self.ghcb.as_mut().unwrap() }
     }
 
@@ -336,7 +911,7 @@ impl PerCpu {
             return Err(());
         }
 
-        let vaddr = allocate_new_vmsa(RMPFlags::VMPL1)?;
+        let vaddr = allocate_new_vmsa(1)?;
         let paddr = virt_to_phys(vaddr);
 
         self.svsm_vmsa = Some(VmsaRef::new(vaddr, paddr, false));
@@ -348,13 +923,20 @@ impl PerCpu {
         &mut self.svsm_vmsa
     }
 
-    pub fn prepare_svsm_vmsa(&mut self, start_rip: u64) {
-        let vmsa = self.svsm_vmsa.unwrap();
+    // Must not be called while the VMSA is executing
+    pub fn free_svsm_vmsa(&mut self) {
+        if let Some(vmsa) = self.svsm_vmsa.take() {
+            free_vmsa(vmsa.vaddr);
+        }
+    }
 
-        vmsa.vmsa().tr = self.vmsa_tr_segment();
-        vmsa.vmsa().rip = start_rip;
-        vmsa.vmsa().rsp = self.get_top_of_stack().try_into().unwrap();
-        vmsa.vmsa().cr3 = self.get_pgtable().cr3_value().try_into().unwrap();
+    pub fn prepare_svsm_vmsa(&self, vmsa: &mut VMSA, start_rip: u64) {
+        VmsaBuilder::svsm()
+            .tr(self.vmsa_tr_segment())
+            .rip(start_rip)
+            .rsp(u64::from(self.get_top_of_stack()))
+            .cr3(self.get_pgtable().cr3_value().try_into().unwrap())
+            .build_into(vmsa);
     }
 
     pub fn unmap_guest_vmsa(&self) {
@@ -400,6 +982,11 @@ impl PerCpu {
         locked.update_caa(Some(caa));
     }
 
+    pub fn update_guest_vmpl_caa(&self, vmpl: usize, caa: PhysAddr) {
+        let mut locked = self.guest_vmsa.lock();
+        locked.update_vmpl_caa(vmpl, Some(caa));
+    }
+
     pub fn guest_vmsa_ref(&self) -> LockGuard<GuestVmsaRef> {
         self.guest_vmsa.lock()
     }
@@ -409,48 +996,89 @@ impl PerCpu {
 
         assert!(locked.vmsa_phys().is_some());
 
-        unsafe { (SVSM_PERCPU_VMSA_BASE as *mut VMSA).as_mut().unwrap() }
+        unsafe { SVSM_PERCPU_VMSA_BASE.as_mut_ptr::<VMSA>().as_mut().unwrap() }
     }
 
-    pub fn alloc_guest_vmsa(&mut self) -> Result<(), ()> {
-        let vaddr = allocate_new_vmsa(RMPFlags::VMPL1)?;
+    // VMPL0 is the SVSM itself and has its VMSA allocated by
+    // alloc_svsm_vmsa(). The VMSA page is marked as a VMSA for the target
+    // VMPL in the RMP.
+    pub fn alloc_guest_vmsa(&mut self, vmpl: u8) -> Result<(), ()> {
+        let idx = vmpl as usize;
+
+        if vmpl == 0 || idx >= VMPL_MAX || self.guest_vmsas[idx].is_some() {
+            return Err(());
+        }
+
+        let vaddr = allocate_new_vmsa(vmpl)?;
         let paddr = virt_to_phys(vaddr);
 
         let vmsa = VMSA::from_virt_addr(vaddr);
-        init_guest_vmsa(vmsa, self.reset_ip);
+        init_guest_vmsa(vmsa, self.reset_ip, vmpl);
 
-        self.update_guest_vmsa(paddr);
+        self.guest_vmsas[idx] = Some(VmsaRef::new(vaddr, paddr, false));
 
         Ok(())
     }
 
+    pub fn get_guest_vmsa(&self, vmpl: u8) -> Option<VmsaRef> {
+        self.guest_vmsas.get(vmpl as usize).copied().flatten()
+    }
+
+    // VMPL1 runs on the guest VMSA mapped by update_mappings(), the other
+    // VMPLs on the VMSAs from alloc_guest_vmsa()
+    pub fn vmpl_guest_vmsa(&mut self, vmpl: usize) -> Option<&mut VMSA> {
+        if vmpl == DEFAULT_CAA_VMPL {
+            self.guest_vmsa_ref().vmsa_phys()?;
+            return Some(self.guest_vmsa());
+        }
+
+        let vaddr = self.get_guest_vmsa(vmpl as u8)?.vaddr;
+        unsafe { vaddr.as_mut_ptr::<VMSA>().as_mut() }
+    }
+
+    // Unmaps the CAAs of all VMPLs. The mappings are only used on this CPU,
+    // so flushing the local TLB is enough.
     pub fn unmap_caa(&self) {
-        self.get_pgtable().unmap_4k(SVSM_PERCPU_CAA_BASE);
+        for vmpl in 0..VMPL_MAX {
+            self.unmap_vmpl_caa(vmpl);
+        }
     }
 
-    pub fn map_guest_caa(&self, paddr: PhysAddr) -> Result<(), ()> {
-        self.unmap_caa();
+    fn unmap_vmpl_caa(&self, vmpl: usize) {
+        let vaddr = caa_vaddr(vmpl);
 
-        let paddr_aligned = page_align(paddr);
-        let flags = PageTable::data_flags();
+        self.get_pgtable().unmap_4k(vaddr);
+        invlpg(vaddr);
+    }
 
-        let vaddr = SVSM_PERCPU_CAA_BASE;
+    pub fn map_guest_caa(&self, vmpl: usize, paddr: PhysAddr) -> Result<(), ()> {
+        let vaddr = caa_vaddr(vmpl);
+        self.unmap_vmpl_caa(vmpl);
+
+        let paddr_aligned = paddr.page_align_down();
+        let flags = PageTable::data_flags();
 
         self.get_pgtable().map_4k(vaddr, paddr_aligned, flags)?;
 
         Ok(())
     }
 
-    pub fn caa_addr(&self) -> Option<VirtAddr> {
+    pub fn caa_addr(&self, vmpl: usize) -> Option<VirtAddr> {
         let locked = self.guest_vmsa.lock();
+        let paddr = locked.vmpl_caa_phys(vmpl)?;
 
-        if locked.caa_phys().is_none() {
-            return None;
-        }
+        Some(caa_vaddr(vmpl) + paddr.page_offset())
+    }
 
-        let offset = page_offset(locked.caa_phys().unwrap());
+    // The CAA must be mapped, see update_mappings()
+    pub fn caa(&self) -> &CallingArea {
+        self.vmpl_caa(DEFAULT_CAA_VMPL)
+    }
 
-        Some((SVSM_PERCPU_CAA_BASE + offset) as VirtAddr)
+    pub fn vmpl_caa(&self, vmpl: usize) -> &CallingArea {
+        let vaddr = self.caa_addr(vmpl).expect("No CAA mapped");
+
+        unsafe { &*vaddr.as_ptr::<CallingArea>() }
     }
 
     fn vmsa_tr_segment(&self) -> VMSASegment {
@@ -465,16 +1093,52 @@ impl PerCpu {
 
 unsafe impl Sync for PerCpu {}
 
+fn caa_vaddr(vmpl: usize) -> VirtAddr {
+    assert!(vmpl < VMPL_MAX);
+    SVSM_PERCPU_CAA_BASE + vmpl * PAGE_SIZE
+}
+
+// Offline CPUs give their GHCB back by default, shared pages are only kept
+// for online CPUs
+static RELEASE_GHCB_ON_OFFLINE: AtomicBool = AtomicBool::new(true);
+
+pub fn set_release_ghcb_on_offline(release: bool) {
+    RELEASE_GHCB_ON_OFFLINE.store(release, Ordering::Relaxed);
+}
+
+// Number of PerCpu areas allocated so far, one more than the highest
+// cpu_index() in use.
+pub fn cpu_count() -> usize {
+    PERCPU_AREAS.len()
+}
+
+// Set once the BSP runs with its per-cpu area mapped at SVSM_PERCPU_BASE.
+// APs are started with their per-cpu page-table already loaded.
+static PERCPU_READY: AtomicBool = AtomicBool::new(false);
+
+pub fn set_percpu_ready() {
+    PERCPU_READY.store(true, Ordering::Release);
+}
+
+// Index of the current CPU, None while per-cpu data is not accessible yet
+pub fn this_cpu_index() -> Option<usize> {
+    if PERCPU_READY.load(Ordering::Acquire) {
+        Some(this_cpu().cpu_index())
+    } else {
+        None
+    }
+}
+
 pub fn this_cpu() -> &'static PerCpu {
     unsafe {
-        let ptr = SVSM_PERCPU_BASE as *mut PerCpu;
+        let ptr = SVSM_PERCPU_BASE.as_mut_ptr::<PerCpu>();
         ptr.as_ref().unwrap()
     }
 }
 
 pub fn this_cpu_mut() -> &'static mut PerCpu {
     unsafe {
-        let ptr = SVSM_PERCPU_BASE as *mut PerCpu;
+        let ptr = SVSM_PERCPU_BASE.as_mut_ptr::<PerCpu>();
         ptr.as_mut().unwrap()
     }
 }
@@ -562,3 +1226,72 @@ impl PerCpuVmsas {
         Ok(guard.swap_remove(index))
     }
 }
+
+#[test]
+fn test_percpu_areas_cpu_index() {
+    let areas = PerCpuAreas::new();
+
+    for (i, apic_id) in [0u32, 4, 2].iter().enumerate() {
+        let ret = areas.push(PerCpuInfo::new(*apic_id, VirtAddr::null()));
+        assert_eq!(ret, Ok(i));
+    }
+    assert_eq!(areas.len(), 3);
+
+    // Duplicate APIC-IDs get no index
+    assert!(areas.push(PerCpuInfo::new(4, VirtAddr::null())).is_err());
+    assert_eq!(areas.len(), 3);
+}
+
+#[test]
+fn test_cpu_online_mask() {
+    extern crate alloc;
+    use alloc::vec::Vec;
+
+    let mask = CpuOnlineMask::new();
+
+    for idx in [0, 3, 64, MAX_CPUS - 1] {
+        mask.set(idx);
+    }
+    mask.clear(3);
+
+    assert!(mask.is_set(64) && !mask.is_set(3));
+    assert_eq!(mask.online_count(), 3);
+
+    let mut online = Vec::new();
+    mask.for_each_online(|idx| online.push(idx));
+    assert_eq!(online, [0, 64, MAX_CPUS - 1]);
+
+    mask.set(1);
+    mask.set(2);
+    assert_eq!(alloc::format!("{}", mask), "0-2,64,511");
+    assert_eq!(alloc::format!("{}", CpuOnlineMask::new()), "none");
+}
+
+#[test]
+fn test_percpu_doorbell() {
+    let cpu = PerCpu::new();
+
+    assert!(!cpu.take_doorbell());
+    cpu.set_doorbell();
+    cpu.set_doorbell();
+    assert!(cpu.take_doorbell());
+    assert!(!cpu.take_doorbell());
+}
+
+#[test]
+fn test_guest_vmsa_ref_vmpl_caas() {
+    let mut vmsa_ref = GuestVmsaRef::new();
+    let caa = PhysAddr::from(0x10_0000u64);
+    let caa2 = PhysAddr::from(0x20_0008u64);
+
+    vmsa_ref.update_vmsa_caa(Some(PhysAddr::from(0x1000u64)), Some(caa));
+    vmsa_ref.set_updated();
+    vmsa_ref.update_vmpl_caa(2, Some(caa2));
+    assert!(vmsa_ref.needs_update());
+
+    // The CAA from the secrets page stays the default
+    assert_eq!(vmsa_ref.caa_phys(), Some(caa));
+    assert_eq!(vmsa_ref.vmpl_caa_phys(2), Some(caa2));
+    assert_eq!(vmsa_ref.vmpl_caa_phys(3), None);
+    assert_eq!(vmsa_ref.vmpl_caa_phys(VMPL_MAX), None);
+}
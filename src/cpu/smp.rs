@@ -7,68 +7,607 @@
 extern crate alloc;
 
 use crate::acpi::tables::ACPICPUInfo;
-use crate::cpu::percpu::{this_cpu_mut, PerCpu};
-use crate::cpu::vmsa::init_svsm_vmsa;
+use crate::cpu::apic::local_apic_id;
+use crate::cpu::ipi::{ring_doorbell, stop_other_cpus};
+use crate::cpu::percpu::{
+    this_cpu, this_cpu_index, this_cpu_mut, PerCpu, CPU_ONLINE_MASK, PERCPU_AREAS,
+};
+use crate::cpu::tsc::Instant;
+#[cfg(feature = "boot_timing")]
+use crate::cpu::tsc::{rdtsc, tsc_to_duration};
+use crate::mm::address_space::SVSM_PERCPU_BASE;
+use crate::mm::alloc::{mem_stats, register_low_mem_hook, unregister_low_mem_hook, MemStats};
 use crate::requests::request_loop;
+use crate::sev::ghcb::GhcbBackend;
+use crate::sev::msr_protocol::{
+    ghcb_terminate, request_termination_msr, GHCB_TERM_SET_SVSM, SVSM_TERM_AP_CREATE,
+};
+use crate::sev::vmsa::VmsaError;
+use crate::sev::{check_sev_features, current_sev_features};
+use crate::types::PhysAddr;
+use crate::utils::halt;
 use alloc::vec::Vec;
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
 
-fn start_cpu(apic_id: u32) {
-    unsafe {
-        let start_rip: u64 = (start_ap as *const u8) as u64;
-        let percpu = PerCpu::alloc(apic_id)
-            .expect("Failed to allocate AP per-cpu data")
-            .as_mut()
-            .unwrap();
-
-        percpu.setup().expect("Failed to setup AP per-cpu area");
-        percpu
-            .alloc_svsm_vmsa()
-            .expect("Failed to allocate AP SVSM VMSA");
-
-        let vmsa = percpu.get_svsm_vmsa().unwrap();
-        init_svsm_vmsa(vmsa.vmsa());
-        percpu.prepare_svsm_vmsa(start_rip);
-
-        let sev_features = vmsa.vmsa().sev_features;
-        let vmsa_pa = vmsa.paddr;
-
-        vmsa.vmsa().enable();
-        this_cpu_mut()
-            .ghcb()
-            .ap_create(vmsa_pa, apic_id.into(), 0, sev_features)
-            .expect("Failed to launch secondary CPU");
-        loop {
-            if percpu.is_online() {
+// Time all launched APs get to report online
+const AP_ONLINE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Time the other CPUs get to acknowledge the stop NMI in stop_all_other_cpus()
+const CPU_STOP_TIMEOUT: Duration = Duration::from_millis(100);
+
+// Consecutive AP_CREATE failures after which the hypervisor is considered
+// broken and the SVSM gives up
+const AP_LAUNCH_MAX_FAILURES: usize = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SmpError {
+    // Allocating per-cpu resources failed
+    Alloc,
+    // Setting up the per-cpu area failed
+    Setup,
+    // The AP_CREATE request to the hypervisor failed
+    Launch,
+    // The initial AP state failed validation
+    InvalidVmsa(VmsaError),
+    // The VMSA requests SEV features which are unknown or not active
+    UnsupportedSevFeatures(u64),
+    // The AP did not report online within the timeout
+    Timeout,
+    // No such AP, or it is the BSP
+    InvalidCpu,
+}
+
+// Order in which start_secondary_cpus() launches the APs
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BringupOrder {
+    // As listed in the MADT
+    #[default]
+    AcpiOrder,
+    // APs in the package of the BSP first, then the rest in MADT order
+    LocalSocketFirst,
+}
+
+fn bringup_candidates(
+    cpus: &[ACPICPUInfo],
+    order: BringupOrder,
+    bsp_apic_id: u32,
+    bsp_package: u32,
+) -> Vec<&ACPICPUInfo> {
+    // The BSP is not necessarily APIC-ID 0
+    let mut candidates: Vec<&ACPICPUInfo> = cpus
+        .iter()
+        .filter(|c| c.apic_id != bsp_apic_id && c.enabled)
+        .collect();
+
+    // The sort is stable, so the MADT order is kept within each group
+    if order == BringupOrder::LocalSocketFirst {
+        candidates.sort_by_key(|c| c.topology.package_id != bsp_package);
+    }
+
+    candidates
+}
+
+// The AP never ran when launching it failed, so all its per-cpu resources
+// are given back
+fn start_cpu(cpu: &ACPICPUInfo) -> Result<(), SmpError> {
+    let percpu = PerCpu::alloc(cpu.apic_id).map_err(|_| SmpError::Alloc)?;
+
+    let ret = launch_cpu(unsafe { percpu.as_mut().unwrap() }, cpu);
+    if ret.is_err() {
+        // SAFETY: the PerCpu was just allocated and the AP is not running
+        unsafe { PerCpu::free(percpu) };
+    }
+
+    ret
+}
+
+fn launch_cpu(percpu: &mut PerCpu, cpu: &ACPICPUInfo) -> Result<(), SmpError> {
+    let start_rip: u64 = (ap_entry as *const u8) as u64;
+
+    percpu.set_topology(cpu.topology);
+
+    percpu.setup().map_err(|_| SmpError::Setup)?;
+    percpu.alloc_svsm_vmsa().map_err(|_| SmpError::Alloc)?;
+
+    let vmsa_ref = percpu.get_svsm_vmsa().unwrap();
+    let vmsa_pa = vmsa_ref.paddr;
+    let mut vmsa = vmsa_ref.try_acquire().map_err(|_| SmpError::Setup)?;
+
+    percpu.prepare_svsm_vmsa(&mut vmsa, start_rip);
+    vmsa.validate().map_err(SmpError::InvalidVmsa)?;
+
+    let sev_features = vmsa.sev_features;
+    check_sev_features(sev_features, current_sev_features())
+        .map_err(SmpError::UnsupportedSevFeatures)?;
+    vmsa.enable();
+    drop(vmsa);
+
+    #[cfg(feature = "boot_timing")]
+    percpu.record_ap_create();
+
+    create_ap(this_cpu_mut().ghcb(), percpu, vmsa_pa, sev_features)
+}
+
+fn create_ap<G: GhcbBackend>(
+    ghcb: &mut G,
+    percpu: &PerCpu,
+    vmsa_pa: PhysAddr,
+    sev_features: u64,
+) -> Result<(), SmpError> {
+    ghcb.ap_create(vmsa_pa, percpu.get_apic_id().into(), 0, sev_features)
+        .map_err(|_| SmpError::Launch)
+}
+
+fn other_cpus_online(self_index: usize) -> usize {
+    let count = CPU_ONLINE_MASK.online_count();
+
+    if CPU_ONLINE_MASK.is_set(self_index) {
+        count - 1
+    } else {
+        count
+    }
+}
+
+// Stops all other CPUs, returns the number of CPUs which are still online.
+// CPUs acknowledge the stop NMI by clearing their online bit, the wait ends
+// after CPU_STOP_TIMEOUT regardless.
+pub fn stop_all_other_cpus() -> usize {
+    // Without per-cpu data there is no GHCB to send IPIs through
+    let self_index = match this_cpu_index() {
+        Some(index) => index,
+        None => return 0,
+    };
+
+    if stop_other_cpus().is_ok() {
+        let start = Instant::now();
+        while other_cpus_online(self_index) > 0 && start.elapsed() < CPU_STOP_TIMEOUT {
+            core::hint::spin_loop();
+        }
+    }
+
+    other_cpus_online(self_index)
+}
+
+// Asks the hypervisor to terminate the guest, the other CPUs must have been
+// stopped already
+pub fn terminate_svsm() -> ! {
+    request_termination_msr();
+
+    loop {
+        halt();
+    }
+}
+
+// Stops all other CPUs and asks the hypervisor to terminate the guest
+pub fn shutdown_all_cpus() -> ! {
+    let remaining = stop_all_other_cpus();
+    if remaining > 0 {
+        log::error!("{} CPU(s) did not stop, terminating anyway", remaining);
+    }
+
+    terminate_svsm();
+}
+
+pub fn wait_for_ap_online(percpu: &PerCpu, timeout: Duration) -> Result<(), SmpError> {
+    let start = Instant::now();
+
+    loop {
+        if percpu.is_online() {
+            return Ok(());
+        }
+        if start.elapsed() >= timeout {
+            return Err(SmpError::Timeout);
+        }
+        core::hint::spin_loop();
+    }
+}
+
+// Waits for a launched AP to come online. One which does not is destroyed,
+// so that it can't start running at some later point.
+fn wait_for_launched_ap<G: GhcbBackend>(
+    ghcb: &mut G,
+    percpu: &PerCpu,
+    timeout: Duration,
+) -> Result<(), SmpError> {
+    let ret = wait_for_ap_online(percpu, timeout);
+    if ret.is_err() {
+        remove_ap(ghcb, percpu)?;
+    }
+
+    ret
+}
+
+// No further APs are launched once free memory drops below this many pages,
+// so that the SVSM keeps enough memory to serve requests.
+const AP_LOW_MEM_PAGES: usize = 256;
+
+static AP_LOW_MEM: AtomicBool = AtomicBool::new(false);
+
+fn ap_low_mem_hook(stats: &MemStats) {
+    log::warn!(
+        "Low on memory during AP bring-up: {} of {} pages free",
+        stats.free_pages,
+        stats.total_pages
+    );
+    AP_LOW_MEM.store(true, Ordering::Relaxed);
+}
+
+// Returns the number of APs brought online. APs which fail to launch or to
+// come online are skipped. Getting low on memory stops the bring-up of
+// further APs, actually running out of memory is reported as an error.
+pub fn start_secondary_cpus(cpus: &[ACPICPUInfo], order: BringupOrder) -> Result<usize, SmpError> {
+    let mut launched: Vec<u32> = Vec::new();
+    let mut launch_failures: usize = 0;
+    let mut result: Result<(), SmpError> = Ok(());
+
+    let stats = mem_stats();
+    log::info!(
+        "Memory before AP bring-up: {} of {} pages free, largest free block {} pages",
+        stats.free_pages,
+        stats.total_pages,
+        stats.largest_free
+    );
+
+    #[cfg(feature = "boot_timing")]
+    let start_tsc = rdtsc();
+
+    AP_LOW_MEM.store(stats.free_pages < AP_LOW_MEM_PAGES, Ordering::Relaxed);
+    let hook = register_low_mem_hook(AP_LOW_MEM_PAGES, ap_low_mem_hook).is_ok();
+
+    // Launch all APs first and only then wait for them, so that they
+    // perform their own initialization in parallel.
+    let bsp_apic_id = this_cpu().get_apic_id();
+    let bsp_package = this_cpu().topology().package_id;
+    for c in bringup_candidates(cpus, order, bsp_apic_id, bsp_package) {
+        if AP_LOW_MEM.load(Ordering::Relaxed) {
+            log::warn!("Not launching further APs, memory is low");
+            break;
+        }
+
+        log::info!("Launching AP with APIC-ID {}", c.apic_id);
+        match start_cpu(c) {
+            Ok(()) => {
+                launched.push(c.apic_id);
+                launch_failures = 0;
+            }
+            Err(SmpError::Launch) => {
+                log::error!("AP_CREATE failed for AP with APIC-ID {}", c.apic_id);
+                launch_failures += 1;
+                if launch_failures >= AP_LAUNCH_MAX_FAILURES {
+                    log::error!(
+                        "AP_CREATE failed {} times in a row, terminating",
+                        launch_failures
+                    );
+                    ghcb_terminate(GHCB_TERM_SET_SVSM, SVSM_TERM_AP_CREATE);
+                }
+            }
+            Err(SmpError::Alloc) => {
+                log::error!("Out of memory launching AP with APIC-ID {}", c.apic_id);
+                result = Err(SmpError::Alloc);
                 break;
             }
+            Err(e) => log::error!("Failed to launch AP with APIC-ID {}: {:?}", c.apic_id, e),
         }
     }
-}
 
-pub fn start_secondary_cpus(cpus: &Vec<ACPICPUInfo>) {
+    if hook {
+        unregister_low_mem_hook();
+    }
+
+    // All APs share one deadline
+    let start = Instant::now();
     let mut count: usize = 0;
-    for c in cpus.iter().filter(|c| c.apic_id != 0 && c.enabled) {
-        log::info!("Launching AP with APIC-ID {}", c.apic_id);
-        start_cpu(c.apic_id);
-        count += 1;
+
+    for &apic_id in launched.iter() {
+        let percpu = PERCPU_AREAS.get(apic_id).unwrap();
+        let timeout = AP_ONLINE_TIMEOUT.saturating_sub(start.elapsed());
+
+        match wait_for_launched_ap(this_cpu_mut().ghcb(), percpu, timeout) {
+            Ok(()) => count += 1,
+            Err(SmpError::Timeout) => {
+                log::error!("AP with APIC-ID {} failed to come online", apic_id);
+                free_ap_vmsa(apic_id);
+            }
+            Err(e) => log::error!("Failed to destroy AP with APIC-ID {}: {:?}", apic_id, e),
+        }
+    }
+
+    log::info!("Brought {} AP(s) online", count);
+
+    #[cfg(feature = "boot_timing")]
+    log_bringup_timing(&launched, rdtsc().saturating_sub(start_tsc));
+
+    result.map(|_| count)
+}
+
+#[cfg(feature = "boot_timing")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct BringupLatency {
+    count: u64,
+    min: u64,
+    max: u64,
+    total: u64,
+}
+
+#[cfg(feature = "boot_timing")]
+impl BringupLatency {
+    fn add(&mut self, ticks: u64) {
+        self.min = if self.count == 0 {
+            ticks
+        } else {
+            self.min.min(ticks)
+        };
+        self.max = self.max.max(ticks);
+        self.total += ticks;
+        self.count += 1;
+    }
+
+    fn mean(&self) -> u64 {
+        self.total.checked_div(self.count).unwrap_or(0)
+    }
+}
+
+// APs which did not come online are left out of the latencies
+#[cfg(feature = "boot_timing")]
+fn log_bringup_timing(launched: &[u32], total_ticks: u64) {
+    let mut latency = BringupLatency::default();
+
+    for &apic_id in launched {
+        if let Some(ticks) = PERCPU_AREAS
+            .get(apic_id)
+            .and_then(|cpu| cpu.bringup_ticks())
+        {
+            latency.add(ticks);
+        }
+    }
+
+    log::info!(
+        "AP bring-up of {} AP(s): min {:?} max {:?} mean {:?}, total {:?}",
+        latency.count,
+        tsc_to_duration(latency.min),
+        tsc_to_duration(latency.max),
+        tsc_to_duration(latency.mean()),
+        tsc_to_duration(total_ticks)
+    );
+}
+
+pub fn request_cpu_offline(apic_id: u32) -> Result<(), SmpError> {
+    if apic_id == this_cpu_mut().get_apic_id() {
+        return Err(SmpError::InvalidCpu);
+    }
+
+    let percpu = PERCPU_AREAS.get(apic_id).ok_or(SmpError::InvalidCpu)?;
+    if !percpu.is_online() {
+        return Err(SmpError::InvalidCpu);
+    }
+
+    percpu.request_offline();
+
+    // The CPU might be halted in its request loop
+    if ring_doorbell(apic_id).is_err() {
+        log::warn!("Failed to wake up CPU with APIC-ID {}", apic_id);
     }
-    log::info!("Brough {} AP(s) online", count);
+
+    Ok(())
 }
 
+// Wait for an AP to finish teardown_on_cpu(), then remove it from the
+// hypervisor and release its SVSM VMSA.
+pub fn wait_for_ap_offline(apic_id: u32, timeout: Duration) -> Result<(), SmpError> {
+    let percpu = PERCPU_AREAS.get(apic_id).ok_or(SmpError::InvalidCpu)?;
+    let start = Instant::now();
+
+    while percpu.is_online() {
+        if start.elapsed() >= timeout {
+            return Err(SmpError::Timeout);
+        }
+        core::hint::spin_loop();
+    }
+
+    destroy_ap(apic_id)
+}
+
+// Removes an AP from the hypervisor and releases its SVSM VMSA. The PerCpu
+// stays registered.
+fn destroy_ap(apic_id: u32) -> Result<(), SmpError> {
+    let percpu = PERCPU_AREAS.get(apic_id).ok_or(SmpError::InvalidCpu)?;
+
+    remove_ap(this_cpu_mut().ghcb(), percpu)?;
+    free_ap_vmsa(apic_id);
+
+    Ok(())
+}
+
+fn remove_ap<G: GhcbBackend>(ghcb: &mut G, percpu: &PerCpu) -> Result<(), SmpError> {
+    ghcb.ap_destroy(percpu.get_apic_id().into())
+        .map_err(|_| SmpError::Launch)?;
+
+    // An AP which timed out might have come online in the meantime
+    CPU_ONLINE_MASK.clear(percpu.cpu_index());
+
+    Ok(())
+}
+
+// Must only be called once the AP is destroyed
+fn free_ap_vmsa(apic_id: u32) {
+    // SAFETY: the AP is destroyed, nothing else accesses its per-cpu data
+    // anymore.
+    unsafe {
+        PERCPU_AREAS.get_mut(apic_id).unwrap().free_svsm_vmsa();
+    }
+}
+
+// Log the statistics of the current CPU and of all APs which are online
+pub fn dump_all_cpu_stats() {
+    let this_apic_id = this_cpu().get_apic_id();
+
+    PERCPU_AREAS.for_each(|cpu| {
+        if !cpu.is_online() && cpu.get_apic_id() != this_apic_id {
+            return;
+        }
+
+        let stats = cpu.stats();
+        log::info!(
+            "CPU {} (APIC-ID {}): {} GHCB exits (ioio {} msr {} psc {} guest-req {} ap-create {} run-vmpl {} other {}), {} PSC pages, {} request loops",
+            cpu.cpu_index(),
+            cpu.get_apic_id(),
+            stats.ghcb_exits(),
+            stats.ghcb_ioio,
+            stats.ghcb_msr,
+            stats.ghcb_psc,
+            stats.ghcb_guest_request,
+            stats.ghcb_ap_create,
+            stats.ghcb_run_vmpl,
+            stats.ghcb_other,
+            stats.psc_pages,
+            stats.request_loops
+        );
+    });
+}
+
+extern "C" {
+    fn ap_entry();
+}
+
+// First code an AP runs. The stack pointer is taken from the per-cpu area,
+// which is mapped at the same address on every CPU, rather than trusting the
+// RSP value from the VMSA. All caller-saved registers and RBP are cleared
+// before entering Rust code.
+global_asm!(
+    r#"
+        .text
+        .globl  ap_entry
+    ap_entry:
+        movabsq ${percpu_base}, %rax
+        movq    (%rax), %rsp
+
+        xorl    %ebp, %ebp
+        xorl    %eax, %eax
+        xorl    %ecx, %ecx
+        xorl    %edx, %edx
+        xorl    %esi, %esi
+        xorl    %edi, %edi
+        xorl    %r8d, %r8d
+        xorl    %r9d, %r9d
+        xorl    %r10d, %r10d
+        xorl    %r11d, %r11d
+
+        call    start_ap
+        ud2
+        "#,
+    percpu_base = const SVSM_PERCPU_BASE.as_usize(),
+    options(att_syntax)
+);
+
 #[no_mangle]
-fn start_ap() {
+extern "C" fn start_ap() -> ! {
+    // Registers the GHCB first, nothing may use it before
     this_cpu_mut()
         .setup_on_cpu()
         .expect("setup_on_cpu() failed");
 
     // Send a life-sign
-    log::info!("AP with APIC-ID {} is online", this_cpu_mut().get_apic_id());
+    log::info!("AP with APIC-ID {} is online", local_apic_id());
 
     // Set CPU online so that BSP can proceed
     this_cpu_mut().set_online();
 
-    // Loop for now
     request_loop();
 
-    panic!("Returned from request_loop!");
+    if this_cpu().offline_requested() {
+        log::info!("AP with APIC-ID {} going offline", this_cpu().get_apic_id());
+        if this_cpu_mut().teardown_on_cpu().is_err() {
+            // Without a working GHCB there is no way to report this
+            this_cpu_mut().set_offline();
+        }
+    } else {
+        log::error!(
+            "AP with APIC-ID {} left request loop, shutting down",
+            this_cpu().get_apic_id()
+        );
+        shutdown_all_cpus();
+    }
+
+    loop {
+        halt();
+    }
+}
+
+#[test]
+fn test_bringup_order() {
+    use crate::cpu::topology::CpuTopology;
+
+    let cpus: Vec<ACPICPUInfo> = [(0, 0), (1, 0), (2, 1), (3, 1), (4, 0)]
+        .iter()
+        .map(|&(apic_id, package_id)| ACPICPUInfo {
+            apic_id,
+            enabled: true,
+            topology: CpuTopology {
+                package_id,
+                core_id: 0,
+                thread_id: 0,
+            },
+        })
+        .collect();
+
+    let ids = |order, bsp_apic_id, bsp_package| -> Vec<u32> {
+        bringup_candidates(&cpus, order, bsp_apic_id, bsp_package)
+            .iter()
+            .map(|c| c.apic_id)
+            .collect()
+    };
+
+    assert_eq!(ids(BringupOrder::AcpiOrder, 0, 1), [1, 2, 3, 4]);
+    assert_eq!(ids(BringupOrder::LocalSocketFirst, 0, 0), [1, 4, 2, 3]);
+    assert_eq!(ids(BringupOrder::LocalSocketFirst, 0, 1), [2, 3, 1, 4]);
+
+    // The BSP is never launched, whatever its APIC-ID
+    assert_eq!(ids(BringupOrder::AcpiOrder, 2, 1), [0, 1, 3, 4]);
+}
+
+#[cfg(feature = "boot_timing")]
+#[test]
+fn test_bringup_latency() {
+    let mut latency = BringupLatency::default();
+    assert_eq!(latency.mean(), 0);
+
+    for ticks in [300, 100, 200] {
+        latency.add(ticks);
+    }
+    assert_eq!((latency.min, latency.max, latency.mean()), (100, 300, 200));
+}
+
+#[test]
+fn test_ap_launch_errors() {
+    use crate::sev::ghcb::{GhcbError, MockGhcb};
+
+    let percpu = PerCpu::new();
+    let vmsa_pa = PhysAddr::from(0x10_0000u64);
+
+    // A failing AP_CREATE leaves no AP behind
+    let mut ghcb = MockGhcb::default();
+    ghcb.error = Some(GhcbError::VmgexitError(1, 0));
+    assert_eq!(
+        create_ap(&mut ghcb, &percpu, vmsa_pa, 0x1),
+        Err(SmpError::Launch)
+    );
+    assert!(ghcb.aps.is_empty());
+
+    // An AP which does not come online is destroyed again
+    let mut ghcb = MockGhcb::default();
+    create_ap(&mut ghcb, &percpu, vmsa_pa, 0x1).unwrap();
+    assert_eq!(ghcb.aps, [u64::from(percpu.get_apic_id())]);
+    assert_eq!(
+        wait_for_launched_ap(&mut ghcb, &percpu, Duration::ZERO),
+        Err(SmpError::Timeout)
+    );
+    assert!(ghcb.aps.is_empty());
+
+    // Failing to destroy it is reported as such
+    assert_eq!(
+        wait_for_launched_ap(&mut ghcb, &percpu, Duration::ZERO),
+        Err(SmpError::Launch)
+    );
 }
@@ -0,0 +1,133 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2026 SUSE LLC
+//
+// Author: agent <agent@local>
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GhcbExitReason {
+    IoIo,
+    Msr,
+    PageStateChange,
+    GuestRequest,
+    ApCreate,
+    RunVmpl,
+    Other,
+}
+
+// Counters are only written by the owning CPU, but may be read from any CPU,
+// hence the atomics. Relaxed ordering is sufficient for statistics.
+#[derive(Debug)]
+pub struct CpuStats {
+    ghcb_ioio: AtomicU64,
+    ghcb_msr: AtomicU64,
+    ghcb_psc: AtomicU64,
+    ghcb_guest_request: AtomicU64,
+    ghcb_ap_create: AtomicU64,
+    ghcb_run_vmpl: AtomicU64,
+    ghcb_other: AtomicU64,
+    psc_pages: AtomicU64,
+    request_loops: AtomicU64,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CpuStatsSnapshot {
+    pub ghcb_ioio: u64,
+    pub ghcb_msr: u64,
+    pub ghcb_psc: u64,
+    pub ghcb_guest_request: u64,
+    pub ghcb_ap_create: u64,
+    pub ghcb_run_vmpl: u64,
+    pub ghcb_other: u64,
+    pub psc_pages: u64,
+    pub request_loops: u64,
+}
+
+impl CpuStatsSnapshot {
+    pub fn ghcb_exits(&self) -> u64 {
+        self.ghcb_ioio
+            + self.ghcb_msr
+            + self.ghcb_psc
+            + self.ghcb_guest_request
+            + self.ghcb_ap_create
+            + self.ghcb_run_vmpl
+            + self.ghcb_other
+    }
+}
+
+impl CpuStats {
+    pub const fn new() -> Self {
+        CpuStats {
+            ghcb_ioio: AtomicU64::new(0),
+            ghcb_msr: AtomicU64::new(0),
+            ghcb_psc: AtomicU64::new(0),
+            ghcb_guest_request: AtomicU64::new(0),
+            ghcb_ap_create: AtomicU64::new(0),
+            ghcb_run_vmpl: AtomicU64::new(0),
+            ghcb_other: AtomicU64::new(0),
+            psc_pages: AtomicU64::new(0),
+            request_loops: AtomicU64::new(0),
+        }
+    }
+
+    pub fn count_ghcb_exit(&self, reason: GhcbExitReason) {
+        let counter = match reason {
+            GhcbExitReason::IoIo => &self.ghcb_ioio,
+            GhcbExitReason::Msr => &self.ghcb_msr,
+            GhcbExitReason::PageStateChange => &self.ghcb_psc,
+            GhcbExitReason::GuestRequest => &self.ghcb_guest_request,
+            GhcbExitReason::ApCreate => &self.ghcb_ap_create,
+            GhcbExitReason::RunVmpl => &self.ghcb_run_vmpl,
+            GhcbExitReason::Other => &self.ghcb_other,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count_psc_pages(&self, pages: u64) {
+        self.psc_pages.fetch_add(pages, Ordering::Relaxed);
+    }
+
+    pub fn count_request_loop(&self) {
+        self.request_loops.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> CpuStatsSnapshot {
+        CpuStatsSnapshot {
+            ghcb_ioio: self.ghcb_ioio.load(Ordering::Relaxed),
+            ghcb_msr: self.ghcb_msr.load(Ordering::Relaxed),
+            ghcb_psc: self.ghcb_psc.load(Ordering::Relaxed),
+            ghcb_guest_request: self.ghcb_guest_request.load(Ordering::Relaxed),
+            ghcb_ap_create: self.ghcb_ap_create.load(Ordering::Relaxed),
+            ghcb_run_vmpl: self.ghcb_run_vmpl.load(Ordering::Relaxed),
+            ghcb_other: self.ghcb_other.load(Ordering::Relaxed),
+            psc_pages: self.psc_pages.load(Ordering::Relaxed),
+            request_loops: self.request_loops.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Default for CpuStats {
+    fn default() -> Self {
+        CpuStats::new()
+    }
+}
+
+#[test]
+fn test_cpu_stats_snapshot() {
+    let stats = CpuStats::new();
+
+    stats.count_ghcb_exit(GhcbExitReason::IoIo);
+    stats.count_ghcb_exit(GhcbExitReason::IoIo);
+    stats.count_ghcb_exit(GhcbExitReason::PageStateChange);
+    stats.count_psc_pages(253);
+    stats.count_request_loop();
+
+    let snap = stats.snapshot();
+    assert_eq!(snap.ghcb_ioio, 2);
+    assert_eq!(snap.ghcb_psc, 1);
+    assert_eq!(snap.ghcb_exits(), 3);
+    assert_eq!(snap.psc_pages, 253);
+    assert_eq!(snap.request_loops, 1);
+}
@@ -4,8 +4,13 @@
 //
 // Author: Joerg Roedel <jroedel@suse.de>
 
-use crate::types::VirtAddr;
-use crate::utils::page_align;
+use crate::cpu::control_regs::{
+    irqs_enabled, read_cr3, read_cr4, write_cr3, write_cr4, CR4Flags, Cr3,
+};
+use crate::cpu::cpuid::cpuid_table;
+use crate::cpu::features::cpu_has_pge;
+use crate::cpu::percpu::this_cpu;
+use crate::types::{PhysAddr, VirtAddr, PAGE_SIZE};
 use core::arch::asm;
 
 const INVLPGB_VALID_VA: u64 = 1u64 << 0;
@@ -52,8 +57,10 @@ pub fn flush_tlb_global_sync() {
 }
 
 pub fn flush_address(va: VirtAddr) {
-    let rax: u64 =
-        (page_align(va) as u64) | INVLPGB_VALID_VA | INVLPGB_VALID_ASID | INVLPGB_VALID_GLOBAL;
+    let rax: u64 = u64::from(va.page_align_down())
+        | INVLPGB_VALID_VA
+        | INVLPGB_VALID_ASID
+        | INVLPGB_VALID_GLOBAL;
     do_invlpgb(rax, 0, 0);
 }
 
@@ -61,3 +68,118 @@ pub fn flush_address_sync(va: VirtAddr) {
     flush_address(va);
     do_tlbsync();
 }
+
+// Flushing more pages than this falls back to flushing the whole ASID
+const FLUSH_RANGE_MAX_PAGES: usize = 512;
+
+// Maximum number of additional pages a single INVLPGB can flush
+fn invlpgb_count_max() -> usize {
+    match cpuid_table(0x80000008) {
+        None => 0,
+        Some(c) => (c.edx & 0xffff) as usize,
+    }
+}
+
+// INVLPGB broadcasts the invalidation to all CPUs in the system and
+// TLBSYNC waits until every CPU has completed it, so no IPIs are needed to
+// shoot down TLB entries on the other online CPUs.
+pub fn flush_tlb_all() {
+    flush_tlb_global_sync();
+}
+
+pub fn flush_tlb_range(start: VirtAddr, len: usize) {
+    let mut va = start.page_align_down();
+    let end = (start + len).page_align_up();
+    let pages = (end - va) / PAGE_SIZE;
+
+    if pages > FLUSH_RANGE_MAX_PAGES {
+        flush_tlb_all();
+        return;
+    }
+
+    let max = invlpgb_count_max();
+
+    while va < end {
+        let count = ((end - va) / PAGE_SIZE - 1).min(max);
+        let rax: u64 = u64::from(va) | INVLPGB_VALID_VA | INVLPGB_VALID_ASID | INVLPGB_VALID_GLOBAL;
+        do_invlpgb(rax, count as u64, 0);
+        va += (count + 1) * PAGE_SIZE;
+    }
+
+    do_tlbsync();
+}
+
+// Invalidates the TLB entry for va on the current CPU only
+pub fn invlpg(va: VirtAddr) {
+    unsafe {
+        asm!("invlpg (%rax)",
+             in("rax") va.as_usize(),
+             options(att_syntax));
+    }
+}
+
+// Flushes the non-global TLB entries of the current PCID on this CPU only
+pub fn flush_tlb_local() {
+    write_cr3(read_cr3());
+}
+
+// Flushes all TLB entries on this CPU only, including global ones. Toggling
+// CR4.PGE also drops the entries of all PCIDs. Without PGE there are no
+// global entries, the current PCID is flushed and all others get flushed on
+// their next load. Must be called with interrupts disabled, so nothing runs
+// in between the two CR4 writes.
+pub fn flush_tlb_global_local() {
+    debug_assert!(!irqs_enabled());
+
+    let cr4 = read_cr4();
+    if cpu_has_pge() && cr4.contains(CR4Flags::PGE) {
+        write_cr4(cr4 & !CR4Flags::PGE);
+        write_cr4(cr4);
+    } else {
+        this_cpu().clear_loaded_pcids();
+        flush_tlb_local();
+    }
+}
+
+// TLB entries are only kept for PCIDs below this, switches to other PCIDs
+// always flush
+const PCID_TRACKED: u16 = 64;
+
+// The CR3 value switch_address_space() writes, kept apart from the register
+// access so it can be tested on the host
+fn switch_cr3(root: PhysAddr, pcid: Option<u16>, pcide: bool, reused: bool) -> Cr3 {
+    match pcid {
+        Some(pcid) if pcide => Cr3::new(root, pcid, reused),
+        _ => Cr3::new(root, 0, false),
+    }
+}
+
+// Loads the page table at root. A PCID must only ever be used for the same
+// root on a CPU. The TLB entries of a PCID are kept when it is loaded again
+// with CR4.PCIDE set, everything else flushes the non-global entries of the
+// new PCID. PCID 0 is shared by the SVSM page tables and always flushes.
+pub fn switch_address_space(root: PhysAddr, pcid: Option<u16>) {
+    let pcide = read_cr4().contains(CR4Flags::PCIDE);
+    let reused = match pcid {
+        Some(pcid) if pcide && pcid != 0 && pcid < PCID_TRACKED => {
+            this_cpu().mark_pcid_loaded(pcid)
+        }
+        _ => false,
+    };
+
+    write_cr3(switch_cr3(root, pcid, pcide, reused));
+}
+
+#[test]
+fn test_switch_cr3() {
+    let root = PhysAddr::from(0x10_0000usize);
+
+    assert_eq!(
+        switch_cr3(root, Some(3), true, true).bits(),
+        0x8000_0000_0010_0003
+    );
+    assert_eq!(switch_cr3(root, Some(3), true, false).bits(), 0x10_0003);
+    // Without PCIDE the low bits are PWT/PCD, the PCID is not used
+    assert_eq!(switch_cr3(root, Some(3), false, true).bits(), 0x10_0000);
+    assert_eq!(switch_cr3(root, None, true, false).bits(), 0x10_0000);
+}
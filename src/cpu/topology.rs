@@ -0,0 +1,75 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2026 SUSE LLC
+//
+// Author: agent <agent@local>
+
+use super::cpuid::{cpuid_table, cpuid_table_raw};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CpuTopology {
+    pub package_id: u32,
+    pub core_id: u32,
+    pub thread_id: u32,
+}
+
+// Returns the number of low APIC-ID bits used for the SMT and the core
+// level as reported in CPUID. Prefers the extended topology leaf and falls
+// back to the AMD specific leaves.
+fn apic_id_shifts() -> (u32, u32) {
+    if let (Some(smt), Some(core)) = (cpuid_table_raw(0xb, 0, 0, 0), cpuid_table_raw(0xb, 1, 0, 0))
+    {
+        let smt_shift = smt.eax & 0x1f;
+        let core_shift = core.eax & 0x1f;
+        if core_shift >= smt_shift {
+            return (smt_shift, core_shift);
+        }
+    }
+
+    let core_shift = match cpuid_table(0x80000008) {
+        Some(c) => (c.ecx >> 12) & 0xf,
+        None => 0,
+    };
+
+    let threads_per_core = match cpuid_table(0x8000001e) {
+        Some(c) => ((c.ebx >> 8) & 0xff) + 1,
+        None => 1,
+    };
+
+    let smt_shift = u32::BITS - (threads_per_core - 1).leading_zeros();
+
+    (smt_shift.min(core_shift), core_shift)
+}
+
+impl CpuTopology {
+    pub fn from_apic_id(apic_id: u32) -> Self {
+        let (smt_shift, core_shift) = apic_id_shifts();
+
+        CpuTopology::decode(apic_id, smt_shift, core_shift)
+    }
+
+    pub fn decode(apic_id: u32, smt_shift: u32, core_shift: u32) -> Self {
+        let mask = |shift: u32| 1u32.checked_shl(shift).map_or(u32::MAX, |v| v - 1);
+
+        CpuTopology {
+            package_id: apic_id.checked_shr(core_shift).unwrap_or(0),
+            core_id: (apic_id & mask(core_shift)) >> smt_shift,
+            thread_id: apic_id & mask(smt_shift),
+        }
+    }
+}
+
+#[test]
+fn test_topology_decode() {
+    // 2 threads per core, 8 cores per package
+    let topo = CpuTopology::decode(0x13, 1, 4);
+
+    assert_eq!(topo.package_id, 1);
+    assert_eq!(topo.core_id, 1);
+    assert_eq!(topo.thread_id, 1);
+
+    let topo = CpuTopology::decode(7, 0, 0);
+    assert_eq!(topo.package_id, 7);
+    assert_eq!(topo.core_id, 0);
+    assert_eq!(topo.thread_id, 0);
+}
@@ -0,0 +1,162 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2026 SUSE LLC
+//
+// Author: agent <agent@local>
+
+use super::cpuid::cpuid_table;
+use super::msr::{read_msr, MSR_GUEST_TSC_FREQ};
+use crate::sev::secrets_page::SecretsPage;
+use crate::sev::status::sev_secure_tsc_enabled;
+use crate::utils::immut_after_init::ImmutAfterInitCell;
+use core::arch::asm;
+use core::time::Duration;
+
+// TSC frequency assumed when it can not be determined. This is a
+// deliberately high upper bound so that timeouts never expire early.
+const TSC_KHZ_UPPER_BOUND: u64 = 5_000_000;
+
+// Nominal frequency and the effective one, which differ under Secure TSC
+static TSC_NOMINAL_KHZ: ImmutAfterInitCell<u64> = ImmutAfterInitCell::new(TSC_KHZ_UPPER_BOUND);
+static TSC_KHZ: ImmutAfterInitCell<u64> = ImmutAfterInitCell::new(TSC_KHZ_UPPER_BOUND);
+
+// Secure TSC runs the guest TSC slower than the nominal frequency by
+// tsc_factor, given in units of 1/100000.
+pub fn scale_tsc_khz(khz: u64, tsc_factor: u32) -> u64 {
+    khz - khz * tsc_factor as u64 / 100_000
+}
+
+pub fn rdtsc() -> u64 {
+    let eax: u32;
+    let edx: u32;
+
+    unsafe {
+        asm!("rdtsc",
+             out("eax") eax,
+             out("edx") edx,
+             options(att_syntax, nomem, nostack));
+    }
+
+    (eax as u64) | (edx as u64) << 32
+}
+
+// TSC frequency from the CPUID table: leaf 0x15 gives the TSC/crystal ratio
+// and possibly the crystal frequency, leaf 0x16 the base frequency in MHz.
+fn tsc_khz_from_cpuid() -> Option<u64> {
+    if let Some(res) = cpuid_table(0x15) {
+        let (denom, numer, crystal_hz) = (res.eax as u64, res.ebx as u64, res.ecx as u64);
+        if denom != 0 && numer != 0 && crystal_hz != 0 {
+            return Some(crystal_hz * numer / denom / 1000);
+        }
+    }
+
+    cpuid_table(0x16)
+        .map(|res| (res.eax & 0xffff) as u64 * 1000)
+        .filter(|khz| *khz != 0)
+}
+
+// Must be called on the BSP after the CPUID table has been registered and
+// SEV status has been initialized. Without frequency information the upper
+// bound stays in effect. With Secure TSC the nominal frequency comes from
+// MSR_GUEST_TSC_FREQ (in MHz) and is scaled by the secrets page TSC factor.
+pub fn tsc_init(secrets: &SecretsPage) {
+    let (nominal, khz) = if sev_secure_tsc_enabled() {
+        let nominal = (read_msr(MSR_GUEST_TSC_FREQ) & 0xffff) * 1000;
+        (nominal, secrets.tsc_scale_khz(nominal))
+    } else {
+        match tsc_khz_from_cpuid() {
+            Some(khz) => (khz, khz),
+            None => (0, 0),
+        }
+    };
+
+    if khz == 0 {
+        log::info!(
+            "TSC frequency unknown, assuming {} kHz",
+            TSC_KHZ_UPPER_BOUND
+        );
+        return;
+    }
+
+    unsafe {
+        TSC_NOMINAL_KHZ.reinit(&nominal);
+        TSC_KHZ.reinit(&khz);
+    }
+    log::info!("TSC frequency: {} kHz", khz);
+}
+
+pub fn tsc_nominal_khz() -> u64 {
+    *TSC_NOMINAL_KHZ
+}
+
+pub fn tsc_khz() -> u64 {
+    *TSC_KHZ
+}
+
+pub fn ticks_to_ns(ticks: u64, khz: u64) -> u64 {
+    ((ticks as u128) * 1_000_000 / khz as u128)
+        .try_into()
+        .unwrap_or(u64::MAX)
+}
+
+fn duration_to_ticks(d: Duration, khz: u64) -> u64 {
+    let ticks = d.as_micros() * khz as u128 / 1000;
+
+    ticks.try_into().unwrap_or(u64::MAX)
+}
+
+fn ticks_to_duration(ticks: u64, khz: u64) -> Duration {
+    Duration::from_micros(((ticks as u128) * 1000 / khz as u128) as u64)
+}
+
+pub fn duration_to_tsc(d: Duration) -> u64 {
+    duration_to_ticks(d, tsc_khz())
+}
+
+pub fn tsc_to_duration(ticks: u64) -> Duration {
+    ticks_to_duration(ticks, tsc_khz())
+}
+
+// Point in time based on the TSC, which is monotonic and synchronized
+// across CPUs on SEV-SNP capable hardware.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant {
+    tsc: u64,
+}
+
+impl Instant {
+    pub fn now() -> Self {
+        Instant { tsc: rdtsc() }
+    }
+
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        tsc_to_duration(self.tsc.saturating_sub(earlier.tsc))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+}
+
+// Spin for at least the given duration. Only meant for short delays, e.g.
+// during CPU bring-up.
+pub fn busy_wait(d: Duration) {
+    let start = Instant::now();
+
+    while start.elapsed() < d {
+        core::hint::spin_loop();
+    }
+}
+
+#[test]
+fn test_tsc_duration_conversion() {
+    // 2 GHz TSC
+    let khz = 2_000_000;
+
+    assert_eq!(duration_to_ticks(Duration::from_millis(1), khz), 2_000_000);
+    assert_eq!(
+        ticks_to_duration(3_000_000_000, khz),
+        Duration::from_millis(1500)
+    );
+    assert_eq!(duration_to_ticks(Duration::MAX, khz), u64::MAX);
+}
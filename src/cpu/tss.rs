@@ -4,15 +4,18 @@
 //
 // Author: Joerg Roedel <jroedel@suse.de>
 
+use crate::types::VirtAddr;
+
 // IST offsets
 pub const _IST_INVALID: usize = 0;
 pub const IST_DF: usize = 1;
+pub const IST_VC: usize = 2;
 
 #[repr(C, packed)]
 pub struct X86Tss {
     reserved1: u32,
-    pub stacks: [usize; 3],
-    pub ist_stacks: [usize; 8],
+    pub stacks: [VirtAddr; 3],
+    pub ist_stacks: [VirtAddr; 8],
     reserved2: u64,
     reserved3: u16,
     io_bmp_base: u16,
@@ -24,8 +27,8 @@ impl X86Tss {
     pub const fn new() -> Self {
         X86Tss {
             reserved1: 0,
-            stacks: [0; 3],
-            ist_stacks: [0; 8],
+            stacks: [VirtAddr::null(); 3],
+            ist_stacks: [VirtAddr::null(); 8],
             reserved2: 0,
             reserved3: 0,
             io_bmp_base: (TSS_LIMIT + 1) as u16,
@@ -11,7 +11,7 @@ use super::control_regs::{read_cr0, read_cr3, read_cr4};
 use super::efer::read_efer;
 use super::gdt::gdt_base_limit;
 use super::idt::idt_base_limit;
-use super::msr::read_msr;
+use crate::sev::current_sev_features;
 
 fn svsm_code_segment() -> VMSASegment {
     VMSASegment {
@@ -51,32 +51,175 @@ fn svsm_idt_segment() -> VMSASegment {
     }
 }
 
+fn null_segment() -> VMSASegment {
+    VMSASegment {
+        selector: 0,
+        flags: 0,
+        limit: 0,
+        base: 0,
+    }
+}
+
+// Collects the initial register state of a VMSA. All data segment registers
+// (ES, SS, DS, FS, GS) share the segment set with ds(). Registers without a
+// setter get their architectural reset values in build_into().
+#[derive(Clone, Copy)]
+pub struct VmsaBuilder {
+    rip: u64,
+    rsp: u64,
+    cr0: u64,
+    cr3: u64,
+    cr4: u64,
+    efer: u64,
+    cs: VMSASegment,
+    ds: VMSASegment,
+    gdt: VMSASegment,
+    idt: VMSASegment,
+    tr: VMSASegment,
+    vmpl: u8,
+    sev_features: u64,
+}
+
+impl VmsaBuilder {
+    pub fn new() -> Self {
+        VmsaBuilder {
+            rip: 0,
+            rsp: 0,
+            cr0: 0,
+            cr3: 0,
+            cr4: 0,
+            efer: 0,
+            cs: null_segment(),
+            ds: null_segment(),
+            gdt: null_segment(),
+            idt: null_segment(),
+            tr: null_segment(),
+            vmpl: 0,
+            sev_features: 0,
+        }
+    }
+
+    // Preset for a VMSA running SVSM code at VMPL0 with the control register
+    // setup, descriptor tables and SEV features of the current CPU
+    pub fn svsm() -> Self {
+        VmsaBuilder::new()
+            .cs(svsm_code_segment())
+            .ds(svsm_data_segment())
+            .gdt(svsm_gdt_segment())
+            .idt(svsm_idt_segment())
+            .cr0(read_cr0().bits())
+            .cr3(read_cr3().bits())
+            .cr4(read_cr4().bits())
+            .efer(read_efer().bits())
+            .vmpl(0)
+            .sev_features(current_sev_features().bits())
+    }
+
+    pub fn rip(mut self, rip: u64) -> Self {
+        self.rip = rip;
+        self
+    }
+
+    pub fn rsp(mut self, rsp: u64) -> Self {
+        self.rsp = rsp;
+        self
+    }
+
+    pub fn cr0(mut self, cr0: u64) -> Self {
+        self.cr0 = cr0;
+        self
+    }
+
+    pub fn cr3(mut self, cr3: u64) -> Self {
+        self.cr3 = cr3;
+        self
+    }
+
+    pub fn cr4(mut self, cr4: u64) -> Self {
+        self.cr4 = cr4;
+        self
+    }
+
+    pub fn efer(mut self, efer: u64) -> Self {
+        self.efer = efer;
+        self
+    }
+
+    pub fn cs(mut self, cs: VMSASegment) -> Self {
+        self.cs = cs;
+        self
+    }
+
+    pub fn ds(mut self, ds: VMSASegment) -> Self {
+        self.ds = ds;
+        self
+    }
+
+    pub fn gdt(mut self, gdt: VMSASegment) -> Self {
+        self.gdt = gdt;
+        self
+    }
+
+    pub fn idt(mut self, idt: VMSASegment) -> Self {
+        self.idt = idt;
+        self
+    }
+
+    pub fn tr(mut self, tr: VMSASegment) -> Self {
+        self.tr = tr;
+        self
+    }
+
+    pub fn vmpl(mut self, vmpl: u8) -> Self {
+        self.vmpl = vmpl;
+        self
+    }
+
+    pub fn sev_features(mut self, sev_features: u64) -> Self {
+        self.sev_features = sev_features;
+        self
+    }
+
+    pub fn build_into(&self, vmsa: &mut VMSA) {
+        vmsa.es = self.ds;
+        vmsa.cs = self.cs;
+        vmsa.ss = self.ds;
+        vmsa.ds = self.ds;
+        vmsa.fs = self.ds;
+        vmsa.gs = self.ds;
+        vmsa.gdt = self.gdt;
+        vmsa.idt = self.idt;
+        vmsa.tr = self.tr;
+
+        vmsa.rip = self.rip;
+        vmsa.rsp = self.rsp;
+        vmsa.cr0 = self.cr0;
+        vmsa.cr3 = self.cr3;
+        vmsa.cr4 = self.cr4;
+        vmsa.efer = self.efer;
+
+        vmsa.rflags = 0x2;
+        vmsa.dr6 = 0xffff0ff0;
+        vmsa.dr7 = 0x400;
+        vmsa.g_pat = 0x0007040600070406u64;
+        vmsa.xcr0 = 1;
+        vmsa.mxcsr = 0x1f80;
+        vmsa.x87_ftw = 0x5555;
+        vmsa.x87_fcw = 0x0040;
+        vmsa.vmpl = self.vmpl;
+
+        vmsa.sev_features = self.sev_features;
+    }
+}
+
+impl Default for VmsaBuilder {
+    fn default() -> Self {
+        VmsaBuilder::new()
+    }
+}
+
 pub fn init_svsm_vmsa(vmsa: &mut VMSA) {
-    vmsa.es = svsm_data_segment();
-    vmsa.cs = svsm_code_segment();
-    vmsa.ss = svsm_data_segment();
-    vmsa.ds = svsm_data_segment();
-    vmsa.fs = svsm_data_segment();
-    vmsa.gs = svsm_data_segment();
-    vmsa.gdt = svsm_gdt_segment();
-    vmsa.idt = svsm_idt_segment();
-
-    vmsa.cr0 = read_cr0().bits();
-    vmsa.cr3 = read_cr3() as u64;
-    vmsa.cr4 = read_cr4().bits();
-    vmsa.efer = read_efer().bits();
-
-    vmsa.rflags = 0x2;
-    vmsa.dr6 = 0xffff0ff0;
-    vmsa.dr7 = 0x400;
-    vmsa.g_pat = 0x0007040600070406u64;
-    vmsa.xcr0 = 1;
-    vmsa.mxcsr = 0x1f80;
-    vmsa.x87_ftw = 0x5555;
-    vmsa.x87_fcw = 0x0040;
-    vmsa.vmpl = 0;
-
-    vmsa.sev_features = read_msr(0xc0010131) >> 2;
+    VmsaBuilder::svsm().build_into(vmsa);
 }
 
 fn real_mode_code_segment(rip: u64) -> VMSASegment {
@@ -105,7 +248,7 @@ fn real_mode_sys_seg(flags: u16) -> VMSASegment {
     }
 }
 
-pub fn init_guest_vmsa(vmsa: *mut VMSA, rip: u64) {
+pub fn init_guest_vmsa(vmsa: *mut VMSA, rip: u64, vmpl: u8) {
     let v = unsafe { vmsa.as_mut().unwrap() };
 
     v.cr0 = 0x6000_0010;
@@ -129,6 +272,6 @@ pub fn init_guest_vmsa(vmsa: *mut VMSA, rip: u64) {
     v.x87_ftw = 0x5555;
     v.x87_fcw = 0x0040;
 
-    v.vmpl = 1;
-    v.sev_features = read_msr(0xc0010131) >> 2;
+    v.vmpl = vmpl;
+    v.sev_features = current_sev_features().bits();
 }
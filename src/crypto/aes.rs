@@ -0,0 +1,176 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2026 SUSE LLC
+//
+// Author: agent <agent@local>
+
+// Software AES-256 block encryption (FIPS-197). The SVSM can not use
+// AES-NI as it runs without SSE, and table lookups indexed by secret data
+// would leak the key through the cache to the hypervisor. So the S-box is
+// evaluated by scanning the whole table for every byte. This is slow, but
+// the only consumers are short guest messages.
+
+use core::ptr;
+use core::sync::atomic::{compiler_fence, Ordering};
+
+pub const AES_BLOCK_SIZE: usize = 16;
+pub const AES256_KEY_SIZE: usize = 32;
+
+const AES256_ROUNDS: usize = 14;
+const AES256_KEY_WORDS: usize = 8;
+const AES256_SCHEDULE_WORDS: usize = 4 * (AES256_ROUNDS + 1);
+
+const SBOX: [u8; 256] = [
+    0x63, 0x7c, 0x77, 0x7b, 0xf2, 0x6b, 0x6f, 0xc5, 0x30, 0x01, 0x67, 0x2b, 0xfe, 0xd7, 0xab, 0x76,
+    0xca, 0x82, 0xc9, 0x7d, 0xfa, 0x59, 0x47, 0xf0, 0xad, 0xd4, 0xa2, 0xaf, 0x9c, 0xa4, 0x72, 0xc0,
+    0xb7, 0xfd, 0x93, 0x26, 0x36, 0x3f, 0xf7, 0xcc, 0x34, 0xa5, 0xe5, 0xf1, 0x71, 0xd8, 0x31, 0x15,
+    0x04, 0xc7, 0x23, 0xc3, 0x18, 0x96, 0x05, 0x9a, 0x07, 0x12, 0x80, 0xe2, 0xeb, 0x27, 0xb2, 0x75,
+    0x09, 0x83, 0x2c, 0x1a, 0x1b, 0x6e, 0x5a, 0xa0, 0x52, 0x3b, 0xd6, 0xb3, 0x29, 0xe3, 0x2f, 0x84,
+    0x53, 0xd1, 0x00, 0xed, 0x20, 0xfc, 0xb1, 0x5b, 0x6a, 0xcb, 0xbe, 0x39, 0x4a, 0x4c, 0x58, 0xcf,
+    0xd0, 0xef, 0xaa, 0xfb, 0x43, 0x4d, 0x33, 0x85, 0x45, 0xf9, 0x02, 0x7f, 0x50, 0x3c, 0x9f, 0xa8,
+    0x51, 0xa3, 0x40, 0x8f, 0x92, 0x9d, 0x38, 0xf5, 0xbc, 0xb6, 0xda, 0x21, 0x10, 0xff, 0xf3, 0xd2,
+    0xcd, 0x0c, 0x13, 0xec, 0x5f, 0x97, 0x44, 0x17, 0xc4, 0xa7, 0x7e, 0x3d, 0x64, 0x5d, 0x19, 0x73,
+    0x60, 0x81, 0x4f, 0xdc, 0x22, 0x2a, 0x90, 0x88, 0x46, 0xee, 0xb8, 0x14, 0xde, 0x5e, 0x0b, 0xdb,
+    0xe0, 0x32, 0x3a, 0x0a, 0x49, 0x06, 0x24, 0x5c, 0xc2, 0xd3, 0xac, 0x62, 0x91, 0x95, 0xe4, 0x79,
+    0xe7, 0xc8, 0x37, 0x6d, 0x8d, 0xd5, 0x4e, 0xa9, 0x6c, 0x56, 0xf4, 0xea, 0x65, 0x7a, 0xae, 0x08,
+    0xba, 0x78, 0x25, 0x2e, 0x1c, 0xa6, 0xb4, 0xc6, 0xe8, 0xdd, 0x74, 0x1f, 0x4b, 0xbd, 0x8b, 0x8a,
+    0x70, 0x3e, 0xb5, 0x66, 0x48, 0x03, 0xf6, 0x0e, 0x61, 0x35, 0x57, 0xb9, 0x86, 0xc1, 0x1d, 0x9e,
+    0xe1, 0xf8, 0x98, 0x11, 0x69, 0xd9, 0x8e, 0x94, 0x9b, 0x1e, 0x87, 0xe9, 0xce, 0x55, 0x28, 0xdf,
+    0x8c, 0xa1, 0x89, 0x0d, 0xbf, 0xe6, 0x42, 0x68, 0x41, 0x99, 0x2d, 0x0f, 0xb0, 0x54, 0xbb, 0x16,
+];
+
+const RCON: [u8; 7] = [0x01, 0x02, 0x04, 0x08, 0x10, 0x20, 0x40];
+
+// Constant-time S-box lookup
+fn sub_byte(x: u8) -> u8 {
+    let mut ret: u8 = 0;
+
+    for (i, v) in SBOX.iter().enumerate() {
+        let diff = (i as u32) ^ (x as u32);
+        let mask = (diff.wrapping_sub(1) >> 8) as u8;
+        ret |= v & mask;
+    }
+
+    ret
+}
+
+fn sub_word(w: [u8; 4]) -> [u8; 4] {
+    [
+        sub_byte(w[0]),
+        sub_byte(w[1]),
+        sub_byte(w[2]),
+        sub_byte(w[3]),
+    ]
+}
+
+fn xtime(b: u8) -> u8 {
+    (b << 1) ^ (0x1b & 0u8.wrapping_sub(b >> 7))
+}
+
+pub struct Aes256 {
+    schedule: [[u8; 4]; AES256_SCHEDULE_WORDS],
+}
+
+impl Aes256 {
+    pub fn new(key: &[u8; AES256_KEY_SIZE]) -> Self {
+        let mut schedule = [[0u8; 4]; AES256_SCHEDULE_WORDS];
+
+        for (i, word) in schedule.iter_mut().take(AES256_KEY_WORDS).enumerate() {
+            word.copy_from_slice(&key[4 * i..4 * i + 4]);
+        }
+
+        for i in AES256_KEY_WORDS..AES256_SCHEDULE_WORDS {
+            let mut temp = schedule[i - 1];
+
+            if i % AES256_KEY_WORDS == 0 {
+                temp = sub_word([temp[1], temp[2], temp[3], temp[0]]);
+                temp[0] ^= RCON[i / AES256_KEY_WORDS - 1];
+            } else if i % AES256_KEY_WORDS == 4 {
+                temp = sub_word(temp);
+            }
+
+            for j in 0..4 {
+                schedule[i][j] = schedule[i - AES256_KEY_WORDS][j] ^ temp[j];
+            }
+        }
+
+        Aes256 { schedule }
+    }
+
+    fn add_round_key(&self, state: &mut [u8; AES_BLOCK_SIZE], round: usize) {
+        for (i, b) in state.iter_mut().enumerate() {
+            *b ^= self.schedule[4 * round + i / 4][i % 4];
+        }
+    }
+
+    fn sub_shift_rows(state: &mut [u8; AES_BLOCK_SIZE]) {
+        let old = *state;
+
+        // State is stored column by column, row r is shifted left by r
+        for c in 0..4 {
+            for r in 0..4 {
+                state[4 * c + r] = sub_byte(old[4 * ((c + r) % 4) + r]);
+            }
+        }
+    }
+
+    fn mix_columns(state: &mut [u8; AES_BLOCK_SIZE]) {
+        for c in 0..4 {
+            let col = [
+                state[4 * c],
+                state[4 * c + 1],
+                state[4 * c + 2],
+                state[4 * c + 3],
+            ];
+            let all = col[0] ^ col[1] ^ col[2] ^ col[3];
+
+            for r in 0..4 {
+                state[4 * c + r] = col[r] ^ all ^ xtime(col[r] ^ col[(r + 1) % 4]);
+            }
+        }
+    }
+
+    pub fn encrypt_block(&self, block: &mut [u8; AES_BLOCK_SIZE]) {
+        self.add_round_key(block, 0);
+
+        for round in 1..AES256_ROUNDS {
+            Self::sub_shift_rows(block);
+            Self::mix_columns(block);
+            self.add_round_key(block, round);
+        }
+
+        Self::sub_shift_rows(block);
+        self.add_round_key(block, AES256_ROUNDS);
+    }
+}
+
+impl Drop for Aes256 {
+    fn drop(&mut self) {
+        let start = self.schedule.as_mut_ptr().cast::<u8>();
+
+        for i in 0..AES256_SCHEDULE_WORDS * 4 {
+            unsafe { ptr::write_volatile(start.add(i), 0) };
+        }
+        compiler_fence(Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn test_aes256_fips197() {
+    let mut key = [0u8; AES256_KEY_SIZE];
+    for (i, b) in key.iter_mut().enumerate() {
+        *b = i as u8;
+    }
+
+    let mut block: [u8; AES_BLOCK_SIZE] = [
+        0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd, 0xee,
+        0xff,
+    ];
+    let expected: [u8; AES_BLOCK_SIZE] = [
+        0x8e, 0xa2, 0xb7, 0xca, 0x51, 0x67, 0x45, 0xbf, 0xea, 0xfc, 0x49, 0x90, 0x4b, 0x49, 0x60,
+        0x89,
+    ];
+
+    Aes256::new(&key).encrypt_block(&mut block);
+    assert_eq!(block, expected);
+}
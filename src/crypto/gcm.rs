@@ -0,0 +1,191 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2026 SUSE LLC
+//
+// Author: agent <agent@local>
+
+// AES-256-GCM (NIST SP 800-38D) with 96-bit IVs and 128-bit tags, as
+// used by the SNP guest message protocol.
+
+extern crate alloc;
+
+use super::aes::{Aes256, AES256_KEY_SIZE, AES_BLOCK_SIZE};
+use alloc::vec::Vec;
+
+pub const GCM_IV_SIZE: usize = 12;
+pub const GCM_TAG_SIZE: usize = 16;
+
+// Constant-time multiplication in GF(2^128) with the GCM bit order
+fn gf128_mul(x: u128, y: u128) -> u128 {
+    let r: u128 = 0xe1 << 120;
+    let mut z: u128 = 0;
+    let mut v = y;
+
+    for i in 0..128 {
+        let bit = (x >> (127 - i)) & 1;
+        z ^= v & 0u128.wrapping_sub(bit);
+        let lsb = v & 1;
+        v = (v >> 1) ^ (r & 0u128.wrapping_sub(lsb));
+    }
+
+    z
+}
+
+struct GHash {
+    h: u128,
+    acc: u128,
+}
+
+impl GHash {
+    fn new(h: u128) -> Self {
+        GHash { h, acc: 0 }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        for chunk in data.chunks(AES_BLOCK_SIZE) {
+            let mut block = [0u8; AES_BLOCK_SIZE];
+            block[..chunk.len()].copy_from_slice(chunk);
+            self.acc = gf128_mul(self.acc ^ u128::from_be_bytes(block), self.h);
+        }
+    }
+
+    fn finish(mut self, aad_len: usize, text_len: usize) -> u128 {
+        let lens = ((aad_len as u128 * 8) << 64) | (text_len as u128 * 8);
+        self.acc = gf128_mul(self.acc ^ lens, self.h);
+        self.acc
+    }
+}
+
+pub struct Aes256Gcm {
+    cipher: Aes256,
+    h: u128,
+}
+
+impl Aes256Gcm {
+    pub fn new(key: &[u8; AES256_KEY_SIZE]) -> Self {
+        let cipher = Aes256::new(key);
+        let mut h = [0u8; AES_BLOCK_SIZE];
+        cipher.encrypt_block(&mut h);
+
+        Aes256Gcm {
+            cipher,
+            h: u128::from_be_bytes(h),
+        }
+    }
+
+    fn counter_block(iv: &[u8; GCM_IV_SIZE], counter: u32) -> [u8; AES_BLOCK_SIZE] {
+        let mut block = [0u8; AES_BLOCK_SIZE];
+        block[..GCM_IV_SIZE].copy_from_slice(iv);
+        block[GCM_IV_SIZE..].copy_from_slice(&counter.to_be_bytes());
+        block
+    }
+
+    fn apply_keystream(&self, iv: &[u8; GCM_IV_SIZE], data: &mut [u8]) {
+        for (i, chunk) in data.chunks_mut(AES_BLOCK_SIZE).enumerate() {
+            // Counter value 1 is reserved for the tag
+            let mut ks = Self::counter_block(iv, (i as u32).wrapping_add(2));
+            self.cipher.encrypt_block(&mut ks);
+            for (b, k) in chunk.iter_mut().zip(ks.iter()) {
+                *b ^= k;
+            }
+        }
+    }
+
+    fn tag(&self, iv: &[u8; GCM_IV_SIZE], aad: &[u8], ciphertext: &[u8]) -> [u8; GCM_TAG_SIZE] {
+        let mut ghash = GHash::new(self.h);
+        ghash.update(aad);
+        ghash.update(ciphertext);
+        let s = ghash.finish(aad.len(), ciphertext.len());
+
+        let mut tag = Self::counter_block(iv, 1);
+        self.cipher.encrypt_block(&mut tag);
+        for (t, s) in tag.iter_mut().zip(s.to_be_bytes().iter()) {
+            *t ^= s;
+        }
+        tag
+    }
+
+    // Returns the ciphertext followed by the authentication tag
+    pub fn encrypt(&self, iv: &[u8; GCM_IV_SIZE], aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(plaintext.len() + GCM_TAG_SIZE);
+        out.extend_from_slice(plaintext);
+        self.apply_keystream(iv, &mut out);
+
+        let tag = self.tag(iv, aad, &out);
+        out.extend_from_slice(&tag);
+        out
+    }
+
+    // Expects the ciphertext followed by the authentication tag
+    pub fn decrypt(&self, iv: &[u8; GCM_IV_SIZE], aad: &[u8], input: &[u8]) -> Result<Vec<u8>, ()> {
+        if input.len() < GCM_TAG_SIZE {
+            return Err(());
+        }
+
+        let (ciphertext, tag) = input.split_at(input.len() - GCM_TAG_SIZE);
+        let expected = self.tag(iv, aad, ciphertext);
+
+        let diff = expected
+            .iter()
+            .zip(tag.iter())
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b));
+        if diff != 0 {
+            return Err(());
+        }
+
+        let mut out = Vec::from(ciphertext);
+        self.apply_keystream(iv, &mut out);
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+fn from_hex(s: &str) -> Vec<u8> {
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+#[test]
+fn test_gcm_zero_key() {
+    let gcm = Aes256Gcm::new(&[0u8; AES256_KEY_SIZE]);
+    let iv = [0u8; GCM_IV_SIZE];
+
+    assert_eq!(
+        gcm.encrypt(&iv, &[], &[]),
+        from_hex("530f8afbc74536b9a963b4f1c4cb738b")
+    );
+    assert_eq!(
+        gcm.encrypt(&iv, &[], &[0u8; 16]),
+        from_hex("cea7403d4d606b6e074ec5d3baf39d18d0d1c8a799996bf0265b98b5d48ab919")
+    );
+}
+
+#[test]
+fn test_gcm_aad_roundtrip() {
+    let key: [u8; AES256_KEY_SIZE] =
+        from_hex("feffe9928665731c6d6a8f9467308308feffe9928665731c6d6a8f9467308308")
+            .try_into()
+            .unwrap();
+    let iv: [u8; GCM_IV_SIZE] = from_hex("cafebabefacedbaddecaf888").try_into().unwrap();
+    let aad = from_hex("feedfacedeadbeeffeedfacedeadbeefabaddad2");
+    let plaintext = from_hex(concat!(
+        "d9313225f88406e5a55909c5aff5269a86a7a9531534f7da2e4c303d8a318a72",
+        "1c3c0c95956809532fcf0e2449a6b525b16aedf5aa0de657ba637b39"
+    ));
+    let expected = from_hex(concat!(
+        "522dc1f099567d07f47f37a32a84427d643a8cdcbfe5c0c97598a2bd2555d1aa",
+        "8cb08e48590dbb3da7b08b1056828838c5f61e6393ba7a0abcc9f662",
+        "76fc6ece0f4e1768cddf8853bb2d551b"
+    ));
+
+    let gcm = Aes256Gcm::new(&key);
+    let sealed = gcm.encrypt(&iv, &aad, &plaintext);
+    assert_eq!(sealed, expected);
+    assert_eq!(gcm.decrypt(&iv, &aad, &sealed), Ok(plaintext));
+
+    let mut tampered = sealed;
+    tampered[0] ^= 1;
+    assert!(gcm.decrypt(&iv, &aad, &tampered).is_err());
+}
@@ -7,7 +7,9 @@
 #[cfg(feature = "enable-stacktrace")]
 use crate::cpu::idt::{is_exception_handler_return_site, X86Regs};
 #[cfg(feature = "enable-stacktrace")]
-use crate::mm::address_space::{STACK_SIZE, SVSM_STACKS_INIT_TASK, SVSM_STACK_IST_DF_BASE};
+use crate::mm::address_space::{
+    STACK_SIZE, SVSM_STACKS_INIT_TASK, SVSM_STACK_IST_DF_BASE, SVSM_STACK_IST_VC_BASE,
+};
 use crate::types::VirtAddr;
 #[cfg(feature = "enable-stacktrace")]
 use core::arch::asm;
@@ -48,7 +50,7 @@ pub enum UnwoundStackFrame {
 }
 
 #[cfg(feature = "enable-stacktrace")]
-type StacksBounds = [StackBounds; 2];
+type StacksBounds = [StackBounds; 3];
 
 #[cfg(feature = "enable-stacktrace")]
 pub struct StackUnwinder {
@@ -67,16 +69,20 @@ impl StackUnwinder {
 
         let stacks: StacksBounds = [
             StackBounds {
-                bottom: SVSM_STACKS_INIT_TASK as VirtAddr,
-                top: (SVSM_STACKS_INIT_TASK + STACK_SIZE) as VirtAddr,
+                bottom: SVSM_STACKS_INIT_TASK,
+                top: SVSM_STACKS_INIT_TASK + STACK_SIZE,
             },
             StackBounds {
-                bottom: SVSM_STACK_IST_DF_BASE as VirtAddr,
-                top: (SVSM_STACK_IST_DF_BASE + STACK_SIZE) as VirtAddr,
+                bottom: SVSM_STACK_IST_DF_BASE,
+                top: SVSM_STACK_IST_DF_BASE + STACK_SIZE,
+            },
+            StackBounds {
+                bottom: SVSM_STACK_IST_VC_BASE,
+                top: SVSM_STACK_IST_VC_BASE + STACK_SIZE,
             },
         ];
 
-        Self::new(rbp as VirtAddr, stacks)
+        Self::new(VirtAddr::from(rbp), stacks)
     }
 
     fn new(rbp: VirtAddr, stacks: StacksBounds) -> Self {
@@ -112,7 +118,7 @@ impl StackUnwinder {
             }
         }
 
-        let stack_depth = stack.top - rsp as usize;
+        let stack_depth = stack.top - rsp;
 
         UnwoundStackFrame::Valid(StackFrame {
             rbp,
@@ -134,9 +140,9 @@ impl StackUnwinder {
             return UnwoundStackFrame::Invalid;
         }
 
-        let rbp = unsafe { (rsp as *const VirtAddr).read_unaligned() };
+        let rbp = unsafe { rsp.as_ptr::<VirtAddr>().read_unaligned() };
         let rsp = rsp + mem::size_of::<VirtAddr>();
-        let rip = unsafe { (rsp as *const VirtAddr).read_unaligned() };
+        let rip = unsafe { rsp.as_ptr::<VirtAddr>().read_unaligned() };
         let rsp = rsp + mem::size_of::<VirtAddr>();
 
         Self::check_unwound_frame(rbp, rsp, rip, stacks)
@@ -150,10 +156,10 @@ impl StackUnwinder {
             return UnwoundStackFrame::Invalid;
         }
 
-        let regs = unsafe { &*(rsp as *const X86Regs) };
-        let rbp = regs.rbp as VirtAddr;
-        let rip = regs.rip as VirtAddr;
-        let rsp = regs.rsp as VirtAddr;
+        let regs = unsafe { &*rsp.as_ptr::<X86Regs>() };
+        let rbp = VirtAddr::from(regs.rbp);
+        let rip = VirtAddr::from(regs.rip);
+        let rsp = VirtAddr::from(regs.rsp);
 
         Self::check_unwound_frame(rbp, rsp, rip, stacks)
     }
@@ -166,7 +172,7 @@ impl StackUnwinder {
         // would point to the word at the top of the runtime stack.
         stacks.iter().any(|stack| {
             let word_size = mem::size_of::<VirtAddr>();
-            stack.top >= word_size && stack.top - word_size == rbp
+            stack.top.as_usize() >= word_size && stack.top - word_size == rbp
         })
     }
 }
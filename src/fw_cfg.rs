@@ -21,6 +21,9 @@ const FW_CFG_FILE_DIR: u16 = 0x19;
 const KERNEL_REGION_SIZE: u64 = 16 * 1024 * 1024;
 const KERNEL_REGION_SIZE_MASK: u64 = !(KERNEL_REGION_SIZE - 1);
 
+// e820 type of memory usable by the OS
+pub const E820_TYPE_RAM: u32 = 1;
+
 //use crate::println;
 
 #[non_exhaustive]
@@ -43,7 +46,7 @@ impl FwCfgFile {
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug)]
 pub struct MemoryRegion {
     pub start: u64,
     pub end: u64,
@@ -140,23 +143,31 @@ impl<'a> FwCfg<'a> {
         }
     }
 
-    pub fn get_memory_regions(&self) -> Result<Vec<MemoryRegion>, ()> {
-        let mut regions: Vec<MemoryRegion> = Vec::new();
+    // All e820 entries together with their type
+    pub fn get_e820_map(&self) -> Result<Vec<(MemoryRegion, u32)>, ()> {
+        let mut entries: Vec<(MemoryRegion, u32)> = Vec::new();
         let file = self.file_selector("etc/e820")?;
-        let entries = file.size / 20;
+        let count = file.size / 20;
 
         self.select(file.selector);
 
-        for _ in 0..entries {
+        for _ in 0..count {
             let region = self.read_memory_region();
             let t: u32 = self.read_le();
 
-            if t == 1 {
-                regions.push(region);
-            }
+            entries.push((region, t));
         }
 
-        Ok(regions)
+        Ok(entries)
+    }
+
+    pub fn get_memory_regions(&self) -> Result<Vec<MemoryRegion>, ()> {
+        Ok(self
+            .get_e820_map()?
+            .into_iter()
+            .filter(|(_, t)| *t == E820_TYPE_RAM)
+            .map(|(region, _)| region)
+            .collect())
     }
 
     fn find_kernel_region_e820(&self) -> Result<MemoryRegion, ()> {
@@ -9,9 +9,9 @@ extern crate alloc;
 use crate::cpu::percpu::this_cpu_mut;
 use crate::mm::PerCPUPageMappingGuard;
 use crate::mm::SIZE_1G;
-use crate::sev::ghcb::PageStateChangeOp;
+use crate::sev::ghcb::PscOp;
 use crate::sev::{pvalidate, rmp_adjust, RMPFlags};
-use crate::types::{PhysAddr, VirtAddr, PAGE_SIZE};
+use crate::types::{PageSize, PhysAddr, VirtAddr, PAGE_SIZE};
 use crate::utils::{overlap, zero_mem_region};
 use alloc::vec::Vec;
 
@@ -173,14 +173,14 @@ const OVMF_SEV_META_DATA_GUID: &str = "dc886566-984a-4798-a75e-5585a7bf67cc";
 const SEV_INFO_BLOCK_GUID: &str = "00f771de-1a7e-4fcb-890e-68c77e2fb44e";
 const SVSM_INFO_GUID: &str = "a789a612-0597-4c4b-a49f-cbb1fe9d1ddd";
 
-unsafe fn find_table(uuid: &Uuid, start: VirtAddr, len: VirtAddr) -> Result<(VirtAddr, usize), ()> {
+unsafe fn find_table(uuid: &Uuid, start: VirtAddr, len: usize) -> Result<(VirtAddr, usize), ()> {
     let mut curr = start;
     let end = start - len;
 
     while curr >= end {
         curr -= mem::size_of::<Uuid>();
 
-        let ptr = curr as *const u8;
+        let ptr = curr.as_ptr::<u8>();
         let curr_uuid = Uuid::from_mem(ptr);
 
         curr -= mem::size_of::<u16>();
@@ -188,7 +188,7 @@ unsafe fn find_table(uuid: &Uuid, start: VirtAddr, len: VirtAddr) -> Result<(Vir
             break;
         }
 
-        let len_ptr = curr as *const u16;
+        let len_ptr = curr.as_ptr::<u16>();
         let orig_len = len_ptr.read() as usize;
 
         if len < mem::size_of::<Uuid>() + mem::size_of::<u16>() {
@@ -227,7 +227,7 @@ const SEV_META_DESC_TYPE_CPUID: u32 = 3;
 const SEV_META_DESC_TYPE_CAA: u32 = 4;
 
 pub fn parse_fw_meta_data() -> Result<SevFWMetaData, ()> {
-    let pstart: PhysAddr = (4 * SIZE_1G) - PAGE_SIZE;
+    let pstart = PhysAddr::from((4 * SIZE_1G) - PAGE_SIZE);
     let mut meta_data = SevFWMetaData::new();
 
     // Map meta-data location, it starts at 32 bytes below 4GiB
@@ -240,7 +240,7 @@ pub fn parse_fw_meta_data() -> Result<SevFWMetaData, ()> {
     let meta_uuid = Uuid::from_str(OVMF_TABLE_FOOTER_GUID)?;
 
     curr -= mem::size_of::<Uuid>();
-    let ptr = curr as *const u8;
+    let ptr = curr.as_ptr::<u8>();
 
     unsafe {
         let uuid = Uuid::from_mem(ptr);
@@ -250,7 +250,7 @@ pub fn parse_fw_meta_data() -> Result<SevFWMetaData, ()> {
         }
 
         curr -= mem::size_of::<u16>();
-        let ptr = curr as *const u16;
+        let ptr = curr.as_ptr::<u16>();
 
         let full_len = ptr.read() as usize;
         let len = full_len - mem::size_of::<u16>() + mem::size_of::<Uuid>();
@@ -269,8 +269,8 @@ pub fn parse_fw_meta_data() -> Result<SevFWMetaData, ()> {
             if len != mem::size_of::<u32>() {
                 return Err(());
             }
-            let info_ptr = base as *const u32;
-            meta_data.reset_ip = Some(info_ptr.read() as PhysAddr);
+            let info_ptr = base.as_ptr::<u32>();
+            meta_data.reset_ip = Some(PhysAddr::from(info_ptr.read() as usize));
         }
 
         // Search and parse Meta Data
@@ -278,10 +278,10 @@ pub fn parse_fw_meta_data() -> Result<SevFWMetaData, ()> {
         let ret = find_table(&sev_meta_uuid, curr, len);
         if let Ok(tbl) = ret {
             let (base, _len) = tbl;
-            let off_ptr = base as *const u32;
+            let off_ptr = base.as_ptr::<u32>();
             let offset = off_ptr.read_unaligned() as usize;
 
-            let meta_ptr = (vend - offset) as *const SevMetaDataHeader;
+            let meta_ptr = (vend - offset).as_ptr::<SevMetaDataHeader>();
             //let len = meta_ptr.read().len;
             let num_descs = meta_ptr.read().num_desc as isize;
             let desc_ptr = meta_ptr.offset(1).cast::<SevMetaDataDesc>();
@@ -289,7 +289,7 @@ pub fn parse_fw_meta_data() -> Result<SevFWMetaData, ()> {
             for i in 0..num_descs {
                 let desc = desc_ptr.offset(i).read();
                 let t = desc.t;
-                let base = desc.base as PhysAddr;
+                let base = PhysAddr::from(desc.base as usize);
                 let len = desc.len as usize;
                 match t {
                     SEV_META_DESC_TYPE_MEM => meta_data.add_valid_mem(base, len),
@@ -328,14 +328,14 @@ fn validate_fw_mem_region(region: SevPreValidMem) -> Result<(), ()> {
 
     this_cpu_mut()
         .ghcb()
-        .page_state_change(pstart, pend, false, PageStateChangeOp::PscPrivate)
+        .page_state_change_region(pstart, pend, PageSize::Page4K, PscOp::Private)
         .expect("GHCB PSC call failed to validate firmware memory");
 
-    for paddr in (pstart..pend).step_by(PAGE_SIZE) {
+    for paddr in pstart.iter_to(pend, PAGE_SIZE) {
         let guard = PerCPUPageMappingGuard::create(paddr, 0, false)?;
         let vaddr = guard.virt_addr();
 
-        if pvalidate(vaddr, false, true).is_err() {
+        if pvalidate(vaddr, PageSize::Page4K, true) != Ok(true) {
             return Err(());
         }
 
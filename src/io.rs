@@ -30,6 +30,18 @@ pub trait IOPort {
             ret
         }
     }
+
+    fn outl(&self, port: u16, value: u32) {
+        unsafe { asm!("outl %eax, %dx", in("eax") value, in("dx") port, options(att_syntax)) }
+    }
+
+    fn inl(&self, port: u16) -> u32 {
+        unsafe {
+            let ret: u32;
+            asm!("inl %dx, %eax", in("dx") port, out("eax") ret, options(att_syntax));
+            ret
+        }
+    }
 }
 
 pub struct DefaultIOPort {}
@@ -12,4 +12,7 @@ pub struct KernelLaunchInfo {
     pub cpuid_page: u64,
     pub secrets_page: u64,
     pub ghcb: u64,
+    // Physical address of the ACPI RSDP, 0 when the tables are not in guest
+    // memory yet
+    pub acpi_rsdp: u64,
 }
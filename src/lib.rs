@@ -9,18 +9,24 @@
 #![feature(maybe_uninit_uninit_array)]
 #![feature(maybe_uninit_array_assume_init)]
 #![feature(sync_unsafe_cell)]
+#![feature(asm_const)]
 
 pub mod acpi;
+pub mod config;
 pub mod console;
 pub mod cpu;
+pub mod crypto;
 pub mod debug;
 pub mod fw_cfg;
 pub mod fw_meta;
 pub mod io;
 pub mod kernel_launch;
 pub mod locking;
+pub mod log_buffer;
 pub mod mm;
 pub mod requests;
+#[cfg(feature = "selftest")]
+pub mod selftest;
 pub mod serial;
 pub mod sev;
 pub mod string;
@@ -6,16 +6,18 @@
 
 use core::cell::UnsafeCell;
 use core::ops::{Deref, DerefMut};
-use core::sync::atomic::{AtomicU64, Ordering};
+use core::sync::atomic::{AtomicU32, Ordering};
 
 pub struct ReadLockGuard<'a, T> {
-    rwlock: &'a AtomicU64,
+    write_turn: &'a AtomicU32,
     data: &'a mut T,
 }
 
 impl<'a, T> Drop for ReadLockGuard<'a, T> {
     fn drop(&mut self) {
-        self.rwlock.fetch_sub(1, Ordering::Release);
+        // A writer waits for all readers before it, each of which passes
+        // the turn on by one
+        self.write_turn.fetch_add(1, Ordering::Release);
     }
 }
 
@@ -27,14 +29,16 @@ impl<'a, T> Deref for ReadLockGuard<'a, T> {
 }
 
 pub struct WriteLockGuard<'a, T> {
-    rwlock: &'a AtomicU64,
+    read_turn: &'a AtomicU32,
+    write_turn: &'a AtomicU32,
     data: &'a mut T,
 }
 
 impl<'a, T> Drop for WriteLockGuard<'a, T> {
     fn drop(&mut self) {
-        // There are no readers - safe to just set lock to 0
-        self.rwlock.store(0, Ordering::Release);
+        // Hand over to the next ticket, whether reader or writer
+        self.read_turn.fetch_add(1, Ordering::Release);
+        self.write_turn.fetch_add(1, Ordering::Release);
     }
 }
 
@@ -51,103 +55,87 @@ impl<'a, T> DerefMut for WriteLockGuard<'a, T> {
     }
 }
 
+// Ticket based and fair: readers and writers get the lock in the order they
+// asked for it. Consecutive readers hold it concurrently, a writer waits for
+// all readers before it and readers after a writer wait for it. Neither
+// side is reentrant and there is no owner tracking, so a CPU must never take
+// the lock again, for read or write, while holding it. That includes
+// exception and interrupt handlers running on that CPU.
 pub struct RWLock<T> {
-    rwlock: AtomicU64,
+    // The next ticket handed out
+    next: AtomicU32,
+    // The ticket which may take the lock for read next
+    read_turn: AtomicU32,
+    // The ticket which may take the lock for write next
+    write_turn: AtomicU32,
     data: UnsafeCell<T>,
 }
 
 unsafe impl<T> Sync for RWLock<T> {}
 
-#[inline]
-fn split_val(val: u64) -> (u64, u64) {
-    (val & 0xffff_ffffu64, val >> 32)
-}
-
-#[inline]
-fn compose_val(readers: u64, writers: u64) -> u64 {
-    (readers & 0xffff_ffffu64) | (writers << 32)
-}
-
 impl<T> RWLock<T> {
     pub const fn new(data: T) -> Self {
         RWLock {
-            rwlock: AtomicU64::new(0),
+            next: AtomicU32::new(0),
+            read_turn: AtomicU32::new(0),
+            write_turn: AtomicU32::new(0),
             data: UnsafeCell::new(data),
         }
     }
 
     #[inline]
-    fn wait_for_writers(&self) -> u64 {
-        loop {
-            let val: u64 = self.rwlock.load(Ordering::Relaxed);
-            let (_, writers) = split_val(val);
-
-            if writers == 0 {
-                return val;
-            }
-            core::hint::spin_loop();
-        }
-    }
-
-    #[inline]
-    fn wait_for_readers(&self) -> u64 {
-        loop {
-            let val: u64 = self.rwlock.load(Ordering::Relaxed);
-            let (readers, _) = split_val(val);
-
-            if readers == 0 {
-                return val;
-            }
+    fn wait_for_turn(turn: &AtomicU32, ticket: u32) {
+        while turn.load(Ordering::Acquire) != ticket {
             core::hint::spin_loop();
         }
     }
 
     pub fn lock_read(&self) -> ReadLockGuard<T> {
-        loop {
-            let val = self.wait_for_writers();
-            let (readers, _) = split_val(val);
-            let new_val = compose_val(readers + 1, 0);
-
-            if self
-                .rwlock
-                .compare_exchange(val, new_val, Ordering::Acquire, Ordering::Relaxed)
-                .is_ok()
-            {
-                break;
-            }
-            core::hint::spin_loop();
-        }
+        let ticket = self.next.fetch_add(1, Ordering::Relaxed);
+        RWLock::<T>::wait_for_turn(&self.read_turn, ticket);
+
+        // Let a reader with the next ticket in right away
+        self.read_turn.fetch_add(1, Ordering::Relaxed);
 
         ReadLockGuard {
-            rwlock: &self.rwlock,
+            write_turn: &self.write_turn,
             data: unsafe { &mut *self.data.get() },
         }
     }
 
     pub fn lock_write(&self) -> WriteLockGuard<T> {
-        // Waiting for current writer to finish
-        loop {
-            let val = self.wait_for_writers();
-            let (readers, _) = split_val(val);
-            let new_val = compose_val(readers, 1);
-
-            if self
-                .rwlock
-                .compare_exchange(val, new_val, Ordering::Acquire, Ordering::Relaxed)
-                .is_ok()
-            {
-                break;
-            }
-            core::hint::spin_loop();
-        }
-
-        // Now locked for write - wait until all readers finished
-        let val: u64 = self.wait_for_readers();
-        assert!(val == compose_val(0, 1));
+        let ticket = self.next.fetch_add(1, Ordering::Relaxed);
+        RWLock::<T>::wait_for_turn(&self.write_turn, ticket);
 
         WriteLockGuard {
-            rwlock: &self.rwlock,
+            read_turn: &self.read_turn,
+            write_turn: &self.write_turn,
             data: unsafe { &mut *self.data.get() },
         }
     }
 }
+
+#[test]
+fn test_rwlock_tickets() {
+    let lock = RWLock::new(0u32);
+
+    // Readers share the lock
+    {
+        let r1 = lock.lock_read();
+        let r2 = lock.lock_read();
+        assert_eq!(*r1 + *r2, 0);
+        assert_eq!(lock.read_turn.load(Ordering::Relaxed), 2);
+        assert_eq!(lock.write_turn.load(Ordering::Relaxed), 0);
+    }
+
+    // The writer with ticket 2 got its turn from both readers
+    assert_eq!(lock.write_turn.load(Ordering::Relaxed), 2);
+    *lock.lock_write() += 1;
+    assert_eq!(lock.read_turn.load(Ordering::Relaxed), 3);
+    assert_eq!(lock.write_turn.load(Ordering::Relaxed), 3);
+
+    assert_eq!(*lock.lock_read(), 1);
+    *lock.lock_write() += 1;
+    assert_eq!(*lock.lock_read(), 2);
+    assert_eq!(lock.next.load(Ordering::Relaxed), 6);
+}
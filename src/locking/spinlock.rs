@@ -8,13 +8,33 @@ use core::cell::UnsafeCell;
 use core::ops::{Deref, DerefMut};
 use core::sync::atomic::{AtomicU64, Ordering};
 
+#[cfg(debug_assertions)]
+use crate::console::_print_unlocked;
+#[cfg(debug_assertions)]
+use crate::cpu::percpu::this_cpu_index;
+#[cfg(debug_assertions)]
+use core::sync::atomic::AtomicUsize;
+
+// Spin iterations after which a waiter reports a suspected deadlock
+#[cfg(debug_assertions)]
+const SPIN_WARN_LIMIT: u64 = 1 << 30;
+
+// In debug builds a lock records which CPU holds it, encoded as cpu index
+// plus one. Zero means no owner or an owner without per-cpu data.
+#[cfg(debug_assertions)]
+const NO_OWNER: usize = 0;
+
 pub struct LockGuard<'a, T> {
     holder: &'a AtomicU64,
+    #[cfg(debug_assertions)]
+    owner: &'a AtomicUsize,
     data: &'a mut T,
 }
 
 impl<'a, T> Drop for LockGuard<'a, T> {
     fn drop(&mut self) {
+        #[cfg(debug_assertions)]
+        self.owner.store(NO_OWNER, Ordering::Relaxed);
         self.holder.fetch_add(1, Ordering::Release);
     }
 }
@@ -22,6 +42,8 @@ impl<'a, T> Drop for LockGuard<'a, T> {
 pub struct SpinLock<T> {
     current: AtomicU64,
     holder: AtomicU64,
+    #[cfg(debug_assertions)]
+    owner: AtomicUsize,
     data: UnsafeCell<T>,
 }
 
@@ -32,23 +54,65 @@ impl<T> SpinLock<T> {
         SpinLock {
             current: AtomicU64::new(0),
             holder: AtomicU64::new(0),
+            #[cfg(debug_assertions)]
+            owner: AtomicUsize::new(NO_OWNER),
             data: UnsafeCell::new(data),
         }
     }
 
+    #[cfg(debug_assertions)]
+    fn check_recursion(&self) -> usize {
+        let me = this_cpu_index().map_or(NO_OWNER, |idx| idx + 1);
+
+        if me != NO_OWNER && self.owner.load(Ordering::Relaxed) == me {
+            panic!("SpinLock: recursive locking on CPU {}", me - 1);
+        }
+
+        me
+    }
+
+    fn guard(&self) -> LockGuard<T> {
+        LockGuard {
+            holder: &self.holder,
+            #[cfg(debug_assertions)]
+            owner: &self.owner,
+            data: unsafe { &mut *self.data.get() },
+        }
+    }
+
     pub fn lock(&self) -> LockGuard<T> {
+        #[cfg(debug_assertions)]
+        let me = self.check_recursion();
+        #[cfg(debug_assertions)]
+        let mut spins: u64 = 0;
+
         let ticket = self.current.fetch_add(1, Ordering::Relaxed);
         loop {
             let h = self.holder.load(Ordering::Acquire);
             if h == ticket {
                 break;
             }
+
+            #[cfg(debug_assertions)]
+            {
+                spins += 1;
+                // Logging takes locks itself, possibly the very one which
+                // is stuck, so the console is written directly
+                if spins == SPIN_WARN_LIMIT {
+                    _print_unlocked(format_args!(
+                        "SpinLock: suspected deadlock, lock held by CPU {:?}\n",
+                        self.owner.load(Ordering::Relaxed).checked_sub(1)
+                    ));
+                }
+            }
+
             core::hint::spin_loop();
         }
-        LockGuard {
-            holder: &self.holder,
-            data: unsafe { &mut *self.data.get() },
-        }
+
+        #[cfg(debug_assertions)]
+        self.owner.store(me, Ordering::Relaxed);
+
+        self.guard()
     }
 
     pub fn try_lock(&self) -> Result<LockGuard<T>, ()> {
@@ -63,17 +127,27 @@ impl<T> SpinLock<T> {
                 Ordering::Relaxed,
             );
             if let Ok(_) = result {
-                return Ok(LockGuard {
-                    holder: &self.holder,
-                    data: unsafe { &mut *self.data.get() },
-                });
+                #[cfg(debug_assertions)]
+                self.owner.store(
+                    this_cpu_index().map_or(NO_OWNER, |idx| idx + 1),
+                    Ordering::Relaxed,
+                );
+                return Ok(self.guard());
             }
         }
 
         Err(())
     }
 
+    // Bypasses the lock, dereferencing the pointer is only sound when the
+    // holder of the lock can't access the data anymore
+    pub fn data_ptr(&self) -> *mut T {
+        self.data.get()
+    }
+
     pub fn unlock(&mut self) {
+        #[cfg(debug_assertions)]
+        self.owner.store(NO_OWNER, Ordering::Relaxed);
         self.holder.fetch_add(1, Ordering::Release);
     }
 }
@@ -90,3 +164,15 @@ impl<'a, T> DerefMut for LockGuard<'a, T> {
         self.data
     }
 }
+
+#[test]
+fn test_spinlock_lock_unlock() {
+    let lock = SpinLock::new(0u32);
+
+    *lock.lock() += 1;
+    {
+        let _guard = lock.lock();
+        assert!(lock.try_lock().is_err());
+    }
+    assert_eq!(*lock.try_lock().unwrap(), 1);
+}
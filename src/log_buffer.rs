@@ -0,0 +1,226 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2026 SUSE LLC
+//
+// Author: agent <agent@local>
+
+extern crate alloc;
+
+use crate::cpu::percpu::this_cpu;
+use crate::locking::SpinLock;
+use crate::mm::GuestPtr;
+use crate::requests::{validate_guest_phys, RequestHandler, RequestParams, SvsmReqError};
+use crate::types::{PhysAddr, PAGE_SIZE};
+use crate::utils::crosses_page;
+use alloc::vec::Vec;
+use core::fmt;
+
+pub const LOG_BUFFER_SIZE: usize = 16 * 1024;
+
+// Vendor-specific protocol for reading the log buffer from the guest
+pub const SVSM_LOG_PROTOCOL_ID: u32 = 0x8000_0000;
+
+const SVSM_REQ_LOG_READ: u32 = 0;
+
+// Circular byte buffer. head and tail count all bytes ever written and
+// consumed, when the buffer is full the oldest bytes get overwritten.
+pub struct LogRing {
+    buf: [u8; LOG_BUFFER_SIZE],
+    head: usize,
+    tail: usize,
+}
+
+impl LogRing {
+    pub const fn new() -> Self {
+        LogRing {
+            buf: [0; LOG_BUFFER_SIZE],
+            head: 0,
+            tail: 0,
+        }
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.buf[self.head % LOG_BUFFER_SIZE] = *b;
+            self.head += 1;
+        }
+
+        if self.head - self.tail > LOG_BUFFER_SIZE {
+            self.tail = self.head - LOG_BUFFER_SIZE;
+        }
+    }
+
+    pub fn unread(&self) -> usize {
+        self.head - self.tail
+    }
+
+    // Unread byte at offset from the oldest one, without consuming it
+    fn peek(&self, offset: usize) -> u8 {
+        self.buf[(self.tail + offset) % LOG_BUFFER_SIZE]
+    }
+
+    fn consume(&mut self, count: usize) {
+        self.tail += count.min(self.unread());
+    }
+
+    pub fn read(&mut self, out: &mut [u8]) -> usize {
+        let count = out.len().min(self.unread());
+
+        for (i, b) in out.iter_mut().take(count).enumerate() {
+            *b = self.peek(i);
+        }
+        self.consume(count);
+
+        count
+    }
+}
+
+impl Default for LogRing {
+    fn default() -> Self {
+        LogRing::new()
+    }
+}
+
+impl fmt::Write for LogRing {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_bytes(s.as_bytes());
+        Ok(())
+    }
+}
+
+pub struct LogBuffer {
+    ring: SpinLock<LogRing>,
+}
+
+impl LogBuffer {
+    pub const fn new() -> Self {
+        LogBuffer {
+            ring: SpinLock::new(LogRing::new()),
+        }
+    }
+
+    pub fn read(&self, out: &mut [u8]) -> usize {
+        self.ring.lock().read(out)
+    }
+
+    pub fn unread(&self) -> usize {
+        self.ring.lock().unread()
+    }
+}
+
+impl Default for LogBuffer {
+    fn default() -> Self {
+        LogBuffer::new()
+    }
+}
+
+fn write_record(ring: &mut LogRing, record: &log::Record) {
+    use core::fmt::Write;
+
+    let _ = writeln!(
+        ring,
+        "{}: {}",
+        record.metadata().level().as_str(),
+        record.args()
+    );
+}
+
+impl LogBuffer {
+    // Drops the record when the buffer is busy instead of waiting for it
+    pub fn try_log(&self, record: &log::Record) {
+        if let Ok(mut ring) = self.ring.try_lock() {
+            write_record(&mut ring, record);
+        }
+    }
+}
+
+impl log::Log for LogBuffer {
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        write_record(&mut self.ring.lock(), record);
+    }
+
+    fn flush(&self) {}
+}
+
+pub static LOG_BUFFER: LogBuffer = LogBuffer::new();
+
+// READ: RCX holds the guest physical address and RDX the size of the target
+// buffer, which must not cross a page boundary. On return RCX holds the
+// number of bytes copied and RDX the number of bytes still unread.
+fn log_read(params: &mut RequestParams) -> Result<(), SvsmReqError> {
+    let gpa = PhysAddr::from(params.rcx);
+    let len = params.rdx as usize;
+
+    if len == 0 || len > PAGE_SIZE || crosses_page(gpa.as_usize(), len) {
+        return Err(SvsmReqError::InvalidParameter);
+    }
+    validate_guest_phys(gpa, len, 1)?;
+
+    // Logging takes the ring lock, so it must not be held while guest memory
+    // is mapped and written. The bytes are consumed even when the write to
+    // the guest fails.
+    let mut buf: Vec<u8> = Vec::new();
+    buf.try_reserve_exact(len)
+        .map_err(|_| SvsmReqError::FatalError(()))?;
+    buf.resize(len, 0);
+    let count = LOG_BUFFER.read(&mut buf);
+
+    this_cpu()
+        .with_temp_map(gpa, |vaddr| {
+            let dst = GuestPtr::<u8>::new(vaddr);
+            buf[..count]
+                .iter()
+                .enumerate()
+                .try_for_each(|(i, b)| dst.offset(i as isize).write(*b))
+        })
+        .map_err(SvsmReqError::FatalError)?
+        .map_err(|_| SvsmReqError::InvalidAddress)?;
+
+    params.rcx = count as u64;
+    params.rdx = LOG_BUFFER.unread() as u64;
+
+    Ok(())
+}
+
+pub struct LogProtocol;
+
+impl RequestHandler for LogProtocol {
+    fn handle(&self, request: u32, params: &mut RequestParams) -> Result<(), SvsmReqError> {
+        match request {
+            SVSM_REQ_LOG_READ => log_read(params),
+            _ => Err(SvsmReqError::UnsupportedCall),
+        }
+    }
+
+    fn versions(&self) -> (u32, u32) {
+        (1, 1)
+    }
+}
+
+#[test]
+fn test_log_ring_wraparound() {
+    extern crate alloc;
+    use alloc::vec;
+
+    let mut ring = LogRing::new();
+
+    ring.write_bytes(b"abc");
+    let mut out = [0u8; 2];
+    assert_eq!(ring.read(&mut out), 2);
+    assert_eq!(&out, b"ab");
+    assert_eq!(ring.unread(), 1);
+
+    // Overflowing the buffer drops the oldest bytes
+    let data: vec::Vec<u8> = (0..LOG_BUFFER_SIZE + 10).map(|i| i as u8).collect();
+    ring.write_bytes(&data);
+    assert_eq!(ring.unread(), LOG_BUFFER_SIZE);
+
+    let mut out = vec![0u8; LOG_BUFFER_SIZE];
+    assert_eq!(ring.read(&mut out), LOG_BUFFER_SIZE);
+    assert_eq!(&out[..], &data[10..]);
+    assert_eq!(ring.unread(), 0);
+}
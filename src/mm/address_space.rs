@@ -17,9 +17,9 @@ struct KernelMapping {
 impl KernelMapping {
     pub const fn new() -> Self {
         KernelMapping {
-            virt_start: 0,
-            virt_end: 0,
-            phys_start: 0,
+            virt_start: VirtAddr::null(),
+            virt_end: VirtAddr::null(),
+            phys_start: PhysAddr::null(),
         }
     }
 }
@@ -27,7 +27,7 @@ impl KernelMapping {
 static KERNEL_MAPPING: ImmutAfterInitCell<KernelMapping> =
     ImmutAfterInitCell::new(KernelMapping::new());
 
-pub fn init_kernel_mapping_info(vstart: VirtAddr, vend: VirtAddr, pstart: VirtAddr) {
+pub fn init_kernel_mapping_info(vstart: VirtAddr, vend: VirtAddr, pstart: PhysAddr) {
     let km = KernelMapping {
         virt_start: vstart,
         virt_end: vend,
@@ -40,7 +40,7 @@ pub fn init_kernel_mapping_info(vstart: VirtAddr, vend: VirtAddr, pstart: VirtAd
 
 pub fn virt_to_phys(vaddr: VirtAddr) -> PhysAddr {
     if vaddr < KERNEL_MAPPING.virt_start || vaddr >= KERNEL_MAPPING.virt_end {
-        panic!("Invalid physical address {:#018x}", vaddr);
+        panic!("Invalid virtual address {:#018x}", vaddr);
     }
 
     let offset: usize = vaddr - KERNEL_MAPPING.virt_start;
@@ -97,54 +97,59 @@ const fn sign_extend(addr: usize) -> usize {
 pub const PGTABLE_LVL3_IDX_SHARED: usize = 511;
 
 /// Base Address of shared memory region
-pub const SVSM_SHARED_BASE: usize = sign_extend(PGTABLE_LVL3_IDX_SHARED << ((3 * 9) + 12));
+pub const SVSM_SHARED_BASE: VirtAddr =
+    VirtAddr::new(sign_extend(PGTABLE_LVL3_IDX_SHARED << ((3 * 9) + 12)));
 
 /// Mapping range for shared stacks
-pub const SVSM_SHARED_STACK_BASE: usize = SVSM_SHARED_BASE + (256 * SIZE_1G);
-pub const SVSM_SHARED_STACK_END: usize = SVSM_SHARED_STACK_BASE + SIZE_1G;
+pub const SVSM_SHARED_STACK_BASE: VirtAddr = SVSM_SHARED_BASE.offset(256 * SIZE_1G);
+pub const SVSM_SHARED_STACK_END: VirtAddr = SVSM_SHARED_STACK_BASE.offset(SIZE_1G);
 
 /// PerCPU mappings level 3 index
 pub const PGTABLE_LVL3_IDX_PERCPU: usize = 510;
 
 /// Base Address of shared memory region
-pub const SVSM_PERCPU_BASE: usize = sign_extend(PGTABLE_LVL3_IDX_PERCPU << ((3 * 9) + 12));
+pub const SVSM_PERCPU_BASE: VirtAddr =
+    VirtAddr::new(sign_extend(PGTABLE_LVL3_IDX_PERCPU << ((3 * 9) + 12)));
 
-/// PerCPU CAA mappings
-pub const SVSM_PERCPU_CAA_BASE: usize = SVSM_PERCPU_BASE + (2 * SIZE_LEVEL0);
+/// PerCPU CAA mappings, one page per VMPL
+pub const SVSM_PERCPU_CAA_BASE: VirtAddr = SVSM_PERCPU_BASE.offset(8 * SIZE_LEVEL0);
 
 /// PerCPU VMSA mappings
-pub const SVSM_PERCPU_VMSA_BASE: usize = SVSM_PERCPU_BASE + (4 * SIZE_LEVEL0);
+pub const SVSM_PERCPU_VMSA_BASE: VirtAddr = SVSM_PERCPU_BASE.offset(4 * SIZE_LEVEL0);
 
 /// Region for PerCPU Stacks
-pub const SVSM_PERCPU_STACKS_BASE: usize = SVSM_PERCPU_BASE + SIZE_LEVEL1;
+pub const SVSM_PERCPU_STACKS_BASE: VirtAddr = SVSM_PERCPU_BASE.offset(SIZE_LEVEL1);
 
 /// Stack address of the per-cpu init task
-pub const SVSM_STACKS_INIT_TASK: usize = SVSM_PERCPU_STACKS_BASE;
+pub const SVSM_STACKS_INIT_TASK: VirtAddr = SVSM_PERCPU_STACKS_BASE;
 
 ///  IST Stacks base address
-pub const SVSM_STACKS_IST_BASE: usize = SVSM_STACKS_INIT_TASK + STACK_TOTAL_SIZE;
+pub const SVSM_STACKS_IST_BASE: VirtAddr = SVSM_STACKS_INIT_TASK.offset(STACK_TOTAL_SIZE);
 
 /// DoubleFault IST stack base address
-pub const SVSM_STACK_IST_DF_BASE: usize = SVSM_STACKS_IST_BASE;
+pub const SVSM_STACK_IST_DF_BASE: VirtAddr = SVSM_STACKS_IST_BASE;
+
+/// #VC IST stack base address, the slot layout leaves a guard area below it
+pub const SVSM_STACK_IST_VC_BASE: VirtAddr = SVSM_STACK_IST_DF_BASE.offset(STACK_TOTAL_SIZE);
 
 /// Base Address for temporary mappings - used by page-table guards
-pub const SVSM_PERCPU_TEMP_BASE: usize = SVSM_PERCPU_BASE + SIZE_LEVEL2;
+pub const SVSM_PERCPU_TEMP_BASE: VirtAddr = SVSM_PERCPU_BASE.offset(SIZE_LEVEL2);
 
 // Below is space for 512 temporary 4k mappings and 511 temporary 2M mappings
 
 /// Start and End for PAGE_SIZEed temporary mappings
-pub const SVSM_PERCPU_TEMP_BASE_4K: usize = SVSM_PERCPU_TEMP_BASE;
-pub const SVSM_PERCPU_TEMP_END_4K: usize = SVSM_PERCPU_TEMP_BASE_4K + SIZE_LEVEL1;
+pub const SVSM_PERCPU_TEMP_BASE_4K: VirtAddr = SVSM_PERCPU_TEMP_BASE;
+pub const SVSM_PERCPU_TEMP_END_4K: VirtAddr = SVSM_PERCPU_TEMP_BASE_4K.offset(SIZE_LEVEL1);
 
 /// Start and End for PAGE_SIZEed temporary mappings
-pub const SVSM_PERCPU_TEMP_BASE_2M: usize = SVSM_PERCPU_TEMP_BASE + SIZE_LEVEL1;
-pub const SVSM_PERCPU_TEMP_END_2M: usize = SVSM_PERCPU_TEMP_BASE + SIZE_LEVEL2;
+pub const SVSM_PERCPU_TEMP_BASE_2M: VirtAddr = SVSM_PERCPU_TEMP_BASE.offset(SIZE_LEVEL1);
+pub const SVSM_PERCPU_TEMP_END_2M: VirtAddr = SVSM_PERCPU_TEMP_BASE.offset(SIZE_LEVEL2);
 
 /// Number of slots - only use half of them to leave guard pages between the mappings
 pub const SVSM_PERCPU_TEMP_4K_SLOTS: usize =
-    ((SVSM_PERCPU_TEMP_END_4K - SVSM_PERCPU_TEMP_BASE_4K) / PAGE_SIZE) / 2;
+    ((SVSM_PERCPU_TEMP_END_4K.as_usize() - SVSM_PERCPU_TEMP_BASE_4K.as_usize()) / PAGE_SIZE) / 2;
 pub const SVSM_PERCPU_TEMP_2M_SLOTS: usize =
-    ((SVSM_PERCPU_TEMP_END_2M - SVSM_PERCPU_TEMP_BASE_2M) / PAGE_SIZE_2M) / 2;
+    ((SVSM_PERCPU_TEMP_END_2M.as_usize() - SVSM_PERCPU_TEMP_BASE_2M.as_usize()) / PAGE_SIZE_2M) / 2;
 
 pub fn percpu_4k_slot_addr(slot: usize) -> Result<VirtAddr, ()> {
     if slot >= SVSM_PERCPU_TEMP_4K_SLOTS {
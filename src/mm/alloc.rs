@@ -5,8 +5,11 @@
 // Author: Joerg Roedel <jroedel@suse.de>
 
 use crate::locking::SpinLock;
-use crate::types::{PhysAddr, VirtAddr, PAGE_SHIFT, PAGE_SIZE};
-use crate::utils::align_up;
+use crate::sev::ghcb::shrink_ghcb_cache;
+use crate::sev::vmsa::shrink_vmsa_cache;
+use crate::types::{align_up, PhysAddr, VirtAddr, PAGE_SHIFT, PAGE_SIZE};
+use crate::utils::zero_mem_region;
+use bitflags::bitflags;
 use core::alloc::{GlobalAlloc, Layout};
 use core::arch::asm;
 use core::mem::size_of;
@@ -48,7 +51,7 @@ impl PageStorageType {
     }
 
     fn encode_slab(slab: VirtAddr) -> Self {
-        PageStorageType(PAGE_TYPE_SLABPAGE | (slab as u64) & PAGE_TYPE_SLABPAGE_MASK)
+        PageStorageType(PAGE_TYPE_SLABPAGE | (u64::from(slab) & PAGE_TYPE_SLABPAGE_MASK))
     }
 }
 
@@ -93,18 +96,41 @@ impl FreeInfo {
     }
 }
 
+// Allocated pages have no next pointer, the lowest bit of its field marks
+// pages which get cleared when freed
+const PAGE_ALLOC_ZERO_ON_FREE: u64 = 1u64 << PAGE_FREE_NEXT_SHIFT;
+
 struct AllocatedInfo {
     order: usize,
+    zero_on_free: bool,
 }
 
 impl AllocatedInfo {
     pub fn encode(&self) -> PageStorageType {
-        PageStorageType::new(PAGE_TYPE_ALLOCATED).encode_order(self.order)
+        let mem = PageStorageType::new(PAGE_TYPE_ALLOCATED).encode_order(self.order);
+        if self.zero_on_free {
+            PageStorageType(mem.0 | PAGE_ALLOC_ZERO_ON_FREE)
+        } else {
+            mem
+        }
     }
 
     pub fn decode(mem: PageStorageType) -> Self {
         let order = ((mem.0 >> PAGE_TYPE_SHIFT) & PAGE_ORDER_MASK) as usize;
-        AllocatedInfo { order }
+        let zero_on_free = (mem.0 & PAGE_ALLOC_ZERO_ON_FREE) != 0;
+        AllocatedInfo {
+            order,
+            zero_on_free,
+        }
+    }
+}
+
+bitflags! {
+    pub struct AllocFlags: u32 {
+        // Clear the pages before returning them
+        const ZERO          = 1 << 0;
+        // Clear the pages when they are freed, for memory holding secrets
+        const ZERO_ON_FREE  = 1 << 1;
     }
 }
 
@@ -119,7 +145,7 @@ impl SlabPageInfo {
 
     pub fn decode(mem: PageStorageType) -> Self {
         SlabPageInfo {
-            slab: (mem.0 & PAGE_TYPE_SLABPAGE_MASK) as VirtAddr,
+            slab: VirtAddr::from(mem.0 & PAGE_TYPE_SLABPAGE_MASK),
         }
     }
 }
@@ -194,6 +220,45 @@ pub struct MemInfo {
     pub free_pages: [usize; MAX_ORDER],
 }
 
+// Volatile writes, so that clearing memory which is about to be freed is not
+// optimized away
+fn clear_pages_volatile(vaddr: VirtAddr, order: usize) {
+    let ptr = vaddr.as_mut_ptr::<u64>();
+
+    for i in 0..((PAGE_SIZE << order) / 8) {
+        unsafe { ptr.add(i).write_volatile(0) };
+    }
+}
+
+impl MemInfo {
+    pub fn stats(&self) -> MemStats {
+        let mut stats = MemStats::default();
+
+        for order in 0..MAX_ORDER {
+            let nr_4k_pages: usize = 1 << order;
+            stats.total_pages += self.total_pages[order] * nr_4k_pages;
+            stats.free_pages += self.free_pages[order] * nr_4k_pages;
+            if self.free_pages[order] > 0 {
+                stats.largest_free = nr_4k_pages;
+            }
+        }
+
+        stats
+    }
+}
+
+// Page allocator statistics, all counts are in 4k pages
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MemStats {
+    pub total_pages: usize,
+    pub free_pages: usize,
+    // Size of the largest free physically contiguous block
+    pub largest_free: usize,
+}
+
+// Number of physical ranges which can be excluded from the root memory region
+pub const MAX_RESERVED_RANGES: usize = 4;
+
 struct MemoryRegion {
     start_phys: PhysAddr,
     start_virt: VirtAddr,
@@ -201,39 +266,93 @@ struct MemoryRegion {
     nr_pages: [usize; MAX_ORDER],
     next_page: [usize; MAX_ORDER],
     free_pages: [usize; MAX_ORDER],
+    // Page frame ranges [start, end) which are never handed out
+    reserved: [(usize, usize); MAX_RESERVED_RANGES],
 }
 
 impl MemoryRegion {
     pub const fn new() -> Self {
         MemoryRegion {
-            start_phys: 0,
-            start_virt: 0,
+            start_phys: PhysAddr::null(),
+            start_virt: VirtAddr::null(),
             page_count: 0,
             nr_pages: [0; MAX_ORDER],
             next_page: [0; MAX_ORDER],
             free_pages: [0; MAX_ORDER],
+            reserved: [(0, 0); MAX_RESERVED_RANGES],
+        }
+    }
+
+    // Ranges not overlapping the region are ignored
+    fn set_reserved(&mut self, ranges: &[(PhysAddr, PhysAddr)]) -> Result<(), ()> {
+        if ranges.len() > MAX_RESERVED_RANGES {
+            return Err(());
         }
+
+        let base_pfn = self.start_phys.as_usize() >> PAGE_SHIFT;
+        self.reserved = [(0, 0); MAX_RESERVED_RANGES];
+        for (i, (start, end)) in ranges.iter().enumerate() {
+            let start_pfn = (start.as_usize() >> PAGE_SHIFT).max(base_pfn);
+            let end_pfn =
+                (align_up(end.as_usize(), PAGE_SIZE) >> PAGE_SHIFT).min(base_pfn + self.page_count);
+            if start_pfn < end_pfn {
+                self.reserved[i] = (start_pfn - base_pfn, end_pfn - base_pfn);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Gives the pages [start_pfn, end_pfn) to the pool, they must form the
+    // end of one of the reserved ranges
+    fn release_reserved(&mut self, start_pfn: usize, end_pfn: usize) -> Result<(), ()> {
+        let idx = self
+            .reserved
+            .iter()
+            .position(|&(s, e)| s <= start_pfn && start_pfn < end_pfn && e == end_pfn)
+            .ok_or(())?;
+        self.reserved[idx].1 = start_pfn;
+
+        for pfn in start_pfn..end_pfn {
+            self.write_page_info(
+                pfn,
+                Page::Allocated(AllocatedInfo {
+                    order: 0,
+                    zero_on_free: false,
+                }),
+            );
+            self.nr_pages[0] += 1;
+            self.free_page_order(pfn, 0);
+        }
+
+        Ok(())
+    }
+
+    fn is_reserved(&self, pfn: usize, order: usize) -> bool {
+        let end = pfn + (1usize << order);
+
+        self.reserved
+            .iter()
+            .any(|&(start_pfn, end_pfn)| pfn < end_pfn && start_pfn < end)
     }
 
-    #[allow(dead_code)]
     pub fn phys_to_virt(&self, paddr: PhysAddr) -> Option<VirtAddr> {
         let end_phys = self.start_phys + (self.page_count * PAGE_SIZE);
 
         if paddr < self.start_phys || paddr >= end_phys {
             // For the initial stage2 identity mapping, the root page table
             // pages are static and outside of the heap memory region.
-            if self.start_phys as VirtAddr == self.start_virt {
-                return Some(paddr as VirtAddr);
+            if self.start_phys.as_usize() == self.start_virt.as_usize() {
+                return Some(VirtAddr::from(paddr.as_usize()));
             }
             return None;
         }
 
         let offset = paddr - self.start_phys;
 
-        Some((self.start_virt + offset) as VirtAddr)
+        Some(self.start_virt + offset)
     }
 
-    #[allow(dead_code)]
     pub fn virt_to_phys(&self, vaddr: VirtAddr) -> Option<PhysAddr> {
         let end_virt = self.start_virt + (self.page_count * PAGE_SIZE);
 
@@ -243,7 +362,7 @@ impl MemoryRegion {
 
         let offset = vaddr - self.start_virt;
 
-        Some((self.start_phys + offset) as PhysAddr)
+        Some(self.start_phys + offset)
     }
 
     fn page_info_virt_addr(&self, pfn: usize) -> VirtAddr {
@@ -270,7 +389,9 @@ impl MemoryRegion {
 
         let info: PageStorageType = pi.to_mem();
         unsafe {
-            let ptr: *mut PageStorageType = self.page_info_virt_addr(pfn) as *mut PageStorageType;
+            let ptr: *mut PageStorageType = self
+                .page_info_virt_addr(pfn)
+                .as_mut_ptr::<PageStorageType>();
             (*ptr) = info;
         }
     }
@@ -279,13 +400,13 @@ impl MemoryRegion {
         self.check_pfn(pfn);
 
         let virt = self.page_info_virt_addr(pfn);
-        let info: PageStorageType = PageStorageType(unsafe { *(virt as *const u64) });
+        let info: PageStorageType = PageStorageType(unsafe { *virt.as_ptr::<u64>() });
 
         Page::from_mem(info)
     }
 
     pub fn get_page_info(&self, vaddr: VirtAddr) -> Result<Page, ()> {
-        if vaddr == 0 || !self.check_virt_addr(vaddr) {
+        if vaddr.is_null() || !self.check_virt_addr(vaddr) {
             return Err(());
         }
 
@@ -368,18 +489,33 @@ impl MemoryRegion {
         self.split_page(pfn, order + 1)
     }
 
-    pub fn allocate_pages(&mut self, order: usize) -> Result<VirtAddr, ()> {
+    pub fn allocate_pages_flags(
+        &mut self,
+        order: usize,
+        flags: AllocFlags,
+    ) -> Result<VirtAddr, ()> {
         self.refill_page_list(order)?;
         if let Ok(pfn) = self.get_next_page(order) {
-            let pg = Page::Allocated(AllocatedInfo { order });
+            assert!(!self.is_reserved(pfn, order));
+            let pg = Page::Allocated(AllocatedInfo {
+                order,
+                zero_on_free: flags.contains(AllocFlags::ZERO_ON_FREE),
+            });
             self.write_page_info(pfn, pg);
             let vaddr = self.start_virt + (pfn * PAGE_SIZE);
+            if flags.contains(AllocFlags::ZERO) {
+                zero_mem_region(vaddr, vaddr + (PAGE_SIZE << order));
+            }
             Ok(vaddr)
         } else {
             Err(())
         }
     }
 
+    pub fn allocate_pages(&mut self, order: usize) -> Result<VirtAddr, ()> {
+        self.allocate_pages_flags(order, AllocFlags::empty())
+    }
+
     pub fn allocate_page(&mut self) -> Result<VirtAddr, ()> {
         self.allocate_pages(0)
     }
@@ -393,7 +529,7 @@ impl MemoryRegion {
 
         unsafe {
             asm!("rep stosq",
-                in("rdi") vaddr.unwrap(),
+                in("rdi") vaddr.unwrap().as_usize(),
                 in("rax") 0,
                 in("rcx") PAGE_SIZE / 8,
                 options(att_syntax));
@@ -405,9 +541,10 @@ impl MemoryRegion {
     pub fn allocate_slab_page(&mut self, slab: Option<VirtAddr>) -> Result<VirtAddr, ()> {
         self.refill_page_list(0)?;
 
-        let slab_vaddr = slab.unwrap_or(0);
+        let slab_vaddr = slab.unwrap_or(VirtAddr::null());
         if let Ok(pfn) = self.get_next_page(0) {
-            assert_eq!(slab_vaddr & (PAGE_TYPE_MASK as usize), 0);
+            assert!(!self.is_reserved(pfn, 0));
+            assert_eq!(slab_vaddr.as_usize() & (PAGE_TYPE_MASK as usize), 0);
             let pg = Page::SlabPage(SlabPageInfo { slab: slab_vaddr });
             self.write_page_info(pfn, pg);
             let vaddr = self.start_virt + (pfn * PAGE_SIZE);
@@ -422,13 +559,17 @@ impl MemoryRegion {
             return Err(());
         }
 
-        assert_eq!(pfn & ((1usize << order) - 1), 0);
-        let pfn = pfn ^ (1usize << order);
-        if pfn >= self.page_count {
+        // Buddies are paired by their physical page frame number, so that
+        // each block is aligned to its size in physical memory.
+        let base_pfn = self.start_phys.as_usize() >> PAGE_SHIFT;
+        let phys_pfn = base_pfn + pfn;
+        assert_eq!(phys_pfn & ((1usize << order) - 1), 0);
+        let neighbor = phys_pfn ^ (1usize << order);
+        if neighbor < base_pfn || neighbor - base_pfn >= self.page_count {
             return Err(());
         }
 
-        Ok(pfn)
+        Ok(neighbor - base_pfn)
     }
 
     fn merge_pages(&mut self, pfn1: usize, pfn2: usize, order: usize) -> Result<usize, ()> {
@@ -440,7 +581,10 @@ impl MemoryRegion {
         let pfn = if pfn1 < pfn2 { pfn1 } else { pfn2 };
 
         // Write new compound head
-        let pg = Page::Allocated(AllocatedInfo { order: order + 1 });
+        let pg = Page::Allocated(AllocatedInfo {
+            order: order + 1,
+            zero_on_free: false,
+        });
         self.write_page_info(pfn, pg);
 
         // Write compound pages
@@ -494,7 +638,10 @@ impl MemoryRegion {
                 });
                 self.write_page_info(old_pfn, pg);
 
-                let pg = Page::Allocated(AllocatedInfo { order });
+                let pg = Page::Allocated(AllocatedInfo {
+                    order,
+                    zero_on_free: false,
+                });
                 self.write_page_info(current_pfn, pg);
 
                 self.free_pages[order] -= 1;
@@ -562,6 +709,9 @@ impl MemoryRegion {
 
         match res.unwrap() {
             Page::Allocated(ai) => {
+                if ai.zero_on_free {
+                    clear_pages_volatile(vaddr, ai.order);
+                }
                 self.free_page_order(pfn, ai.order);
             }
             Page::SlabPage(_si) => {
@@ -590,17 +740,28 @@ impl MemoryRegion {
             self.write_page_info(i, pg);
         }
 
-        self.nr_pages[0] = self.page_count - meta_pages;
-
-        /* Mark all pages as allocated */
+        /* Mark all pages as allocated, reserved ones stay out of the pool */
+        let mut nr_pages = 0;
         for i in meta_pages..self.page_count {
-            let pg = Page::Allocated(AllocatedInfo { order: 0 });
+            let pg = if self.is_reserved(i, 0) {
+                Page::Reserved(ReservedInfo {})
+            } else {
+                nr_pages += 1;
+                Page::Allocated(AllocatedInfo {
+                    order: 0,
+                    zero_on_free: false,
+                })
+            };
             self.write_page_info(i, pg);
         }
 
+        self.nr_pages[0] = nr_pages;
+
         /* Now free all pages */
         for i in meta_pages..self.page_count {
-            self.free_page_order(i, 0);
+            if !self.is_reserved(i, 0) {
+                self.free_page_order(i, 0);
+            }
         }
     }
 }
@@ -630,30 +791,245 @@ pub fn print_memory_info(info: &MemInfo) {
 
 static ROOT_MEM: SpinLock<MemoryRegion> = SpinLock::new(MemoryRegion::new());
 
+struct LowMemHook {
+    threshold: usize,
+    hook: fn(&MemStats),
+    // Set while free memory is below the threshold, so that the hook fires
+    // once per crossing
+    below: bool,
+}
+
+static LOW_MEM_HOOK: SpinLock<Option<LowMemHook>> = SpinLock::new(None);
+
+// Call f when the number of free pages drops below threshold. Only one hook
+// can be registered at a time.
+pub fn register_low_mem_hook(threshold: usize, f: fn(&MemStats)) -> Result<(), ()> {
+    let mut hook = LOW_MEM_HOOK.lock();
+
+    if hook.is_some() {
+        return Err(());
+    }
+
+    *hook = Some(LowMemHook {
+        threshold,
+        hook: f,
+        below: false,
+    });
+
+    Ok(())
+}
+
+pub fn unregister_low_mem_hook() {
+    *LOW_MEM_HOOK.lock() = None;
+}
+
+// Runs after ROOT_MEM is unlocked, so that the hook can use the allocator
+fn check_low_mem() {
+    let mut guard = LOW_MEM_HOOK.lock();
+    let hook = match guard.as_mut() {
+        Some(hook) => hook,
+        None => return,
+    };
+
+    let stats = mem_stats();
+    let below = stats.free_pages < hook.threshold;
+    let fire = below && !hook.below;
+    hook.below = below;
+
+    if fire {
+        let f = hook.hook;
+        drop(guard);
+        f(&stats);
+    }
+}
+
+// The VMSA and GHCB caches keep their slabs until shrunk, returns the number
+// of slabs they gave back
+fn shrink_page_caches() -> usize {
+    shrink_vmsa_cache() + shrink_ghcb_cache()
+}
+
+// A failed allocation is retried once after the page caches gave back their
+// unused slabs. ROOT_MEM is not locked while they are shrunk.
+fn allocate_or_shrink<F>(f: F) -> Result<VirtAddr, ()>
+where
+    F: Fn(&mut MemoryRegion) -> Result<VirtAddr, ()>,
+{
+    let mut result = f(&mut ROOT_MEM.lock());
+    if result.is_err() && shrink_page_caches() > 0 {
+        result = f(&mut ROOT_MEM.lock());
+    }
+    check_low_mem();
+    result
+}
+
 pub fn allocate_page() -> Result<VirtAddr, ()> {
-    ROOT_MEM.lock().allocate_page()
+    allocate_or_shrink(|mem| mem.allocate_page())
 }
 
 pub fn allocate_pages(order: usize) -> Result<VirtAddr, ()> {
-    ROOT_MEM.lock().allocate_pages(order)
+    allocate_or_shrink(|mem| mem.allocate_pages(order))
 }
 
 pub fn allocate_slab_page(slab: Option<VirtAddr>) -> Result<VirtAddr, ()> {
-    ROOT_MEM.lock().allocate_slab_page(slab)
+    allocate_or_shrink(|mem| mem.allocate_slab_page(slab))
 }
 
 pub fn allocate_zeroed_page() -> Result<VirtAddr, ()> {
-    ROOT_MEM.lock().allocate_zeroed_page()
+    allocate_or_shrink(|mem| mem.allocate_zeroed_page())
+}
+
+pub fn allocate_pages_flags(order: usize, flags: AllocFlags) -> Result<VirtAddr, ()> {
+    allocate_or_shrink(|mem| mem.allocate_pages_flags(order, flags))
+}
+
+// A zeroed page which is cleared again when freed, for keys and other secrets
+pub fn alloc_secret_page() -> Result<VirtAddr, ()> {
+    allocate_pages_flags(0, AllocFlags::ZERO | AllocFlags::ZERO_ON_FREE)
 }
 
 pub fn free_page(vaddr: VirtAddr) {
-    ROOT_MEM.lock().free_page(vaddr)
+    ROOT_MEM.lock().free_page(vaddr);
+    check_low_mem();
 }
 
 pub fn memory_info() -> MemInfo {
     ROOT_MEM.lock().memory_info()
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AllocError {
+    OutOfMemory,
+    // Orders must be below MAX_ORDER
+    InvalidOrder(usize),
+}
+
+// Allocate 2^order physically contiguous pages. The returned address is
+// aligned to the size of the allocation.
+pub fn alloc_pages(order: usize) -> Result<PhysAddr, AllocError> {
+    alloc_pages_flags(order, AllocFlags::empty())
+}
+
+pub fn alloc_pages_flags(order: usize, flags: AllocFlags) -> Result<PhysAddr, AllocError> {
+    if order >= MAX_ORDER {
+        return Err(AllocError::InvalidOrder(order));
+    }
+
+    let vaddr = allocate_pages_flags(order, flags).map_err(|_| AllocError::OutOfMemory)?;
+    let paddr = ROOT_MEM.lock().virt_to_phys(vaddr).unwrap();
+
+    Ok(paddr)
+}
+
+// Free pages allocated with alloc_pages(), order must match the allocation
+pub fn free_pages(paddr: PhysAddr, order: usize) {
+    {
+        let mut root_mem = ROOT_MEM.lock();
+        let vaddr = root_mem
+            .phys_to_virt(paddr)
+            .expect("free_pages(): address not in memory region");
+
+        match root_mem.get_page_info(vaddr) {
+            Ok(Page::Allocated(ai)) if ai.order == order => root_mem.free_page(vaddr),
+            _ => panic!(
+                "free_pages(): no order-{} allocation at {:#x}",
+                order, paddr
+            ),
+        }
+    }
+
+    check_low_mem();
+}
+
+// Owns an allocation of 2^order contiguous pages and frees it on drop
+#[derive(Debug)]
+pub struct PagesRef {
+    paddr: PhysAddr,
+    order: usize,
+}
+
+impl PagesRef {
+    pub fn new(order: usize) -> Result<Self, AllocError> {
+        PagesRef::new_flags(order, AllocFlags::empty())
+    }
+
+    pub fn new_zeroed(order: usize) -> Result<Self, AllocError> {
+        PagesRef::new_flags(order, AllocFlags::ZERO)
+    }
+
+    pub fn new_flags(order: usize, flags: AllocFlags) -> Result<Self, AllocError> {
+        let paddr = alloc_pages_flags(order, flags)?;
+        Ok(PagesRef { paddr, order })
+    }
+
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.paddr
+    }
+
+    pub fn virt_addr(&self) -> VirtAddr {
+        ROOT_MEM.lock().phys_to_virt(self.paddr).unwrap()
+    }
+
+    pub fn order(&self) -> usize {
+        self.order
+    }
+
+    pub fn size(&self) -> usize {
+        PAGE_SIZE << self.order
+    }
+
+    // Give up ownership without freeing the pages, they have to be released
+    // with free_pages() later
+    pub fn into_phys_addr(self) -> PhysAddr {
+        let paddr = self.paddr;
+        core::mem::forget(self);
+        paddr
+    }
+}
+
+impl Drop for PagesRef {
+    fn drop(&mut self) {
+        free_pages(self.paddr, self.order);
+    }
+}
+
+// Single page version of PagesRef
+#[derive(Debug)]
+pub struct PageRef(PagesRef);
+
+impl PageRef {
+    pub fn new() -> Result<Self, AllocError> {
+        Ok(PageRef(PagesRef::new(0)?))
+    }
+
+    pub fn new_zeroed() -> Result<Self, AllocError> {
+        Ok(PageRef(PagesRef::new_zeroed(0)?))
+    }
+
+    pub fn new_flags(flags: AllocFlags) -> Result<Self, AllocError> {
+        Ok(PageRef(PagesRef::new_flags(0, flags)?))
+    }
+
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.0.phys_addr()
+    }
+
+    pub fn virt_addr(&self) -> VirtAddr {
+        self.0.virt_addr()
+    }
+
+    // The page has to be released with free_page() later
+    pub fn into_virt_addr(self) -> VirtAddr {
+        let vaddr = self.virt_addr();
+        self.0.into_phys_addr();
+        vaddr
+    }
+}
+
+pub fn mem_stats() -> MemStats {
+    memory_info().stats()
+}
+
 struct SlabPage {
     vaddr: VirtAddr,
     capacity: u16,
@@ -666,12 +1042,12 @@ struct SlabPage {
 impl SlabPage {
     pub const fn new() -> Self {
         SlabPage {
-            vaddr: 0,
+            vaddr: VirtAddr::null(),
             capacity: 0,
             free: 0,
             item_size: 0,
             used_bitmap: [0; 2],
-            next_page: 0,
+            next_page: VirtAddr::null(),
         }
     }
 
@@ -681,7 +1057,7 @@ impl SlabPage {
         }
 
         assert!(item_size <= (PAGE_SIZE / 2) as u16);
-        assert_eq!(self.vaddr, 0);
+        assert!(self.vaddr.is_null());
 
         if item_size < 32 {
             item_size = 32;
@@ -700,7 +1076,7 @@ impl SlabPage {
     }
 
     pub fn destroy(&mut self) {
-        if self.vaddr == 0 {
+        if self.vaddr.is_null() {
             return;
         }
 
@@ -735,7 +1111,7 @@ impl SlabPage {
             if self.used_bitmap[idx] & mask == 0 {
                 self.used_bitmap[idx] |= mask;
                 self.free -= 1;
-                let offset = (self.item_size * i) as VirtAddr;
+                let offset = (self.item_size * i) as usize;
                 return Ok(self.vaddr + offset);
             }
         }
@@ -750,7 +1126,7 @@ impl SlabPage {
 
         assert!(self.item_size > 0);
 
-        let item_size = self.item_size as VirtAddr;
+        let item_size = self.item_size as usize;
         let offset = vaddr - self.vaddr;
         let i = offset / item_size;
         let idx = i / 64;
@@ -805,7 +1181,7 @@ impl SlabCommon {
         let old_next_page = self.page.get_next_page();
         new_page.set_next_page(old_next_page);
         self.page
-            .set_next_page((new_page as *mut SlabPage) as VirtAddr);
+            .set_next_page(VirtAddr::from_ptr(new_page as *mut SlabPage));
 
         let capacity = new_page.get_capacity() as u32;
         self.pages += 1;
@@ -845,8 +1221,8 @@ impl SlabCommon {
             }
 
             let next_page = (*page).get_next_page();
-            assert_ne!(next_page, 0); // Cannot happen with free slots on entry.
-            page = unsafe { &mut *(next_page as *mut SlabPage) };
+            assert!(!next_page.is_null()); // Cannot happen with free slots on entry.
+            page = unsafe { &mut *next_page.as_mut_ptr::<SlabPage>() };
         }
     }
 
@@ -869,8 +1245,8 @@ impl SlabCommon {
             }
 
             let next_page = page.get_next_page();
-            assert_ne!(next_page, 0); // Object does not belong to this Slab.
-            page = unsafe { &mut *(next_page as *mut SlabPage) };
+            assert!(!next_page.is_null()); // Object does not belong to this Slab.
+            page = unsafe { &mut *next_page.as_mut_ptr::<SlabPage>() };
         }
     }
 }
@@ -905,7 +1281,7 @@ impl SlabPageSlab {
         assert_ne!(self.common.free, 0);
 
         let page_vaddr = self.common.allocate_slot();
-        let slab_page = unsafe { &mut *(page_vaddr as *mut SlabPage) };
+        let slab_page = unsafe { &mut *page_vaddr.as_mut_ptr::<SlabPage>() };
 
         *slab_page = SlabPage::new();
         if let Err(_e) = slab_page.init(None, self.common.item_size) {
@@ -925,10 +1301,10 @@ impl SlabPageSlab {
             let mut next_page_vaddr = self.common.page.get_next_page();
             let mut freed_one = false;
             loop {
-                if next_page_vaddr == 0 {
+                if next_page_vaddr.is_null() {
                     break;
                 }
-                let slab_page = unsafe { &mut *(next_page_vaddr as *mut SlabPage) };
+                let slab_page = unsafe { &mut *next_page_vaddr.as_mut_ptr::<SlabPage>() };
                 next_page_vaddr = slab_page.get_next_page();
 
                 let capacity = slab_page.get_capacity();
@@ -938,7 +1314,7 @@ impl SlabPageSlab {
                         .remove_slab_page(unsafe { &mut *last_page }, slab_page);
                     slab_page.destroy();
                     self.common
-                        .deallocate_slot(slab_page as *mut SlabPage as VirtAddr);
+                        .deallocate_slot(VirtAddr::from_ptr(slab_page as *mut SlabPage));
                     freed_one = true;
                 } else {
                     last_page = slab_page;
@@ -953,11 +1329,11 @@ impl SlabPageSlab {
             return Err(());
         }
 
-        Ok(unsafe { &mut *(self.common.allocate_slot() as *mut SlabPage) })
+        Ok(unsafe { &mut *self.common.allocate_slot().as_mut_ptr::<SlabPage>() })
     }
 
     fn deallocate(&mut self, slab_page: *mut SlabPage) {
-        self.common.deallocate_slot(slab_page as VirtAddr);
+        self.common.deallocate_slot(VirtAddr::from_ptr(slab_page));
         self.shrink_slab();
     }
 }
@@ -974,7 +1350,7 @@ impl Slab {
     }
 
     fn init(&mut self) -> Result<(), ()> {
-        let slab_vaddr = (self as *mut Slab) as VirtAddr;
+        let slab_vaddr = VirtAddr::from_ptr(self as *mut Slab);
         self.common.init(Some(slab_vaddr))
     }
 
@@ -994,7 +1370,7 @@ impl Slab {
             Ok(slab_page) => unsafe { &mut *slab_page },
             Err(_) => return Err(()),
         };
-        let slab_vaddr = (self as *mut Slab) as VirtAddr;
+        let slab_vaddr = VirtAddr::from_ptr(self as *mut Slab);
         *slab_page = SlabPage::new();
         if let Err(_e) = slab_page.init(Some(slab_vaddr), self.common.item_size) {
             SLAB_PAGE_SLAB.lock().deallocate(slab_page);
@@ -1017,10 +1393,10 @@ impl Slab {
         }
 
         loop {
-            if next_page_vaddr == 0 {
+            if next_page_vaddr.is_null() {
                 break;
             }
-            let slab_page = unsafe { &mut *(next_page_vaddr as *mut SlabPage) };
+            let slab_page = unsafe { &mut *next_page_vaddr.as_mut_ptr::<SlabPage>() };
             next_page_vaddr = slab_page.get_next_page();
 
             let capacity = slab_page.get_capacity();
@@ -1112,11 +1488,11 @@ unsafe impl GlobalAlloc for SvsmAllocator {
             return ptr::null_mut();
         }
 
-        ret.unwrap() as *mut u8
+        ret.unwrap().as_mut_ptr::<u8>()
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
-        let virt_addr = ptr as VirtAddr;
+        let virt_addr = VirtAddr::from_ptr(ptr);
 
         let result = ROOT_MEM.lock().get_page_info(virt_addr);
 
@@ -1131,8 +1507,8 @@ unsafe impl GlobalAlloc for SvsmAllocator {
                 free_page(virt_addr);
             }
             Page::SlabPage(si) => {
-                assert_ne!(si.slab, 0);
-                let slab = si.slab as *mut Slab;
+                assert!(!si.slab.is_null());
+                let slab = si.slab.as_mut_ptr::<Slab>();
 
                 (*slab).deallocate(virt_addr);
             }
@@ -1147,11 +1523,25 @@ unsafe impl GlobalAlloc for SvsmAllocator {
 pub static mut ALLOCATOR: SvsmAllocator = SvsmAllocator::new();
 
 pub fn root_mem_init(pstart: PhysAddr, vstart: VirtAddr, page_count: usize) {
+    root_mem_init_reserved(pstart, vstart, page_count, &[]);
+}
+
+// Like root_mem_init(), but pages within the given physical ranges are never
+// handed out
+pub fn root_mem_init_reserved(
+    pstart: PhysAddr,
+    vstart: VirtAddr,
+    page_count: usize,
+    reserved: &[(PhysAddr, PhysAddr)],
+) {
     {
         let mut region = ROOT_MEM.lock();
         region.start_phys = pstart;
         region.start_virt = vstart;
         region.page_count = page_count;
+        region
+            .set_reserved(reserved)
+            .expect("Too many reserved memory ranges");
         region.init_memory();
         // drop lock here so slab initialization does not deadlock
     }
@@ -1161,6 +1551,21 @@ pub fn root_mem_init(pstart: PhysAddr, vstart: VirtAddr, page_count: usize) {
     }
 }
 
+// Hands the end of a range reserved in root_mem_init_reserved() to the
+// allocator
+pub fn release_reserved_pages(start: PhysAddr, end: PhysAddr) -> Result<(), ()> {
+    let mut root_mem = ROOT_MEM.lock();
+    let base_pfn = root_mem.start_phys.as_usize() >> PAGE_SHIFT;
+    let start_pfn = (start.as_usize() >> PAGE_SHIFT)
+        .checked_sub(base_pfn)
+        .ok_or(())?;
+    let end_pfn = (end.as_usize() >> PAGE_SHIFT)
+        .checked_sub(base_pfn)
+        .ok_or(())?;
+
+    root_mem.release_reserved(start_pfn, end_pfn)
+}
+
 pub fn print_alloc_info() {
     for i in 0..MAX_ORDER {
         let nr_pages = ROOT_MEM.lock().nr_pages[i];
@@ -1179,13 +1584,26 @@ static TEST_ROOT_MEM_LOCK: SpinLock<()> = SpinLock::new(());
 
 #[cfg(test)]
 use crate::locking::LockGuard;
+#[cfg(test)]
+use crate::types::is_page_aligned;
 
 #[cfg(test)]
 // Allocate a memory region from the standard Rust allocator and pass it to
 // root_mem_init().
-fn setup_test_root_mem(size: usize) -> LockGuard<'static, ()> {
+pub(crate) fn setup_test_root_mem(size: usize) -> LockGuard<'static, ()> {
+    setup_test_root_mem_reserved(size, &[])
+}
+
+#[cfg(test)]
+// Like setup_test_root_mem(), with the given page ranges of the test memory
+// region reserved.
+fn setup_test_root_mem_reserved(
+    size: usize,
+    reserved: &[(usize, usize)],
+) -> LockGuard<'static, ()> {
     extern crate alloc;
     use alloc::alloc::{alloc, handle_alloc_error};
+    use alloc::vec::Vec;
 
     let layout = Layout::from_size_align(size, PAGE_SIZE)
         .unwrap()
@@ -1193,25 +1611,31 @@ fn setup_test_root_mem(size: usize) -> LockGuard<'static, ()> {
     let ptr = unsafe { alloc(layout) };
     if ptr.is_null() {
         handle_alloc_error(layout);
-    } else if ptr as usize & (PAGE_SIZE - 1) != 0 {
+    } else if !is_page_aligned(ptr as usize) {
         panic!("test memory region allocation not aligned to page size");
     }
 
     let page_count = layout.size() / PAGE_SIZE;
+    let pstart = PhysAddr::from(ptr as usize);
+    let ranges: Vec<(PhysAddr, PhysAddr)> = reserved
+        .iter()
+        .map(|&(start, end)| (pstart + start * PAGE_SIZE, pstart + end * PAGE_SIZE))
+        .collect();
+
     let lock = TEST_ROOT_MEM_LOCK.lock();
-    root_mem_init(ptr as PhysAddr, ptr as VirtAddr, page_count);
+    root_mem_init_reserved(pstart, VirtAddr::from_ptr(ptr), page_count, &ranges);
     lock
 }
 
 #[cfg(test)]
 // Undo the setup done from setup_test_root_mem().
-fn destroy_test_root_mem(lock: LockGuard<'static, ()>) {
+pub(crate) fn destroy_test_root_mem(lock: LockGuard<'static, ()>) {
     extern crate alloc;
     use alloc::alloc::dealloc;
 
     let mut root_mem = ROOT_MEM.lock();
     let layout = Layout::from_size_align(root_mem.page_count * PAGE_SIZE, PAGE_SIZE).unwrap();
-    unsafe { dealloc(root_mem.start_phys as *mut u8, layout) };
+    unsafe { dealloc(root_mem.start_virt.as_mut_ptr::<u8>(), layout) };
     *root_mem = MemoryRegion::new();
 
     // Reset the Slabs
@@ -1222,7 +1646,7 @@ fn destroy_test_root_mem(lock: LockGuard<'static, ()>) {
 }
 
 #[cfg(test)]
-const DEFAULT_TEST_MEMORY_SIZE: usize = 16usize * 1024 * 1024;
+pub(crate) const DEFAULT_TEST_MEMORY_SIZE: usize = 16usize * 1024 * 1024;
 
 #[test]
 fn test_root_mem_setup() {
@@ -1238,7 +1662,7 @@ fn test_page_alloc_one() {
 
     let info_before = root_mem.memory_info();
     let page = root_mem.allocate_page().unwrap();
-    assert_ne!(page, 0);
+    assert!(!page.is_null());
     assert_ne!(info_before.free_pages, root_mem.memory_info().free_pages);
     root_mem.free_page(page);
     assert_eq!(info_before.free_pages, root_mem.memory_info().free_pages);
@@ -1247,6 +1671,90 @@ fn test_page_alloc_one() {
     destroy_test_root_mem(test_mem_lock);
 }
 
+#[test]
+// Allocations through alloc_pages() are naturally aligned and PagesRef gives
+// the memory back when dropped.
+fn test_alloc_pages_aligned() {
+    let test_mem_lock = setup_test_root_mem(DEFAULT_TEST_MEMORY_SIZE);
+    let stats = mem_stats();
+
+    for order in 0..MAX_ORDER {
+        let paddr = alloc_pages(order).unwrap();
+        assert!(paddr.is_aligned(PAGE_SIZE << order));
+        free_pages(paddr, order);
+
+        let pages = PagesRef::new_zeroed(order).unwrap();
+        assert!(pages.phys_addr().is_aligned(pages.size()));
+        assert_eq!(unsafe { *pages.virt_addr().as_ptr::<u64>() }, 0);
+        assert_eq!(mem_stats().free_pages, stats.free_pages - (1 << order));
+    }
+
+    assert_eq!(mem_stats().free_pages, stats.free_pages);
+    assert_eq!(
+        alloc_pages(MAX_ORDER),
+        Err(AllocError::InvalidOrder(MAX_ORDER))
+    );
+
+    destroy_test_root_mem(test_mem_lock);
+}
+
+#[test]
+// Pages allocated with ZERO_ON_FREE are cleared when they go back to the
+// allocator.
+fn test_page_alloc_zero_on_free() {
+    let test_mem_lock = setup_test_root_mem(DEFAULT_TEST_MEMORY_SIZE);
+
+    let page = alloc_secret_page().unwrap();
+    let ptr = page.as_mut_ptr::<u64>();
+    unsafe {
+        assert_eq!(*ptr.add(1), 0);
+        *ptr.add(1) = 0x5ec9e7;
+    }
+    free_page(page);
+    assert_eq!(unsafe { *ptr.add(1) }, 0);
+
+    destroy_test_root_mem(test_mem_lock);
+}
+
+#[cfg(test)]
+static TEST_LOW_MEM_FIRED: core::sync::atomic::AtomicUsize =
+    core::sync::atomic::AtomicUsize::new(0);
+
+#[cfg(test)]
+fn test_low_mem_hook(_stats: &MemStats) {
+    TEST_LOW_MEM_FIRED.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+}
+
+#[test]
+// The low memory hook fires once when free memory drops below the threshold
+// and again only after it recovered.
+fn test_page_alloc_low_mem_hook() {
+    use core::sync::atomic::Ordering;
+
+    let test_mem_lock = setup_test_root_mem(DEFAULT_TEST_MEMORY_SIZE);
+
+    let stats = mem_stats();
+    assert_eq!(stats.largest_free, 1 << (MAX_ORDER - 1));
+    assert!(stats.free_pages <= stats.total_pages);
+
+    register_low_mem_hook(stats.free_pages, test_low_mem_hook).unwrap();
+    assert!(register_low_mem_hook(0, test_low_mem_hook).is_err());
+
+    let page1 = allocate_page().unwrap();
+    let page2 = allocate_page().unwrap();
+    assert_eq!(TEST_LOW_MEM_FIRED.load(Ordering::Relaxed), 1);
+    assert_eq!(mem_stats().free_pages, stats.free_pages - 2);
+
+    free_page(page2);
+    free_page(page1);
+    let page1 = allocate_page().unwrap();
+    assert_eq!(TEST_LOW_MEM_FIRED.load(Ordering::Relaxed), 2);
+
+    unregister_low_mem_hook();
+    free_page(page1);
+    destroy_test_root_mem(test_mem_lock);
+}
+
 #[test]
 // Allocate and free all available compound pages, verify that memory_info()
 // reflects it.
@@ -1262,7 +1770,7 @@ fn test_page_alloc_all_compound() {
     for o in 0..MAX_ORDER {
         for _i in 0..info_before.free_pages[o] {
             let pages = root_mem.allocate_pages(o).unwrap();
-            assert_ne!(pages, 0);
+            assert!(!pages.is_null());
             allocs[o].push(pages);
         }
     }
@@ -1298,7 +1806,7 @@ fn test_page_alloc_all_single() {
         for _i in 0..info_before.free_pages[o] {
             for _j in 0..(1usize << o) {
                 let page = root_mem.allocate_page().unwrap();
-                assert_ne!(page, 0);
+                assert!(!page.is_null());
                 allocs.push(page);
             }
         }
@@ -1332,7 +1840,7 @@ fn test_page_alloc_oom() {
     for o in 0..MAX_ORDER {
         for _i in 0..info_before.free_pages[o] {
             let pages = root_mem.allocate_pages(o).unwrap();
-            assert_ne!(pages, 0);
+            assert!(!pages.is_null());
             allocs[o].push(pages);
         }
     }
@@ -1357,6 +1865,70 @@ fn test_page_alloc_oom() {
     destroy_test_root_mem(test_mem_lock);
 }
 
+#[test]
+// Reserved pages must never be allocated, not even when memory is exhausted.
+fn test_page_alloc_reserved() {
+    extern crate alloc;
+    use alloc::vec::Vec;
+
+    // The page meta-data takes up the first 8 pages of the region
+    let reserved = [(8, 24), (100, 101)];
+    let test_mem_lock = setup_test_root_mem_reserved(DEFAULT_TEST_MEMORY_SIZE, &reserved);
+    let mut root_mem = ROOT_MEM.lock();
+
+    let info = root_mem.memory_info();
+    let free_pages: usize = (0..MAX_ORDER).map(|o| info.free_pages[o] << o).sum();
+    // Only the meta-data, the reserved ranges and the slab page are in use
+    assert_eq!(
+        free_pages,
+        DEFAULT_TEST_MEMORY_SIZE / PAGE_SIZE - 8 - 17 - 1
+    );
+
+    let start = root_mem.start_virt;
+    let mut allocs: Vec<VirtAddr> = Vec::new();
+    while let Ok(page) = root_mem.allocate_page() {
+        let pfn = (page - start) / PAGE_SIZE;
+        assert!(reserved.iter().all(|&(s, e)| pfn < s || pfn >= e));
+        allocs.push(page);
+    }
+    assert_eq!(allocs.len(), free_pages);
+
+    for page in &allocs[..] {
+        root_mem.free_page(*page);
+    }
+
+    drop(root_mem);
+    destroy_test_root_mem(test_mem_lock);
+}
+
+#[test]
+// Released pages of a reserved range can be allocated, the rest of the range
+// stays reserved.
+fn test_page_alloc_release_reserved() {
+    let test_mem_lock = setup_test_root_mem_reserved(DEFAULT_TEST_MEMORY_SIZE, &[(8, 24)]);
+    let pstart = ROOT_MEM.lock().start_phys;
+    let free_before: usize = {
+        let info = ROOT_MEM.lock().memory_info();
+        (0..MAX_ORDER).map(|o| info.free_pages[o] << o).sum()
+    };
+
+    // Only the end of the range can be released
+    let page = |pfn: usize| pstart + pfn * PAGE_SIZE;
+    assert!(release_reserved_pages(page(8), page(16)).is_err());
+    release_reserved_pages(page(16), page(24)).unwrap();
+    assert!(release_reserved_pages(page(16), page(24)).is_err());
+
+    let mut root_mem = ROOT_MEM.lock();
+    let info = root_mem.memory_info();
+    let free_pages: usize = (0..MAX_ORDER).map(|o| info.free_pages[o] << o).sum();
+    assert_eq!(free_pages, free_before + 8);
+    assert!(root_mem.is_reserved(15, 0));
+    assert!(!root_mem.is_reserved(16, 0));
+
+    drop(root_mem);
+    destroy_test_root_mem(test_mem_lock);
+}
+
 #[cfg(test)]
 const TEST_SLAB_SIZES: [usize; 7] = [32, 64, 128, 256, 512, 1024, 2048];
 
@@ -0,0 +1,135 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2026 SUSE LLC
+//
+// Author: agent <agent@local>
+
+use crate::cpu::percpu::PERCPU_AREAS;
+use crate::mm::alloc::release_reserved_pages;
+use crate::mm::virt_to_phys;
+use crate::types::{VirtAddr, PAGE_SIZE};
+use crate::utils::zero_mem_region;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+// Set once the buddy allocator took over, early allocators are frozen
+// from then on
+static BUDDY_ALLOCATOR_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+// Hands out pages from a fixed region until the buddy allocator takes over
+// CPU setup. Pages are never freed. When the allocator is frozen the pages
+// it did not hand out go to the buddy allocator, the ones it handed out stay
+// in use forever.
+#[derive(Debug)]
+pub struct EarlyAllocator {
+    next: VirtAddr,
+    end: VirtAddr,
+    frozen: bool,
+}
+
+impl EarlyAllocator {
+    pub const fn new() -> Self {
+        EarlyAllocator {
+            next: VirtAddr::null(),
+            end: VirtAddr::null(),
+            frozen: false,
+        }
+    }
+
+    // The region must be mapped and not be used for anything else
+    pub fn seed(&mut self, start: VirtAddr, end: VirtAddr) {
+        assert!(!self.frozen);
+        self.next = start.page_align_up();
+        self.end = end.page_align_down();
+    }
+
+    pub fn allocate_zeroed_page(&mut self) -> Result<VirtAddr, ()> {
+        if self.frozen || self.next >= self.end {
+            return Err(());
+        }
+
+        let vaddr = self.next;
+        self.next = vaddr + PAGE_SIZE;
+        zero_mem_region(vaddr, vaddr + PAGE_SIZE);
+
+        Ok(vaddr)
+    }
+
+    pub fn free_pages(&self) -> usize {
+        if self.frozen || self.next >= self.end {
+            0
+        } else {
+            (self.end - self.next) / PAGE_SIZE
+        }
+    }
+
+    // Returns the range of pages which were not handed out, None when there
+    // are none
+    pub fn freeze(&mut self) -> Option<(VirtAddr, VirtAddr)> {
+        let unused = (self.free_pages() > 0).then_some((self.next, self.end));
+        self.frozen = true;
+        unused
+    }
+
+    pub fn is_frozen(&self) -> bool {
+        self.frozen
+    }
+}
+
+impl Default for EarlyAllocator {
+    fn default() -> Self {
+        EarlyAllocator::new()
+    }
+}
+
+pub fn buddy_allocator_active() -> bool {
+    BUDDY_ALLOCATOR_ACTIVE.load(Ordering::Acquire)
+}
+
+// Must be called once the CPUs using an early allocator are set up and
+// before any other CPU is. The regions of the early allocators must be
+// reserved in the buddy allocator, see root_mem_init_reserved(). All early
+// allocators are frozen and the pages they did not hand out are released to
+// the buddy allocator.
+pub fn promote_to_buddy_allocator() {
+    BUDDY_ALLOCATOR_ACTIVE.store(true, Ordering::Release);
+    PERCPU_AREAS.for_each(|cpu| {
+        if let Some((start, end)) = cpu.freeze_early_allocator() {
+            release_reserved_pages(virt_to_phys(start), virt_to_phys(end))
+                .expect("Early allocation region is not reserved");
+        }
+    });
+}
+
+#[test]
+fn test_early_alloc() {
+    extern crate alloc;
+    use alloc::alloc::{alloc, dealloc};
+    use core::alloc::Layout;
+
+    let layout = Layout::from_size_align(4 * PAGE_SIZE, PAGE_SIZE).unwrap();
+    let ptr = unsafe { alloc(layout) };
+    let start = VirtAddr::from_ptr(ptr);
+
+    // Only the three full pages in the unaligned range are used
+    let mut early = EarlyAllocator::new();
+    early.seed(start + 1, start + 4 * PAGE_SIZE);
+    assert_eq!(early.free_pages(), 3);
+    for i in 1..3 {
+        assert_eq!(early.allocate_zeroed_page(), Ok(start + i * PAGE_SIZE));
+    }
+
+    // The last page was not handed out
+    assert_eq!(
+        early.freeze(),
+        Some((start + 3 * PAGE_SIZE, start + 4 * PAGE_SIZE))
+    );
+    assert_eq!(early.free_pages(), 0);
+    assert!(early.allocate_zeroed_page().is_err());
+
+    let mut early = EarlyAllocator::new();
+    early.seed(start, start + PAGE_SIZE);
+    assert_eq!(early.allocate_zeroed_page(), Ok(start));
+    assert_eq!(early.freeze(), None);
+
+    unsafe { dealloc(ptr, layout) };
+}
@@ -23,7 +23,7 @@ unsafe fn read_u8(v: VirtAddr) -> Result<u8, ()> {
          ".quad (1b)",
          ".quad (2b)",
          ".popsection",
-            in(reg) v,
+            in(reg) v.as_usize(),
             out(reg) val,
             out("rcx") rcx,
             options(att_syntax, nostack));
@@ -50,7 +50,7 @@ unsafe fn read_u16(v: VirtAddr) -> Result<u16, ()> {
          ".quad (1b)",
          ".quad (2b)",
          ".popsection",
-            in(reg) v,
+            in(reg) v.as_usize(),
             out(reg) val,
             out("rcx") rcx,
             options(att_syntax, nostack));
@@ -77,7 +77,7 @@ unsafe fn read_u32(v: VirtAddr) -> Result<u32, ()> {
          ".quad (1b)",
          ".quad (2b)",
          ".popsection",
-            in(reg) v,
+            in(reg) v.as_usize(),
             out(reg) val,
             out("rcx") rcx,
             options(att_syntax, nostack));
@@ -104,7 +104,7 @@ unsafe fn read_u64(v: VirtAddr) -> Result<u64, ()> {
          ".quad (1b)",
          ".quad (2b)",
          ".popsection",
-            in(reg) v,
+            in(reg) v.as_usize(),
             out(reg) val,
             out("rcx") rcx,
             options(att_syntax, nostack));
@@ -150,7 +150,9 @@ where
 
 impl<T: Sized + Copy> GuestPtr<T> {
     pub fn new(v: VirtAddr) -> Self {
-        GuestPtr { ptr: v as *mut T }
+        GuestPtr {
+            ptr: v.as_mut_ptr::<T>(),
+        }
     }
 
     pub fn from_ptr(p: *mut T) -> Self {
@@ -182,7 +184,7 @@ impl<T: Sized + Copy> GuestPtr<T> {
     where
         N: Sized + Copy,
     {
-        GuestPtr::<N>::new(self.ptr as VirtAddr)
+        GuestPtr::<N>::new(VirtAddr::from_ptr(self.ptr))
     }
 
     pub fn offset(&self, count: isize) -> Self {
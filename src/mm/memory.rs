@@ -6,48 +6,206 @@
 
 extern crate alloc;
 
+use crate::config::LaunchConfig;
 use crate::cpu::percpu::PERCPU_VMSAS;
-use crate::fw_cfg::{FwCfg, MemoryRegion};
-use crate::kernel_launch::KernelLaunchInfo;
 use crate::locking::RWLock;
 use crate::types::PhysAddr;
-use crate::utils::page_align;
 use alloc::vec::Vec;
 use log;
 
-static MEMORY_MAP: RWLock<Vec<MemoryRegion>> = RWLock::new(Vec::new());
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemoryKind {
+    // Guest memory
+    Usable,
+    // Firmware reserved memory and MMIO ranges
+    Reserved,
+    // Memory of the SVSM itself
+    Svsm,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryRegion {
+    pub base: PhysAddr,
+    pub size: usize,
+    pub kind: MemoryKind,
+}
 
-pub fn init_memory_map(fwcfg: &FwCfg, launch_info: &KernelLaunchInfo) -> Result<(), ()> {
-    let mut regions = fwcfg.get_memory_regions()?;
+impl MemoryRegion {
+    pub fn end(&self) -> PhysAddr {
+        self.base + self.size
+    }
 
-    // Remove SVSM memory from guest memory map
-    for mut region in regions.iter_mut() {
-        if (launch_info.kernel_start > region.start) && (launch_info.kernel_start < region.end) {
-            region.end = launch_info.kernel_start;
+    pub fn contains(&self, paddr: PhysAddr) -> bool {
+        paddr >= self.base && paddr < self.end()
+    }
+}
+
+// Regions are kept sorted by base address, without overlaps and with
+// adjacent regions of the same kind merged.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryMap {
+    regions: Vec<MemoryRegion>,
+}
+
+impl MemoryMap {
+    pub const fn new() -> Self {
+        MemoryMap {
+            regions: Vec::new(),
         }
     }
 
+    // The new region replaces whatever the map had in its range before
+    pub fn insert(&mut self, base: PhysAddr, size: usize, kind: MemoryKind) {
+        if size == 0 {
+            return;
+        }
+
+        let end = base + size;
+        let mut regions: Vec<MemoryRegion> = Vec::with_capacity(self.regions.len() + 2);
+
+        for r in self.regions.iter() {
+            if r.end() <= base || r.base >= end {
+                regions.push(*r);
+                continue;
+            }
+            if r.base < base {
+                regions.push(MemoryRegion {
+                    base: r.base,
+                    size: base - r.base,
+                    kind: r.kind,
+                });
+            }
+            if r.end() > end {
+                regions.push(MemoryRegion {
+                    base: end,
+                    size: r.end() - end,
+                    kind: r.kind,
+                });
+            }
+        }
+
+        regions.push(MemoryRegion { base, size, kind });
+        regions.sort_unstable_by_key(|r| r.base);
+
+        self.regions.clear();
+        for r in regions {
+            match self.regions.last_mut() {
+                Some(last) if last.kind == r.kind && last.end() == r.base => last.size += r.size,
+                _ => self.regions.push(r),
+            }
+        }
+    }
+
+    // Takes the memory of the SVSM, as reported to the guest in svsm_base
+    // and svsm_size of the secrets page, out of guest memory
+    pub fn carve_out_svsm(&mut self, start: PhysAddr, end: PhysAddr) {
+        self.insert(start, end - start, MemoryKind::Svsm);
+    }
+
+    pub fn regions(&self) -> &[MemoryRegion] {
+        &self.regions
+    }
+
+    pub fn iter_usable(&self) -> impl Iterator<Item = &MemoryRegion> {
+        self.regions.iter().filter(|r| r.kind == MemoryKind::Usable)
+    }
+
+    pub fn total_usable(&self) -> usize {
+        self.iter_usable().map(|r| r.size).sum()
+    }
+
+    // True if paddr is in usable guest memory
+    pub fn contains(&self, paddr: PhysAddr) -> bool {
+        self.iter_usable().any(|r| r.contains(paddr))
+    }
+}
+
+static MEMORY_MAP: RWLock<MemoryMap> = RWLock::new(MemoryMap::new());
+
+// Physical range of the SVSM image, reported to the guest as svsm_base and
+// svsm_size in the secrets page
+static SVSM_REGION: RWLock<(PhysAddr, PhysAddr)> =
+    RWLock::new((PhysAddr::null(), PhysAddr::null()));
+
+pub fn init_memory_map(config: &LaunchConfig) -> Result<(), ()> {
+    let map = config.memory_map.clone();
+
+    if map.total_usable() == 0 {
+        return Err(());
+    }
+
     log::info!("Guest Memory Regions:");
-    for r in regions.iter() {
-        log::info!("  {:018x}-{:018x}", r.start, r.end);
+    for r in map.regions() {
+        log::info!("  {:018x}-{:018x} {:?}", r.base, r.end(), r.kind);
     }
 
-    let mut map = MEMORY_MAP.lock_write();
-    *map = regions;
+    *MEMORY_MAP.lock_write() = map;
+    *SVSM_REGION.lock_write() = config.svsm_region;
 
     Ok(())
 }
 
+// True if [start, end) overlaps the memory of the SVSM image
+pub fn overlaps_svsm_region(start: PhysAddr, end: PhysAddr) -> bool {
+    let (svsm_start, svsm_end) = *SVSM_REGION.lock_read();
+
+    start < svsm_end && svsm_start < end
+}
+
+// Reserved and MMIO ranges, as well as the SVSM memory, are not valid
 pub fn valid_phys_address(paddr: PhysAddr) -> bool {
-    let page_addr = page_align(paddr);
-    let addr = paddr as u64;
+    let page_addr = paddr.page_align_down();
 
     if PERCPU_VMSAS.exists(page_addr) {
         return false;
     }
 
-    MEMORY_MAP
-        .lock_read()
-        .iter()
-        .any(|region| addr >= region.start && addr < region.end)
+    MEMORY_MAP.lock_read().contains(paddr)
+}
+
+#[test]
+fn test_memory_map() {
+    let pa = |addr: usize| PhysAddr::from(addr);
+    let mut map = MemoryMap::new();
+
+    map.insert(pa(0x10_0000), 0x7ff0_0000, MemoryKind::Usable);
+    map.insert(pa(0), 0xa_0000, MemoryKind::Usable);
+    map.insert(pa(0xa_0000), 0x6_0000, MemoryKind::Usable);
+    map.insert(pa(0xfeb0_0000), 0x40_0000, MemoryKind::Reserved);
+    assert_eq!(map.regions().len(), 2);
+    assert_eq!(map.total_usable(), 0x8000_0000);
+
+    map.carve_out_svsm(pa(0x7f00_0000), pa(0x7f10_0000));
+    assert_eq!(
+        map.regions(),
+        [
+            MemoryRegion {
+                base: pa(0),
+                size: 0x7f00_0000,
+                kind: MemoryKind::Usable,
+            },
+            MemoryRegion {
+                base: pa(0x7f00_0000),
+                size: 0x10_0000,
+                kind: MemoryKind::Svsm,
+            },
+            MemoryRegion {
+                base: pa(0x7f10_0000),
+                size: 0xf0_0000,
+                kind: MemoryKind::Usable,
+            },
+            MemoryRegion {
+                base: pa(0xfeb0_0000),
+                size: 0x40_0000,
+                kind: MemoryKind::Reserved,
+            },
+        ]
+    );
+    assert_eq!(map.total_usable(), 0x7ff0_0000);
+
+    assert!(map.contains(pa(0x7eff_ffff)));
+    assert!(!map.contains(pa(0x7f00_0000)));
+    assert!(map.contains(pa(0x7f10_0000)));
+    assert!(!map.contains(pa(0x8000_0000)));
+    assert!(!map.contains(pa(0xfec0_0000)));
 }
@@ -6,14 +6,16 @@
 
 pub mod address_space;
 pub mod alloc;
+pub mod early_alloc;
 pub mod guestmem;
 pub mod memory;
 pub mod pagetable;
 pub mod ptguards;
+pub mod slab_cache;
 pub mod stack;
 pub mod validate;
 
 pub use address_space::*;
 pub use guestmem::GuestPtr;
-pub use memory::valid_phys_address;
+pub use memory::{overlaps_svsm_region, valid_phys_address};
 pub use ptguards::*;
@@ -4,16 +4,15 @@
 //
 // Author: Joerg Roedel <jroedel@suse.de>
 
-use crate::cpu::control_regs::write_cr3;
+use crate::cpu::control_regs::{read_cr3, write_cr3_root};
 use crate::cpu::cpuid::cpuid_table;
 use crate::cpu::features::{cpu_has_nx, cpu_has_pge};
 use crate::cpu::flush_tlb_global_sync;
 use crate::locking::{LockGuard, SpinLock};
-use crate::mm::alloc::allocate_zeroed_page;
-use crate::mm::{phys_to_virt, virt_to_phys, PGTABLE_LVL3_IDX_SHARED};
-use crate::types::{PhysAddr, VirtAddr, PAGE_SIZE, PAGE_SIZE_2M};
+use crate::mm::alloc::{allocate_zeroed_page, free_page};
+use crate::mm::{map_phys, phys_to_virt, virt_to_phys, MappingFlags, PGTABLE_LVL3_IDX_SHARED};
+use crate::types::{PageSize, PhysAddr, VirtAddr, PAGE_SIZE, PAGE_SIZE_2M};
 use crate::utils::immut_after_init::ImmutAfterInitCell;
-use crate::utils::is_aligned;
 use bitflags::bitflags;
 use core::ops::{Deref, DerefMut, Index, IndexMut};
 use core::ptr;
@@ -23,6 +22,42 @@ static ENCRYPT_MASK: ImmutAfterInitCell<usize> = ImmutAfterInitCell::new(0);
 static FEATURE_MASK: ImmutAfterInitCell<PTEntryFlags> =
     ImmutAfterInitCell::new(PTEntryFlags::empty());
 
+// Page-aligned range of the SVSM text segment. Once it is set, only this range
+// may be mapped executable and it must not be mapped any other way.
+static CODE_REGION: ImmutAfterInitCell<(VirtAddr, VirtAddr)> =
+    ImmutAfterInitCell::new((VirtAddr::null(), VirtAddr::null()));
+
+pub fn set_code_region(start: VirtAddr, end: VirtAddr) {
+    unsafe { CODE_REGION.reinit(&(start.page_align_down(), end.page_align_up())) };
+}
+
+fn exec_policy_ok(
+    code: (VirtAddr, VirtAddr),
+    vaddr: VirtAddr,
+    size: usize,
+    flags: PTEntryFlags,
+) -> bool {
+    let (code_start, code_end) = code;
+
+    if code_start == code_end {
+        return true;
+    }
+
+    let executable = !flags.contains(PTEntryFlags::NX);
+    let in_code = vaddr >= code_start && vaddr + size <= code_end;
+
+    executable == in_code
+}
+
+fn check_exec_policy(vaddr: VirtAddr, size: usize, flags: PTEntryFlags) {
+    assert!(
+        exec_policy_ok(*CODE_REGION, vaddr, size, flags),
+        "Mapping {:#018x} with NX={} violates the code region policy",
+        vaddr,
+        flags.contains(PTEntryFlags::NX)
+    );
+}
+
 pub fn paging_init_early(encrypt_mask: u64) {
     unsafe { ENCRYPT_MASK.reinit(&(encrypt_mask as usize)) };
 
@@ -72,11 +107,11 @@ fn supported_flags(flags: PTEntryFlags) -> PTEntryFlags {
 }
 
 fn strip_c_bit(paddr: PhysAddr) -> PhysAddr {
-    paddr & !encrypt_mask()
+    PhysAddr::from(paddr.as_usize() & !encrypt_mask())
 }
 
 fn set_c_bit(paddr: PhysAddr) -> PhysAddr {
-    paddr | encrypt_mask()
+    PhysAddr::from(paddr.as_usize() | encrypt_mask())
 }
 
 bitflags! {
@@ -118,12 +153,12 @@ impl PTEntry {
     }
 
     pub fn set(&mut self, addr: PhysAddr, flags: PTEntryFlags) {
-        assert_eq!(addr & !0x000f_ffff_ffff_f000, 0);
-        self.0 = (addr as u64) | supported_flags(flags).bits();
+        assert_eq!(addr.as_usize() & !0x000f_ffff_ffff_f000, 0);
+        self.0 = u64::from(addr) | supported_flags(flags).bits();
     }
 
     pub fn address(&self) -> PhysAddr {
-        strip_c_bit((self.0 & 0x000f_ffff_ffff_f000) as PhysAddr)
+        strip_c_bit(PhysAddr::from(self.0 & 0x000f_ffff_ffff_f000))
     }
 }
 
@@ -160,13 +195,17 @@ pub struct PageTable {
 
 impl PageTable {
     pub fn load(&self) {
-        write_cr3(self.cr3_value());
+        write_cr3_root(self.root_phys(), 0, false);
+    }
+
+    // Physical address of the root table, with the C-bit set
+    pub fn root_phys(&self) -> PhysAddr {
+        let pgtable = VirtAddr::from_ptr(self as *const PageTable);
+        set_c_bit(virt_to_phys(pgtable))
     }
 
     pub fn cr3_value(&self) -> usize {
-        let pgtable: usize = (self as *const PageTable) as usize;
-        let cr3 = virt_to_phys(pgtable);
-        set_c_bit(cr3)
+        self.root_phys().as_usize()
     }
 
     pub fn clone_shared(&self) -> Result<PageTableRef, ()> {
@@ -183,6 +222,36 @@ impl PageTable {
         })
     }
 
+    // Frees the table at the given level and all tables below it. Mapped
+    // pages are left alone.
+    fn free_tables(page: &mut PTPage, level: usize) {
+        if level > 0 {
+            for entry in page.entries.iter_mut() {
+                if let Some(next) = PageTable::entry_to_pagetable(*entry) {
+                    PageTable::free_tables(next, level - 1);
+                    entry.clear();
+                }
+            }
+        }
+
+        free_page(VirtAddr::from_ptr(page as *const PTPage));
+    }
+
+    // Frees the tables of everything but the shared part, which belongs to
+    // the page table clone_shared() was called on
+    fn free_private_tables(&mut self) {
+        for (idx, entry) in self.root.entries.iter_mut().enumerate() {
+            if idx == PGTABLE_LVL3_IDX_SHARED {
+                continue;
+            }
+
+            if let Some(page) = PageTable::entry_to_pagetable(*entry) {
+                PageTable::free_tables(page, 2);
+                entry.clear();
+            }
+        }
+    }
+
     pub fn exec_flags() -> PTEntryFlags {
         PTEntryFlags::PRESENT | PTEntryFlags::GLOBAL | PTEntryFlags::ACCESSED | PTEntryFlags::DIRTY
     }
@@ -206,11 +275,15 @@ impl PageTable {
 
     fn allocate_page_table() -> Result<*mut PTPage, ()> {
         let ptr = allocate_zeroed_page()?;
-        Ok(ptr as *mut PTPage)
+        Ok(ptr.as_mut_ptr::<PTPage>())
     }
 
     fn index<const L: usize>(vaddr: VirtAddr) -> usize {
-        vaddr >> (12 + L * 9) & 0x1ff
+        PageTable::index_at(L, vaddr)
+    }
+
+    fn index_at(level: usize, vaddr: VirtAddr) -> usize {
+        vaddr.as_usize() >> (12 + level * 9) & 0x1ff
     }
 
     fn entry_to_pagetable(entry: PTEntry) -> Option<&'static mut PTPage> {
@@ -220,7 +293,7 @@ impl PageTable {
         }
 
         let address = phys_to_virt(entry.address());
-        Some(unsafe { &mut *(address as *mut PTPage) })
+        Some(unsafe { &mut *address.as_mut_ptr::<PTPage>() })
     }
 
     fn walk_addr_lvl0(page: &mut PTPage, vaddr: VirtAddr) -> Mapping {
@@ -278,7 +351,7 @@ impl PageTable {
             _ => return Mapping::Level3(entry),
         };
 
-        let paddr = virt_to_phys(page as VirtAddr);
+        let paddr = virt_to_phys(VirtAddr::from_ptr(page));
         let flags = PTEntryFlags::PRESENT
             | PTEntryFlags::WRITABLE
             | PTEntryFlags::USER
@@ -303,7 +376,7 @@ impl PageTable {
             _ => return Mapping::Level2(entry),
         };
 
-        let paddr = virt_to_phys(page as VirtAddr);
+        let paddr = virt_to_phys(VirtAddr::from_ptr(page));
         let flags = PTEntryFlags::PRESENT
             | PTEntryFlags::WRITABLE
             | PTEntryFlags::USER
@@ -328,7 +401,7 @@ impl PageTable {
             _ => return Mapping::Level1(entry),
         };
 
-        let paddr = virt_to_phys(page as VirtAddr);
+        let paddr = virt_to_phys(VirtAddr::from_ptr(page));
         let flags = PTEntryFlags::PRESENT
             | PTEntryFlags::WRITABLE
             | PTEntryFlags::USER
@@ -369,7 +442,7 @@ impl PageTable {
 
         assert!(flags.contains(PTEntryFlags::HUGE));
 
-        let addr_2m = entry.address() & 0x000f_ffff_fff0_0000;
+        let addr_2m = PhysAddr::from(entry.address().as_usize() & 0x000f_ffff_fff0_0000);
 
         flags.remove(PTEntryFlags::HUGE);
 
@@ -382,7 +455,7 @@ impl PageTable {
             }
         }
 
-        entry.set(set_c_bit(virt_to_phys(page as VirtAddr)), flags);
+        entry.set(set_c_bit(virt_to_phys(VirtAddr::from_ptr(page))), flags);
 
         flush_tlb_global_sync();
 
@@ -458,8 +531,9 @@ impl PageTable {
         paddr: PhysAddr,
         flags: PTEntryFlags,
     ) -> Result<(), ()> {
-        assert!(is_aligned(vaddr, PAGE_SIZE_2M));
-        assert!(is_aligned(paddr, PAGE_SIZE_2M));
+        assert!(vaddr.is_aligned(PAGE_SIZE_2M));
+        assert!(paddr.is_aligned(PAGE_SIZE_2M));
+        check_exec_policy(vaddr, PAGE_SIZE_2M, flags);
 
         let mapping = self.alloc_pte_2m(vaddr);
 
@@ -472,7 +546,7 @@ impl PageTable {
     }
 
     pub fn unmap_2m(&mut self, vaddr: VirtAddr) {
-        assert!(is_aligned(vaddr, PAGE_SIZE_2M));
+        assert!(vaddr.is_aligned(PAGE_SIZE_2M));
 
         let mapping = self.walk_addr(vaddr);
 
@@ -490,6 +564,8 @@ impl PageTable {
         paddr: PhysAddr,
         flags: PTEntryFlags,
     ) -> Result<(), ()> {
+        check_exec_policy(vaddr, PAGE_SIZE, flags);
+
         let mapping = self.alloc_pte_4k(vaddr);
 
         if let Mapping::Level0(entry) = mapping {
@@ -516,14 +592,14 @@ impl PageTable {
 
         match mapping {
             Mapping::Level0(entry) => {
-                let offset = vaddr & (PAGE_SIZE - 1);
+                let offset = vaddr.page_offset();
                 if !entry.flags().contains(PTEntryFlags::PRESENT) {
                     return Err(());
                 }
                 Ok(entry.address() + offset)
             }
             Mapping::Level1(entry) => {
-                let offset = vaddr & (PAGE_SIZE_2M - 1);
+                let offset = vaddr - vaddr.align_down(PAGE_SIZE_2M);
                 if !entry.flags().contains(PTEntryFlags::PRESENT)
                     || !entry.flags().contains(PTEntryFlags::HUGE)
                 {
@@ -544,7 +620,7 @@ impl PageTable {
         phys: PhysAddr,
         flags: PTEntryFlags,
     ) -> Result<(), ()> {
-        for addr in (start..end).step_by(PAGE_SIZE) {
+        for addr in start.iter_to(end, PAGE_SIZE) {
             let offset = addr - start;
             self.map_4k(addr, phys + offset, flags)?;
         }
@@ -552,7 +628,7 @@ impl PageTable {
     }
 
     pub fn unmap_region_4k(&mut self, start: VirtAddr, end: VirtAddr) {
-        for addr in (start..end).step_by(PAGE_SIZE) {
+        for addr in start.iter_to(end, PAGE_SIZE) {
             self.unmap_4k(addr);
         }
     }
@@ -564,7 +640,7 @@ impl PageTable {
         phys: PhysAddr,
         flags: PTEntryFlags,
     ) -> Result<(), ()> {
-        for addr in (start..end).step_by(PAGE_SIZE_2M) {
+        for addr in start.iter_to(end, PAGE_SIZE_2M) {
             let offset = addr - start;
             self.map_2m(addr, phys + offset, flags)?;
         }
@@ -572,7 +648,7 @@ impl PageTable {
     }
 
     pub fn unmap_region_2m(&mut self, start: VirtAddr, end: VirtAddr) {
-        for addr in (start..end).step_by(PAGE_SIZE_2M) {
+        for addr in start.iter_to(end, PAGE_SIZE_2M) {
             self.unmap_2m(addr);
         }
     }
@@ -588,8 +664,8 @@ impl PageTable {
         let mut paddr = phys;
 
         while vaddr < end {
-            if is_aligned(vaddr, PAGE_SIZE_2M)
-                && is_aligned(paddr, PAGE_SIZE_2M)
+            if vaddr.is_aligned(PAGE_SIZE_2M)
+                && paddr.is_aligned(PAGE_SIZE_2M)
                 && vaddr + PAGE_SIZE_2M <= end
                 && self.map_2m(vaddr, paddr, flags).is_ok()
             {
@@ -629,6 +705,79 @@ impl PageTable {
     }
 }
 
+// Size of the page mapped by entry at level, or None if entry points to the
+// next level table
+fn leaf_page_size(level: usize, entry: PTEntry) -> Option<PageSize> {
+    let huge = entry.flags().contains(PTEntryFlags::HUGE);
+
+    match level {
+        0 => Some(PageSize::Page4K),
+        1 if huge => Some(PageSize::Page2M),
+        2 if huge => Some(PageSize::Page1G),
+        _ => None,
+    }
+}
+
+const LEVEL_NAMES: [&str; 4] = ["PT", "PD", "PDPT", "PML4"];
+
+// Logs all entries used to translate va in the currently loaded page table.
+// Table pages are accessed through map_phys(), so this must not be called
+// with the page table of the current CPU locked.
+pub fn dump_page_table_walk(va: VirtAddr) {
+    let cr3 = read_cr3();
+    let mut table = strip_c_bit(cr3.root_phys());
+
+    log::info!(
+        "Page-table walk for {:#018x} (CR3={:#018x})",
+        va,
+        cr3.bits()
+    );
+
+    for level in (0..4).rev() {
+        let idx = PageTable::index_at(level, va);
+        let entry = match map_phys(table, PAGE_SIZE, MappingFlags::empty()) {
+            Ok(mapping) => unsafe { *mapping.virt_addr().as_ptr::<PTEntry>().add(idx) },
+            Err(e) => {
+                log::info!(
+                    "  Can't map {} at {:#018x}: {:?}",
+                    LEVEL_NAMES[level],
+                    table,
+                    e
+                );
+                return;
+            }
+        };
+        let flags = entry.flags();
+
+        log::info!(
+            "  {:<4}[{:3}] = {:#018x} addr={:#018x} P={} W={} U={} NX={} PS={}",
+            LEVEL_NAMES[level],
+            idx,
+            entry.raw(),
+            entry.address(),
+            flags.contains(PTEntryFlags::PRESENT) as u8,
+            flags.contains(PTEntryFlags::WRITABLE) as u8,
+            flags.contains(PTEntryFlags::USER) as u8,
+            flags.contains(PTEntryFlags::NX) as u8,
+            flags.contains(PTEntryFlags::HUGE) as u8
+        );
+
+        if !entry.present() {
+            log::info!("  {:#018x} is not mapped", va);
+            return;
+        }
+
+        if let Some(size) = leaf_page_size(level, entry) {
+            let mask = size.bytes() - 1;
+            let pa = PhysAddr::from((entry.address().as_usize() & !mask) | (va.as_usize() & mask));
+            log::info!("  {:?} page: {:#018x} -> {:#018x}", size, va, pa);
+            return;
+        }
+
+        table = entry.address();
+    }
+}
+
 static INIT_PGTABLE: SpinLock<PageTableRef> = SpinLock::new(PageTableRef::unset());
 
 pub fn set_init_pgtable(pgtable: PageTableRef) {
@@ -661,6 +810,18 @@ impl PageTableRef {
     fn is_set(&self) -> bool {
         !self.pgtable_ptr.is_null()
     }
+
+    // Frees a page table created by clone_shared(), which must not be loaded
+    // on any CPU. Pages mapped in it are not freed.
+    pub fn free_shared_clone(&mut self) {
+        if !self.is_set() {
+            return;
+        }
+
+        self.free_private_tables();
+        free_page(VirtAddr::from_ptr(self.pgtable_ptr as *const PageTable));
+        *self = PageTableRef::unset();
+    }
 }
 
 impl Deref for PageTableRef {
@@ -678,3 +839,67 @@ impl DerefMut for PageTableRef {
         unsafe { &mut *self.pgtable_ptr }
     }
 }
+
+#[test]
+fn test_leaf_page_size() {
+    let table = PTEntry(0x1000 | PTEntryFlags::PRESENT.bits());
+    let huge = PTEntry(0x20_0000 | (PTEntryFlags::PRESENT | PTEntryFlags::HUGE).bits());
+
+    assert_eq!(leaf_page_size(0, table), Some(PageSize::Page4K));
+    assert_eq!(leaf_page_size(1, table), None);
+    assert_eq!(leaf_page_size(1, huge), Some(PageSize::Page2M));
+    assert_eq!(leaf_page_size(2, huge), Some(PageSize::Page1G));
+    // PS is reserved in PML4 entries
+    assert_eq!(leaf_page_size(3, huge), None);
+}
+
+#[test]
+fn test_exec_policy() {
+    let code = (
+        VirtAddr::from(0x10_0000usize),
+        VirtAddr::from(0x20_0000usize),
+    );
+    let exec = PageTable::exec_flags();
+    let data = PageTable::data_flags();
+
+    assert!(exec_policy_ok(
+        code,
+        VirtAddr::from(0x10_0000usize),
+        PAGE_SIZE,
+        exec
+    ));
+    assert!(!exec_policy_ok(
+        code,
+        VirtAddr::from(0x10_0000usize),
+        PAGE_SIZE,
+        data
+    ));
+    assert!(exec_policy_ok(
+        code,
+        VirtAddr::from(0x20_0000usize),
+        PAGE_SIZE,
+        data
+    ));
+    assert!(!exec_policy_ok(
+        code,
+        VirtAddr::from(0x20_0000usize),
+        PAGE_SIZE,
+        exec
+    ));
+    // A 2M page reaching beyond the code region can't be executable
+    assert!(!exec_policy_ok(
+        code,
+        VirtAddr::from(0x10_0000usize),
+        PAGE_SIZE_2M,
+        exec
+    ));
+
+    // Nothing is enforced before the code region is known
+    let none = (VirtAddr::null(), VirtAddr::null());
+    assert!(exec_policy_ok(
+        none,
+        VirtAddr::from(0x20_0000usize),
+        PAGE_SIZE,
+        exec
+    ));
+}
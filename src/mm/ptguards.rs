@@ -5,11 +5,14 @@
 // Author: Joerg Roedel <jroedel@suse.de>
 
 use super::pagetable::{get_init_pgtable_locked, PageTable};
-use crate::cpu::percpu::this_cpu_mut;
-use crate::cpu::tlb::{flush_address_sync, flush_tlb_global_sync};
+use crate::cpu::percpu::{this_cpu, this_cpu_mut};
+use crate::cpu::tlb::{flush_address_sync, flush_tlb_global_sync, flush_tlb_range};
 use crate::mm::{percpu_2m_slot_addr, percpu_4k_slot_addr};
-use crate::types::{PhysAddr, VirtAddr, PAGE_SIZE, PAGE_SIZE_2M};
-use crate::utils::is_aligned;
+use crate::types::{page_align_up, PhysAddr, VirtAddr, PAGE_SIZE, PAGE_SIZE_2M};
+use bitflags::bitflags;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use core::slice;
 
 struct RawPTMappingGuard {
     start: VirtAddr,
@@ -27,10 +30,10 @@ pub struct PerCPUPageMappingGuard {
 }
 
 impl PerCPUPageMappingGuard {
-    pub fn create(paddr: VirtAddr, slot: usize, huge: bool) -> Result<Self, ()> {
+    pub fn create(paddr: PhysAddr, slot: usize, huge: bool) -> Result<Self, ()> {
         let size = if huge { PAGE_SIZE_2M } else { PAGE_SIZE };
 
-        assert!(is_aligned(paddr, size));
+        assert!(paddr.is_aligned(size));
 
         let vaddr = if huge {
             percpu_2m_slot_addr(slot)?
@@ -104,3 +107,121 @@ impl Drop for PTMappingGuard {
         }
     }
 }
+
+bitflags! {
+    pub struct MappingFlags: u32 {
+        const WRITABLE = 1 << 0;
+        // Map without the C-bit set, for pages shared with the hypervisor
+        const SHARED   = 1 << 1;
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MapError {
+    // Size is zero or the range does not fit into one mapping slot
+    InvalidSize,
+    // All mapping slots of this CPU are in use
+    NoSlot,
+    PageTable,
+}
+
+// A physical range mapped into one of the per-cpu map_phys() slots. The range
+// gets unmapped and flushed from the TLB when the Mapping is dropped. Since
+// the slots are per-cpu, a Mapping must not leave the CPU it was created on.
+pub struct Mapping {
+    slot: usize,
+    start: VirtAddr,
+    offset: usize,
+    len: usize,
+    pages: usize,
+    _not_send: PhantomData<*const u8>,
+}
+
+impl Mapping {
+    pub fn virt_addr(&self) -> VirtAddr {
+        self.start + self.offset
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Deref for Mapping {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.virt_addr().as_ptr::<u8>(), self.len) }
+    }
+}
+
+impl DerefMut for Mapping {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.virt_addr().as_mut_ptr::<u8>(), self.len) }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        if self.pages > 0 {
+            let mut pgtable = this_cpu_mut().get_pgtable();
+            for i in 0..self.pages {
+                pgtable.unmap_4k(self.start + i * PAGE_SIZE);
+            }
+            drop(pgtable);
+            flush_tlb_range(self.start, self.pages * PAGE_SIZE);
+        }
+        this_cpu().free_map_phys_slot(self.slot);
+    }
+}
+
+// Maps size bytes starting at paddr into the address space of the current CPU.
+// paddr does not need to be page aligned, but the pages covering the range
+// must fit into 2M.
+pub fn map_phys(paddr: PhysAddr, size: usize, flags: MappingFlags) -> Result<Mapping, MapError> {
+    let offset = paddr.page_offset();
+    if size == 0 || size > PAGE_SIZE_2M - offset {
+        return Err(MapError::InvalidSize);
+    }
+
+    let slot = this_cpu().alloc_map_phys_slot().ok_or(MapError::NoSlot)?;
+    // From here on dropping the mapping releases the slot and everything that
+    // got mapped so far
+    let mut mapping = Mapping {
+        slot,
+        start: percpu_2m_slot_addr(slot).map_err(|_| MapError::NoSlot)?,
+        offset,
+        len: size,
+        pages: 0,
+        _not_send: PhantomData,
+    };
+
+    let pt_flags = if flags.contains(MappingFlags::WRITABLE) {
+        PageTable::data_flags()
+    } else {
+        PageTable::data_ro_flags()
+    };
+    let base = paddr.page_align_down();
+    let pages = page_align_up(offset + size) / PAGE_SIZE;
+
+    let mut pgtable = this_cpu_mut().get_pgtable();
+    for i in 0..pages {
+        let vaddr = mapping.start + i * PAGE_SIZE;
+        pgtable
+            .map_4k(vaddr, base + i * PAGE_SIZE, pt_flags)
+            .map_err(|_| MapError::PageTable)?;
+        mapping.pages += 1;
+        if flags.contains(MappingFlags::SHARED) {
+            pgtable
+                .set_shared_4k(vaddr)
+                .map_err(|_| MapError::PageTable)?;
+        }
+    }
+    drop(pgtable);
+
+    Ok(mapping)
+}
@@ -10,7 +10,8 @@ use crate::mm::alloc::{allocate_zeroed_page, free_page};
 use crate::mm::pagetable::{get_init_pgtable_locked, PageTable, PageTableRef};
 use crate::mm::{phys_to_virt, virt_to_phys};
 use crate::mm::{
-    STACK_PAGES, STACK_SIZE, STACK_TOTAL_SIZE, SVSM_SHARED_STACK_BASE, SVSM_SHARED_STACK_END,
+    STACK_GUARD_SIZE, STACK_PAGES, STACK_SIZE, STACK_TOTAL_SIZE, SVSM_PERCPU_STACKS_BASE,
+    SVSM_PERCPU_TEMP_BASE, SVSM_SHARED_STACK_BASE, SVSM_SHARED_STACK_END,
 };
 use crate::types::{VirtAddr, PAGE_SIZE};
 use crate::utils::ffs;
@@ -77,8 +78,18 @@ static STACK_ALLOC: SpinLock<StackRange> = SpinLock::new(StackRange::new(
     SVSM_SHARED_STACK_END,
 ));
 
+// Stacks are placed in slots of STACK_TOTAL_SIZE, the stack itself at the
+// bottom of the slot followed by STACK_GUARD_SIZE of unmapped guard area.
+// So every stack has the guard area of the slot below it (or the unmapped
+// space below the region for the first slot) right underneath, and an
+// overflow faults instead of silently corrupting memory.
 pub fn allocate_stack_addr(stack: VirtAddr, pgtable: &mut PageTableRef) -> Result<(), ()> {
     let flags = PageTable::data_flags();
+
+    // Refuse to set up a stack without guard page
+    if pgtable.phys_addr(stack - PAGE_SIZE).is_ok() {
+        return Err(());
+    }
     for i in 0..STACK_PAGES {
         let page = allocate_zeroed_page()?;
         let paddr = virt_to_phys(page);
@@ -88,6 +99,18 @@ pub fn allocate_stack_addr(stack: VirtAddr, pgtable: &mut PageTableRef) -> Resul
     Ok(())
 }
 
+// Counterpart of allocate_stack_addr(), also takes care of stacks which were
+// only partially set up. The page table must not be in use.
+pub fn free_stack_addr(stack: VirtAddr, pgtable: &mut PageTableRef) {
+    for i in 0..STACK_PAGES {
+        let addr = stack + (i * PAGE_SIZE);
+        if let Ok(paddr) = pgtable.phys_addr(addr) {
+            pgtable.unmap_4k(addr);
+            free_page(phys_to_virt(paddr));
+        }
+    }
+}
+
 pub fn allocate_stack() -> Result<VirtAddr, ()> {
     let stack = STACK_ALLOC.lock().alloc()?;
     allocate_stack_addr(stack, &mut get_init_pgtable_locked())?;
@@ -95,11 +118,11 @@ pub fn allocate_stack() -> Result<VirtAddr, ()> {
 }
 
 pub fn stack_base_pointer(stack: VirtAddr) -> VirtAddr {
-    (stack & !(STACK_SIZE - 1)) + STACK_SIZE
+    stack.align_down(STACK_SIZE) + STACK_SIZE
 }
 
 pub fn free_stack(stack: VirtAddr) {
-    let mut pages: [VirtAddr; STACK_PAGES] = [0; STACK_PAGES];
+    let mut pages: [VirtAddr; STACK_PAGES] = [VirtAddr::null(); STACK_PAGES];
 
     let mut pgtable = get_init_pgtable_locked();
     for (i, page) in pages.iter_mut().enumerate() {
@@ -122,3 +145,17 @@ pub fn free_stack(stack: VirtAddr) {
 
     STACK_ALLOC.lock().dealloc(stack);
 }
+
+fn guard_hit_in(addr: VirtAddr, base: VirtAddr, end: VirtAddr) -> bool {
+    if addr < base - STACK_GUARD_SIZE || addr >= end {
+        return false;
+    }
+
+    addr < base || (addr - base) % STACK_TOTAL_SIZE >= STACK_SIZE
+}
+
+// Returns true if addr is within the guard area below any SVSM stack
+pub fn stack_guard_hit(addr: VirtAddr) -> bool {
+    guard_hit_in(addr, SVSM_PERCPU_STACKS_BASE, SVSM_PERCPU_TEMP_BASE)
+        || guard_hit_in(addr, SVSM_SHARED_STACK_BASE, SVSM_SHARED_STACK_END)
+}
@@ -4,87 +4,129 @@
 //
 // Author: Joerg Roedel <jroedel@suse.de>
 
+extern crate alloc;
+
 use crate::cpu::flush_tlb_global_sync;
-use crate::cpu::percpu::{this_cpu, this_cpu_mut, PERCPU_AREAS, PERCPU_VMSAS};
+use crate::cpu::ipi::{ring_doorbell, wait_for_doorbell};
+use crate::cpu::percpu::{this_cpu, this_cpu_mut, DEFAULT_CAA_VMPL, PERCPU_AREAS, PERCPU_VMSAS};
+use crate::cpu::smp::shutdown_all_cpus;
+use crate::locking::RWLock;
+use crate::log_buffer::{LogProtocol, SVSM_LOG_PROTOCOL_ID};
 use crate::mm::PerCPUPageMappingGuard;
-use crate::mm::{valid_phys_address, GuestPtr};
+use crate::mm::{overlaps_svsm_region, valid_phys_address, GuestPtr};
+use crate::sev::caa::{CAA_ALIGN, CAA_SIZE};
+use crate::sev::secrets_page::SecretsPage;
 use crate::sev::utils::{
     pvalidate, rmp_clear_guest_vmsa, rmp_grant_guest_access, rmp_revoke_guest_access,
     rmp_set_guest_vmsa, RMPFlags, SevSnpError,
 };
-use crate::sev::vmsa::{GuestVMExit, VMSA};
-use crate::types::{PhysAddr, VirtAddr, PAGE_SIZE, PAGE_SIZE_2M};
-use crate::utils::{crosses_page, halt, is_aligned, page_align, page_offset};
-
-#[derive(Debug, Clone, Copy)]
-#[allow(non_camel_case_types, dead_code, clippy::upper_case_acronyms)]
-enum SvsmResultCode {
-    SUCCESS,
-    INCOMPLETE,
-    UNSUPPORTED_PROTOCOL,
-    UNSUPPORTED_CALL,
-    INVALID_ADDRESS,
-    INVALID_FORMAT,
-    INVALID_PARAMETER,
-    INVALID_REQUEST,
-    BUSY,
-    PROTOCOL_BASE(u64),
-}
-
-impl From<SvsmResultCode> for u64 {
-    fn from(res: SvsmResultCode) -> u64 {
-        match res {
-            SvsmResultCode::SUCCESS => 0x0000_0000,
-            SvsmResultCode::INCOMPLETE => 0x8000_0000,
-            SvsmResultCode::UNSUPPORTED_PROTOCOL => 0x8000_0001,
-            SvsmResultCode::UNSUPPORTED_CALL => 0x8000_0002,
-            SvsmResultCode::INVALID_ADDRESS => 0x8000_0003,
-            SvsmResultCode::INVALID_FORMAT => 0x8000_0004,
-            SvsmResultCode::INVALID_PARAMETER => 0x8000_0005,
-            SvsmResultCode::INVALID_REQUEST => 0x8000_0006,
-            SvsmResultCode::BUSY => 0x8000_0007,
-            SvsmResultCode::PROTOCOL_BASE(code) => 0x8000_1000 + code,
-        }
-    }
-}
-
-#[derive(Debug, Clone, Copy)]
-enum SvsmError {
-    RequestError(SvsmResultCode),
+use crate::sev::vmsa::{GuestVMExit, VMPL_MAX, VMSA};
+use crate::sev::{check_sev_features, current_sev_features};
+use crate::types::{PageSize, PhysAddr, VirtAddr, PAGE_SIZE, PAGE_SIZE_2M};
+use crate::utils::crosses_page;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::mem;
+
+const SVSM_SUCCESS: u64 = 0x0000_0000;
+const SVSM_ERR_INCOMPLETE: u64 = 0x8000_0000;
+const SVSM_ERR_UNSUPPORTED_PROTOCOL: u64 = 0x8000_0001;
+const SVSM_ERR_UNSUPPORTED_CALL: u64 = 0x8000_0002;
+const SVSM_ERR_INVALID_ADDRESS: u64 = 0x8000_0003;
+const SVSM_ERR_INVALID_FORMAT: u64 = 0x8000_0004;
+const SVSM_ERR_INVALID_PARAMETER: u64 = 0x8000_0005;
+const SVSM_ERR_INVALID_REQUEST: u64 = 0x8000_0006;
+const SVSM_ERR_BUSY: u64 = 0x8000_0007;
+const SVSM_ERR_PROTOCOL_BASE: u64 = 0x8000_1000;
+
+// Errors a request handler can report. All but FatalError are returned to
+// the guest as the corresponding SVSM_ERR_* result code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum SvsmReqError {
+    Incomplete,
+    UnsupportedProtocol,
+    UnsupportedCall,
+    InvalidAddress,
+    InvalidFormat,
+    InvalidParameter,
+    InvalidRequest,
+    Busy,
+    // Protocol-specific error, offset from SVSM_ERR_PROTOCOL_BASE
+    Protocol(u64),
+    // The SVSM itself is in a state where it can't continue
     FatalError(()),
 }
 
-macro_rules! impl_req_err {
-    ($name:ident, $v:ident) => {
-        fn $name() -> Self {
-            Self::RequestError(SvsmResultCode::$v)
+impl SvsmReqError {
+    // Result code for the guest, None for fatal errors
+    pub fn result_code(&self) -> Option<u64> {
+        match self {
+            SvsmReqError::Incomplete => Some(SVSM_ERR_INCOMPLETE),
+            SvsmReqError::UnsupportedProtocol => Some(SVSM_ERR_UNSUPPORTED_PROTOCOL),
+            SvsmReqError::UnsupportedCall => Some(SVSM_ERR_UNSUPPORTED_CALL),
+            SvsmReqError::InvalidAddress => Some(SVSM_ERR_INVALID_ADDRESS),
+            SvsmReqError::InvalidFormat => Some(SVSM_ERR_INVALID_FORMAT),
+            SvsmReqError::InvalidParameter => Some(SVSM_ERR_INVALID_PARAMETER),
+            SvsmReqError::InvalidRequest => Some(SVSM_ERR_INVALID_REQUEST),
+            SvsmReqError::Busy => Some(SVSM_ERR_BUSY),
+            SvsmReqError::Protocol(code) => Some(SVSM_ERR_PROTOCOL_BASE + code),
+            SvsmReqError::FatalError(..) => None,
         }
-    };
-}
+    }
 
-#[allow(dead_code)]
-impl SvsmError {
-    impl_req_err!(incomplete, INCOMPLETE);
-    impl_req_err!(unsupported_protocol, UNSUPPORTED_PROTOCOL);
-    impl_req_err!(unsupported_call, UNSUPPORTED_CALL);
-    impl_req_err!(invalid_address, INVALID_ADDRESS);
-    impl_req_err!(invalid_format, INVALID_FORMAT);
-    impl_req_err!(invalid_parameter, INVALID_PARAMETER);
-    impl_req_err!(invalid_request, INVALID_REQUEST);
-    impl_req_err!(busy, BUSY);
-    fn protocol(code: u64) -> Self {
-        Self::RequestError(SvsmResultCode::PROTOCOL_BASE(code))
+    pub fn is_fatal(&self) -> bool {
+        matches!(self, SvsmReqError::FatalError(..))
     }
 }
 
 // SEV-SNP errors obtained from PVALIDATE or RMPADJUST are returned
 // to the guest as protocol-specific errors.
-impl From<SevSnpError> for SvsmError {
-    fn from(err: SevSnpError) -> SvsmError {
-        SvsmError::protocol(err.ret())
+impl From<SevSnpError> for SvsmReqError {
+    fn from(err: SevSnpError) -> SvsmReqError {
+        SvsmReqError::Protocol(err.ret())
     }
 }
 
+// Gatekeeper for all guest physical addresses handed to the SVSM in
+// requests. The range must be aligned to align, be guest-owned memory and
+// must not overlap the SVSM itself.
+pub fn validate_guest_phys(gpa: PhysAddr, len: usize, align: usize) -> Result<(), SvsmReqError> {
+    debug_assert!(align.is_power_of_two());
+
+    if len == 0 || !gpa.is_aligned(align) {
+        return Err(SvsmReqError::InvalidParameter);
+    }
+
+    let end = gpa.checked_add(len).ok_or(SvsmReqError::InvalidAddress)?;
+
+    if overlaps_svsm_region(gpa, end) {
+        return Err(SvsmReqError::InvalidAddress);
+    }
+
+    if !gpa
+        .page_align_down()
+        .iter_to(end, PAGE_SIZE)
+        .all(valid_phys_address)
+    {
+        return Err(SvsmReqError::InvalidAddress);
+    }
+
+    Ok(())
+}
+
+// The CAA the guest firmware starts with must be the one announced in the
+// secrets page
+pub fn validate_boot_caa(gpa: PhysAddr, secrets: &SecretsPage) -> Result<(), SvsmReqError> {
+    validate_guest_phys(gpa, CAA_SIZE, CAA_ALIGN)?;
+
+    if u64::from(gpa) != secrets.svsm_caa() {
+        return Err(SvsmReqError::InvalidAddress);
+    }
+
+    Ok(())
+}
+
 const SVSM_REQ_CORE_REMAP_CA: u32 = 0;
 const SVSM_REQ_CORE_PVALIDATE: u32 = 1;
 const SVSM_REQ_CORE_CREATE_VCPU: u32 = 2;
@@ -93,23 +135,28 @@ const SVSM_REQ_CORE_DEPOSIT_MEM: u32 = 4;
 const SVSM_REQ_CORE_WITHDRAW_MEM: u32 = 5;
 const SVSM_REQ_CORE_QUERY_PROTOCOL: u32 = 6;
 const SVSM_REQ_CORE_CONFIGURE_VTOM: u32 = 7;
+// Not part of the SVSM specification
+const SVSM_REQ_CORE_REGISTER_CAA: u32 = 8;
 
-const CORE_PROTOCOL: u32 = 1;
 const CORE_PROTOCOL_VERSION_MIN: u32 = 1;
-const CORE_PROTOCOL_VERSION_MAX: u32 = 1;
-
-struct RequestParams {
-    guest_exit_code: GuestVMExit,
-    sev_features: u64,
-    rcx: u64,
-    rdx: u64,
-    r8: u64,
+// Announced as svsm_max_version in the secrets page of the guest
+pub const CORE_PROTOCOL_VERSION_MAX: u32 = 1;
+
+pub struct RequestParams {
+    pub guest_exit_code: GuestVMExit,
+    // VMPL the request came from
+    pub vmpl: u8,
+    pub sev_features: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub r8: u64,
 }
 
 impl RequestParams {
     fn from_vmsa(vmsa: &VMSA) -> Self {
         RequestParams {
             guest_exit_code: vmsa.guest_exit_code,
+            vmpl: vmsa.vmpl,
             sev_features: vmsa.sev_features,
             rcx: vmsa.rcx,
             rdx: vmsa.rdx,
@@ -132,7 +179,7 @@ struct PValidateRequest {
     resv: u32,
 }
 
-fn core_create_vcpu_error_restore(vaddr: VirtAddr) -> Result<(), SvsmError> {
+fn core_create_vcpu_error_restore(vaddr: VirtAddr) -> Result<(), SvsmReqError> {
     if let Err(err) = rmp_clear_guest_vmsa(vaddr) {
         log::error!(
             "Failed to restore page permissions ({}, code: {})",
@@ -146,42 +193,38 @@ fn core_create_vcpu_error_restore(vaddr: VirtAddr) -> Result<(), SvsmError> {
     Ok(())
 }
 
-// VMSA validity checks according to SVSM spec
+// VMSA validity checks according to SVSM spec. On top of that the same
+// checks as for the SVSM's own APs apply, see start_cpu().
 fn check_vmsa(new: &VMSA, sev_features: u64, svme_mask: u64) -> bool {
     new.vmpl == RMPFlags::VMPL1.bits() as u8
         && new.efer & svme_mask == svme_mask
         && new.sev_features == sev_features
+        && new.validate().is_ok()
+        && check_sev_features(new.sev_features, current_sev_features()).is_ok()
 }
 
 /// per-cpu request mapping area size (1GB)
-fn core_create_vcpu(params: &RequestParams) -> Result<(), SvsmError> {
-    let paddr = params.rcx as PhysAddr;
-    let pcaa = params.rdx as PhysAddr;
+fn core_create_vcpu(params: &RequestParams) -> Result<(), SvsmReqError> {
+    let paddr = PhysAddr::from(params.rcx);
+    let pcaa = PhysAddr::from(params.rdx);
     let apic_id: u32 = (params.r8 & 0xffff_ffff) as u32;
 
-    // Check VMSA address
-    if !valid_phys_address(paddr) || !is_aligned(paddr, PAGE_SIZE) {
-        return Err(SvsmError::invalid_address());
-    }
-
-    // Check CAA address
-    if !valid_phys_address(pcaa) || !is_aligned(pcaa, 8) {
-        return Err(SvsmError::invalid_address());
-    }
+    validate_guest_phys(paddr, PAGE_SIZE, PAGE_SIZE)?;
+    validate_guest_phys(pcaa, CAA_SIZE, CAA_ALIGN)?;
 
     let target_cpu = PERCPU_AREAS
         .get(apic_id)
-        .ok_or_else(SvsmError::invalid_parameter)?;
+        .ok_or(SvsmReqError::InvalidParameter)?;
 
     // Got valid gPAs and APIC ID, register VMSA immediately to avoid races
     PERCPU_VMSAS
         .register(paddr, apic_id, true)
-        .map_err(|_| SvsmError::invalid_address())?;
+        .map_err(|_| SvsmReqError::InvalidAddress)?;
 
     // Time to map the VMSA. No need to clean up the registered VMSA on the
     // error path since this is a fatal error anyway.
     let mapping_guard =
-        PerCPUPageMappingGuard::create(paddr, 1, false).map_err(SvsmError::FatalError)?;
+        PerCPUPageMappingGuard::create(paddr, 1, false).map_err(SvsmReqError::FatalError)?;
     let vaddr = mapping_guard.virt_addr();
 
     // Make sure the guest can't make modifications to the VMSA page
@@ -204,25 +247,30 @@ fn core_create_vcpu(params: &RequestParams) -> Result<(), SvsmError> {
     if !check_vmsa(new_vmsa, params.sev_features, svme_mask) {
         PERCPU_VMSAS.unregister(paddr, false).unwrap();
         core_create_vcpu_error_restore(vaddr)?;
-        return Err(SvsmError::invalid_parameter());
+        return Err(SvsmReqError::InvalidParameter);
     }
 
     assert!(PERCPU_VMSAS.set_used(paddr) == Some(apic_id));
     target_cpu.update_guest_vmsa_caa(paddr, pcaa);
 
+    // The target CPU might wait for a VMSA in its request loop
+    if ring_doorbell(apic_id).is_err() {
+        log::warn!("Failed to wake up CPU with APIC-ID {}", apic_id);
+    }
+
     Ok(())
 }
 
-fn core_delete_vcpu(params: &RequestParams) -> Result<(), SvsmError> {
-    let paddr = params.rcx as PhysAddr;
+fn core_delete_vcpu(params: &RequestParams) -> Result<(), SvsmReqError> {
+    let paddr = PhysAddr::from(params.rcx);
 
     PERCPU_VMSAS
         .unregister(paddr, true)
-        .map_err(|_| SvsmError::invalid_parameter())?;
+        .map_err(|_| SvsmReqError::InvalidParameter)?;
 
     // Map the VMSA
     let mapping_guard =
-        PerCPUPageMappingGuard::create(paddr, 0, false).map_err(SvsmError::FatalError)?;
+        PerCPUPageMappingGuard::create(paddr, 0, false).map_err(SvsmReqError::FatalError)?;
     let vaddr = mapping_guard.virt_addr();
 
     // Clear EFER.SVME on deleted VMSA. If the VMSA is executing
@@ -231,7 +279,7 @@ fn core_delete_vcpu(params: &RequestParams) -> Result<(), SvsmError> {
     del_vmsa.disable();
 
     // Do not return early here, as we need to do a TLB flush
-    let res = rmp_clear_guest_vmsa(vaddr).map_err(|_| SvsmError::invalid_address());
+    let res = rmp_clear_guest_vmsa(vaddr).map_err(|_| SvsmReqError::InvalidAddress);
 
     // Unmap the page
     drop(mapping_guard);
@@ -242,14 +290,14 @@ fn core_delete_vcpu(params: &RequestParams) -> Result<(), SvsmError> {
     res
 }
 
-fn core_deposit_mem(_params: &RequestParams) -> Result<(), SvsmError> {
+fn core_deposit_mem(_params: &RequestParams) -> Result<(), SvsmReqError> {
     log::info!("Request SVSM_REQ_CORE_DEPOSIT_MEM not yet supported");
-    Err(SvsmError::unsupported_call())
+    Err(SvsmReqError::UnsupportedCall)
 }
 
-fn core_withdraw_mem(_params: &RequestParams) -> Result<(), SvsmError> {
+fn core_withdraw_mem(_params: &RequestParams) -> Result<(), SvsmReqError> {
     log::info!("Request SVSM_REQ_CORE_WITHDRAW_MEM not yet supported");
-    Err(SvsmError::unsupported_call())
+    Err(SvsmReqError::UnsupportedCall)
 }
 
 fn protocol_supported(version: u32, version_min: u32, version_max: u32) -> u64 {
@@ -263,26 +311,7 @@ fn protocol_supported(version: u32, version_min: u32, version_max: u32) -> u64 {
     }
 }
 
-fn core_query_protocol(params: &mut RequestParams) -> Result<(), SvsmError> {
-    let rcx: u64 = params.rcx;
-    let protocol: u32 = (rcx >> 32).try_into().unwrap();
-    let version: u32 = (rcx & 0xffff_ffffu64).try_into().unwrap();
-
-    let ret_val = match protocol {
-        CORE_PROTOCOL => protocol_supported(
-            version,
-            CORE_PROTOCOL_VERSION_MIN,
-            CORE_PROTOCOL_VERSION_MAX,
-        ),
-        _ => 0,
-    };
-
-    params.rcx = ret_val;
-
-    Ok(())
-}
-
-fn core_configure_vtom(params: &mut RequestParams) -> Result<(), SvsmError> {
+fn core_configure_vtom(params: &mut RequestParams) -> Result<(), SvsmReqError> {
     let query: bool = (params.rcx & 1) == 1;
 
     // Report that vTOM configuration is unsupported
@@ -290,15 +319,15 @@ fn core_configure_vtom(params: &mut RequestParams) -> Result<(), SvsmError> {
         params.rcx = 0;
         Ok(())
     } else {
-        Err(SvsmError::invalid_request())
+        Err(SvsmReqError::InvalidRequest)
     }
 }
 
-fn core_pvalidate_one(entry: u64, flush: &mut bool) -> Result<(), SvsmError> {
+fn core_pvalidate_one(entry: u64, flush: &mut bool) -> Result<(), SvsmReqError> {
     let page_size: u64 = entry & 3;
 
     if page_size > 1 {
-        return Err(SvsmError::invalid_parameter());
+        return Err(SvsmReqError::InvalidParameter);
     }
 
     let huge = page_size == 1;
@@ -312,54 +341,123 @@ fn core_pvalidate_one(entry: u64, flush: &mut bool) -> Result<(), SvsmError> {
             PAGE_SIZE
         }
     };
-    let paddr: PhysAddr = (entry as usize) & !(PAGE_SIZE - 1);
+    let paddr = PhysAddr::from(entry).page_align_down();
 
-    if !is_aligned(paddr, alignment) {
-        return Err(SvsmError::invalid_parameter());
+    if !paddr.is_aligned(alignment) {
+        return Err(SvsmReqError::InvalidParameter);
     }
 
-    if !valid_phys_address(paddr) {
+    validate_guest_phys(paddr, alignment, alignment).inspect_err(|_| {
         log::debug!("Invalid phys address: {:#x}", paddr);
-        return Err(SvsmError::invalid_address());
-    }
+    })?;
 
-    let guard = PerCPUPageMappingGuard::create(paddr, 1, huge).map_err(SvsmError::FatalError)?;
+    let guard = PerCPUPageMappingGuard::create(paddr, 1, huge).map_err(SvsmReqError::FatalError)?;
     let vaddr = guard.virt_addr();
 
+    let changed = match pvalidate_guest_page(vaddr, huge, valid, flush) {
+        // The RMP covers the range with 4k entries, nothing was changed yet
+        Err(SevSnpError::FAIL_SIZEMISMATCH(_)) if huge => {
+            pvalidate_guest_range_4k(vaddr, valid, flush)?
+        }
+        ret => ret?,
+    };
+
+    if !changed && !ign_cf {
+        return Err(SevSnpError::FAIL_UNCHANGED(0x10).into());
+    }
+
+    Ok(())
+}
+
+const PAGES_PER_2M: usize = PAGE_SIZE_2M / PAGE_SIZE;
+
+// Splits a 2M entry into 4k PVALIDATEs. Like a 2M PVALIDATE it reports the
+// range as changed when any page changed, so ign_cf applies to the whole
+// entry. A failing page undoes the changes to the pages before it, the
+// entry either takes effect completely or not at all.
+fn pvalidate_guest_range_4k(
+    vaddr: VirtAddr,
+    valid: bool,
+    flush: &mut bool,
+) -> Result<bool, SvsmReqError> {
+    let mut changed = [false; PAGES_PER_2M];
+
+    for (i, addr) in vaddr.iter_to(vaddr + PAGE_SIZE_2M, PAGE_SIZE).enumerate() {
+        match pvalidate_guest_page(addr, false, valid, flush) {
+            Ok(c) => changed[i] = c,
+            Err(e) => {
+                rollback_guest_range_4k(vaddr, &changed[..i], valid, flush)?;
+                return Err(e.into());
+            }
+        }
+    }
+
+    Ok(changed.iter().any(|&c| c))
+}
+
+// Pages which can't be put back leave the guest memory in a state it did
+// not ask for, which is fatal
+fn rollback_guest_range_4k(
+    vaddr: VirtAddr,
+    changed: &[bool],
+    valid: bool,
+    flush: &mut bool,
+) -> Result<(), SvsmReqError> {
+    for (i, _) in changed.iter().enumerate().filter(|(_, &c)| c) {
+        let addr = vaddr + i * PAGE_SIZE;
+        if pvalidate_guest_page(addr, false, !valid, flush).is_err() {
+            log::error!("Failed to roll back PVALIDATE of {:#018x}", addr);
+            return Err(SvsmReqError::FatalError(()));
+        }
+    }
+
+    Ok(())
+}
+
+// Guest access is revoked before a page is invalidated and only granted once
+// it is validated. Returns whether PVALIDATE changed the RMP entry.
+fn pvalidate_guest_page(
+    vaddr: VirtAddr,
+    huge: bool,
+    valid: bool,
+    flush: &mut bool,
+) -> Result<bool, SevSnpError> {
     if !valid {
-        *flush |= true;
         rmp_revoke_guest_access(vaddr, huge)?;
+        *flush |= true;
     }
 
-    pvalidate(vaddr, huge, valid).or_else(|err| match err {
-        SevSnpError::FAIL_UNCHANGED(_) if ign_cf => Ok(()),
-        _ => Err(err),
-    })?;
+    let size = if huge {
+        PageSize::Page2M
+    } else {
+        PageSize::Page4K
+    };
+    let changed = pvalidate(vaddr, size, valid)?;
 
     if valid {
         rmp_grant_guest_access(vaddr, huge)?;
     }
 
-    Ok(())
+    Ok(changed)
 }
 
-fn core_pvalidate(params: &RequestParams) -> Result<(), SvsmError> {
-    let gpa: PhysAddr = params.rcx.try_into().unwrap();
+fn core_pvalidate(params: &RequestParams) -> Result<(), SvsmReqError> {
+    let gpa = PhysAddr::from(params.rcx);
 
-    if !is_aligned(gpa, 8) || !valid_phys_address(gpa) {
-        return Err(SvsmError::invalid_parameter());
-    }
+    // The header and the entries following it are 8 bytes each
+    validate_guest_phys(gpa, mem::size_of::<PValidateRequest>(), 8)?;
 
-    let paddr = page_align(gpa);
-    let offset = page_offset(gpa);
+    let paddr = gpa.page_align_down();
+    let offset = gpa.page_offset();
 
-    let guard = PerCPUPageMappingGuard::create(paddr, 0, false).map_err(SvsmError::FatalError)?;
+    let guard =
+        PerCPUPageMappingGuard::create(paddr, 0, false).map_err(SvsmReqError::FatalError)?;
     let start = guard.virt_addr();
 
     let guest_page = GuestPtr::<PValidateRequest>::new(start + offset);
     let mut request = guest_page
         .read()
-        .map_err(|_| SvsmError::invalid_address())?;
+        .map_err(|_| SvsmReqError::InvalidAddress)?;
 
     let entries = request.entries;
     let next = request.next;
@@ -368,7 +466,7 @@ fn core_pvalidate(params: &RequestParams) -> Result<(), SvsmError> {
     let max_entries: u16 = ((PAGE_SIZE - offset - 8) / 8).try_into().unwrap();
 
     if entries == 0 || entries > max_entries || entries <= next {
-        return Err(SvsmError::invalid_parameter());
+        return Err(SvsmReqError::InvalidParameter);
     }
 
     let mut loop_result = Ok(());
@@ -380,7 +478,7 @@ fn core_pvalidate(params: &RequestParams) -> Result<(), SvsmError> {
         let entry = match guest_entries.offset(index).read() {
             Ok(v) => v,
             Err(_) => {
-                loop_result = Err(SvsmError::invalid_address());
+                loop_result = Err(SvsmReqError::InvalidAddress);
                 break;
             }
         };
@@ -388,13 +486,13 @@ fn core_pvalidate(params: &RequestParams) -> Result<(), SvsmError> {
         loop_result = core_pvalidate_one(entry, &mut flush);
         match loop_result {
             Ok(()) => request.next += 1,
-            Err(SvsmError::RequestError(..)) => break,
-            Err(SvsmError::FatalError(..)) => return loop_result,
+            Err(e) if e.is_fatal() => return loop_result,
+            Err(_) => break,
         }
     }
 
     if guest_page.write_ref(&request).is_err() {
-        loop_result = Err(SvsmError::invalid_address());
+        loop_result = Err(SvsmReqError::InvalidAddress);
     }
 
     if flush {
@@ -404,44 +502,174 @@ fn core_pvalidate(params: &RequestParams) -> Result<(), SvsmError> {
     loop_result
 }
 
-fn core_remap_ca(params: &RequestParams) -> Result<(), SvsmError> {
-    let gpa: PhysAddr = params.rcx.try_into().unwrap();
-
-    if !is_aligned(gpa, 8) || !valid_phys_address(gpa) || crosses_page(gpa, 8) {
-        return Err(SvsmError::invalid_parameter());
+// Validates a new CAA and clears it. validate_guest_phys() makes sure it does
+// not overlap SVSM memory.
+fn prepare_caa(gpa: PhysAddr) -> Result<(), SvsmReqError> {
+    if crosses_page(gpa.as_usize(), CAA_SIZE) {
+        return Err(SvsmReqError::InvalidParameter);
     }
+    validate_guest_phys(gpa, CAA_SIZE, CAA_ALIGN)?;
 
-    let offset = page_offset(gpa);
-    let paddr = page_align(gpa);
+    let offset = gpa.page_offset();
+    let paddr = gpa.page_align_down();
 
     // Temporarily map new CAA to clear it
     let mapping_guard =
-        PerCPUPageMappingGuard::create(paddr, 1, false).map_err(SvsmError::FatalError)?;
+        PerCPUPageMappingGuard::create(paddr, 1, false).map_err(SvsmReqError::FatalError)?;
 
     let vaddr = mapping_guard.virt_addr() + offset;
 
     let pending = GuestPtr::<u64>::new(vaddr);
-    pending.write(0).map_err(|_| SvsmError::invalid_address())?;
+    pending.write(0).map_err(|_| SvsmReqError::InvalidAddress)
+}
+
+// The new CAA is cleared before it is published, so that no stale pending
+// call is seen in it. The switch itself only bumps the mapping generation
+// under the guest VMSA lock. The old CAA stays mapped until update_mappings()
+// runs before the guest is resumed, which replaces the mapping and flushes
+// the old translation. The pending call in the old CAA was already consumed
+// by the request loop for this request.
+fn core_remap_ca(params: &RequestParams) -> Result<(), SvsmReqError> {
+    let gpa = PhysAddr::from(params.rcx);
 
+    prepare_caa(gpa)?;
     this_cpu_mut().update_guest_caa(gpa);
 
     Ok(())
 }
 
-fn core_protocol_request(request: u32, params: &mut RequestParams) -> Result<(), SvsmError> {
-    match request {
-        SVSM_REQ_CORE_REMAP_CA => core_remap_ca(params),
-        SVSM_REQ_CORE_PVALIDATE => core_pvalidate(params),
-        SVSM_REQ_CORE_CREATE_VCPU => core_create_vcpu(params),
-        SVSM_REQ_CORE_DELETE_VCPU => core_delete_vcpu(params),
-        SVSM_REQ_CORE_DEPOSIT_MEM => core_deposit_mem(params),
-        SVSM_REQ_CORE_WITHDRAW_MEM => core_withdraw_mem(params),
-        SVSM_REQ_CORE_QUERY_PROTOCOL => core_query_protocol(params),
-        SVSM_REQ_CORE_CONFIGURE_VTOM => core_configure_vtom(params),
-        _ => Err(SvsmError::unsupported_call()),
+// Only VMPLs less privileged than the caller may get a CAA registered
+fn check_caa_vmpl(vmpl: u64, caller_vmpl: u8) -> Result<usize, SvsmReqError> {
+    if vmpl <= caller_vmpl as u64 || vmpl >= VMPL_MAX as u64 {
+        return Err(SvsmReqError::InvalidParameter);
+    }
+
+    Ok(vmpl as usize)
+}
+
+// Registers the CAA of a lower privileged VMPL on a CPU. RCX holds the gPA
+// of the CAA, RDX the VMPL and R8 the APIC ID of the CPU.
+fn core_register_caa(params: &RequestParams) -> Result<(), SvsmReqError> {
+    let gpa = PhysAddr::from(params.rcx);
+    let vmpl = check_caa_vmpl(params.rdx, params.vmpl)?;
+    let apic_id: u32 = (params.r8 & 0xffff_ffff) as u32;
+
+    let target_cpu = PERCPU_AREAS
+        .get(apic_id)
+        .ok_or(SvsmReqError::InvalidParameter)?;
+
+    prepare_caa(gpa)?;
+    target_cpu.update_guest_vmpl_caa(vmpl, gpa);
+
+    Ok(())
+}
+
+// Handles all calls of one SVSM protocol. The call-id is passed as request,
+// arguments and results go through params.
+pub trait RequestHandler: Send + Sync {
+    fn handle(&self, request: u32, params: &mut RequestParams) -> Result<(), SvsmReqError>;
+
+    // Minimum and maximum supported protocol version
+    fn versions(&self) -> (u32, u32);
+}
+
+struct CoreProtocol;
+
+// SVSM_REQ_CORE_QUERY_PROTOCOL is answered by ProtocolTable::dispatch(),
+// which knows all registered protocols
+impl RequestHandler for CoreProtocol {
+    fn handle(&self, request: u32, params: &mut RequestParams) -> Result<(), SvsmReqError> {
+        match request {
+            SVSM_REQ_CORE_REMAP_CA => core_remap_ca(params),
+            SVSM_REQ_CORE_PVALIDATE => core_pvalidate(params),
+            SVSM_REQ_CORE_CREATE_VCPU => core_create_vcpu(params),
+            SVSM_REQ_CORE_DELETE_VCPU => core_delete_vcpu(params),
+            SVSM_REQ_CORE_DEPOSIT_MEM => core_deposit_mem(params),
+            SVSM_REQ_CORE_WITHDRAW_MEM => core_withdraw_mem(params),
+            SVSM_REQ_CORE_CONFIGURE_VTOM => core_configure_vtom(params),
+            SVSM_REQ_CORE_REGISTER_CAA => core_register_caa(params),
+            _ => Err(SvsmReqError::UnsupportedCall),
+        }
+    }
+
+    fn versions(&self) -> (u32, u32) {
+        (CORE_PROTOCOL_VERSION_MIN, CORE_PROTOCOL_VERSION_MAX)
     }
 }
 
+// Protocol number of the core protocol in the guest request (RAX[63:32])
+const SVSM_CORE_PROTOCOL_ID: u32 = 0;
+
+struct ProtocolTable {
+    handlers: Vec<(u32, Box<dyn RequestHandler>)>,
+}
+
+impl ProtocolTable {
+    const fn new() -> Self {
+        ProtocolTable {
+            handlers: Vec::new(),
+        }
+    }
+
+    fn register(&mut self, protocol: u32, handler: Box<dyn RequestHandler>) -> Result<(), ()> {
+        if self.handlers.iter().any(|(p, _)| *p == protocol) {
+            return Err(());
+        }
+
+        self.handlers.try_reserve(1).map_err(|_| ())?;
+        self.handlers.push((protocol, handler));
+
+        Ok(())
+    }
+
+    // RCX holds the protocol in bits 63:32 and the version in bits 31:0.
+    // Returns the supported version range in RCX, 0 when the protocol or the
+    // version is not supported.
+    fn query_protocol(&self, params: &mut RequestParams) {
+        let protocol = (params.rcx >> 32) as u32;
+        let version = (params.rcx & 0xffff_ffff) as u32;
+
+        params.rcx = match self.handlers.iter().find(|(p, _)| *p == protocol) {
+            Some((_, handler)) => {
+                let (min, max) = handler.versions();
+                protocol_supported(version, min, max)
+            }
+            None => 0,
+        };
+    }
+
+    fn dispatch(
+        &self,
+        protocol: u32,
+        request: u32,
+        params: &mut RequestParams,
+    ) -> Result<(), SvsmReqError> {
+        if protocol == SVSM_CORE_PROTOCOL_ID && request == SVSM_REQ_CORE_QUERY_PROTOCOL {
+            self.query_protocol(params);
+            return Ok(());
+        }
+
+        match self.handlers.iter().find(|(p, _)| *p == protocol) {
+            Some((_, handler)) => handler.handle(request, params),
+            None => Err(SvsmReqError::UnsupportedProtocol),
+        }
+    }
+}
+
+static PROTOCOLS: RWLock<ProtocolTable> = RWLock::new(ProtocolTable::new());
+
+// Fails if a handler for the protocol is already registered
+pub fn register_protocol(protocol: u32, handler: Box<dyn RequestHandler>) -> Result<(), ()> {
+    PROTOCOLS.lock_write().register(protocol, handler)
+}
+
+// Install the handlers for the protocols implemented by the SVSM itself. Must
+// be called before the first guest request is processed.
+pub fn register_default_protocols() -> Result<(), ()> {
+    register_protocol(SVSM_CORE_PROTOCOL_ID, Box::new(CoreProtocol))?;
+    register_protocol(SVSM_LOG_PROTOCOL_ID, Box::new(LogProtocol))
+}
+
 /// Returns true if there is a valid VMSA mapping
 pub fn update_mappings() -> Result<(), ()> {
     let mut locked = this_cpu_mut().guest_vmsa_ref();
@@ -459,9 +687,15 @@ pub fn update_mappings() -> Result<(), ()> {
         None => ret = Err(()),
     }
 
-    match locked.caa_phys() {
-        Some(paddr) => this_cpu_mut().map_guest_caa(paddr)?,
-        None => ret = Err(()),
+    for vmpl in 0..VMPL_MAX {
+        if let Some(paddr) = locked.vmpl_caa_phys(vmpl) {
+            this_cpu_mut().map_guest_caa(vmpl, paddr)?;
+        }
+    }
+
+    // The default CAA is needed for VMPL1, which the request loop starts with
+    if locked.caa_phys().is_none() {
+        ret = Err(());
     }
 
     locked.set_updated();
@@ -469,47 +703,69 @@ pub fn update_mappings() -> Result<(), ()> {
     ret
 }
 
+// The request is taken from the CAA of the VMPL which trapped
 fn request_loop_once(
     params: &mut RequestParams,
+    vmpl: usize,
     protocol: u32,
     request: u32,
-) -> Result<bool, SvsmError> {
+) -> Result<bool, SvsmReqError> {
     if !matches!(params.guest_exit_code, GuestVMExit::VMGEXIT) {
         return Ok(false);
     }
 
-    let caa_addr = this_cpu().caa_addr().ok_or_else(|| {
-        log::error!("No CAA mapped - bailing out");
-        SvsmError::FatalError(())
-    })?;
-
-    let guest_pending = GuestPtr::<u64>::new(caa_addr);
-    let pending = guest_pending
-        .read()
-        .map_err(|_| SvsmError::invalid_address())?;
-    guest_pending
-        .write(0)
-        .map_err(|_| SvsmError::invalid_address())?;
+    if this_cpu().caa_addr(vmpl).is_none() {
+        log::error!("No CAA mapped for VMPL{} - bailing out", vmpl);
+        return Err(SvsmReqError::FatalError(()));
+    }
 
-    if pending != 1 {
+    if !this_cpu().vmpl_caa(vmpl).take_call_pending() {
         return Ok(false);
     }
 
-    match protocol {
-        0 => core_protocol_request(request, params).map(|_| true),
-        _ => Err(SvsmError::unsupported_protocol()),
+    PROTOCOLS
+        .lock_read()
+        .dispatch(protocol, request, params)
+        .map(|_| true)
+}
+
+// The RAX value the guest sees after a request, None if the request failed
+// fatally. RAX stays unchanged when no request was pending.
+fn request_result(ret: Result<bool, SvsmReqError>, rax: u64) -> Option<u64> {
+    match ret {
+        Ok(true) => Some(SVSM_SUCCESS),
+        Ok(false) => Some(rax),
+        Err(e) => e.result_code(),
     }
 }
 
+// Returns when the CPU was asked to go offline, fatal errors shut down the
+// SVSM. Requests are handled for the VMPL run last, which is the one that
+// trapped, and that VMPL is resumed afterwards.
 pub fn request_loop() {
+    let mut vmpl = DEFAULT_CAA_VMPL;
+
     loop {
+        if this_cpu().offline_requested() {
+            break;
+        }
+
+        this_cpu().counters().count_request_loop();
+
         if update_mappings().is_err() {
             log::debug!("No VMSA or CAA! Halting");
-            halt();
+            wait_for_doorbell();
             continue;
         }
 
-        let vmsa = this_cpu_mut().guest_vmsa();
+        // Fall back to VMPL1 when the VMSA of the trapping VMPL is gone
+        let vmsa = match this_cpu_mut().vmpl_guest_vmsa(vmpl) {
+            Some(vmsa) => vmsa,
+            None => {
+                vmpl = DEFAULT_CAA_VMPL;
+                continue;
+            }
+        };
 
         // Clear EFER.SVME in guest VMSA
         vmsa.disable();
@@ -519,23 +775,25 @@ pub fn request_loop() {
         let request = (rax & 0xffff_ffff) as u32;
         let mut params = RequestParams::from_vmsa(vmsa);
 
-        vmsa.rax = match request_loop_once(&mut params, protocol, request) {
-            Ok(success) => match success {
-                true => SvsmResultCode::SUCCESS.into(),
-                false => vmsa.rax,
-            },
-            Err(SvsmError::RequestError(code)) => {
-                log::debug!(
-                    "Soft error handling protocol {} request {}: {:?}",
+        let ret = request_loop_once(&mut params, vmpl, protocol, request);
+        if let Err(e) = ret {
+            log::debug!(
+                "Error handling protocol {} request {}: {:?}",
+                protocol,
+                request,
+                e
+            );
+        }
+
+        vmsa.rax = match request_result(ret, vmsa.rax) {
+            Some(rax) => rax,
+            None => {
+                log::error!(
+                    "Fatal error handling protocol {} request {}",
                     protocol,
-                    request,
-                    code
+                    request
                 );
-                code.into()
-            }
-            Err(SvsmError::FatalError(..)) => {
-                log::error!("Fatal error handling core protocol request {}", request);
-                break;
+                shutdown_all_cpus();
             }
         };
 
@@ -551,8 +809,129 @@ pub fn request_loop() {
         if update_mappings().is_ok() {
             this_cpu_mut()
                 .ghcb()
-                .run_vmpl(1)
-                .expect("Failed to run VMPL 1");
+                .run_vmpl(vmpl as u64)
+                .expect("Failed to run guest VMPL");
         }
     }
 }
+
+#[cfg(test)]
+struct EchoProtocol;
+
+#[cfg(test)]
+impl RequestHandler for EchoProtocol {
+    fn handle(&self, request: u32, params: &mut RequestParams) -> Result<(), SvsmReqError> {
+        params.rcx = request as u64;
+        Ok(())
+    }
+
+    fn versions(&self) -> (u32, u32) {
+        (1, 2)
+    }
+}
+
+#[cfg(test)]
+fn test_params(rcx: u64) -> RequestParams {
+    RequestParams {
+        guest_exit_code: GuestVMExit::VMGEXIT,
+        vmpl: 1,
+        sev_features: 0,
+        rcx,
+        rdx: 0,
+        r8: 0,
+    }
+}
+
+#[test]
+fn test_protocol_table_dispatch() {
+    let mut table = ProtocolTable::new();
+    table.register(7, Box::new(EchoProtocol)).unwrap();
+    assert!(table.register(7, Box::new(EchoProtocol)).is_err());
+
+    let mut params = test_params(0);
+    assert!(table.dispatch(7, 42, &mut params).is_ok());
+    assert_eq!(params.rcx, 42);
+
+    assert!(matches!(
+        table.dispatch(8, 0, &mut params),
+        Err(SvsmReqError::UnsupportedProtocol)
+    ));
+}
+
+#[test]
+fn test_query_protocol() {
+    let mut table = ProtocolTable::new();
+    table.register(7, Box::new(EchoProtocol)).unwrap();
+
+    let mut query = |protocol: u64, version: u64| {
+        let mut params = test_params(protocol << 32 | version);
+        table
+            .dispatch(
+                SVSM_CORE_PROTOCOL_ID,
+                SVSM_REQ_CORE_QUERY_PROTOCOL,
+                &mut params,
+            )
+            .unwrap();
+        params.rcx
+    };
+
+    assert_eq!(query(7, 2), 2 << 32 | 1);
+    assert_eq!(query(7, 3), 0);
+    assert_eq!(query(7, 0), 0);
+    assert_eq!(query(8, 1), 0);
+}
+
+#[test]
+fn test_core_configure_vtom() {
+    let core = CoreProtocol;
+
+    // Query reports vTOM configuration as unsupported
+    let mut params = test_params(1);
+    assert!(core
+        .handle(SVSM_REQ_CORE_CONFIGURE_VTOM, &mut params)
+        .is_ok());
+    assert_eq!(params.rcx, 0);
+
+    let mut params = test_params(0);
+    assert!(matches!(
+        core.handle(SVSM_REQ_CORE_CONFIGURE_VTOM, &mut params),
+        Err(SvsmReqError::InvalidRequest)
+    ));
+}
+
+#[test]
+fn test_request_result_codes() {
+    assert_eq!(request_result(Ok(true), 0x1234), Some(0));
+    assert_eq!(request_result(Ok(false), 0x1234), Some(0x1234));
+    assert_eq!(
+        request_result(Err(SvsmReqError::InvalidParameter), 0),
+        Some(0x8000_0005)
+    );
+    assert_eq!(
+        request_result(Err(SvsmReqError::Protocol(6)), 0),
+        Some(0x8000_1006)
+    );
+    assert_eq!(request_result(Err(SvsmReqError::FatalError(())), 0), None);
+}
+
+#[test]
+fn test_validate_guest_phys_alignment() {
+    // Both are rejected before the memory map is consulted
+    assert_eq!(
+        validate_guest_phys(PhysAddr::from(0x1004usize), 24, 8),
+        Err(SvsmReqError::InvalidParameter)
+    );
+    assert_eq!(
+        validate_guest_phys(PhysAddr::from(0x1000usize), 0, 1),
+        Err(SvsmReqError::InvalidParameter)
+    );
+}
+
+#[test]
+fn test_check_caa_vmpl() {
+    assert_eq!(check_caa_vmpl(2, 1), Ok(2));
+    assert_eq!(check_caa_vmpl(3, 2), Ok(3));
+    assert_eq!(check_caa_vmpl(1, 1), Err(SvsmReqError::InvalidParameter));
+    assert_eq!(check_caa_vmpl(2, 3), Err(SvsmReqError::InvalidParameter));
+    assert_eq!(check_caa_vmpl(4, 1), Err(SvsmReqError::InvalidParameter));
+}
@@ -0,0 +1,148 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2026 SUSE LLC
+//
+// Author: agent <agent@local>
+
+// Smoke tests for real SEV-SNP hardware, where the unit tests can't run.
+// They run on the BSP before the APs are started. Once all tests ran the
+// guest is terminated with SVSM_TERM_SELFTEST_PASS or SVSM_TERM_SELFTEST_FAIL,
+// so that the result can be picked up from the hypervisor.
+
+use crate::cpu::control_regs::{read_cr0, read_cr3, read_cr4, write_cr0, write_cr3, write_cr4};
+use crate::cpu::msr::{read_msr, SEV_GHCB};
+use crate::cpu::percpu::{this_cpu, this_cpu_mut};
+use crate::mm::alloc::{allocate_zeroed_page, free_page};
+use crate::mm::virt_to_phys;
+use crate::sev::msr_protocol::{
+    ghcb_terminate, GHCB_TERM_SET_SVSM, SVSM_TERM_SELFTEST_FAIL, SVSM_TERM_SELFTEST_PASS,
+};
+use crate::sev::pvalidate;
+use crate::sev::secrets_page::SecretsPage;
+use crate::types::{PageSize, VirtAddr};
+
+struct SelfTest {
+    name: &'static str,
+    run: fn(&SecretsPage) -> Result<(), ()>,
+}
+
+const SELFTESTS: [SelfTest; 5] = [
+    SelfTest {
+        name: "control registers",
+        run: test_control_regs,
+    },
+    SelfTest {
+        name: "page map/unmap",
+        run: test_map_unmap,
+    },
+    SelfTest {
+        name: "pvalidate",
+        run: test_pvalidate,
+    },
+    SelfTest {
+        name: "GHCB MSR",
+        run: test_ghcb_msr,
+    },
+    SelfTest {
+        name: "secrets page",
+        run: test_secrets_page,
+    },
+];
+
+fn check(cond: bool) -> Result<(), ()> {
+    if cond {
+        Ok(())
+    } else {
+        Err(())
+    }
+}
+
+// Writing back the current values must not change anything
+fn test_control_regs(_secrets: &SecretsPage) -> Result<(), ()> {
+    let cr0 = read_cr0();
+    write_cr0(cr0);
+    check(read_cr0() == cr0)?;
+
+    let cr3 = read_cr3();
+    write_cr3(cr3);
+    check(read_cr3() == cr3)?;
+
+    let cr4 = read_cr4();
+    write_cr4(cr4);
+    check(read_cr4() == cr4)
+}
+
+// Data written through a temporary mapping must show up in the heap mapping
+// of the same page
+fn test_map_unmap(_secrets: &SecretsPage) -> Result<(), ()> {
+    let vaddr = allocate_zeroed_page()?;
+    let paddr = virt_to_phys(vaddr);
+
+    let ret = this_cpu().with_temp_map(paddr, |temp| unsafe {
+        temp.as_mut_ptr::<u64>()
+            .write_volatile(0x5a5a_a5a5_5a5a_a5a5);
+        temp != vaddr
+    });
+    let val = unsafe { vaddr.as_ptr::<u64>().read_volatile() };
+
+    free_page(vaddr);
+
+    check(ret == Ok(true) && val == 0x5a5a_a5a5_5a5a_a5a5)
+}
+
+// Rescinds the validation of a scratch page and validates it again. The page
+// is not accessed in between.
+fn test_pvalidate(_secrets: &SecretsPage) -> Result<(), ()> {
+    let vaddr = allocate_zeroed_page()?;
+
+    let rescinded = pvalidate(vaddr, PageSize::Page4K, false);
+    let validated = pvalidate(vaddr, PageSize::Page4K, true);
+
+    // The page is only known to be validated again when the second PVALIDATE
+    // changed its state. Otherwise it is leaked, so that it is never handed
+    // out while unvalidated.
+    if validated == Ok(true) {
+        free_page(vaddr);
+    } else {
+        log::error!(
+            "Leaking page {:#018x} in an unknown validation state",
+            vaddr
+        );
+    }
+
+    check(rescinded == Ok(true) && validated == Ok(true))
+}
+
+// The GHCB MSR holds the GPA of the registered GHCB
+fn test_ghcb_msr(_secrets: &SecretsPage) -> Result<(), ()> {
+    let ghcb = VirtAddr::from_ptr(this_cpu_mut().ghcb());
+    let paddr = virt_to_phys(ghcb);
+
+    check(read_msr(SEV_GHCB) == paddr.as_usize() as u64)
+}
+
+fn test_secrets_page(secrets: &SecretsPage) -> Result<(), ()> {
+    secrets.validate().map_err(|_| ())
+}
+
+pub fn run_selftests(secrets: &SecretsPage) -> ! {
+    let mut failed = 0;
+
+    for test in SELFTESTS.iter() {
+        match (test.run)(secrets) {
+            Ok(()) => log::info!("selftest {}: PASS", test.name),
+            Err(()) => {
+                log::error!("selftest {}: FAIL", test.name);
+                failed += 1;
+            }
+        }
+    }
+
+    if failed > 0 {
+        log::error!("{} of {} selftests failed", failed, SELFTESTS.len());
+        ghcb_terminate(GHCB_TERM_SET_SVSM, SVSM_TERM_SELFTEST_FAIL);
+    }
+
+    log::info!("All {} selftests passed", SELFTESTS.len());
+    ghcb_terminate(GHCB_TERM_SET_SVSM, SVSM_TERM_SELFTEST_PASS);
+}
@@ -0,0 +1,536 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2026 SUSE LLC
+//
+// Author: agent <agent@local>
+
+extern crate alloc;
+
+use crate::cpu::percpu::this_cpu_mut;
+use crate::cpu::tsc::Instant;
+use crate::crypto::gcm::GCM_TAG_SIZE;
+use crate::locking::SpinLock;
+use crate::mm::alloc::{alloc_secret_page, free_page};
+use crate::sev::ghcb::{make_page_private, make_page_shared, GhcbError};
+use crate::sev::guest_msg::{
+    GuestMsg, MsgSeqno, SeqnoExhausted, GUEST_MSG_AUTHTAG_SIZE, GUEST_MSG_PAYLOAD_SIZE,
+};
+use crate::sev::secrets_page::{zero_volatile, VmpckKey};
+use crate::types::VirtAddr;
+use alloc::vec::Vec;
+use bitflags::bitflags;
+use core::sync::atomic::{compiler_fence, Ordering};
+use core::time::Duration;
+
+// AEAD algorithm of guest messages, AES-256-GCM
+const SNP_AEAD_AES_256_GCM: u8 = 1;
+
+const SNP_MSG_KEY_REQ: u8 = 3;
+const SNP_MSG_KEY_RSP: u8 = 4;
+const SNP_MSG_REPORT_REQ: u8 = 5;
+const SNP_MSG_REPORT_RSP: u8 = 6;
+
+const REPORT_REQ_SIZE: usize = 96;
+const REPORT_RSP_HDR_SIZE: usize = 32;
+const KEY_REQ_SIZE: usize = 32;
+const KEY_RSP_SIZE: usize = 64;
+
+pub const DERIVED_KEY_SIZE: usize = 32;
+
+pub const ATTESTATION_REPORT_SIZE: usize = 0x4a0;
+
+// Time a throttled guest request is retried before giving up
+const GUEST_REQUEST_THROTTLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AttestError {
+    // attestation_init() was not called
+    NotInitialized,
+    // The request does not fit into a guest message
+    InvalidRequest,
+    Ghcb(GhcbError),
+    // The response is not the answer to the request
+    InvalidResponse,
+    // The response does not authenticate with the VMPCK
+    Authentication,
+    // Status reported by the firmware in the response
+    Firmware(u32),
+    NoMemory,
+    // All message sequence numbers of the VMPCK are used up
+    SeqnoExhausted,
+}
+
+impl From<GhcbError> for AttestError {
+    fn from(err: GhcbError) -> Self {
+        AttestError::Ghcb(err)
+    }
+}
+
+impl From<SeqnoExhausted> for AttestError {
+    fn from(_err: SeqnoExhausted) -> Self {
+        AttestError::SeqnoExhausted
+    }
+}
+
+// Encrypts payload into msg with the given message sequence number
+fn seal_msg(
+    msg: &mut GuestMsg,
+    key: &VmpckKey,
+    vmpck: u8,
+    seqno: u64,
+    msg_type: u8,
+    payload: &[u8],
+) -> Result<(), AttestError> {
+    msg.clear();
+    msg.set_header(SNP_AEAD_AES_256_GCM, msg_type, 1, payload.len(), vmpck)
+        .map_err(|_| AttestError::InvalidRequest)?;
+    msg.set_seqno(seqno);
+
+    let sealed = key.aead_encrypt(seqno, &msg.aad(), payload);
+    let (ciphertext, tag) = sealed.split_at(payload.len());
+
+    msg.payload_mut()[..payload.len()].copy_from_slice(ciphertext);
+    let mut authtag = [0u8; GUEST_MSG_AUTHTAG_SIZE];
+    authtag[..GCM_TAG_SIZE].copy_from_slice(tag);
+    msg.set_authtag(&authtag);
+
+    Ok(())
+}
+
+// Checks that msg is the expected message and returns its decrypted payload
+fn open_msg(
+    msg: &GuestMsg,
+    key: &VmpckKey,
+    vmpck: u8,
+    seqno: u64,
+    msg_type: u8,
+) -> Result<Vec<u8>, AttestError> {
+    let size = msg.msg_size();
+
+    if msg.algo() != SNP_AEAD_AES_256_GCM
+        || msg.hdr_version() != 1
+        || msg.msg_type() != msg_type
+        || msg.vmpck() != vmpck
+        || msg.seqno() != seqno
+        || size > GUEST_MSG_PAYLOAD_SIZE
+    {
+        return Err(AttestError::InvalidResponse);
+    }
+
+    let mut sealed = Vec::new();
+    sealed
+        .try_reserve(size + GCM_TAG_SIZE)
+        .map_err(|_| AttestError::NoMemory)?;
+    sealed.extend_from_slice(&msg.payload()[..size]);
+    sealed.extend_from_slice(&msg.authtag()[..GCM_TAG_SIZE]);
+
+    key.aead_decrypt(seqno, &msg.aad(), &sealed)
+        .map_err(|_| AttestError::Authentication)
+}
+
+// Channel to the firmware through SNP guest requests, protected with VMPCK0.
+// Request and response pages are shared with the hypervisor.
+struct GuestMsgChannel {
+    key: VmpckKey,
+    vmpck: u8,
+    // The firmware expects strictly increasing sequence numbers
+    seqno: MsgSeqno,
+    req: VirtAddr,
+    resp: VirtAddr,
+}
+
+impl GuestMsgChannel {
+    fn exchange(
+        &mut self,
+        req_type: u8,
+        resp_type: u8,
+        payload: &[u8],
+    ) -> Result<Vec<u8>, AttestError> {
+        let req = unsafe { &mut *self.req.as_mut_ptr::<GuestMsg>() };
+        let resp = unsafe { &mut *self.resp.as_mut_ptr::<GuestMsg>() };
+        let seqno = self.seqno.current()?;
+
+        seal_msg(req, &self.key, self.vmpck, seqno, req_type, payload)?;
+
+        // A throttled request never reached the firmware, so the same
+        // message is sent again with the same sequence number. One which is
+        // still throttled after the timeout fails with GhcbError::Throttled.
+        let start = Instant::now();
+        let ret = loop {
+            resp.clear();
+            match this_cpu_mut().ghcb().guest_request(req, resp) {
+                Err(GhcbError::Throttled) if start.elapsed() < GUEST_REQUEST_THROTTLE_TIMEOUT => {
+                    core::hint::spin_loop()
+                }
+                ret => break ret,
+            }
+        };
+        req.clear();
+        ret?;
+
+        self.seqno.advance();
+
+        let plain = open_msg(resp, &self.key, self.vmpck, seqno + 1, resp_type);
+        resp.clear();
+        plain
+    }
+
+    // Wipes the VMPCK and gives the message pages back. Used when it is
+    // unknown whether the firmware saw a request, as the sequence numbers
+    // may not match the ones of the firmware anymore.
+    fn disable(self) {
+        let GuestMsgChannel { key, req, resp, .. } = self;
+
+        drop(key);
+        for vaddr in [req, resp] {
+            if make_page_private(vaddr).is_ok() {
+                free_page(vaddr);
+            }
+        }
+    }
+}
+
+static GUEST_MSG_CHANNEL: SpinLock<Option<GuestMsgChannel>> = SpinLock::new(None);
+
+// Nothing of the messages stays behind in the pages once they are freed
+fn allocate_shared_page() -> Result<VirtAddr, ()> {
+    let vaddr = alloc_secret_page()?;
+
+    make_page_shared(vaddr).map_err(|_| {
+        free_page(vaddr);
+    })?;
+
+    Ok(vaddr)
+}
+
+// Sets up the guest message channel with the VMPCK0 from the secrets page
+pub fn attestation_init(key: VmpckKey) -> Result<(), ()> {
+    let req = allocate_shared_page()?;
+    let resp = allocate_shared_page().map_err(|_| {
+        if make_page_private(req).is_ok() {
+            free_page(req);
+        }
+    })?;
+
+    *GUEST_MSG_CHANNEL.lock() = Some(GuestMsgChannel {
+        key,
+        vmpck: 0,
+        seqno: MsgSeqno::new(),
+        req,
+        resp,
+    });
+
+    Ok(())
+}
+
+// A failed guest request disables the channel, later requests fail with
+// AttestError::NotInitialized
+fn guest_msg_exchange(req_type: u8, resp_type: u8, payload: &[u8]) -> Result<Vec<u8>, AttestError> {
+    let mut channel = GUEST_MSG_CHANNEL.lock();
+    let ret = channel
+        .as_mut()
+        .ok_or(AttestError::NotInitialized)?
+        .exchange(req_type, resp_type, payload);
+
+    if let Err(AttestError::Ghcb(err)) = ret {
+        log::error!("Guest request failed: {:?}, disabling VMPCK0", err);
+        if let Some(channel) = channel.take() {
+            channel.disable();
+        }
+    }
+
+    ret
+}
+
+// Attestation report as signed by the firmware, see the SEV-SNP firmware ABI
+// for the layout
+#[derive(Clone, Debug)]
+pub struct AttestationReport {
+    raw: [u8; ATTESTATION_REPORT_SIZE],
+}
+
+impl AttestationReport {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, AttestError> {
+        let raw = bytes.try_into().map_err(|_| AttestError::InvalidResponse)?;
+
+        Ok(AttestationReport { raw })
+    }
+
+    fn u32_at(&self, offset: usize) -> u32 {
+        u32::from_le_bytes(self.raw[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn u64_at(&self, offset: usize) -> u64 {
+        u64::from_le_bytes(self.raw[offset..offset + 8].try_into().unwrap())
+    }
+
+    pub fn version(&self) -> u32 {
+        self.u32_at(0x00)
+    }
+
+    pub fn guest_svn(&self) -> u32 {
+        self.u32_at(0x04)
+    }
+
+    pub fn policy(&self) -> u64 {
+        self.u64_at(0x08)
+    }
+
+    pub fn vmpl(&self) -> u32 {
+        self.u32_at(0x30)
+    }
+
+    pub fn signature_algo(&self) -> u32 {
+        self.u32_at(0x34)
+    }
+
+    pub fn current_tcb(&self) -> u64 {
+        self.u64_at(0x38)
+    }
+
+    pub fn platform_info(&self) -> u64 {
+        self.u64_at(0x40)
+    }
+
+    pub fn report_data(&self) -> &[u8] {
+        &self.raw[0x50..0x90]
+    }
+
+    pub fn measurement(&self) -> &[u8] {
+        &self.raw[0x90..0xc0]
+    }
+
+    pub fn host_data(&self) -> &[u8] {
+        &self.raw[0xc0..0xe0]
+    }
+
+    pub fn reported_tcb(&self) -> u64 {
+        self.u64_at(0x180)
+    }
+
+    pub fn chip_id(&self) -> &[u8] {
+        &self.raw[0x1a0..0x1e0]
+    }
+
+    // Signed are all bytes in front of the signature
+    pub fn signed_data(&self) -> &[u8] {
+        &self.raw[..0x2a0]
+    }
+
+    pub fn signature(&self) -> &[u8] {
+        &self.raw[0x2a0..]
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.raw
+    }
+}
+
+// MSG_REPORT_RSP starts with the firmware status and the report size
+fn parse_report_rsp(rsp: &[u8]) -> Result<AttestationReport, AttestError> {
+    if rsp.len() < REPORT_RSP_HDR_SIZE {
+        return Err(AttestError::InvalidResponse);
+    }
+
+    let status = u32::from_le_bytes(rsp[0..4].try_into().unwrap());
+    let size = u32::from_le_bytes(rsp[4..8].try_into().unwrap()) as usize;
+
+    if status != 0 {
+        return Err(AttestError::Firmware(status));
+    }
+
+    let report = rsp
+        .get(REPORT_RSP_HDR_SIZE..REPORT_RSP_HDR_SIZE + size)
+        .ok_or(AttestError::InvalidResponse)?;
+
+    AttestationReport::from_bytes(report)
+}
+
+// Requests an attestation report for VMPL0 from the firmware, with
+// report_data included in the signed report
+pub fn get_attestation_report(report_data: [u8; 64]) -> Result<AttestationReport, AttestError> {
+    // REPORT_DATA, VMPL and KEY_SEL, the rest is reserved
+    let mut req = [0u8; REPORT_REQ_SIZE];
+    req[..64].copy_from_slice(&report_data);
+
+    let rsp = guest_msg_exchange(SNP_MSG_REPORT_REQ, SNP_MSG_REPORT_RSP, &req)?;
+
+    parse_report_rsp(&rsp)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RootKey {
+    // Versioned chip endorsement key
+    Vcek,
+    // VM root key, provided at launch by the migration agent
+    Vmrk,
+}
+
+bitflags! {
+    // Guest data mixed into the derived key
+    pub struct GuestFieldSelect: u64 {
+        const POLICY      = 1 << 0;
+        const IMAGE_ID    = 1 << 1;
+        const FAMILY_ID   = 1 << 2;
+        const MEASUREMENT = 1 << 3;
+        const GUEST_SVN   = 1 << 4;
+        const TCB_VERSION = 1 << 5;
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct KeyDeriveParams {
+    pub root_key: RootKey,
+    pub guest_fields: GuestFieldSelect,
+    // Must not be more privileged than the requesting VMPL
+    pub vmpl: u32,
+    pub guest_svn: u32,
+    pub tcb_version: u64,
+}
+
+impl KeyDeriveParams {
+    fn to_bytes(self) -> [u8; KEY_REQ_SIZE] {
+        let mut req = [0u8; KEY_REQ_SIZE];
+        let root_key_select: u32 = match self.root_key {
+            RootKey::Vcek => 0,
+            RootKey::Vmrk => 1,
+        };
+
+        req[0..4].copy_from_slice(&root_key_select.to_le_bytes());
+        req[8..16].copy_from_slice(&self.guest_fields.bits().to_le_bytes());
+        req[16..20].copy_from_slice(&self.vmpl.to_le_bytes());
+        req[20..24].copy_from_slice(&self.guest_svn.to_le_bytes());
+        req[24..32].copy_from_slice(&self.tcb_version.to_le_bytes());
+        req
+    }
+}
+
+// Key material from MSG_KEY_RSP, wiped on drop. Deliberately not Clone.
+pub struct DerivedKey {
+    key: [u8; DERIVED_KEY_SIZE],
+}
+
+impl DerivedKey {
+    pub fn as_bytes(&self) -> &[u8; DERIVED_KEY_SIZE] {
+        &self.key
+    }
+}
+
+impl Drop for DerivedKey {
+    fn drop(&mut self) {
+        zero_volatile(self.key.as_mut_ptr(), self.key.len());
+        compiler_fence(Ordering::SeqCst);
+    }
+}
+
+// MSG_KEY_RSP holds the firmware status followed by the key at offset 32
+fn parse_key_rsp(rsp: &[u8]) -> Result<DerivedKey, AttestError> {
+    if rsp.len() != KEY_RSP_SIZE {
+        return Err(AttestError::InvalidResponse);
+    }
+
+    let status = u32::from_le_bytes(rsp[0..4].try_into().unwrap());
+    if status != 0 {
+        return Err(AttestError::Firmware(status));
+    }
+
+    let mut key = DerivedKey {
+        key: [0u8; DERIVED_KEY_SIZE],
+    };
+    key.key.copy_from_slice(&rsp[32..]);
+
+    Ok(key)
+}
+
+// Asks the firmware for a key derived from the selected root key and guest
+// data
+pub fn get_derived_key(params: KeyDeriveParams) -> Result<DerivedKey, AttestError> {
+    let mut rsp = guest_msg_exchange(SNP_MSG_KEY_REQ, SNP_MSG_KEY_RSP, &params.to_bytes())?;
+
+    let ret = parse_key_rsp(&rsp);
+    zero_volatile(rsp.as_mut_ptr(), rsp.len());
+    compiler_fence(Ordering::SeqCst);
+
+    ret
+}
+
+#[test]
+fn test_guest_msg_seal_open() {
+    let key = VmpckKey::new(&[0x11u8; 32]);
+    let mut msg = GuestMsg::new();
+
+    seal_msg(&mut msg, &key, 0, 5, SNP_MSG_REPORT_REQ, b"report request").unwrap();
+    assert_eq!(msg.seqno(), 5);
+    assert_ne!(&msg.payload()[..14], b"report request");
+
+    assert_eq!(
+        open_msg(&msg, &key, 0, 5, SNP_MSG_REPORT_REQ).unwrap(),
+        b"report request"
+    );
+    // A response to another request or of another type is rejected
+    assert_eq!(
+        open_msg(&msg, &key, 0, 6, SNP_MSG_REPORT_REQ),
+        Err(AttestError::InvalidResponse)
+    );
+    assert_eq!(
+        open_msg(&msg, &key, 0, 5, SNP_MSG_REPORT_RSP),
+        Err(AttestError::InvalidResponse)
+    );
+
+    msg.payload_mut()[0] ^= 1;
+    assert_eq!(
+        open_msg(&msg, &key, 0, 5, SNP_MSG_REPORT_REQ),
+        Err(AttestError::Authentication)
+    );
+}
+
+#[test]
+fn test_parse_report_rsp() {
+    let mut rsp = [0u8; REPORT_RSP_HDR_SIZE + ATTESTATION_REPORT_SIZE];
+    rsp[4..8].copy_from_slice(&(ATTESTATION_REPORT_SIZE as u32).to_le_bytes());
+    rsp[REPORT_RSP_HDR_SIZE] = 2;
+    rsp[REPORT_RSP_HDR_SIZE + 0x50] = 0xaa;
+
+    let report = parse_report_rsp(&rsp).unwrap();
+    assert_eq!(report.version(), 2);
+    assert_eq!(report.report_data()[0], 0xaa);
+    assert_eq!(report.signature().len(), 512);
+
+    rsp[0] = 0x16;
+    assert!(matches!(
+        parse_report_rsp(&rsp),
+        Err(AttestError::Firmware(0x16))
+    ));
+    rsp[0] = 0;
+    rsp[4] = 0xff;
+    assert!(matches!(
+        parse_report_rsp(&rsp),
+        Err(AttestError::InvalidResponse)
+    ));
+}
+
+#[test]
+fn test_key_req_encoding() {
+    let params = KeyDeriveParams {
+        root_key: RootKey::Vmrk,
+        guest_fields: GuestFieldSelect::MEASUREMENT | GuestFieldSelect::GUEST_SVN,
+        vmpl: 1,
+        guest_svn: 2,
+        tcb_version: 0x0102_0304_0506_0708,
+    };
+    let req = params.to_bytes();
+
+    assert_eq!(req[..8], [1, 0, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(u64::from_le_bytes(req[8..16].try_into().unwrap()), 0x18);
+    assert_eq!(req[16..24], [1, 0, 0, 0, 2, 0, 0, 0]);
+    assert_eq!(req[24], 0x08);
+
+    let mut rsp = [0u8; KEY_RSP_SIZE];
+    rsp[32..].copy_from_slice(&[0x5au8; DERIVED_KEY_SIZE]);
+    assert_eq!(parse_key_rsp(&rsp).unwrap().as_bytes(), &[0x5au8; 32]);
+    rsp[0] = 0x16;
+    assert!(matches!(
+        parse_key_rsp(&rsp),
+        Err(AttestError::Firmware(0x16))
+    ));
+}
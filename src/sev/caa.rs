@@ -0,0 +1,71 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2026 SUSE LLC
+//
+// Author: agent <agent@local>
+
+use core::mem::size_of;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+// Calling area of a vCPU as defined by the SVSM specification. It lives in
+// guest memory and the guest can change it at any time, so all fields are
+// only accessed atomically.
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct CallingArea {
+    call_pending: AtomicU8,
+    mem_available: AtomicU8,
+    no_eoi_required: AtomicU8,
+    reserved: [u8; 5],
+}
+
+pub const CAA_SIZE: usize = size_of::<CallingArea>();
+pub const CAA_ALIGN: usize = 8;
+
+const _: () = assert!(CAA_SIZE == 8);
+
+impl CallingArea {
+    // The guest sets call_pending before it issues the VMGEXIT for a
+    // request. Returns whether a request was pending and clears the flag.
+    pub fn take_call_pending(&self) -> bool {
+        self.call_pending.swap(0, Ordering::AcqRel) == 1
+    }
+
+    pub fn mem_available(&self) -> bool {
+        self.mem_available.load(Ordering::Acquire) != 0
+    }
+
+    pub fn set_mem_available(&self, available: bool) {
+        self.mem_available
+            .store(u8::from(available), Ordering::Release);
+    }
+
+    // Set by the guest when the last interrupt does not need an EOI
+    pub fn no_eoi_required(&self) -> bool {
+        self.no_eoi_required.load(Ordering::Acquire) != 0
+    }
+
+    pub fn clear_no_eoi_required(&self) {
+        self.no_eoi_required.store(0, Ordering::Release);
+    }
+}
+
+#[test]
+fn test_caa_call_pending() {
+    let caa = CallingArea::default();
+
+    assert!(!caa.take_call_pending());
+
+    caa.call_pending.store(1, Ordering::Relaxed);
+    assert!(caa.take_call_pending());
+    assert!(!caa.take_call_pending());
+
+    // Only a value of 1 marks a pending call
+    caa.call_pending.store(2, Ordering::Relaxed);
+    assert!(!caa.take_call_pending());
+    assert_eq!(caa.call_pending.load(Ordering::Relaxed), 0);
+
+    caa.set_mem_available(true);
+    assert!(caa.mem_available());
+    assert!(!caa.no_eoi_required());
+}
@@ -4,23 +4,37 @@
 //
 // Author: Joerg Roedel <jroedel@suse.de>
 
+#[cfg(test)]
+extern crate alloc;
+
+use crate::cpu::cache::clflush_range;
 use crate::cpu::flush_tlb_global_sync;
 use crate::cpu::msr::{write_msr, SEV_GHCB};
+use crate::cpu::percpu::this_cpu;
+use crate::cpu::stats::GhcbExitReason;
 use crate::io::IOPort;
+use crate::locking::SpinLock;
 use crate::mm::pagetable::get_init_pgtable_locked;
+use crate::mm::slab_cache::SlabCache;
 use crate::mm::validate::{
     valid_bitmap_clear_valid_4k, valid_bitmap_set_valid_4k, valid_bitmap_valid_addr,
 };
 use crate::mm::virt_to_phys;
 use crate::sev::sev_snp_enabled;
-use crate::types::{PhysAddr, VirtAddr, PAGE_SIZE, PAGE_SIZE_2M};
-use crate::utils::is_aligned;
+use crate::types::{PageSize, PhysAddr, VirtAddr, PAGE_SIZE};
+use crate::utils::zero_mem_region;
 use core::arch::asm;
 use core::cell::RefCell;
+use core::sync::atomic::{AtomicU16, Ordering};
 use core::{mem, ptr};
 
+#[cfg(test)]
+use alloc::vec::Vec;
+
+use super::guest_msg::GuestMsg;
 use super::msr_protocol::{
-    invalidate_page_msr, register_ghcb_gpa_msr, request_termination_msr, validate_page_msr,
+    invalidate_page_msr, register_ghcb_gpa_msr, request_termination_msr, sev_info_msr,
+    validate_page_msr, SevInfo,
 };
 use super::pvalidate;
 
@@ -43,33 +57,71 @@ const _OFF_BUFFER: u16 = 0x800;
 const OFF_VERSION: u16 = 0xffa;
 const OFF_USAGE: u16 = 0xffc;
 
-#[repr(C, packed)]
-pub struct PageStateChangeHeader {
-    cur_entry: u16,
-    end_entry: u16,
-    reserved: u32,
+// The PSC buffer starts with a header holding the index of the current
+// and the last entry, followed by 4 reserved bytes
+fn psc_header(cur_entry: u16, end_entry: u16) -> u64 {
+    u64::from(cur_entry) | u64::from(end_entry) << 16
 }
 
-pub enum PageStateChangeOp {
-    PscPrivate,
-    PscShared,
-    PscPsmash,
-    PscUnsmash,
+fn psc_header_entries(header: u64) -> (u16, u16) {
+    (header as u16, (header >> 16) as u16)
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PscOp {
+    Private,
+    Shared,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PscEntry {
+    pub gfn: u64,
+    pub page_size: PageSize,
+    pub op: PscOp,
+}
+
+const PSC_GFN_SHIFT: u8 = 12;
 const PSC_GFN_MASK: u64 = ((1u64 << 52) - 1) & !0xfffu64;
 
 const PSC_OP_SHIFT: u8 = 52;
 const PSC_OP_PRIVATE: u64 = 1 << PSC_OP_SHIFT;
 const PSC_OP_SHARED: u64 = 2 << PSC_OP_SHIFT;
-const PSC_OP_PSMASH: u64 = 3 << PSC_OP_SHIFT;
-const PSC_OP_UNSMASH: u64 = 4 << PSC_OP_SHIFT;
 
 const PSC_FLAG_HUGE_SHIFT: u8 = 56;
 const PSC_FLAG_HUGE: u64 = 1 << PSC_FLAG_HUGE_SHIFT;
 
+impl PscEntry {
+    pub fn new(paddr: PhysAddr, page_size: PageSize, op: PscOp) -> Self {
+        assert!(paddr.is_aligned(page_size.bytes()));
+        PscEntry {
+            gfn: u64::from(paddr) >> PSC_GFN_SHIFT,
+            page_size,
+            op,
+        }
+    }
+
+    // Encoding of the entry in the GHCB PSC buffer, with cur_page zero
+    fn encode(&self) -> Result<u64, GhcbError> {
+        let gfn = (self.gfn << PSC_GFN_SHIFT) & PSC_GFN_MASK;
+        let op = match self.op {
+            PscOp::Private => PSC_OP_PRIVATE,
+            PscOp::Shared => PSC_OP_SHARED,
+        };
+        let size = match self.page_size {
+            PageSize::Page4K => 0,
+            PageSize::Page2M => PSC_FLAG_HUGE,
+            PageSize::Page1G => return Err(GhcbError::InvalidParameter),
+        };
+
+        Ok(gfn | op | size)
+    }
+}
+
 const GHCB_BUFFER_SIZE: usize = 0x7f0;
 
+// Maximum entries (8 bytes each) minus 8 bytes for header
+const PSC_MAX_ENTRIES: usize = (GHCB_BUFFER_SIZE - 8) / 8;
+
 #[repr(C, packed)]
 pub struct GHCB {
     reserved_1: [u8; 0xcb],
@@ -106,83 +158,318 @@ enum GHCBExitCode {}
 
 impl GHCBExitCode {
     pub const IOIO: u64 = 0x7b;
+    pub const MSR: u64 = 0x7c;
+    pub const MMIO_READ: u64 = 0x8000_0001;
     pub const SNP_PSC: u64 = 0x8000_0010;
+    pub const GUEST_REQUEST: u64 = 0x8000_0011;
     pub const AP_CREATE: u64 = 0x80000013;
     pub const RUN_VMPL: u64 = 0x80000018;
+
+    // Lowest GHCB protocol version defining the exit code
+    fn min_version(exit_code: u64) -> u16 {
+        match exit_code {
+            Self::IOIO | Self::MSR | Self::MMIO_READ => 1,
+            _ => GHCB_VERSION_SNP,
+        }
+    }
+
+    fn reason(exit_code: u64) -> GhcbExitReason {
+        match exit_code {
+            Self::IOIO => GhcbExitReason::IoIo,
+            Self::MSR => GhcbExitReason::Msr,
+            Self::SNP_PSC => GhcbExitReason::PageStateChange,
+            Self::GUEST_REQUEST => GhcbExitReason::GuestRequest,
+            Self::AP_CREATE => GhcbExitReason::ApCreate,
+            Self::RUN_VMPL => GhcbExitReason::RunVmpl,
+            _ => GhcbExitReason::Other,
+        }
+    }
+}
+
+// Version 2 of the GHCB protocol is the first one supporting SEV-SNP, which
+// the SVSM depends on.
+pub const GHCB_VERSION_SNP: u16 = 2;
+pub const GHCB_VERSION_MIN: u16 = GHCB_VERSION_SNP;
+pub const GHCB_VERSION_MAX: u16 = 2;
+
+// Protocol version agreed on with the hypervisor, 0 until negotiated
+static GHCB_VERSION: AtomicU16 = AtomicU16::new(0);
+
+pub fn ghcb_version() -> u16 {
+    GHCB_VERSION.load(Ordering::Relaxed)
+}
+
+// GHCB pages are allocated and freed with each AP bring-up and teardown
+static GHCB_CACHE: SpinLock<SlabCache<GHCB>> = SpinLock::new(SlabCache::new());
+
+pub fn allocate_ghcb_page() -> Result<VirtAddr, ()> {
+    GHCB_CACHE.lock().alloc()
+}
+
+// Number of shared GHCB pages kept for reuse after their CPU went offline
+const GHCB_POOL_SIZE: usize = 4;
+
+// Pages in the pool are unregistered but still shared with the hypervisor,
+// so handing them to another CPU saves the page state changes.
+static GHCB_POOL: SpinLock<[Option<VirtAddr>; GHCB_POOL_SIZE]> =
+    SpinLock::new([None; GHCB_POOL_SIZE]);
+
+// Returns a shared GHCB page, which still needs to be registered on the CPU
+// using it
+pub fn allocate_ghcb() -> Result<*mut GHCB, ()> {
+    let pooled = GHCB_POOL.lock().iter_mut().find_map(|slot| slot.take());
+    if let Some(vaddr) = pooled {
+        zero_mem_region(vaddr, vaddr + PAGE_SIZE);
+        return Ok(vaddr.as_mut_ptr::<GHCB>());
+    }
+
+    let vaddr = allocate_ghcb_page()?;
+    let ghcb = vaddr.as_mut_ptr::<GHCB>();
+    if unsafe { (*ghcb).init() }.is_err() {
+        free_ghcb_page(vaddr);
+        return Err(());
+    }
+
+    Ok(ghcb)
+}
+
+// Must run on the CPU the GHCB is registered on. The page goes to the pool
+// when there is room, otherwise it is made private and freed.
+pub fn release_ghcb(ghcb: &mut GHCB) -> Result<(), ()> {
+    let vaddr = VirtAddr::from_ptr(ghcb as *const GHCB);
+
+    ghcb.unregister()?;
+
+    if let Some(slot) = GHCB_POOL.lock().iter_mut().find(|slot| slot.is_none()) {
+        *slot = Some(vaddr);
+        return Ok(());
+    }
+
+    ghcb.make_private()?;
+    free_ghcb_page(vaddr);
+
+    Ok(())
+}
+
+// The page must be private again, see GHCB::shutdown(). Pages which did not
+// come from the cache are ignored, like the ones of the early allocator.
+pub fn free_ghcb_page(vaddr: VirtAddr) {
+    let mut cache = GHCB_CACHE.lock();
+
+    if cache.owns(vaddr) {
+        cache.free(vaddr);
+    }
+}
+
+// Returns the number of slabs given back to the page allocator, a locked
+// cache is skipped like in shrink_vmsa_cache()
+pub fn shrink_ghcb_cache() -> usize {
+    GHCB_CACHE.try_lock().map_or(0, |mut cache| cache.shrink())
+}
+
+// Pick the highest version supported by both sides
+fn select_version(info: &SevInfo) -> Result<u16, GhcbError> {
+    if info.min_version > GHCB_VERSION_MAX || info.max_version < GHCB_VERSION_MIN {
+        return Err(GhcbError::UnsupportedVersion(
+            info.min_version,
+            info.max_version,
+        ));
+    }
+
+    Ok(info.max_version.min(GHCB_VERSION_MAX))
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GhcbError {
+    // The hypervisor reported an error, SW_EXITINFO1 and SW_EXITINFO2 as
+    // returned in the GHCB
+    VmgexitError(u64, u64),
+    // The hypervisor did not mark SW_EXITINFO1 or a result register valid
+    InvalidResponse,
+    // The request can not be expressed in the GHCB protocol
+    InvalidParameter,
+    // The hypervisor is rate-limiting guest requests, retry later
+    Throttled,
+    // The firmware failed the guest request with the given error code
+    FirmwareError(u32),
+    // The hypervisor supports only the given range of GHCB protocol
+    // versions, or the negotiated version lacks the requested exit
+    UnsupportedVersion(u16, u16),
+    // The hypervisor confirmed a different GHCB GPA than the one registered
+    GpaMismatch(PhysAddr),
+}
+
+const GUEST_REQUEST_VMM_ERR_SHIFT: u64 = 32;
+const GUEST_REQUEST_VMM_ERR_BUSY: u64 = 2;
+
+// Decodes SW_EXITINFO2 returned for a guest request, the upper 32 bits carry
+// a hypervisor error, the lower 32 bits the firmware error code.
+fn guest_request_status(info_2: u64) -> Result<(), GhcbError> {
+    let vmm_err = info_2 >> GUEST_REQUEST_VMM_ERR_SHIFT;
+    let fw_err = (info_2 & 0xffff_ffff) as u32;
+
+    if vmm_err == GUEST_REQUEST_VMM_ERR_BUSY {
+        Err(GhcbError::Throttled)
+    } else if vmm_err != 0 {
+        Err(GhcbError::VmgexitError(0, info_2))
+    } else if fw_err != 0 {
+        Err(GhcbError::FirmwareError(fw_err))
+    } else {
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum GHCBIOSize {
     Size8,
     Size16,
     Size32,
 }
 
-impl GHCB {
-    pub fn init(&mut self) -> Result<(), ()> {
-        let vaddr = (self as *const GHCB) as VirtAddr;
-        let paddr = virt_to_phys(vaddr);
+impl GHCBIOSize {
+    fn mask(self) -> u64 {
+        match self {
+            GHCBIOSize::Size8 => 0xff,
+            GHCBIOSize::Size16 => 0xffff,
+            GHCBIOSize::Size32 => 0xffff_ffff,
+        }
+    }
+}
 
-        if sev_snp_enabled() {
-            // Make page invalid
-            if let Err(_e) = pvalidate(vaddr, false, false) {
-                return Err(());
-            }
+const IOIO_TYPE_IN: u64 = 1 << 0;
+const IOIO_SZ8: u64 = 1 << 4;
+const IOIO_SZ16: u64 = 1 << 5;
+const IOIO_SZ32: u64 = 1 << 6;
+const IOIO_PORT_SHIFT: u64 = 16;
 
-            // Let the Hypervisor take the page back
-            if let Err(_e) = invalidate_page_msr(paddr) {
-                return Err(());
-            }
+// SW_EXITINFO1 for a single IN or OUT instruction. The string and REP bits
+// stay clear, string I/O is not supported through the GHCB here.
+fn ioio_exitinfo(port: u16, size: GHCBIOSize, is_in: bool) -> u64 {
+    let mut info: u64 = (port as u64) << IOIO_PORT_SHIFT;
 
-            // Needs guarding for Stage2 GHCB
-            if valid_bitmap_valid_addr(paddr) {
-                valid_bitmap_clear_valid_4k(paddr);
-            }
+    if is_in {
+        info |= IOIO_TYPE_IN;
+    }
+
+    info |= match size {
+        GHCBIOSize::Size8 => IOIO_SZ8,
+        GHCBIOSize::Size16 => IOIO_SZ16,
+        GHCBIOSize::Size32 => IOIO_SZ32,
+    };
+
+    info
+}
+
+// Hands a page of SVSM memory over to the hypervisor and maps it
+// unencrypted, for data exchanged with the hypervisor like the GHCB itself
+pub fn make_page_shared(vaddr: VirtAddr) -> Result<(), ()> {
+    let paddr = virt_to_phys(vaddr);
+
+    // Write back dirty encrypted lines before the page leaves the guest
+    clflush_range(vaddr, PAGE_SIZE);
+
+    if sev_snp_enabled() {
+        // Make page invalid
+        if pvalidate(vaddr, PageSize::Page4K, false) != Ok(true) {
+            return Err(());
         }
 
-        // Map page unencrypted
-        if let Err(_e) = get_init_pgtable_locked().set_shared_4k(vaddr) {
+        // Let the Hypervisor take the page back
+        if let Err(_e) = invalidate_page_msr(paddr) {
             return Err(());
         }
 
-        flush_tlb_global_sync();
+        // Needs guarding for Stage2 GHCB
+        if valid_bitmap_valid_addr(paddr) {
+            valid_bitmap_clear_valid_4k(paddr);
+        }
+    }
 
-        Ok(())
+    // Map page unencrypted
+    if let Err(_e) = get_init_pgtable_locked().set_shared_4k(vaddr) {
+        return Err(());
     }
 
-    pub fn register(&self) -> Result<(), ()> {
-        let vaddr = (self as *const GHCB) as VirtAddr;
-        let paddr = virt_to_phys(vaddr);
+    flush_tlb_global_sync();
+
+    Ok(())
+}
 
-        // Register GHCB GPA
-        register_ghcb_gpa_msr(paddr)
+// Reverts make_page_shared(), the page content is lost
+pub fn make_page_private(vaddr: VirtAddr) -> Result<(), ()> {
+    let paddr = virt_to_phys(vaddr);
+
+    // Drop lines cached through the shared mapping, they must not be
+    // written back over the private page later
+    clflush_range(vaddr, PAGE_SIZE);
+
+    // Re-encrypt page
+    get_init_pgtable_locked().set_encrypted_4k(vaddr)?;
+
+    // Make page guest-invalid
+    validate_page_msr(paddr)?;
+
+    // Make page guest-valid
+    if pvalidate(vaddr, PageSize::Page4K, true) != Ok(true) {
+        return Err(());
     }
 
-    pub fn shutdown(&mut self) -> Result<(), ()> {
-        let vaddr = (self as *const GHCB) as VirtAddr;
-        let paddr = virt_to_phys(vaddr);
+    // Needs guarding for Stage2 GHCB
+    if valid_bitmap_valid_addr(paddr) {
+        valid_bitmap_set_valid_4k(paddr);
+    }
 
-        // Re-encrypt page
-        get_init_pgtable_locked().set_encrypted_4k(vaddr)?;
+    Ok(())
+}
 
-        // Unregister GHCB PA
-        register_ghcb_gpa_msr(0usize)?;
+impl GHCB {
+    // Must run once on the BSP before the first VMGEXIT through a GHCB page.
+    // Uses the GHCB MSR protocol, so no GHCB needs to be registered yet.
+    pub fn negotiate_version() -> Result<u16, GhcbError> {
+        let info = sev_info_msr().map_err(|_| GhcbError::InvalidResponse)?;
+        let version = select_version(&info)?;
 
-        // Make page guest-invalid
-        validate_page_msr(paddr)?;
+        GHCB_VERSION.store(version, Ordering::Relaxed);
 
-        // Make page guest-valid
-        if pvalidate(vaddr, false, true).is_err() {
-            return Err(());
+        Ok(version)
+    }
+
+    pub fn init(&mut self) -> Result<(), ()> {
+        make_page_shared(VirtAddr::from_ptr(self as *const GHCB))
+    }
+
+    // Register the GHCB GPA with the hypervisor. This must happen on the CPU
+    // using the GHCB, before its first VMGEXIT through the page.
+    pub fn register(&self) -> Result<(), GhcbError> {
+        let vaddr = VirtAddr::from_ptr(self as *const GHCB);
+        let paddr = virt_to_phys(vaddr);
+
+        let confirmed = register_ghcb_gpa_msr(paddr).map_err(|_| GhcbError::InvalidResponse)?;
+        if confirmed != paddr {
+            return Err(GhcbError::GpaMismatch(confirmed));
         }
 
-        // Needs guarding for Stage2 GHCB
-        if valid_bitmap_valid_addr(paddr) {
-            valid_bitmap_set_valid_4k(paddr);
+        Ok(())
+    }
+
+    pub fn unregister(&self) -> Result<(), ()> {
+        if register_ghcb_gpa_msr(PhysAddr::null())? != PhysAddr::null() {
+            return Err(());
         }
 
         Ok(())
     }
 
+    pub fn shutdown(&mut self) -> Result<(), ()> {
+        self.unregister()?;
+        self.make_private()
+    }
+
+    // Undoes init(), the GHCB must not be registered anymore
+    fn make_private(&mut self) -> Result<(), ()> {
+        make_page_private(VirtAddr::from_ptr(self as *const GHCB))
+    }
+
     pub fn clear(&mut self) {
         // Clear valid bitmap
         self.valid_bitmap[0] = 0;
@@ -209,9 +496,18 @@ impl GHCB {
         (self.valid_bitmap[index] & mask) == mask
     }
 
-    fn vmgexit(&mut self, exit_code: u64, exit_info_1: u64, exit_info_2: u64) -> Result<(), ()> {
-        // GHCB is version 2
-        self.version = 2;
+    fn vmgexit(
+        &mut self,
+        exit_code: u64,
+        exit_info_1: u64,
+        exit_info_2: u64,
+    ) -> Result<(), GhcbError> {
+        let version = ghcb_version();
+        if version < GHCBExitCode::min_version(exit_code) {
+            return Err(GhcbError::UnsupportedVersion(version, version));
+        }
+
+        self.version = version;
         self.set_valid(OFF_VERSION);
 
         // GHCB Follows standard format
@@ -227,18 +523,32 @@ impl GHCB {
         self.sw_exit_info_2 = exit_info_2;
         self.set_valid(OFF_SW_EXIT_INFO_2);
 
+        this_cpu()
+            .counters()
+            .count_ghcb_exit(GHCBExitCode::reason(exit_code));
+
         unsafe {
-            let ghcb_address = (self as *const GHCB) as VirtAddr;
-            let ghcb_pa: u64 = virt_to_phys(ghcb_address) as u64;
+            let ghcb_address = VirtAddr::from_ptr(self as *const GHCB);
+            let ghcb_pa: u64 = u64::from(virt_to_phys(ghcb_address));
             write_msr(SEV_GHCB, ghcb_pa);
             asm!("rep; vmmcall", options(att_syntax));
         }
 
-        if self.is_valid(OFF_SW_EXIT_INFO_1) && self.sw_exit_info_1 == 0 {
-            Ok(())
-        } else {
-            Err(())
+        if !self.is_valid(OFF_SW_EXIT_INFO_1) {
+            return Err(GhcbError::InvalidResponse);
         }
+
+        let info_1 = self.sw_exit_info_1;
+        if info_1 != 0 {
+            let info_2 = if self.is_valid(OFF_SW_EXIT_INFO_2) {
+                self.sw_exit_info_2
+            } else {
+                0
+            };
+            return Err(GhcbError::VmgexitError(info_1, info_2));
+        }
+
+        Ok(())
     }
 
     pub fn set_cpl(&mut self, cpl: u8) {
@@ -291,47 +601,73 @@ impl GHCB {
         self.set_valid(OFF_X87_STATE_GPA);
     }
 
-    pub fn ioio_in(&mut self, port: u16, size: GHCBIOSize) -> Result<u64, ()> {
+    pub fn ioio_in(&mut self, port: u16, size: GHCBIOSize) -> Result<u64, GhcbError> {
         self.clear();
 
-        let mut info: u64 = 1; // IN instruction
-
-        info |= (port as u64) << 16;
+        let info = ioio_exitinfo(port, size, true);
+        self.vmgexit(GHCBExitCode::IOIO, info, 0)?;
 
-        match size {
-            GHCBIOSize::Size8 => info |= 1 << 4,
-            GHCBIOSize::Size16 => info |= 1 << 5,
-            GHCBIOSize::Size32 => info |= 1 << 6,
-        }
-
-        match self.vmgexit(GHCBExitCode::IOIO, info, 0) {
-            Ok(()) => {
-                if self.is_valid(OFF_RAX) {
-                    Ok(self.rax)
-                } else {
-                    Err(())
-                }
-            }
-            Err(()) => Err(()),
+        if self.is_valid(OFF_RAX) {
+            Ok(self.rax & size.mask())
+        } else {
+            Err(GhcbError::InvalidResponse)
         }
     }
 
-    pub fn ioio_out(&mut self, port: u16, size: GHCBIOSize, value: u64) -> Result<(), ()> {
+    pub fn ioio_out(&mut self, port: u16, size: GHCBIOSize, value: u64) -> Result<(), GhcbError> {
         self.clear();
 
-        let mut info: u64 = 0; // OUT instruction
+        let info = ioio_exitinfo(port, size, false);
+        self.set_rax(value & size.mask());
 
-        info |= (port as u64) << 16;
+        self.vmgexit(GHCBExitCode::IOIO, info, 0)
+    }
 
-        match size {
-            GHCBIOSize::Size8 => info |= 1 << 4,
-            GHCBIOSize::Size16 => info |= 1 << 5,
-            GHCBIOSize::Size32 => info |= 1 << 6,
-        }
+    pub fn inb(&mut self, port: u16) -> Result<u8, GhcbError> {
+        Ok(self.ioio_in(port, GHCBIOSize::Size8)? as u8)
+    }
 
-        self.set_rax(value);
+    pub fn outb(&mut self, port: u16, value: u8) -> Result<(), GhcbError> {
+        self.ioio_out(port, GHCBIOSize::Size8, value as u64)
+    }
 
-        self.vmgexit(GHCBExitCode::IOIO, info, 0)
+    pub fn inw(&mut self, port: u16) -> Result<u16, GhcbError> {
+        Ok(self.ioio_in(port, GHCBIOSize::Size16)? as u16)
+    }
+
+    pub fn outw(&mut self, port: u16, value: u16) -> Result<(), GhcbError> {
+        self.ioio_out(port, GHCBIOSize::Size16, value as u64)
+    }
+
+    pub fn inl(&mut self, port: u16) -> Result<u32, GhcbError> {
+        Ok(self.ioio_in(port, GHCBIOSize::Size32)? as u32)
+    }
+
+    pub fn outl(&mut self, port: u16, value: u32) -> Result<(), GhcbError> {
+        self.ioio_out(port, GHCBIOSize::Size32, value as u64)
+    }
+
+    pub fn rdmsr(&mut self, msr: u32) -> Result<u64, GhcbError> {
+        GhcbBackend::rdmsr(self, msr)
+    }
+
+    pub fn wrmsr(&mut self, msr: u32, value: u64) -> Result<(), GhcbError> {
+        GhcbBackend::wrmsr(self, msr, value)
+    }
+
+    // Emulated 32-bit MMIO read, the hypervisor returns the data in the
+    // shared buffer.
+    pub fn mmio_read_u32(&mut self, gpa: PhysAddr) -> Result<u32, GhcbError> {
+        self.clear();
+
+        let buffer_va = VirtAddr::from_ptr(self.buffer.as_ptr());
+        let buffer_pa: u64 = u64::from(virt_to_phys(buffer_va));
+        self.set_sw_scratch(buffer_pa);
+
+        self.vmgexit(GHCBExitCode::MMIO_READ, u64::from(gpa), 4)?;
+
+        self.read_buffer::<u32>(0)
+            .map_err(|_| GhcbError::InvalidResponse)
     }
 
     fn write_buffer<T>(&mut self, data: &T, offset: isize) -> Result<(), ()>
@@ -359,101 +695,299 @@ impl GHCB {
         Ok(())
     }
 
-    pub fn psc_entry(&self, paddr: PhysAddr, op_mask: u64, current_page: u64, huge: bool) -> u64 {
-        assert!(!huge || is_aligned(paddr, PAGE_SIZE_2M));
+    fn read_buffer<T>(&self, offset: isize) -> Result<T, ()>
+    where
+        T: Sized,
+    {
+        let size: isize = mem::size_of::<T>() as isize;
+
+        if offset < 0 || offset + size > (GHCB_BUFFER_SIZE as isize) {
+            return Err(());
+        }
+
+        unsafe {
+            let src = self.buffer.as_ptr().cast::<u8>().offset(offset).cast::<T>();
 
-        let mut entry: u64 = ((paddr as u64) & PSC_GFN_MASK) | op_mask | (current_page & 0xfffu64);
-        if huge {
-            entry |= PSC_FLAG_HUGE;
+            Ok(ptr::read_volatile(src))
         }
+    }
 
-        entry
+    pub fn page_state_change(&mut self, entries: &[PscEntry]) -> Result<(), GhcbError> {
+        GhcbBackend::page_state_change(self, entries)
     }
 
-    pub fn page_state_change(
+    pub fn page_state_change_region(
         &mut self,
         start: PhysAddr,
         end: PhysAddr,
-        huge: bool,
-        op: PageStateChangeOp,
-    ) -> Result<(), ()> {
-        // Maximum entries (8 bytes each_ minus 8 bytes for header
-        let max_entries: u16 = ((GHCB_BUFFER_SIZE - 8) / 8).try_into().unwrap();
-        let mut entries: u16 = 0;
-        let mut vaddr = start;
-        let op_mask: u64 = match op {
-            PageStateChangeOp::PscPrivate => PSC_OP_PRIVATE,
-            PageStateChangeOp::PscShared => PSC_OP_SHARED,
-            PageStateChangeOp::PscPsmash => PSC_OP_PSMASH,
-            PageStateChangeOp::PscUnsmash => PSC_OP_UNSMASH,
-        };
-        let pgsize: usize = match huge {
-            true => PAGE_SIZE_2M,
-            false => PAGE_SIZE,
-        };
+        page_size: PageSize,
+        op: PscOp,
+    ) -> Result<(), GhcbError> {
+        GhcbBackend::page_state_change_region(self, start, end, page_size, op)
+    }
 
+    pub fn ap_create(
+        &mut self,
+        vmsa_gpa: PhysAddr,
+        apic_id: u64,
+        vmpl: u64,
+        sev_features: u64,
+    ) -> Result<(), GhcbError> {
+        GhcbBackend::ap_create(self, vmsa_gpa, apic_id, vmpl, sev_features)
+    }
+
+    pub fn ap_destroy(&mut self, apic_id: u64) -> Result<(), GhcbError> {
+        GhcbBackend::ap_destroy(self, apic_id)
+    }
+
+    // Both messages must live in pages which are shared with the hypervisor.
+    pub fn guest_request(&mut self, req: &GuestMsg, resp: &mut GuestMsg) -> Result<(), GhcbError> {
         self.clear();
 
-        while vaddr < end {
-            let entry = self.psc_entry(vaddr, op_mask, 0, huge);
-            let offset: isize = (entries as isize) * 8 + 8;
-            self.write_buffer(&entry, offset)?;
-            entries += 1;
-            vaddr += pgsize;
+        let req_pa = u64::from(virt_to_phys(VirtAddr::from_ptr(req as *const GuestMsg)));
+        let resp_pa = u64::from(virt_to_phys(VirtAddr::from_ptr(resp as *const GuestMsg)));
 
-            if entries == max_entries {
-                let header = PageStateChangeHeader {
-                    cur_entry: 0,
-                    end_entry: entries - 1,
-                    reserved: 0,
-                };
-                self.write_buffer(&header, 0)?;
+        self.vmgexit(GHCBExitCode::GUEST_REQUEST, req_pa, resp_pa)?;
 
-                let buffer_va = self.buffer.as_ptr() as VirtAddr;
-                let buffer_pa: u64 = virt_to_phys(buffer_va) as u64;
-                self.set_sw_scratch(buffer_pa);
+        if !self.is_valid(OFF_SW_EXIT_INFO_2) {
+            return Err(GhcbError::InvalidResponse);
+        }
 
-                if self.vmgexit(GHCBExitCode::SNP_PSC, 0, 0).is_err() {
-                    if !self.is_valid(OFF_SW_EXIT_INFO_2) {
-                        return Err(());
-                    }
+        guest_request_status(self.sw_exit_info_2)
+    }
 
-                    let info_high: u32 = (self.sw_exit_info_2 >> 32) as u32;
-                    let info_low: u32 = (self.sw_exit_info_2 & 0xffff_ffffu64) as u32;
+    pub fn run_vmpl(&mut self, vmpl: u64) -> Result<(), GhcbError> {
+        self.clear();
+        self.vmgexit(GHCBExitCode::RUN_VMPL, vmpl, 0)
+    }
+}
 
-                    log::error!(
-                        "GHCB SnpPageStateChange failed err_high: {:#x} err_low: {:#x}",
-                        info_high,
-                        info_low
-                    );
+// Minimal register-level interface to a GHCB. The MSR, AP creation and page
+// state change encodings are implemented on top of it, so that they can be
+// exercised without a hypervisor.
+pub trait GhcbBackend {
+    fn clear(&mut self);
+    fn set_rax(&mut self, rax: u64);
+    fn set_rcx(&mut self, rcx: u64);
+    fn set_rdx(&mut self, rdx: u64);
+    fn rax(&self) -> Option<u64>;
+    fn rdx(&self) -> Option<u64>;
+    fn sw_exit_info_2(&self) -> Option<u64>;
+    // Offsets are into the shared buffer, which the hypervisor finds through
+    // SW_SCRATCH
+    fn write_buffer_u64(&mut self, offset: usize, value: u64) -> Result<(), GhcbError>;
+    fn read_buffer_u64(&self, offset: usize) -> Result<u64, GhcbError>;
+    fn set_sw_scratch_buffer(&mut self);
+    fn call(&mut self, exit_code: u64, exit_info_1: u64, exit_info_2: u64)
+        -> Result<(), GhcbError>;
+
+    fn count_psc_pages(&self, _pages: u64) {}
+
+    fn rdmsr(&mut self, msr: u32) -> Result<u64, GhcbError> {
+        self.clear();
 
-                    return Err(());
-                }
+        self.set_rcx(msr as u64);
 
-                entries = 0;
-            }
+        // EXITINFO1 = 0 for RDMSR
+        self.call(GHCBExitCode::MSR, 0, 0)?;
+
+        match (self.rax(), self.rdx()) {
+            (Some(rax), Some(rdx)) => Ok((rax & 0xffff_ffff) | (rdx << 32)),
+            _ => Err(GhcbError::InvalidResponse),
         }
+    }
 
-        Ok(())
+    fn wrmsr(&mut self, msr: u32, value: u64) -> Result<(), GhcbError> {
+        self.clear();
+
+        self.set_rcx(msr as u64);
+        self.set_rax(value & 0xffff_ffff);
+        self.set_rdx(value >> 32);
+
+        // EXITINFO1 = 1 for WRMSR
+        self.call(GHCBExitCode::MSR, 1, 0)
     }
 
-    pub fn ap_create(
+    fn ap_create(
         &mut self,
         vmsa_gpa: PhysAddr,
         apic_id: u64,
         vmpl: u64,
         sev_features: u64,
-    ) -> Result<(), ()> {
+    ) -> Result<(), GhcbError> {
         self.clear();
         let exit_info_1: u64 = 1 | (vmpl & 0xf) << 16 | apic_id << 32;
-        let exit_info_2: u64 = vmsa_gpa as u64;
+        let exit_info_2: u64 = u64::from(vmsa_gpa);
         self.set_rax(sev_features);
-        self.vmgexit(GHCBExitCode::AP_CREATE, exit_info_1, exit_info_2)
+        self.call(GHCBExitCode::AP_CREATE, exit_info_1, exit_info_2)
     }
 
-    pub fn run_vmpl(&mut self, vmpl: u64) -> Result<(), ()> {
+    fn ap_destroy(&mut self, apic_id: u64) -> Result<(), GhcbError> {
         self.clear();
-        self.vmgexit(GHCBExitCode::RUN_VMPL, vmpl, 0)
+        let exit_info_1: u64 = 2 | apic_id << 32;
+        self.call(GHCBExitCode::AP_CREATE, exit_info_1, 0)
+    }
+
+    fn write_psc_entry(&mut self, index: usize, entry: u64) -> Result<(), GhcbError> {
+        self.write_buffer_u64(index * 8 + 8, entry)
+    }
+
+    // Submits the first `count` entries in the PSC buffer. The hypervisor is
+    // allowed to process only part of them, so the request is re-issued until
+    // all entries are done.
+    fn psc_submit(&mut self, count: usize) -> Result<(), GhcbError> {
+        assert!(count > 0 && count <= PSC_MAX_ENTRIES);
+
+        let end_entry: u16 = (count - 1) as u16;
+        self.write_buffer_u64(0, psc_header(0, end_entry))?;
+
+        let mut cur_entry: u16 = 0;
+
+        self.count_psc_pages(count as u64);
+
+        while cur_entry <= end_entry {
+            self.set_sw_scratch_buffer();
+
+            let ret = self.call(GHCBExitCode::SNP_PSC, 0, 0);
+            let ret = match ret {
+                Ok(()) => match self.sw_exit_info_2() {
+                    Some(info_2) if info_2 != 0 => Err(GhcbError::VmgexitError(0, info_2)),
+                    _ => Ok(()),
+                },
+                ret => ret,
+            };
+
+            if let Err(e) = ret {
+                if let GhcbError::VmgexitError(_, info_2) = e {
+                    log::error!(
+                        "GHCB SnpPageStateChange failed err_high: {:#x} err_low: {:#x}",
+                        info_2 >> 32,
+                        info_2 & 0xffff_ffffu64
+                    );
+                }
+                return Err(e);
+            }
+
+            let (new_cur, new_end) = psc_header_entries(self.read_buffer_u64(0)?);
+
+            // Every exit has to make progress, or the loop would not end
+            if new_end != end_entry || new_cur <= cur_entry {
+                log::error!(
+                    "GHCB SnpPageStateChange invalid header: cur {} end {}",
+                    new_cur,
+                    new_end
+                );
+                return Err(GhcbError::InvalidResponse);
+            }
+
+            cur_entry = new_cur;
+        }
+
+        Ok(())
+    }
+
+    fn page_state_change(&mut self, entries: &[PscEntry]) -> Result<(), GhcbError> {
+        for chunk in entries.chunks(PSC_MAX_ENTRIES) {
+            self.clear();
+
+            for (i, entry) in chunk.iter().enumerate() {
+                self.write_psc_entry(i, entry.encode()?)?;
+            }
+
+            self.psc_submit(chunk.len())?;
+        }
+
+        Ok(())
+    }
+
+    fn page_state_change_region(
+        &mut self,
+        start: PhysAddr,
+        end: PhysAddr,
+        page_size: PageSize,
+        op: PscOp,
+    ) -> Result<(), GhcbError> {
+        let mut entries: usize = 0;
+
+        self.clear();
+
+        for paddr in start.iter_to(end, page_size.bytes()) {
+            let entry = PscEntry::new(paddr, page_size, op);
+            self.write_psc_entry(entries, entry.encode()?)?;
+            entries += 1;
+
+            if entries == PSC_MAX_ENTRIES {
+                self.psc_submit(entries)?;
+                self.clear();
+                entries = 0;
+            }
+        }
+
+        if entries > 0 {
+            self.psc_submit(entries)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl GhcbBackend for GHCB {
+    fn clear(&mut self) {
+        GHCB::clear(self)
+    }
+
+    fn set_rax(&mut self, rax: u64) {
+        GHCB::set_rax(self, rax)
+    }
+
+    fn set_rcx(&mut self, rcx: u64) {
+        GHCB::set_rcx(self, rcx)
+    }
+
+    fn set_rdx(&mut self, rdx: u64) {
+        GHCB::set_rdx(self, rdx)
+    }
+
+    fn rax(&self) -> Option<u64> {
+        self.is_valid(OFF_RAX).then_some(self.rax)
+    }
+
+    fn rdx(&self) -> Option<u64> {
+        self.is_valid(OFF_RDX).then_some(self.rdx)
+    }
+
+    fn sw_exit_info_2(&self) -> Option<u64> {
+        self.is_valid(OFF_SW_EXIT_INFO_2)
+            .then_some(self.sw_exit_info_2)
+    }
+
+    fn write_buffer_u64(&mut self, offset: usize, value: u64) -> Result<(), GhcbError> {
+        self.write_buffer(&value, offset as isize)
+            .map_err(|_| GhcbError::InvalidParameter)
+    }
+
+    fn read_buffer_u64(&self, offset: usize) -> Result<u64, GhcbError> {
+        self.read_buffer(offset as isize)
+            .map_err(|_| GhcbError::InvalidResponse)
+    }
+
+    fn set_sw_scratch_buffer(&mut self) {
+        let buffer_va = VirtAddr::from_ptr(self.buffer.as_ptr());
+        let buffer_pa: u64 = u64::from(virt_to_phys(buffer_va));
+        self.set_sw_scratch(buffer_pa);
+    }
+
+    fn count_psc_pages(&self, pages: u64) {
+        this_cpu().counters().count_psc_pages(pages);
+    }
+
+    fn call(
+        &mut self,
+        exit_code: u64,
+        exit_info_1: u64,
+        exit_info_2: u64,
+    ) -> Result<(), GhcbError> {
+        self.vmgexit(exit_code, exit_info_1, exit_info_2)
     }
 }
 
@@ -470,42 +1004,338 @@ unsafe impl<'a> Sync for GHCBIOPort<'a> {}
 
 impl<'a> IOPort for GHCBIOPort<'a> {
     fn outb(&self, port: u16, value: u8) {
-        let mut g = self.ghcb.borrow_mut();
-        let ret = g.ioio_out(port, GHCBIOSize::Size8, value as u64);
-        if let Err(()) = ret {
+        let ret = self.ghcb.borrow_mut().outb(port, value);
+        if ret.is_err() {
             request_termination_msr();
         }
     }
 
     fn inb(&self, port: u16) -> u8 {
-        let mut g = self.ghcb.borrow_mut();
-        let ret = g.ioio_in(port, GHCBIOSize::Size8);
-        match ret {
-            Ok(v) => (v & 0xff) as u8,
-            Err(_e) => {
-                request_termination_msr();
-                0
-            }
-        }
+        let ret = self.ghcb.borrow_mut().inb(port);
+        ret.unwrap_or_else(|_| {
+            request_termination_msr();
+            0
+        })
     }
 
     fn outw(&self, port: u16, value: u16) {
-        let mut g = self.ghcb.borrow_mut();
-        let ret = g.ioio_out(port, GHCBIOSize::Size16, value as u64);
-        if let Err(()) = ret {
+        let ret = self.ghcb.borrow_mut().outw(port, value);
+        if ret.is_err() {
             request_termination_msr();
         }
     }
 
     fn inw(&self, port: u16) -> u16 {
-        let mut g = self.ghcb.borrow_mut();
-        let ret = g.ioio_in(port, GHCBIOSize::Size16);
-        match ret {
-            Ok(v) => (v & 0xffff) as u16,
-            Err(_e) => {
-                request_termination_msr();
-                0
+        let ret = self.ghcb.borrow_mut().inw(port);
+        ret.unwrap_or_else(|_| {
+            request_termination_msr();
+            0
+        })
+    }
+
+    fn outl(&self, port: u16, value: u32) {
+        let ret = self.ghcb.borrow_mut().outl(port, value);
+        if ret.is_err() {
+            request_termination_msr();
+        }
+    }
+
+    fn inl(&self, port: u16) -> u32 {
+        let ret = self.ghcb.borrow_mut().inl(port);
+        ret.unwrap_or_else(|_| {
+            request_termination_msr();
+            0
+        })
+    }
+}
+
+#[cfg(test)]
+#[derive(Default)]
+pub(crate) struct MockGhcb {
+    rax: Option<u64>,
+    rcx: Option<u64>,
+    rdx: Option<u64>,
+    exit: Option<(u64, u64, u64)>,
+    exits: usize,
+    response: Option<(u64, u64)>,
+    pub(crate) error: Option<GhcbError>,
+    // Shared buffer in qwords, grown on write
+    buffer: Vec<u64>,
+    sw_scratch: bool,
+    sw_exit_info_2: Option<u64>,
+    // PSC entries the "hypervisor" processes per exit, 0 for all
+    psc_step: u16,
+    // APIC IDs of the APs created and not yet destroyed
+    pub(crate) aps: Vec<u64>,
+}
+
+#[cfg(test)]
+impl GhcbBackend for MockGhcb {
+    fn clear(&mut self) {
+        self.rax = None;
+        self.rcx = None;
+        self.rdx = None;
+    }
+
+    fn set_rax(&mut self, rax: u64) {
+        self.rax = Some(rax);
+    }
+
+    fn set_rcx(&mut self, rcx: u64) {
+        self.rcx = Some(rcx);
+    }
+
+    fn set_rdx(&mut self, rdx: u64) {
+        self.rdx = Some(rdx);
+    }
+
+    fn rax(&self) -> Option<u64> {
+        self.rax
+    }
+
+    fn rdx(&self) -> Option<u64> {
+        self.rdx
+    }
+
+    fn sw_exit_info_2(&self) -> Option<u64> {
+        self.sw_exit_info_2
+    }
+
+    fn write_buffer_u64(&mut self, offset: usize, value: u64) -> Result<(), GhcbError> {
+        if offset % 8 != 0 || offset + 8 > GHCB_BUFFER_SIZE {
+            return Err(GhcbError::InvalidParameter);
+        }
+        if self.buffer.len() <= offset / 8 {
+            self.buffer.resize(offset / 8 + 1, 0);
+        }
+        self.buffer[offset / 8] = value;
+        Ok(())
+    }
+
+    fn read_buffer_u64(&self, offset: usize) -> Result<u64, GhcbError> {
+        Ok(self.buffer.get(offset / 8).copied().unwrap_or(0))
+    }
+
+    fn set_sw_scratch_buffer(&mut self) {
+        self.sw_scratch = true;
+    }
+
+    fn call(
+        &mut self,
+        exit_code: u64,
+        exit_info_1: u64,
+        exit_info_2: u64,
+    ) -> Result<(), GhcbError> {
+        self.exit = Some((exit_code, exit_info_1, exit_info_2));
+        self.exits += 1;
+        if let Some(err) = self.error {
+            return Err(err);
+        }
+        if exit_code == GHCBExitCode::SNP_PSC && self.sw_exit_info_2.is_none() {
+            let (cur, end) = psc_header_entries(self.read_buffer_u64(0)?);
+            let next = match self.psc_step {
+                0 => end + 1,
+                step => (cur + step).min(end + 1),
+            };
+            self.write_buffer_u64(0, psc_header(next, end))?;
+        }
+        if exit_code == GHCBExitCode::AP_CREATE {
+            let apic_id = exit_info_1 >> 32;
+            let known = self.aps.iter().position(|&id| id == apic_id);
+            match (exit_info_1 & 0xffff, known) {
+                (1, None) => self.aps.push(apic_id),
+                (2, Some(i)) => {
+                    self.aps.remove(i);
+                }
+                _ => return Err(GhcbError::VmgexitError(1, 0)),
             }
         }
+        if let Some((rax, rdx)) = self.response {
+            self.rax = Some(rax);
+            self.rdx = Some(rdx);
+        }
+        Ok(())
     }
 }
+
+#[test]
+fn test_ghcb_wrmsr_encoding() {
+    let mut ghcb = MockGhcb::default();
+
+    GhcbBackend::wrmsr(&mut ghcb, 0x830, 0x1234_5678_9abc_def0).unwrap();
+    assert_eq!(ghcb.exit, Some((GHCBExitCode::MSR, 1, 0)));
+    assert_eq!(ghcb.rcx, Some(0x830));
+    assert_eq!(ghcb.rax, Some(0x9abc_def0));
+    assert_eq!(ghcb.rdx, Some(0x1234_5678));
+}
+
+#[test]
+fn test_ghcb_rdmsr_encoding() {
+    let mut ghcb = MockGhcb {
+        response: Some((0xffff_ffff_fee0_0900, 0x1)),
+        ..Default::default()
+    };
+
+    assert_eq!(GhcbBackend::rdmsr(&mut ghcb, 0x1b), Ok(0x1_fee0_0900));
+    assert_eq!(ghcb.exit, Some((GHCBExitCode::MSR, 0, 0)));
+    assert_eq!(ghcb.rcx, Some(0x1b));
+
+    // A response without result registers must not be taken as a value
+    let mut ghcb = MockGhcb::default();
+    assert_eq!(
+        GhcbBackend::rdmsr(&mut ghcb, 0x1b),
+        Err(GhcbError::InvalidResponse)
+    );
+
+    let mut ghcb = MockGhcb {
+        error: Some(GhcbError::VmgexitError(1, 0)),
+        ..Default::default()
+    };
+    assert_eq!(
+        GhcbBackend::rdmsr(&mut ghcb, 0x1b),
+        Err(GhcbError::VmgexitError(1, 0))
+    );
+}
+
+#[test]
+fn test_ghcb_ap_create_encoding() {
+    let mut ghcb = MockGhcb::default();
+
+    GhcbBackend::ap_create(&mut ghcb, PhysAddr::from(0x1234_5000u64), 7, 1, 0x1).unwrap();
+    assert_eq!(
+        ghcb.exit,
+        Some((GHCBExitCode::AP_CREATE, 1 | 1 << 16 | 7 << 32, 0x1234_5000))
+    );
+    assert_eq!(ghcb.rax, Some(0x1));
+    assert_eq!(ghcb.aps, [7]);
+
+    GhcbBackend::ap_destroy(&mut ghcb, 7).unwrap();
+    assert_eq!(ghcb.exit, Some((GHCBExitCode::AP_CREATE, 2 | 7 << 32, 0)));
+    assert!(ghcb.aps.is_empty());
+
+    // An AP which does not exist can't be destroyed
+    assert_eq!(
+        GhcbBackend::ap_destroy(&mut ghcb, 7),
+        Err(GhcbError::VmgexitError(1, 0))
+    );
+}
+
+#[test]
+fn test_ghcb_page_state_change() {
+    let entries: Vec<PscEntry> = (0..3u64)
+        .map(|i| {
+            PscEntry::new(
+                PhysAddr::from(0x10_0000 + i * 0x1000),
+                PageSize::Page4K,
+                PscOp::Private,
+            )
+        })
+        .collect();
+
+    // Two entries per exit, so the request is issued twice
+    let mut ghcb = MockGhcb {
+        psc_step: 2,
+        ..Default::default()
+    };
+    GhcbBackend::page_state_change(&mut ghcb, &entries).unwrap();
+    assert_eq!(ghcb.exits, 2);
+    assert_eq!(ghcb.exit, Some((GHCBExitCode::SNP_PSC, 0, 0)));
+    assert!(ghcb.sw_scratch);
+    assert_eq!(ghcb.buffer[0], psc_header(3, 2));
+    assert_eq!(
+        ghcb.buffer[1..],
+        [
+            0x10_0000 | PSC_OP_PRIVATE,
+            0x10_1000 | PSC_OP_PRIVATE,
+            0x10_2000 | PSC_OP_PRIVATE
+        ]
+    );
+
+    let mut ghcb = MockGhcb {
+        sw_exit_info_2: Some(0x1_0000_0002),
+        ..Default::default()
+    };
+    assert_eq!(
+        GhcbBackend::page_state_change(&mut ghcb, &entries),
+        Err(GhcbError::VmgexitError(0, 0x1_0000_0002))
+    );
+
+    // A hypervisor which reports success without processing any entries
+    let mut ghcb = MockGhcb {
+        sw_exit_info_2: Some(0),
+        ..Default::default()
+    };
+    assert_eq!(
+        GhcbBackend::page_state_change(&mut ghcb, &entries),
+        Err(GhcbError::InvalidResponse)
+    );
+    assert_eq!(ghcb.exits, 1);
+}
+
+#[test]
+fn test_ghcb_select_version() {
+    let info = |min, max| SevInfo {
+        min_version: min,
+        max_version: max,
+        c_bit_pos: 51,
+    };
+
+    assert_eq!(select_version(&info(1, 2)), Ok(2));
+    assert_eq!(select_version(&info(2, 5)), Ok(GHCB_VERSION_MAX));
+    assert_eq!(
+        select_version(&info(1, 1)),
+        Err(GhcbError::UnsupportedVersion(1, 1))
+    );
+    assert_eq!(GHCBExitCode::min_version(GHCBExitCode::MSR), 1);
+    assert_eq!(
+        GHCBExitCode::min_version(GHCBExitCode::AP_CREATE),
+        GHCB_VERSION_SNP
+    );
+}
+
+#[test]
+fn test_ioio_exitinfo() {
+    assert_eq!(ioio_exitinfo(0x3f8, GHCBIOSize::Size8, false), 0x03f8_0010);
+    assert_eq!(ioio_exitinfo(0x3f8, GHCBIOSize::Size8, true), 0x03f8_0011);
+    assert_eq!(ioio_exitinfo(0x510, GHCBIOSize::Size16, false), 0x0510_0020);
+    assert_eq!(ioio_exitinfo(0xcfc, GHCBIOSize::Size32, true), 0x0cfc_0041);
+}
+
+#[test]
+fn test_psc_entry_encoding() {
+    let entry = PscEntry::new(
+        PhysAddr::from(0x1234_5000usize),
+        PageSize::Page4K,
+        PscOp::Private,
+    );
+    assert_eq!(entry.gfn, 0x12345);
+    assert_eq!(entry.encode(), Ok(0x0010_0000_1234_5000));
+
+    let entry = PscEntry::new(
+        PhysAddr::from(0x4020_0000usize),
+        PageSize::Page2M,
+        PscOp::Shared,
+    );
+    assert_eq!(entry.encode(), Ok(0x0120_0000_4020_0000));
+
+    let entry = PscEntry {
+        gfn: 0,
+        page_size: PageSize::Page1G,
+        op: PscOp::Private,
+    };
+    assert_eq!(entry.encode(), Err(GhcbError::InvalidParameter));
+}
+
+#[test]
+fn test_guest_request_status() {
+    assert_eq!(guest_request_status(0), Ok(()));
+    assert_eq!(guest_request_status(2 << 32), Err(GhcbError::Throttled));
+    assert_eq!(
+        guest_request_status(0x16),
+        Err(GhcbError::FirmwareError(0x16))
+    );
+    assert_eq!(
+        guest_request_status(1 << 32),
+        Err(GhcbError::VmgexitError(0, 1 << 32))
+    );
+}
@@ -0,0 +1,246 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2026 SUSE LLC
+//
+// Author: agent <agent@local>
+
+use crate::types::PAGE_SIZE;
+
+pub const GUEST_MSG_HDR_SIZE: usize = 0x60;
+pub const GUEST_MSG_PAYLOAD_SIZE: usize = PAGE_SIZE - GUEST_MSG_HDR_SIZE;
+
+pub const GUEST_MSG_AUTHTAG_SIZE: usize = 32;
+
+// SNP guest message header as defined in the SEV-SNP firmware ABI. All fields
+// are naturally aligned, so no packing is needed.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct GuestMsgHdr {
+    authtag: [u8; GUEST_MSG_AUTHTAG_SIZE],
+    msg_seqno: u64,
+    rsvd1: [u8; 8],
+    algo: u8,
+    hdr_version: u8,
+    hdr_sz: u16,
+    msg_type: u8,
+    msg_version: u8,
+    msg_sz: u16,
+    rsvd2: u32,
+    msg_vmpck: u8,
+    rsvd3: [u8; 35],
+}
+
+// A guest message occupies one page. Request and response messages are
+// exchanged with the firmware through pages shared with the hypervisor.
+#[repr(C, align(4096))]
+pub struct GuestMsg {
+    hdr: GuestMsgHdr,
+    payload: [u8; GUEST_MSG_PAYLOAD_SIZE],
+}
+
+impl GuestMsg {
+    pub const fn new() -> Self {
+        GuestMsg {
+            hdr: GuestMsgHdr {
+                authtag: [0; GUEST_MSG_AUTHTAG_SIZE],
+                msg_seqno: 0,
+                rsvd1: [0; 8],
+                algo: 0,
+                hdr_version: 0,
+                hdr_sz: 0,
+                msg_type: 0,
+                msg_version: 0,
+                msg_sz: 0,
+                rsvd2: 0,
+                msg_vmpck: 0,
+                rsvd3: [0; 35],
+            },
+            payload: [0; GUEST_MSG_PAYLOAD_SIZE],
+        }
+    }
+
+    pub fn clear(&mut self) {
+        *self = GuestMsg::new();
+    }
+
+    pub fn authtag(&self) -> &[u8; GUEST_MSG_AUTHTAG_SIZE] {
+        &self.hdr.authtag
+    }
+
+    pub fn set_authtag(&mut self, tag: &[u8; GUEST_MSG_AUTHTAG_SIZE]) {
+        self.hdr.authtag = *tag;
+    }
+
+    pub fn seqno(&self) -> u64 {
+        self.hdr.msg_seqno
+    }
+
+    pub fn set_seqno(&mut self, seqno: u64) {
+        self.hdr.msg_seqno = seqno;
+    }
+
+    pub fn algo(&self) -> u8 {
+        self.hdr.algo
+    }
+
+    pub fn hdr_version(&self) -> u8 {
+        self.hdr.hdr_version
+    }
+
+    pub fn msg_type(&self) -> u8 {
+        self.hdr.msg_type
+    }
+
+    pub fn msg_version(&self) -> u8 {
+        self.hdr.msg_version
+    }
+
+    pub fn msg_size(&self) -> usize {
+        self.hdr.msg_sz as usize
+    }
+
+    pub fn vmpck(&self) -> u8 {
+        self.hdr.msg_vmpck
+    }
+
+    // Fills in the header fields of a request, the sequence number and the
+    // authentication tag are set separately.
+    pub fn set_header(
+        &mut self,
+        algo: u8,
+        msg_type: u8,
+        msg_version: u8,
+        msg_size: usize,
+        vmpck: u8,
+    ) -> Result<(), ()> {
+        if msg_size > GUEST_MSG_PAYLOAD_SIZE {
+            return Err(());
+        }
+
+        self.hdr.algo = algo;
+        self.hdr.hdr_version = 1;
+        self.hdr.hdr_sz = GUEST_MSG_HDR_SIZE as u16;
+        self.hdr.msg_type = msg_type;
+        self.hdr.msg_version = msg_version;
+        self.hdr.msg_sz = msg_size as u16;
+        self.hdr.msg_vmpck = vmpck;
+
+        Ok(())
+    }
+
+    // The header bytes from algo onwards, which are authenticated as
+    // additional data of the AEAD
+    pub fn aad(&self) -> [u8; 0x30] {
+        let hdr = &self.hdr;
+        let mut aad = [0u8; 0x30];
+
+        aad[0] = hdr.algo;
+        aad[1] = hdr.hdr_version;
+        aad[2..4].copy_from_slice(&hdr.hdr_sz.to_le_bytes());
+        aad[4] = hdr.msg_type;
+        aad[5] = hdr.msg_version;
+        aad[6..8].copy_from_slice(&hdr.msg_sz.to_le_bytes());
+        aad[8..12].copy_from_slice(&hdr.rsvd2.to_le_bytes());
+        aad[12] = hdr.msg_vmpck;
+        aad[13..].copy_from_slice(&hdr.rsvd3);
+
+        aad
+    }
+
+    pub fn payload(&self) -> &[u8; GUEST_MSG_PAYLOAD_SIZE] {
+        &self.payload
+    }
+
+    pub fn payload_mut(&mut self) -> &mut [u8; GUEST_MSG_PAYLOAD_SIZE] {
+        &mut self.payload
+    }
+}
+
+impl Default for GuestMsg {
+    fn default() -> Self {
+        GuestMsg::new()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SeqnoExhausted;
+
+// Message sequence numbers of one VMPCK. They are used as AES-GCM IVs, so a
+// number must never be used twice with the same key. Each request takes two
+// numbers, one for the request and the following one for the response.
+// Instead of wrapping around, the counter stays exhausted and the VMPCK can
+// not be used anymore.
+#[derive(Debug)]
+pub struct MsgSeqno {
+    next: Option<u64>,
+}
+
+impl MsgSeqno {
+    pub const fn new() -> Self {
+        MsgSeqno { next: Some(1) }
+    }
+
+    // Returns the sequence number for the next request, the response uses
+    // the one after it. The number is only used up by advance().
+    pub fn current(&self) -> Result<u64, SeqnoExhausted> {
+        match self.next {
+            Some(seqno) if seqno < u64::MAX => Ok(seqno),
+            _ => Err(SeqnoExhausted),
+        }
+    }
+
+    // To be called once the firmware has seen the request with the current
+    // sequence number
+    pub fn advance(&mut self) {
+        self.next = self.next.and_then(|seqno| seqno.checked_add(2));
+    }
+}
+
+impl Default for MsgSeqno {
+    fn default() -> Self {
+        MsgSeqno::new()
+    }
+}
+
+#[cfg(test)]
+use core::mem::{align_of, size_of};
+
+#[test]
+fn test_guest_msg_layout() {
+    assert_eq!(size_of::<GuestMsgHdr>(), GUEST_MSG_HDR_SIZE);
+    assert_eq!(size_of::<GuestMsg>(), PAGE_SIZE);
+    assert_eq!(align_of::<GuestMsg>(), PAGE_SIZE);
+
+    let mut msg = GuestMsg::new();
+    msg.set_header(1, 5, 1, 0x20, 0).unwrap();
+    assert_eq!(
+        &msg.aad()[..8],
+        &[0x01, 0x01, 0x60, 0x00, 0x05, 0x01, 0x20, 0x00]
+    );
+    assert!(msg
+        .set_header(1, 5, 1, GUEST_MSG_PAYLOAD_SIZE + 1, 0)
+        .is_err());
+}
+
+#[test]
+fn test_msg_seqno_exhausted() {
+    let mut seqno = MsgSeqno::new();
+    assert_eq!(seqno.current(), Ok(1));
+    // Not used up until the request went through
+    assert_eq!(seqno.current(), Ok(1));
+    seqno.advance();
+    assert_eq!(seqno.current(), Ok(3));
+
+    seqno.next = Some(u64::MAX - 2);
+    assert_eq!(seqno.current(), Ok(u64::MAX - 2));
+    seqno.advance();
+    // No room for the response of another request
+    assert_eq!(seqno.current(), Err(SeqnoExhausted));
+    seqno.advance();
+    assert_eq!(seqno.current(), Err(SeqnoExhausted));
+
+    seqno.next = Some(u64::MAX - 1);
+    assert_eq!(seqno.current(), Ok(u64::MAX - 1));
+    seqno.advance();
+    assert_eq!(seqno.current(), Err(SeqnoExhausted));
+}
@@ -4,16 +4,82 @@
 //
 // Author: Joerg Roedel <jroedel@suse.de>
 
+pub mod attestation;
+pub mod caa;
 pub mod ghcb;
+pub mod guest_msg;
 pub mod msr_protocol;
+pub mod rmp;
 pub mod secrets_page;
 pub mod status;
 pub mod vmsa;
 
 pub mod utils;
 
+pub use rmp::{rmpadjust, RmpError, RmpPerms};
 pub use status::sev_status_init;
 pub use status::sev_status_verify;
 pub use status::{sev_es_enabled, sev_snp_enabled};
-pub use utils::{pvalidate, pvalidate_range, SevSnpError};
+pub use utils::{pvalidate, pvalidate_range, PvalidateError, SevSnpError};
 pub use utils::{rmp_adjust, RMPFlags};
+
+use crate::cpu::msr::{read_msr, SEV_STATUS};
+use bitflags::bitflags;
+
+// SEV features as found in the VMSA, which are the SEV_STATUS MSR bits
+// shifted down by 2.
+bitflags! {
+    pub struct SevFeatures: u64 {
+        const SNP_ACTIVE        = 1 << 0;
+        const VTOM              = 1 << 1;
+        const REFLECT_VC        = 1 << 2;
+        const RESTRICT_INJ      = 1 << 3;
+        const ALTERNATE_INJ     = 1 << 4;
+        const DEBUG_SWAP        = 1 << 5;
+        const PREVENT_HOST_IBS  = 1 << 6;
+        const BTB_ISOLATION     = 1 << 7;
+        const VMPL_SSS          = 1 << 8;
+        const SECURE_TSC        = 1 << 9;
+        const VMGEXIT_PARAM     = 1 << 10;
+        const IBS_VIRT          = 1 << 12;
+        const VMSA_REG_PROT     = 1 << 14;
+        const SMT_PROTECTION    = 1 << 15;
+    }
+}
+
+pub fn current_sev_features() -> SevFeatures {
+    SevFeatures::from_bits_truncate(read_msr(SEV_STATUS) >> 2)
+}
+
+// Check raw VMSA sev_features against the features active for the SVSM. A
+// VMSA must not request unknown features or features the platform does not
+// provide to this guest.
+pub fn check_sev_features(features: u64, current: SevFeatures) -> Result<SevFeatures, u64> {
+    let features = SevFeatures::from_bits(features).ok_or(features)?;
+    let missing = features - current;
+
+    if missing.is_empty() {
+        Ok(features)
+    } else {
+        Err(missing.bits())
+    }
+}
+
+#[test]
+fn test_check_sev_features() {
+    let current = SevFeatures::SNP_ACTIVE | SevFeatures::RESTRICT_INJ;
+
+    assert_eq!(
+        check_sev_features(SevFeatures::SNP_ACTIVE.bits(), current),
+        Ok(SevFeatures::SNP_ACTIVE)
+    );
+    assert_eq!(
+        check_sev_features(
+            (SevFeatures::SNP_ACTIVE | SevFeatures::SECURE_TSC).bits(),
+            current
+        ),
+        Err(SevFeatures::SECURE_TSC.bits())
+    );
+    // Reserved bit 11
+    assert_eq!(check_sev_features(1 << 11, current), Err(1 << 11));
+}
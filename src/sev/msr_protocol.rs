@@ -5,7 +5,7 @@
 // Author: Joerg Roedel <jroedel@suse.de>
 
 use crate::cpu::msr::{read_msr, write_msr, SEV_GHCB};
-use crate::types::{PhysAddr, VirtAddr};
+use crate::types::PhysAddr;
 
 use super::utils::raw_vmgexit;
 
@@ -22,27 +22,54 @@ impl GHCBMsr {
     pub const TERM_REQ: u64 = 0x100;
 }
 
-pub fn register_ghcb_gpa_msr(addr: VirtAddr) -> Result<(), ()> {
-    let mut info: u64 = addr as u64;
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SevInfo {
+    pub min_version: u16,
+    pub max_version: u16,
+    pub c_bit_pos: u8,
+}
 
-    info |= GHCBMsr::SNP_REG_GHCB_GPA_REQ;
-    write_msr(SEV_GHCB, info);
+fn decode_sev_info(info: u64) -> Result<SevInfo, ()> {
+    if (info & 0xfffu64) != GHCBMsr::SEV_INFO_RESP {
+        return Err(());
+    }
+
+    Ok(SevInfo {
+        min_version: ((info >> 32) & 0xffff) as u16,
+        max_version: ((info >> 48) & 0xffff) as u16,
+        c_bit_pos: ((info >> 24) & 0xff) as u8,
+    })
+}
+
+// Ask the hypervisor for the range of GHCB protocol versions it supports
+pub fn sev_info_msr() -> Result<SevInfo, ()> {
+    write_msr(SEV_GHCB, GHCBMsr::SEV_INFO_REQ);
     raw_vmgexit();
-    info = read_msr(SEV_GHCB);
+    decode_sev_info(read_msr(SEV_GHCB))
+}
 
+fn decode_ghcb_gpa_resp(info: u64) -> Result<PhysAddr, ()> {
     if (info & 0xfffu64) != GHCBMsr::SNP_REG_GHCB_GPA_RESP {
         return Err(());
     }
 
-    if (info & !0xfffu64) == (addr as u64) {
-        Ok(())
-    } else {
-        Err(())
-    }
+    Ok(PhysAddr::from(info & !0xfffu64))
+}
+
+// Returns the GHCB GPA confirmed by the hypervisor, which the caller must
+// compare against the requested one
+pub fn register_ghcb_gpa_msr(addr: PhysAddr) -> Result<PhysAddr, ()> {
+    let mut info: u64 = u64::from(addr);
+
+    info |= GHCBMsr::SNP_REG_GHCB_GPA_REQ;
+    write_msr(SEV_GHCB, info);
+    raw_vmgexit();
+
+    decode_ghcb_gpa_resp(read_msr(SEV_GHCB))
 }
 
 fn set_page_valid_status_msr(addr: PhysAddr, valid: bool) -> Result<(), ()> {
-    let mut info: u64 = (addr as u64) & 0x000f_ffff_ffff_f000;
+    let mut info: u64 = u64::from(addr) & 0x000f_ffff_ffff_f000;
 
     if valid {
         info |= 1u64 << 52;
@@ -74,10 +101,67 @@ pub fn invalidate_page_msr(addr: PhysAddr) -> Result<(), ()> {
     set_page_valid_status_msr(addr, false)
 }
 
-pub fn request_termination_msr() {
-    let info: u64 = GHCBMsr::TERM_REQ;
+// Reason set 0 holds the reason codes defined by the GHCB specification
+pub const GHCB_TERM_SET_GENERAL: u8 = 0;
+pub const GHCB_TERM_GENERAL: u8 = 0;
+pub const GHCB_TERM_UNSUPPORTED_PROTOCOL: u8 = 1;
+pub const GHCB_TERM_UNSUPPORTED_FEATURES: u8 = 2;
+
+// SVSM specific reason codes. Reason set 1 is taken by the Linux guest, so a
+// separate set keeps hypervisor logs from decoding SVSM codes as Linux ones.
+pub const GHCB_TERM_SET_SVSM: u8 = 3;
+// Launching APs via AP_CREATE failed repeatedly
+pub const SVSM_TERM_AP_CREATE: u8 = 1;
+// Result of the selftest feature, it terminates the guest in either case
+pub const SVSM_TERM_SELFTEST_FAIL: u8 = 2;
+pub const SVSM_TERM_SELFTEST_PASS: u8 = 3;
+
+// The reason set is a 4-bit field
+fn termination_request(reason_set: u8, reason_code: u8) -> u64 {
+    debug_assert!(reason_set <= 0xf);
+    GHCBMsr::TERM_REQ | (u64::from(reason_set) << 12) | (u64::from(reason_code) << 16)
+}
 
-    write_msr(SEV_GHCB, info);
+// The hypervisor is not expected to resume the guest after a termination
+// request, loop in case it does anyway.
+pub fn ghcb_terminate(reason_set: u8, reason_code: u8) -> ! {
+    write_msr(SEV_GHCB, termination_request(reason_set, reason_code));
     raw_vmgexit();
     loop {}
 }
+
+pub fn request_termination_msr() {
+    ghcb_terminate(GHCB_TERM_SET_GENERAL, GHCB_TERM_GENERAL);
+}
+
+#[test]
+fn test_decode_sev_info() {
+    let info = decode_sev_info(0x0002_0001_3300_0001).unwrap();
+    assert_eq!(info.min_version, 1);
+    assert_eq!(info.max_version, 2);
+    assert_eq!(info.c_bit_pos, 0x33);
+
+    assert!(decode_sev_info(0x0002_0001_3300_0013).is_err());
+}
+
+#[test]
+fn test_decode_ghcb_gpa_resp() {
+    assert_eq!(
+        decode_ghcb_gpa_resp(0x1234_5013),
+        Ok(PhysAddr::from(0x1234_5000u64))
+    );
+    assert!(decode_ghcb_gpa_resp(0x1234_5012).is_err());
+}
+
+#[test]
+fn test_termination_request() {
+    assert_eq!(
+        termination_request(GHCB_TERM_SET_GENERAL, GHCB_TERM_GENERAL),
+        0x100
+    );
+    assert_eq!(
+        termination_request(GHCB_TERM_SET_SVSM, SVSM_TERM_AP_CREATE),
+        0x0001_3100
+    );
+    assert_eq!(termination_request(0xf, 0xff), 0x00ff_f100);
+}
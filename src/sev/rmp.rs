@@ -0,0 +1,81 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2026 SUSE LLC
+//
+// Author: agent <agent@local>
+
+use super::utils::{rmp_adjust, RMPFlags, SevSnpError};
+use crate::sev::vmsa::VMPL_MAX;
+use crate::types::VirtAddr;
+use bitflags::bitflags;
+
+bitflags! {
+    pub struct RmpPerms: u8 {
+        const READ              = 1 << 0;
+        const WRITE             = 1 << 1;
+        const EXEC_USER         = 1 << 2;
+        const EXEC_SUPERVISOR   = 1 << 3;
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RmpError {
+    // Invalid address, VMPL or permission combination, or the instruction
+    // raised an exception
+    Input,
+    // The target VMPL is not less privileged than the current one, or the
+    // requested permissions exceed the current VMPL's permissions
+    Permission,
+    // The page is mapped as 2M in the RMP
+    SizeMismatch,
+    InvalidVmpl(u8),
+}
+
+impl From<SevSnpError> for RmpError {
+    fn from(err: SevSnpError) -> Self {
+        match err {
+            SevSnpError::FAIL_PERMISSION(_) => RmpError::Permission,
+            SevSnpError::FAIL_SIZEMISMATCH(_) => RmpError::SizeMismatch,
+            SevSnpError::FAIL_INPUT(_) | SevSnpError::FAIL_UNCHANGED(_) => RmpError::Input,
+        }
+    }
+}
+
+fn rmp_flags(vmpl: u8, perms: RmpPerms, vmsa: bool) -> Result<RMPFlags, RmpError> {
+    if vmpl as usize >= VMPL_MAX {
+        return Err(RmpError::InvalidVmpl(vmpl));
+    }
+
+    let mut flags = RMPFlags::from_bits_truncate(vmpl as u64);
+    flags.set(RMPFlags::READ, perms.contains(RmpPerms::READ));
+    flags.set(RMPFlags::WRITE, perms.contains(RmpPerms::WRITE));
+    flags.set(RMPFlags::X_USER, perms.contains(RmpPerms::EXEC_USER));
+    flags.set(RMPFlags::X_SUPER, perms.contains(RmpPerms::EXEC_SUPERVISOR));
+    flags.set(RMPFlags::BIT_VMSA, vmsa);
+
+    Ok(flags)
+}
+
+// Sets the permissions vmpl has on the 4k page at va. With vmsa set the page
+// is turned into a VMSA page for vmpl.
+pub fn rmpadjust(va: VirtAddr, vmpl: u8, perms: RmpPerms, vmsa: bool) -> Result<(), RmpError> {
+    let flags = rmp_flags(vmpl, perms, vmsa)?;
+
+    rmp_adjust(va, flags, false).map_err(RmpError::from)
+}
+
+#[test]
+fn test_rmp_flags() {
+    let all = RmpPerms::all();
+
+    assert_eq!(
+        rmp_flags(1, all, false),
+        Ok(RMPFlags::VMPL1 | RMPFlags::RWX)
+    );
+    assert_eq!(
+        rmp_flags(2, RmpPerms::READ, true),
+        Ok(RMPFlags::VMPL2 | RMPFlags::VMSA)
+    );
+    assert_eq!(rmp_flags(3, RmpPerms::empty(), false), Ok(RMPFlags::VMPL3));
+    assert_eq!(rmp_flags(4, all, false), Err(RmpError::InvalidVmpl(4)));
+}
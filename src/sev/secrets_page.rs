@@ -4,36 +4,368 @@
 //
 // Author: Joerg Roedel <jroedel@suse.de>
 
-use crate::types::VirtAddr;
+extern crate alloc;
+
+use crate::cpu::tsc::{scale_tsc_khz, ticks_to_ns, tsc_nominal_khz};
+use crate::crypto::gcm::{Aes256Gcm, GCM_IV_SIZE};
+use crate::mm::{map_phys, MapError, MappingFlags};
+use crate::types::PhysAddr;
+use alloc::vec::Vec;
+use core::mem::{offset_of, size_of};
+use core::ptr;
+use core::sync::atomic::{compiler_fence, Ordering};
+
+// Secrets page layout versions this code understands
+const SECRETS_PAGE_VERSION_MIN: u32 = 2;
+const SECRETS_PAGE_VERSION_MAX: u32 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SecretsError {
+    // Carries the version found in the page
+    UnsupportedVersion(u32),
+    // The page could not be mapped for copying
+    Map(MapError),
+    // The source address is not page aligned
+    Misaligned(PhysAddr),
+}
 
-#[derive(Copy, Clone)]
 #[repr(C, packed)]
 pub struct SecretsPage {
-    pub version: u32,
-    pub gctxt: u32,
-    pub fms: u32,
+    version: u32,
+    gctxt: u32,
+    fms: u32,
     reserved_00c: u32,
-    pub gosvw: [u8; 16],
-    pub vmpck0: [u8; 32],
-    pub vmpck1: [u8; 32],
-    pub vmpck2: [u8; 32],
-    pub vmpck3: [u8; 32],
+    gosvw: [u8; 16],
+    vmpck0: [u8; 32],
+    vmpck1: [u8; 32],
+    vmpck2: [u8; 32],
+    vmpck3: [u8; 32],
     reserved_0a0: [u8; 96],
-    pub vmsa_tweak_bmp: [u64; 8],
-    pub svsm_base: u64,
-    pub svsm_size: u64,
-    pub svsm_caa: u64,
-    pub svsm_max_version: u32,
-    pub svsm_guest_vmpl: u8,
+    vmsa_tweak_bmp: [u64; 8],
+    svsm_base: u64,
+    svsm_size: u64,
+    svsm_caa: u64,
+    svsm_max_version: u32,
+    svsm_guest_vmpl: u8,
     reserved_15d: [u8; 3],
-    pub tsc_factor: u32,
+    tsc_factor: u32,
     reserved_164: [u8; 3740],
 }
 
-pub fn copy_secrets_page(target: &mut SecretsPage, source: VirtAddr) {
-    let table = source as *const SecretsPage;
+// The layout is defined by the SEV-SNP firmware ABI and the page is copied
+// as a whole, so any change to it must fail to build
+const _: () = assert!(size_of::<SecretsPage>() == 4096);
+const _: () = assert!(offset_of!(SecretsPage, gosvw) == 0x10);
+const _: () = assert!(offset_of!(SecretsPage, vmpck0) == 0x20);
+const _: () = assert!(offset_of!(SecretsPage, vmpck1) == 0x40);
+const _: () = assert!(offset_of!(SecretsPage, vmpck2) == 0x60);
+const _: () = assert!(offset_of!(SecretsPage, vmpck3) == 0x80);
+const _: () = assert!(offset_of!(SecretsPage, vmsa_tweak_bmp) == 0x100);
+const _: () = assert!(offset_of!(SecretsPage, svsm_base) == 0x140);
+const _: () = assert!(offset_of!(SecretsPage, svsm_size) == 0x148);
+const _: () = assert!(offset_of!(SecretsPage, svsm_caa) == 0x150);
+const _: () = assert!(offset_of!(SecretsPage, svsm_max_version) == 0x158);
+const _: () = assert!(offset_of!(SecretsPage, svsm_guest_vmpl) == 0x15c);
+const _: () = assert!(offset_of!(SecretsPage, tsc_factor) == 0x160);
+
+pub(crate) fn zero_volatile(start: *mut u8, len: usize) {
+    for i in 0..len {
+        unsafe { ptr::write_volatile(start.add(i), 0) };
+    }
+}
+
+// Single pass over the source, which the compiler can neither elide nor
+// split into multiple reads of the same byte
+fn copy_volatile(dst: *mut u8, src: *const u8, len: usize) {
+    for i in 0..len {
+        unsafe { ptr::write_volatile(dst.add(i), ptr::read_volatile(src.add(i))) };
+    }
+}
+
+// The structure is packed, so fields must not be borrowed. All accessors
+// copy by value with unaligned reads.
+impl SecretsPage {
+    pub fn version(&self) -> u32 {
+        unsafe { ptr::addr_of!(self.version).read_unaligned() }
+    }
+
+    pub fn validate(&self) -> Result<(), SecretsError> {
+        let version = self.version();
+
+        if !(SECRETS_PAGE_VERSION_MIN..=SECRETS_PAGE_VERSION_MAX).contains(&version) {
+            return Err(SecretsError::UnsupportedVersion(version));
+        }
+
+        Ok(())
+    }
+
+    pub fn gctxt(&self) -> u32 {
+        unsafe { ptr::addr_of!(self.gctxt).read_unaligned() }
+    }
+
+    pub fn fms(&self) -> u32 {
+        unsafe { ptr::addr_of!(self.fms).read_unaligned() }
+    }
+
+    pub fn vmpck(&self, idx: usize) -> [u8; 32] {
+        unsafe {
+            match idx {
+                0 => ptr::addr_of!(self.vmpck0).read_unaligned(),
+                1 => ptr::addr_of!(self.vmpck1).read_unaligned(),
+                2 => ptr::addr_of!(self.vmpck2).read_unaligned(),
+                3 => ptr::addr_of!(self.vmpck3).read_unaligned(),
+                _ => panic!("Invalid VMPCK index {}", idx),
+            }
+        }
+    }
+
+    pub fn vmpck_key(&self, idx: usize) -> VmpckKey {
+        let mut key = self.vmpck(idx);
+        let ret = VmpckKey::new(&key);
+        zero_volatile(key.as_mut_ptr(), key.len());
+        compiler_fence(Ordering::SeqCst);
+        ret
+    }
+
+    pub fn vmsa_tweak_bmp(&self) -> [u64; 8] {
+        unsafe { ptr::addr_of!(self.vmsa_tweak_bmp).read_unaligned() }
+    }
+
+    pub fn svsm_base(&self) -> u64 {
+        unsafe { ptr::addr_of!(self.svsm_base).read_unaligned() }
+    }
+
+    pub fn svsm_size(&self) -> u64 {
+        unsafe { ptr::addr_of!(self.svsm_size).read_unaligned() }
+    }
+
+    pub fn svsm_caa(&self) -> u64 {
+        unsafe { ptr::addr_of!(self.svsm_caa).read_unaligned() }
+    }
+
+    pub fn svsm_max_version(&self) -> u32 {
+        unsafe { ptr::addr_of!(self.svsm_max_version).read_unaligned() }
+    }
+
+    pub fn svsm_guest_vmpl(&self) -> u8 {
+        self.svsm_guest_vmpl
+    }
+
+    pub fn tsc_factor(&self) -> u32 {
+        unsafe { ptr::addr_of!(self.tsc_factor).read_unaligned() }
+    }
+
+    // Guest TSC frequency under Secure TSC for the given nominal frequency
+    pub fn tsc_scale_khz(&self, khz: u64) -> u64 {
+        scale_tsc_khz(khz, self.tsc_factor())
+    }
+
+    // Convert raw Secure TSC ticks to nanoseconds
+    pub fn tsc_scale_ns(&self, raw: u64) -> u64 {
+        ticks_to_ns(raw, self.tsc_scale_khz(tsc_nominal_khz()))
+    }
+
+    // Fill in the SVSM specific fields for the guest
+    pub fn set_svsm_data(&mut self, base: u64, size: u64, caa: u64, max_version: u32, vmpl: u8) {
+        unsafe {
+            ptr::addr_of_mut!(self.svsm_base).write_unaligned(base);
+            ptr::addr_of_mut!(self.svsm_size).write_unaligned(size);
+            ptr::addr_of_mut!(self.svsm_caa).write_unaligned(caa);
+            ptr::addr_of_mut!(self.svsm_max_version).write_unaligned(max_version);
+        }
+        self.svsm_guest_vmpl = vmpl;
+    }
+
+    // Wipe a single VMPCK, used to withhold keys from less privileged levels
+    pub fn clear_vmpck_idx(&mut self, idx: usize) {
+        let key = match idx {
+            0 => ptr::addr_of_mut!(self.vmpck0),
+            1 => ptr::addr_of_mut!(self.vmpck1),
+            2 => ptr::addr_of_mut!(self.vmpck2),
+            3 => ptr::addr_of_mut!(self.vmpck3),
+            _ => panic!("Invalid VMPCK index {}", idx),
+        };
+
+        zero_volatile(key.cast::<u8>(), 32);
+        compiler_fence(Ordering::SeqCst);
+    }
+
+    // Wipe the VMPCKs, e.g. once a key derived from them is all that is
+    // needed anymore
+    pub fn clear_vmpck(&mut self) {
+        zero_volatile(ptr::addr_of_mut!(self.vmpck0).cast::<u8>(), 32);
+        zero_volatile(ptr::addr_of_mut!(self.vmpck1).cast::<u8>(), 32);
+        zero_volatile(ptr::addr_of_mut!(self.vmpck2).cast::<u8>(), 32);
+        zero_volatile(ptr::addr_of_mut!(self.vmpck3).cast::<u8>(), 32);
+        compiler_fence(Ordering::SeqCst);
+    }
+}
+
+// Make sure no key material stays behind in memory
+impl Drop for SecretsPage {
+    fn drop(&mut self) {
+        self.clear_vmpck();
+        zero_volatile(ptr::addr_of_mut!(self.gosvw).cast::<u8>(), 16);
+        compiler_fence(Ordering::SeqCst);
+    }
+}
+
+// A VMPCK used to protect guest messages to the PSP. Deliberately not
+// Clone, so that the key material exists once and is wiped on drop.
+pub struct VmpckKey {
+    key: [u8; 32],
+}
+
+impl VmpckKey {
+    pub fn new(key: &[u8; 32]) -> Self {
+        VmpckKey { key: *key }
+    }
+
+    // The SNP guest message protocol uses the message sequence number,
+    // little endian and zero-extended, as the AES-GCM IV
+    fn iv(seqno: u64) -> [u8; GCM_IV_SIZE] {
+        let mut iv = [0u8; GCM_IV_SIZE];
+        iv[..8].copy_from_slice(&seqno.to_le_bytes());
+        iv
+    }
+
+    // Returns the ciphertext followed by the 16 byte authentication tag
+    pub fn aead_encrypt(&self, seqno: u64, aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        Aes256Gcm::new(&self.key).encrypt(&Self::iv(seqno), aad, plaintext)
+    }
+
+    // Expects the ciphertext followed by the authentication tag and fails
+    // when authentication does not succeed
+    pub fn aead_decrypt(&self, seqno: u64, aad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, ()> {
+        Aes256Gcm::new(&self.key).decrypt(&Self::iv(seqno), aad, ciphertext)
+    }
+}
+
+impl Drop for VmpckKey {
+    fn drop(&mut self) {
+        zero_volatile(self.key.as_mut_ptr(), self.key.len());
+        compiler_fence(Ordering::SeqCst);
+    }
+}
+
+// Maps the secrets page at source and copies it into target. The copy is
+// only trusted once it passed validation.
+pub fn copy_secrets_page(target: &mut SecretsPage, source: PhysAddr) -> Result<(), SecretsError> {
+    if !source.is_page_aligned() {
+        return Err(SecretsError::Misaligned(source));
+    }
+
+    let mapping = map_phys(source, size_of::<SecretsPage>(), MappingFlags::empty())
+        .map_err(SecretsError::Map)?;
+    let table = mapping.virt_addr().as_ptr::<u8>();
+
+    compiler_fence(Ordering::SeqCst);
+    copy_volatile(
+        (target as *mut SecretsPage).cast::<u8>(),
+        table,
+        size_of::<SecretsPage>(),
+    );
+    compiler_fence(Ordering::SeqCst);
+
+    let ret = target.validate();
+    if ret.is_err() {
+        // Don't keep keys from a page we can't interpret
+        target.clear_vmpck();
+    }
+
+    ret
+}
+
+#[cfg(test)]
+const VMPCK0_OFFSET: usize = 0x20;
+#[cfg(test)]
+const GOSVW_OFFSET: usize = 0x10;
+
+#[test]
+fn test_secrets_page_version() {
+    use core::mem::MaybeUninit;
+
+    let mut page = MaybeUninit::<SecretsPage>::zeroed();
+    let raw = page.as_mut_ptr();
+
+    unsafe {
+        assert_eq!((*raw).validate(), Err(SecretsError::UnsupportedVersion(0)));
+        ptr::addr_of_mut!((*raw).version).write_unaligned(SECRETS_PAGE_VERSION_MIN);
+        assert_eq!((*raw).validate(), Ok(()));
+        ptr::addr_of_mut!((*raw).version).write_unaligned(SECRETS_PAGE_VERSION_MAX + 1);
+        assert_eq!(
+            (*raw).validate(),
+            Err(SecretsError::UnsupportedVersion(
+                SECRETS_PAGE_VERSION_MAX + 1
+            ))
+        );
+    }
+}
+
+#[test]
+fn test_secrets_page_zeroed_on_drop() {
+    use core::mem::{size_of, MaybeUninit};
+
+    let mut page = MaybeUninit::<SecretsPage>::uninit();
+    let raw = page.as_mut_ptr().cast::<u8>();
+
+    unsafe {
+        ptr::write_bytes(raw, 0xff, size_of::<SecretsPage>());
+        ptr::drop_in_place(page.as_mut_ptr());
+
+        for i in 0..16 {
+            assert_eq!(raw.add(GOSVW_OFFSET + i).read(), 0);
+        }
+        for i in 0..4 * 32 {
+            assert_eq!(raw.add(VMPCK0_OFFSET + i).read(), 0);
+        }
+        // Fields outside of the key material are left alone
+        assert_eq!(raw.read(), 0xff);
+        assert_eq!(raw.add(VMPCK0_OFFSET + 4 * 32).read(), 0xff);
+    }
+}
+
+#[test]
+fn test_copy_secrets_page_misaligned() {
+    use core::mem::MaybeUninit;
+
+    let mut page = MaybeUninit::<SecretsPage>::zeroed();
+    let target = unsafe { &mut *page.as_mut_ptr() };
+    let source = PhysAddr::from(0x8000_0010u64);
+
+    assert_eq!(
+        copy_secrets_page(target, source),
+        Err(SecretsError::Misaligned(source))
+    );
+}
+
+#[test]
+fn test_vmpck_key_seqno() {
+    let key = VmpckKey::new(&[0x5au8; 32]);
+    let sealed = key.aead_encrypt(1, b"hdr", b"report request");
+
+    assert_eq!(
+        key.aead_decrypt(1, b"hdr", &sealed).unwrap(),
+        b"report request"
+    );
+    // A different sequence number means a different IV
+    assert!(key.aead_decrypt(3, b"hdr", &sealed).is_err());
+    assert!(key.aead_decrypt(1, b"hdx", &sealed).is_err());
+}
+
+#[test]
+fn test_tsc_scale_ns() {
+    use core::mem::MaybeUninit;
+
+    let mut page = MaybeUninit::<SecretsPage>::zeroed();
+    let raw = page.as_mut_ptr();
 
     unsafe {
-        *target = *table;
+        // 1% reduction of the nominal frequency
+        ptr::addr_of_mut!((*raw).tsc_factor).write_unaligned(1000);
+        assert_eq!((*raw).tsc_scale_khz(2_000_000), 1_980_000);
+
+        let khz = (*raw).tsc_scale_khz(tsc_nominal_khz());
+        assert_eq!((*raw).tsc_scale_ns(khz * 1000), 1_000_000_000);
     }
 }
@@ -150,6 +150,10 @@ pub fn sev_snp_enabled() -> bool {
     sev_flags().contains(SEVStatusFlags::SEV_SNP)
 }
 
+pub fn sev_secure_tsc_enabled() -> bool {
+    sev_flags().contains(SEVStatusFlags::SECURE_TSC)
+}
+
 pub fn sev_status_verify() {
     let required = SEVStatusFlags::SEV | SEVStatusFlags::SEV_ES | SEVStatusFlags::SEV_SNP;
     let not_supported = SEVStatusFlags::VTOM
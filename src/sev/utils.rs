@@ -4,8 +4,7 @@
 //
 // Author: Joerg Roedel <jroedel@suse.de>
 
-use crate::types::{VirtAddr, PAGE_SIZE, PAGE_SIZE_2M};
-use crate::utils::is_aligned;
+use crate::types::{PageSize, VirtAddr, PAGE_SIZE, PAGE_SIZE_2M};
 use core::arch::asm;
 use core::fmt;
 
@@ -43,40 +42,84 @@ impl fmt::Display for SevSnpError {
     }
 }
 
-fn pvalidate_range_4k(start: VirtAddr, end: VirtAddr, valid: bool) -> Result<(), SevSnpError> {
-    for addr in (start..end).step_by(PAGE_SIZE) {
-        pvalidate(addr, false, valid)?;
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PvalidateError {
+    FailInput,
+    // The page is mapped as 4k in the RMP, a 2M PVALIDATE has to be split
+    // into 4k operations by the caller
+    SizeMismatch,
+}
+
+impl From<PvalidateError> for SevSnpError {
+    fn from(err: PvalidateError) -> Self {
+        match err {
+            PvalidateError::FailInput => SevSnpError::FAIL_INPUT(1),
+            PvalidateError::SizeMismatch => SevSnpError::FAIL_SIZEMISMATCH(6),
+        }
+    }
+}
+
+// Returns whether all PVALIDATEs changed the RMP entries
+fn pvalidate_range_4k(start: VirtAddr, end: VirtAddr, valid: bool) -> Result<bool, PvalidateError> {
+    let mut changed = true;
+
+    for addr in start.iter_to(end, PAGE_SIZE) {
+        changed &= pvalidate(addr, PageSize::Page4K, valid)?;
     }
 
-    Ok(())
+    Ok(changed)
 }
 
-pub fn pvalidate_range(start: VirtAddr, end: VirtAddr, valid: bool) -> Result<(), SevSnpError> {
+pub fn pvalidate_range(
+    start: VirtAddr,
+    end: VirtAddr,
+    valid: bool,
+) -> Result<bool, PvalidateError> {
     let mut addr = start;
+    let mut changed = true;
 
     while addr < end {
-        if is_aligned(addr, PAGE_SIZE_2M) && (addr + PAGE_SIZE_2M) <= end {
+        if addr.is_aligned(PAGE_SIZE_2M) && (addr + PAGE_SIZE_2M) <= end {
             // Try to validate as a huge page.
             // If we fail, try to fall back to regular-sized pages.
-            pvalidate(addr, true, valid).or_else(|err| match err {
-                SevSnpError::FAIL_SIZEMISMATCH(_) => {
+            changed &= pvalidate(addr, PageSize::Page2M, valid).or_else(|err| match err {
+                PvalidateError::SizeMismatch => {
                     pvalidate_range_4k(addr, addr + PAGE_SIZE_2M, valid)
                 }
                 _ => Err(err),
             })?;
             addr += PAGE_SIZE_2M;
         } else {
-            pvalidate(addr, false, valid)?;
+            changed &= pvalidate(addr, PageSize::Page4K, valid)?;
             addr += PAGE_SIZE;
         }
     }
 
-    Ok(())
+    Ok(changed)
 }
 
-pub fn pvalidate(vaddr: VirtAddr, huge_page: bool, valid: bool) -> Result<(), SevSnpError> {
-    let rax = vaddr;
-    let rcx = huge_page as u64;
+fn decode_pvalidate_result(ret: u64, cf: bool) -> Result<bool, PvalidateError> {
+    match ret {
+        // CF is set when the RMP entry was already in the requested state
+        0 => Ok(!cf),
+        1 => Err(PvalidateError::FailInput),
+        6 => Err(PvalidateError::SizeMismatch),
+        _ => {
+            log::error!("PVALIDATE: unexpected return value: {}", ret);
+            unreachable!();
+        }
+    }
+}
+
+// Sets the validated state of the page at vaddr and returns whether its RMP
+// entry changed. Only 4k and 2M pages can be validated.
+pub fn pvalidate(vaddr: VirtAddr, size: PageSize, valid: bool) -> Result<bool, PvalidateError> {
+    let rax = vaddr.as_usize();
+    let rcx: u64 = match size {
+        PageSize::Page4K => 0,
+        PageSize::Page2M => 1,
+        PageSize::Page1G => return Err(PvalidateError::FailInput),
+    };
     let rdx = valid as u64;
     let ret: u64;
     let cf: u64;
@@ -95,18 +138,7 @@ pub fn pvalidate(vaddr: VirtAddr, huge_page: bool, valid: bool) -> Result<(), Se
              options(att_syntax));
     }
 
-    let changed = cf == 0;
-
-    match ret {
-        0 if changed => Ok(()),
-        0 if !changed => Err(SevSnpError::FAIL_UNCHANGED(0x10)),
-        1 => Err(SevSnpError::FAIL_INPUT(ret)),
-        6 => Err(SevSnpError::FAIL_SIZEMISMATCH(ret)),
-        _ => {
-            log::error!("PVALIDATE: unexpected return value: {}", ret);
-            unreachable!();
-        }
-    }
+    decode_pvalidate_result(ret, cf != 0)
 }
 
 pub fn raw_vmgexit() {
@@ -134,7 +166,7 @@ bitflags::bitflags! {
 
 pub fn rmp_adjust(addr: VirtAddr, flags: RMPFlags, huge: bool) -> Result<(), SevSnpError> {
     let rcx: usize = if huge { 1 } else { 0 };
-    let rax: u64 = addr as u64;
+    let rax: u64 = u64::from(addr);
     let rdx: u64 = flags.bits();
     let mut ret: u64;
     let mut ex: u64;
@@ -190,3 +222,17 @@ pub fn rmp_clear_guest_vmsa(vaddr: VirtAddr) -> Result<(), SevSnpError> {
     rmp_revoke_guest_access(vaddr, false)?;
     rmp_grant_guest_access(vaddr, false)
 }
+
+#[test]
+fn test_decode_pvalidate_result() {
+    assert_eq!(decode_pvalidate_result(0, false), Ok(true));
+    assert_eq!(decode_pvalidate_result(0, true), Ok(false));
+    assert_eq!(
+        decode_pvalidate_result(1, false),
+        Err(PvalidateError::FailInput)
+    );
+    assert_eq!(
+        decode_pvalidate_result(6, false),
+        Err(PvalidateError::SizeMismatch)
+    );
+}
@@ -4,9 +4,17 @@
 //
 // Author: Joerg Roedel <jroedel@suse.de>
 
-use super::utils::{rmp_adjust, RMPFlags};
-use crate::mm::alloc::{allocate_zeroed_page, free_page};
-use crate::types::VirtAddr;
+extern crate alloc;
+
+use super::rmp::{rmpadjust, RmpPerms};
+use crate::cpu::control_regs::{CR0Flags, CR4Flags};
+use crate::cpu::efer::EFERFlags;
+use crate::locking::SpinLock;
+use crate::mm::alloc::AllocFlags;
+use crate::mm::slab_cache::SlabCache;
+use crate::types::{VirtAddr, SVSM_CS, SVSM_DS};
+use alloc::vec::Vec;
+use core::ops::{Deref, DerefMut};
 
 pub const VMPL_MAX: usize = 4;
 
@@ -49,6 +57,7 @@ pub enum GuestVMExit {
 }
 
 #[repr(C, packed)]
+#[derive(Clone, Copy)]
 pub struct VMSASegment {
     pub selector: u16,
     pub flags: u16,
@@ -171,31 +180,214 @@ pub struct VMSA {
 impl VMSA {
     pub fn from_virt_addr(v: VirtAddr) -> &'static mut VMSA {
         unsafe {
-            let ptr = v as *mut VMSA;
+            let ptr = v.as_mut_ptr::<VMSA>();
             ptr.as_mut().unwrap()
         }
     }
 
+    // Catches a malformed initial state before an AP is started with it, the
+    // hardware gives almost no diagnostics when that fails. Segment selectors
+    // are only checked for VMSAs running SVSM code at VMPL0.
+    pub fn validate(&self) -> Result<(), VmsaError> {
+        let cr0 = CR0Flags::from_bits_truncate(self.cr0);
+        let cr4 = CR4Flags::from_bits_truncate(self.cr4);
+        let efer = EFERFlags::from_bits_truncate(self.efer);
+
+        if cr0.contains(CR0Flags::PG) && !cr0.contains(CR0Flags::PE) {
+            return Err(VmsaError::Cr0(self.cr0));
+        }
+
+        // LMA must be set exactly when LME and paging are enabled
+        let long_mode = efer.contains(EFERFlags::LME) && cr0.contains(CR0Flags::PG);
+        if long_mode != efer.contains(EFERFlags::LMA) {
+            return Err(VmsaError::Efer(self.efer));
+        }
+
+        if long_mode && !cr4.contains(CR4Flags::PAE) {
+            return Err(VmsaError::Cr4(self.cr4));
+        }
+
+        if long_mode && !is_canonical(self.rip) {
+            return Err(VmsaError::Rip(self.rip));
+        }
+
+        if self.vmpl == 0 {
+            let segments = [
+                ("CS", self.cs.selector, SVSM_CS),
+                ("DS", self.ds.selector, SVSM_DS),
+                ("ES", self.es.selector, SVSM_DS),
+                ("SS", self.ss.selector, SVSM_DS),
+                ("FS", self.fs.selector, SVSM_DS),
+                ("GS", self.gs.selector, SVSM_DS),
+            ];
+
+            for (name, selector, expected) in segments {
+                if selector != expected {
+                    return Err(VmsaError::Selector(name, selector));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn enable(&mut self) {
         self.efer |= 1u64 << 12;
     }
 
+    pub fn is_enabled(&self) -> bool {
+        (self.efer & (1u64 << 12)) != 0
+    }
+
+    // Take exclusive software ownership of the VMSA at vaddr for editing.
+    // Fails while another owner exists or while the VMSA is enabled, i.e.
+    // EFER.SVME is set and the hardware may be executing it.
+    pub fn try_acquire(vaddr: VirtAddr) -> Result<VmsaGuard, VmsaBusy> {
+        let mut busy = VMSA_BUSY.lock();
+
+        if busy.contains(&vaddr) {
+            return Err(VmsaBusy);
+        }
+
+        let vmsa = VMSA::from_virt_addr(vaddr);
+        if vmsa.is_enabled() {
+            return Err(VmsaBusy);
+        }
+
+        busy.push(vaddr);
+
+        Ok(VmsaGuard { vaddr, vmsa })
+    }
+
     pub fn disable(&mut self) {
         self.efer &= !(1u64 << 12);
     }
 }
 
-pub fn allocate_new_vmsa(vmpl: RMPFlags) -> Result<VirtAddr, ()> {
-    assert!(vmpl.bits() < (VMPL_MAX as u64));
-    let vmsa_page = allocate_zeroed_page()?;
-    if rmp_adjust(vmsa_page, RMPFlags::VMSA | vmpl, false).is_err() {
-        free_page(vmsa_page);
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VmsaError {
+    // CR0.PG set without CR0.PE
+    Cr0(u64),
+    // EFER.LMA inconsistent with EFER.LME and CR0.PG
+    Efer(u64),
+    // Long mode without CR4.PAE
+    Cr4(u64),
+    // Non-canonical RIP in long mode
+    Rip(u64),
+    // Segment register name and its unexpected selector
+    Selector(&'static str, u16),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VmsaBusy;
+
+// VMSAs currently owned through a VmsaGuard
+static VMSA_BUSY: SpinLock<Vec<VirtAddr>> = SpinLock::new(Vec::new());
+
+pub struct VmsaGuard {
+    vaddr: VirtAddr,
+    vmsa: &'static mut VMSA,
+}
+
+impl Deref for VmsaGuard {
+    type Target = VMSA;
+
+    fn deref(&self) -> &VMSA {
+        self.vmsa
+    }
+}
+
+impl DerefMut for VmsaGuard {
+    fn deref_mut(&mut self) -> &mut VMSA {
+        self.vmsa
+    }
+}
+
+impl Drop for VmsaGuard {
+    fn drop(&mut self) {
+        VMSA_BUSY.lock().retain(|v| *v != self.vaddr);
+    }
+}
+
+fn is_canonical(addr: u64) -> bool {
+    let upper = (addr as i64) >> 47;
+    upper == 0 || upper == -1
+}
+
+// VMSAs come and go with CPU hotplug, the cache clears them on free. As
+// they hold register state, their slabs are cleared like pages from
+// alloc_secret_page() when shrunk.
+static VMSA_CACHE: SpinLock<SlabCache<VMSA>> =
+    SpinLock::new(SlabCache::with_flags(AllocFlags::ZERO_ON_FREE));
+
+pub fn allocate_new_vmsa(vmpl: u8) -> Result<VirtAddr, ()> {
+    assert!((vmpl as usize) < VMPL_MAX);
+    let vaddr = VMSA_CACHE.lock().alloc()?;
+    if rmpadjust(vaddr, vmpl, RmpPerms::READ, true).is_err() {
+        VMSA_CACHE.lock().free(vaddr);
         return Err(());
     }
-    Ok(vmsa_page)
+    Ok(vaddr)
 }
 
 pub fn free_vmsa(vaddr: VirtAddr) {
-    rmp_adjust(vaddr, RMPFlags::RWX | RMPFlags::VMPL0, false).expect("Failed to free VMSA page");
-    free_page(vaddr);
+    rmpadjust(vaddr, 0, RmpPerms::all(), false).expect("Failed to free VMSA page");
+    VMSA_CACHE.lock().free(vaddr);
+}
+
+// Returns the number of slabs given back to the page allocator. Called on
+// allocation failures, which can happen while the cache is locked for
+// growing it, so a locked cache is skipped.
+pub fn shrink_vmsa_cache() -> usize {
+    VMSA_CACHE.try_lock().map_or(0, |mut cache| cache.shrink())
+}
+
+#[test]
+fn test_vmsa_validate() {
+    let mut vmsa: VMSA = unsafe { core::mem::zeroed() };
+
+    // Real mode at VMPL1 is valid
+    vmsa.vmpl = 1;
+    assert_eq!(vmsa.validate(), Ok(()));
+
+    vmsa.vmpl = 0;
+    vmsa.cr0 = (CR0Flags::PE | CR0Flags::PG).bits();
+    vmsa.cr4 = CR4Flags::PAE.bits();
+    vmsa.efer = EFERFlags::LME.bits();
+    assert_eq!(vmsa.validate(), Err(VmsaError::Efer(vmsa.efer)));
+
+    vmsa.efer |= EFERFlags::LMA.bits();
+    vmsa.rip = 0x0000_8000_0000_0000;
+    assert_eq!(vmsa.validate(), Err(VmsaError::Rip(vmsa.rip)));
+
+    vmsa.rip = 0xffff_8000_0000_0000;
+    assert_eq!(vmsa.validate(), Err(VmsaError::Selector("CS", 0)));
+
+    vmsa.cs.selector = SVSM_CS;
+    vmsa.ds.selector = SVSM_DS;
+    vmsa.es.selector = SVSM_DS;
+    vmsa.ss.selector = SVSM_DS;
+    vmsa.fs.selector = SVSM_DS;
+    vmsa.gs.selector = SVSM_DS;
+    assert_eq!(vmsa.validate(), Ok(()));
+}
+
+#[test]
+fn test_vmsa_try_acquire() {
+    use alloc::boxed::Box;
+
+    let vmsa: Box<VMSA> = Box::new(unsafe { core::mem::zeroed() });
+    let vaddr = VirtAddr::from_ptr(Box::into_raw(vmsa));
+
+    let mut guard = VMSA::try_acquire(vaddr).unwrap();
+    assert_eq!(VMSA::try_acquire(vaddr).err(), Some(VmsaBusy));
+
+    guard.enable();
+    drop(guard);
+    assert_eq!(VMSA::try_acquire(vaddr).err(), Some(VmsaBusy));
+
+    VMSA::from_virt_addr(vaddr).disable();
+    assert!(VMSA::try_acquire(vaddr).is_ok());
+
+    drop(unsafe { Box::from_raw(vaddr.as_mut_ptr::<VMSA>()) });
 }
@@ -16,11 +16,13 @@ use core::panic::PanicInfo;
 use log;
 use svsm::console::{init_console, install_console_logger, WRITER};
 use svsm::cpu::cpuid::{register_cpuid_table, SnpCpuidTable};
+use svsm::cpu::features::init_cpu_features;
 use svsm::cpu::msr;
-use svsm::cpu::percpu::{this_cpu_mut, PerCpu};
+use svsm::cpu::percpu::{set_percpu_ready, this_cpu_mut, PerCpu};
 use svsm::fw_cfg::{FwCfg, MemoryRegion};
 use svsm::kernel_launch::KernelLaunchInfo;
 use svsm::mm::alloc::{memory_info, print_memory_info, root_mem_init};
+use svsm::mm::early_alloc::promote_to_buddy_allocator;
 use svsm::mm::init_kernel_mapping_info;
 use svsm::mm::pagetable::{
     get_init_pgtable_locked, paging_init, paging_init_early, set_init_pgtable, PTEntryFlags,
@@ -28,13 +30,15 @@ use svsm::mm::pagetable::{
 };
 use svsm::mm::validate::{init_valid_bitmap_alloc, valid_bitmap_addr, valid_bitmap_set_valid_2m};
 use svsm::serial::{SerialPort, DEFAULT_SERIAL_PORT, SERIAL_PORT};
-use svsm::sev::ghcb::PageStateChangeOp;
-use svsm::sev::msr_protocol::GHCBMsr;
+use svsm::sev::ghcb::{PscOp, GHCB};
+use svsm::sev::msr_protocol::{
+    ghcb_terminate, GHCBMsr, GHCB_TERM_SET_GENERAL, GHCB_TERM_UNSUPPORTED_PROTOCOL,
+};
 use svsm::sev::status::SEVStatusFlags;
 use svsm::sev::{pvalidate_range, sev_status_init, sev_status_verify};
 use svsm::svsm_console::SVSMIOPort;
-use svsm::types::{PhysAddr, VirtAddr, PAGE_SIZE, PAGE_SIZE_2M};
-use svsm::utils::{halt, is_aligned, page_align, page_align_up};
+use svsm::types::{PageSize, PhysAddr, VirtAddr, PAGE_SIZE, PAGE_SIZE_2M};
+use svsm::utils::halt;
 
 extern "C" {
     pub static heap_start: u8;
@@ -44,12 +48,13 @@ extern "C" {
 }
 
 fn setup_stage2_allocator() {
-    let vstart = unsafe { page_align_up((&heap_start as *const u8) as VirtAddr) };
-    let vend = unsafe { page_align((&heap_end as *const u8) as VirtAddr) };
-    let pstart = vstart as PhysAddr; // Identity mapping
+    let vstart = unsafe { VirtAddr::from_ptr(&heap_start as *const u8).page_align_up() };
+    let vend = unsafe { VirtAddr::from_ptr(&heap_end as *const u8).page_align_down() };
+    let pstart = PhysAddr::from(vstart.as_usize()); // Identity mapping
     let nr_pages = (vend - vstart) / PAGE_SIZE;
 
     root_mem_init(pstart, vstart, nr_pages);
+    promote_to_buddy_allocator();
 }
 
 pub static mut PERCPU: PerCpu = PerCpu::new();
@@ -63,6 +68,7 @@ fn init_percpu() {
 
         bsp_percpu.set_pgtable(PageTableRef::new(&mut pgtable));
         bsp_percpu.map_self().expect("Failed to map per-cpu area");
+        set_percpu_ready();
         bsp_percpu.setup_ghcb().expect("Failed to setup BSP GHCB");
         bsp_percpu.register_ghcb().expect("Failed to register GHCB");
     }
@@ -164,7 +170,11 @@ fn sev_ghcb_msr_available() -> Result<u64, ()> {
 
 fn setup_env() {
     install_console_logger("Stage2");
-    init_kernel_mapping_info(0, 640 * 1024, 0);
+    init_kernel_mapping_info(
+        VirtAddr::null(),
+        VirtAddr::from(640 * 1024usize),
+        PhysAddr::null(),
+    );
 
     // Under SVM-ES, the only means to communicate with the user is through the
     // SVSMIOPort console, which requires a functional GHCB protocol. If the
@@ -186,6 +196,9 @@ fn setup_env() {
 
     // Bring up the GCHB for use from the SVSMIOPort console.
     sev_status_init();
+    if GHCB::negotiate_version().is_err() {
+        ghcb_terminate(GHCB_TERM_SET_GENERAL, GHCB_TERM_UNSUPPORTED_PROTOCOL);
+    }
     set_init_pgtable(PageTableRef::new(unsafe { &mut pgtable }));
     setup_stage2_allocator();
     init_percpu();
@@ -201,6 +214,7 @@ fn setup_env() {
 
     // At this point, SEV-SNP is confirmed. Register the supplied CPUID page.
     register_cpuid_table(unsafe { &CPUID_PAGE });
+    init_cpu_features();
 
     // At this point SEV-SNP is confirmed to be active and the CPUID table
     // should be available. Fully initialize the paging subsystem now. In
@@ -214,7 +228,7 @@ fn map_kernel_region(vaddr: VirtAddr, region: &MemoryRegion) -> Result<(), ()> {
         | PTEntryFlags::WRITABLE
         | PTEntryFlags::ACCESSED
         | PTEntryFlags::DIRTY;
-    let paddr = region.start as PhysAddr;
+    let paddr = PhysAddr::from(region.start);
     let size = (region.end - region.start) as usize;
 
     let mut pgtbl = get_init_pgtable_locked();
@@ -230,21 +244,24 @@ fn map_kernel_region(vaddr: VirtAddr, region: &MemoryRegion) -> Result<(), ()> {
 }
 
 fn validate_kernel_region(vaddr: VirtAddr, region: &MemoryRegion) -> Result<(), ()> {
-    let pstart = region.start as PhysAddr;
-    let pend = region.end as PhysAddr;
+    let pstart = PhysAddr::from(region.start);
+    let pend = PhysAddr::from(region.end);
     let size: usize = pend - pstart;
 
-    assert!(is_aligned(pstart, PAGE_SIZE_2M));
-    assert!(is_aligned(pend, PAGE_SIZE_2M));
+    assert!(pstart.is_aligned(PAGE_SIZE_2M));
+    assert!(pend.is_aligned(PAGE_SIZE_2M));
 
     this_cpu_mut()
         .ghcb()
-        .page_state_change(pstart, pend, true, PageStateChangeOp::PscPrivate)
+        .page_state_change_region(pstart, pend, PageSize::Page2M, PscOp::Private)
         .expect("GHCB::PAGE_STATE_CHANGE call failed for kernel region");
 
-    pvalidate_range(vaddr, vaddr + size, true).expect("PVALIDATE kernel region failed");
+    let changed =
+        pvalidate_range(vaddr, vaddr + size, true).expect("PVALIDATE kernel region failed");
+    // Pages which are already valid might have been prepared by the hypervisor
+    assert!(changed, "Kernel region is already validated");
 
-    for paddr in (pstart..pend).step_by(PAGE_SIZE_2M) {
+    for paddr in pstart.iter_to(pend, PAGE_SIZE_2M) {
         valid_bitmap_set_valid_2m(paddr);
     }
 
@@ -269,12 +286,14 @@ struct KInfo {
 unsafe fn copy_and_launch_kernel(kli: KInfo) {
     let image_size = kli.k_image_end - kli.k_image_start;
     let kernel_launch_info = KernelLaunchInfo {
-        kernel_start: kli.phys_base as u64,
-        kernel_end: kli.phys_end as u64,
-        virt_base: kli.virt_base as u64,
+        kernel_start: u64::from(kli.phys_base),
+        kernel_end: u64::from(kli.phys_end),
+        virt_base: u64::from(kli.virt_base),
         cpuid_page: 0x9f000u64,
         secrets_page: 0x9e000u64,
         ghcb: 0,
+        // The guest firmware installs the ACPI tables from fw_cfg only later
+        acpi_rsdp: 0,
     };
 
     log::info!(
@@ -297,6 +316,10 @@ unsafe fn copy_and_launch_kernel(kli: KInfo) {
         "  secrets_page          = {:#018x}",
         kernel_launch_info.secrets_page
     );
+    log::info!(
+        "  acpi_rsdp             = {:#018x}",
+        kernel_launch_info.acpi_rsdp
+    );
     log::info!("Launching SVSM kernel...");
 
     // Shut down the GHCB
@@ -305,14 +328,14 @@ unsafe fn copy_and_launch_kernel(kli: KInfo) {
     let valid_bitmap: PhysAddr = valid_bitmap_addr();
 
     compiler_builtins::mem::memcpy(
-        kli.virt_base as *mut u8,
-        kli.k_image_start as *const u8,
+        kli.virt_base.as_mut_ptr::<u8>(),
+        kli.k_image_start.as_usize() as *const u8,
         image_size,
     );
     asm!("jmp *%rax",
-          in("rax") kli.entry,
+          in("rax") kli.entry.as_usize(),
           in("r8") &kernel_launch_info,
-          in("r9") valid_bitmap,
+          in("r9") valid_bitmap.as_usize(),
           options(att_syntax));
 }
 
@@ -328,7 +351,7 @@ pub extern "C" fn stage2_main(kernel_start: PhysAddr, kernel_end: PhysAddr) {
     log::info!("COCONUT Secure Virtual Machine Service Module (SVSM) Stage 2 Loader");
 
     let (kernel_virt_base, kernel_entry) = unsafe {
-        let kmd: *const KernelMetaData = kernel_start as *const KernelMetaData;
+        let kmd = kernel_start.as_usize() as *const KernelMetaData;
         ((*kmd).virt_addr, (*kmd).entry)
     };
 
@@ -345,8 +368,8 @@ pub extern "C" fn stage2_main(kernel_start: PhysAddr, kernel_end: PhysAddr) {
         copy_and_launch_kernel(KInfo {
             k_image_start: kernel_start,
             k_image_end: kernel_end,
-            phys_base: r.start as usize,
-            phys_end: r.end as usize,
+            phys_base: PhysAddr::from(r.start),
+            phys_end: PhysAddr::from(r.end),
             virt_base: kernel_virt_base,
             entry: kernel_entry,
         });
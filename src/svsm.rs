@@ -13,29 +13,41 @@ use svsm::fw_meta::{parse_fw_meta_data, print_fw_meta, validate_fw_memory, SevFW
 
 use core::arch::{asm, global_asm};
 use core::panic::PanicInfo;
-use svsm::acpi::tables::load_acpi_cpu_info;
-use svsm::console::{init_console, install_console_logger, WRITER};
-use svsm::cpu::control_regs::{cr0_init, cr4_init};
+use svsm::acpi::tables::{acpi_cpu_info, load_acpi_tables, srat_proximity_domain};
+use svsm::config::{init_launch_config, launch_config, LaunchConfig};
+use svsm::console::{console_enter_unlocked, init_console, install_console_logger, WRITER};
+use svsm::cpu::control_regs::{cr0_init, cr4_init, read_cr2, read_cr3};
 use svsm::cpu::cpuid::{register_cpuid_table, SnpCpuidTable};
 use svsm::cpu::efer::efer_init;
+use svsm::cpu::features::init_cpu_features;
 use svsm::cpu::gdt::load_gdt;
 use svsm::cpu::idt::{early_idt_init, idt_init};
 use svsm::cpu::percpu::PerCpu;
-use svsm::cpu::percpu::{this_cpu, this_cpu_mut};
-use svsm::cpu::smp::start_secondary_cpus;
+use svsm::cpu::percpu::{this_cpu, this_cpu_index, this_cpu_mut, CPU_ONLINE_MASK, PERCPU_AREAS};
+use svsm::cpu::smp::{start_secondary_cpus, stop_all_other_cpus, terminate_svsm, BringupOrder};
+use svsm::cpu::tsc::tsc_init;
 use svsm::debug::stacktrace::print_stack;
 use svsm::fw_cfg::FwCfg;
 use svsm::kernel_launch::KernelLaunchInfo;
-use svsm::mm::alloc::{memory_info, print_memory_info, root_mem_init};
+use svsm::mm::alloc::{alloc_secret_page, memory_info, print_memory_info, root_mem_init_reserved};
+use svsm::mm::early_alloc::promote_to_buddy_allocator;
 use svsm::mm::memory::init_memory_map;
 use svsm::mm::pagetable::paging_init;
 use svsm::mm::{init_kernel_mapping_info, PerCPUPageMappingGuard};
-use svsm::requests::{request_loop, update_mappings};
+use svsm::requests::{
+    register_default_protocols, request_loop, update_mappings, validate_boot_caa,
+    CORE_PROTOCOL_VERSION_MAX,
+};
 use svsm::serial::SerialPort;
 use svsm::serial::SERIAL_PORT;
+use svsm::sev::attestation::attestation_init;
+use svsm::sev::ghcb::GHCB;
+use svsm::sev::msr_protocol::{
+    ghcb_terminate, GHCB_TERM_SET_GENERAL, GHCB_TERM_UNSUPPORTED_PROTOCOL,
+};
 use svsm::sev::secrets_page::{copy_secrets_page, SecretsPage};
-use svsm::sev::sev_status_init;
 use svsm::sev::utils::{rmp_adjust, RMPFlags};
+use svsm::sev::{check_sev_features, current_sev_features, sev_status_init};
 use svsm::svsm_console::SVSMIOPort;
 use svsm::types::{PhysAddr, VirtAddr, PAGE_SIZE};
 use svsm::utils::{halt, immut_after_init::ImmutAfterInitCell, zero_mem_region};
@@ -44,11 +56,11 @@ use svsm_paging::{init_page_table, invalidate_stage2};
 use svsm::mm::validate::{init_valid_bitmap_ptr, migrate_valid_bitmap};
 
 use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 use log;
 
 extern "C" {
-    pub static mut SECRETS_PAGE: SecretsPage;
     pub static bsp_stack_end: u8;
 }
 
@@ -91,11 +103,6 @@ global_asm!(
     bsp_stack:
         .fill 8192, 1, 0
     bsp_stack_end:
-
-        .align 4096
-        .globl SECRETS_PAGE
-    SECRETS_PAGE:
-        .fill 4096, 1, 0
         "#,
     options(att_syntax)
 );
@@ -117,69 +124,72 @@ static LAUNCH_INFO: ImmutAfterInitCell<KernelLaunchInfo> = ImmutAfterInitCell::u
 
 pub static mut PERCPU: PerCpu = PerCpu::new();
 
-fn copy_cpuid_table_to_fw(fw_addr: PhysAddr) -> Result<(), ()> {
-    let guard = PerCPUPageMappingGuard::create(fw_addr, 0, false)?;
-    let start = guard.virt_addr();
-    let end = start + PAGE_SIZE;
+// The copy of the secrets page, in a page from alloc_secret_page()
+static mut SECRETS_PAGE: *mut SecretsPage = ptr::null_mut();
 
-    let target = ptr::NonNull::new(start as *mut SnpCpuidTable).unwrap();
-
-    // Zero target
-    zero_mem_region(start, end);
-
-    // Copy data
-    unsafe {
-        let dst = target.as_ptr();
-        *dst = *CPUID_PAGE;
-    }
+fn secrets_page() -> &'static SecretsPage {
+    unsafe { SECRETS_PAGE.as_ref().unwrap() }
+}
 
-    Ok(())
+fn secrets_page_mut() -> &'static mut SecretsPage {
+    unsafe { SECRETS_PAGE.as_mut().unwrap() }
 }
 
-fn copy_secrets_page_to_fw(fw_addr: PhysAddr, caa_addr: PhysAddr) -> Result<(), ()> {
-    let guard = PerCPUPageMappingGuard::create(fw_addr, 0, false)?;
-    let start = guard.virt_addr();
+fn copy_cpuid_table_to_fw(fw_addr: PhysAddr) -> Result<(), ()> {
+    this_cpu().with_temp_map(fw_addr, |start| {
+        let end = start + PAGE_SIZE;
 
-    let mut target = ptr::NonNull::new(start as *mut SecretsPage).unwrap();
+        let target = ptr::NonNull::new(start.as_mut_ptr::<SnpCpuidTable>()).unwrap();
 
-    // Zero target
-    unsafe {
-        let mut page_ptr = target.cast::<u8>();
-        ptr::write_bytes(page_ptr.as_mut(), 0, PAGE_SIZE);
-    }
+        // Zero target
+        zero_mem_region(start, end);
 
-    // Copy and initialize data
-    unsafe {
-        let dst = target.as_ptr();
-        *dst = SECRETS_PAGE;
+        // Copy data
+        unsafe {
+            let dst = target.as_ptr();
+            *dst = *CPUID_PAGE;
+        }
+    })
+}
 
-        // Copy Table
-        let mut fw_sp = target.as_mut();
+fn copy_secrets_page_to_fw(fw_addr: PhysAddr, caa_addr: PhysAddr) -> Result<(), ()> {
+    this_cpu().with_temp_map(fw_addr, |start| {
+        let mut target = ptr::NonNull::new(start.as_mut_ptr::<SecretsPage>()).unwrap();
 
-        // Zero VMCK0 key
-        for i in 0..32 {
-            fw_sp.vmpck0[i] = 0;
+        // Zero target
+        unsafe {
+            let mut page_ptr = target.cast::<u8>();
+            ptr::write_bytes(page_ptr.as_mut(), 0, PAGE_SIZE);
         }
 
-        let &li = &*LAUNCH_INFO;
+        // Copy and initialize data
+        unsafe {
+            let dst = target.as_ptr();
+            ptr::copy_nonoverlapping(secrets_page(), dst, 1);
 
-        fw_sp.svsm_base = li.kernel_start;
-        fw_sp.svsm_size = li.kernel_end - li.kernel_start;
-        fw_sp.svsm_caa = caa_addr as u64;
-        fw_sp.svsm_max_version = 1;
-        fw_sp.svsm_guest_vmpl = 1;
-    }
+            // Copy Table
+            let mut fw_sp = target.as_mut();
 
-    Ok(())
-}
+            // Zero VMCK0 key
+            fw_sp.clear_vmpck_idx(0);
 
-fn zero_caa_page(fw_addr: PhysAddr) -> Result<(), ()> {
-    let guard = PerCPUPageMappingGuard::create(fw_addr, 0, false)?;
-    let vaddr = guard.virt_addr();
+            let &li = &*LAUNCH_INFO;
 
-    zero_mem_region(vaddr, vaddr + PAGE_SIZE);
+            fw_sp.set_svsm_data(
+                li.kernel_start,
+                li.kernel_end - li.kernel_start,
+                u64::from(caa_addr),
+                CORE_PROTOCOL_VERSION_MAX,
+                1,
+            );
+        }
+    })
+}
 
-    Ok(())
+fn zero_caa_page(fw_addr: PhysAddr) -> Result<(), ()> {
+    this_cpu().with_temp_map(fw_addr, |vaddr| {
+        zero_mem_region(vaddr, vaddr + PAGE_SIZE);
+    })
 }
 
 pub fn copy_tables_to_fw(fw_meta: &SevFWMetaData) -> Result<(), ()> {
@@ -209,8 +219,16 @@ fn prepare_fw_launch(fw_meta: &SevFWMetaData) -> Result<(), ()> {
     let caa = fw_meta.caa_page.unwrap();
     let cpu = this_cpu_mut();
 
-    cpu.alloc_guest_vmsa()?;
-    cpu.update_guest_caa(caa);
+    // Check against the secrets page the firmware will find
+    cpu.with_temp_map(fw_meta.secrets_page.unwrap(), |vaddr| {
+        let secrets = unsafe { &*vaddr.as_ptr::<SecretsPage>() };
+        validate_boot_caa(caa, secrets)
+    })?
+    .map_err(|e| log::error!("Invalid firmware CAA {:#018x}: {:?}", caa, e))?;
+
+    cpu.alloc_guest_vmsa(1)?;
+    let vmsa = cpu.get_guest_vmsa(1).unwrap();
+    cpu.update_guest_vmsa_caa(vmsa.paddr, caa);
     update_mappings()?;
 
     Ok(())
@@ -222,13 +240,18 @@ fn launch_fw() -> Result<(), ()> {
 
     log::info!("VMSA PA: {:#x}", vmsa_pa);
 
-    vmsa.enable();
     let sev_features = vmsa.sev_features;
+    if let Err(missing) = check_sev_features(sev_features, current_sev_features()) {
+        log::error!("Firmware VMSA has unsupported SEV features {:#x}", missing);
+        return Err(());
+    }
+    vmsa.enable();
 
     log::info!("Launching Firmware");
     this_cpu_mut()
         .ghcb()
-        .ap_create(vmsa_pa, 0, 1, sev_features)?;
+        .ap_create(vmsa_pa, 0, 1, sev_features)
+        .map_err(|_| ())?;
 
     Ok(())
 }
@@ -237,8 +260,8 @@ fn validate_flash() -> Result<(), ()> {
     let mut fw_cfg = FwCfg::new(&CONSOLE_IO);
 
     for (i, region) in fw_cfg.iter_flash_regions().enumerate() {
-        let pstart = region.start as PhysAddr;
-        let pend = region.end as PhysAddr;
+        let pstart = PhysAddr::from(region.start);
+        let pend = PhysAddr::from(region.end);
         log::info!(
             "Flash region {} at {:#018x} size {:018x}",
             i,
@@ -246,7 +269,7 @@ fn validate_flash() -> Result<(), ()> {
             pend - pstart
         );
 
-        for paddr in (pstart..pend).step_by(PAGE_SIZE) {
+        for paddr in pstart.iter_to(pend, PAGE_SIZE) {
             let guard = PerCPUPageMappingGuard::create(paddr, 0, false)?;
             let vaddr = guard.virt_addr();
             if let Err(_) = rmp_adjust(vaddr, RMPFlags::VMPL1 | RMPFlags::RWX, false) {
@@ -259,15 +282,32 @@ fn validate_flash() -> Result<(), ()> {
     Ok(())
 }
 
-pub fn memory_init(launch_info: &KernelLaunchInfo) {
+// Pages at the end of the heap which the BSP sets itself up from, see
+// promote_to_buddy_allocator(). The start of the heap holds the page
+// meta-data of the buddy allocator.
+const EARLY_ALLOC_PAGES: usize = 4;
+
+// Returns the region for the early allocator of the BSP, it is reserved in
+// the buddy allocator
+pub fn memory_init(launch_info: &KernelLaunchInfo) -> (PhysAddr, PhysAddr) {
     let mem_size = launch_info.kernel_end - launch_info.kernel_start;
-    let vstart = unsafe { (&heap_start as *const u8) as VirtAddr };
-    let vend = (launch_info.virt_base + mem_size) as VirtAddr;
+    let vstart = unsafe { VirtAddr::from_ptr(&heap_start as *const u8) };
+    let vend = VirtAddr::from(launch_info.virt_base + mem_size);
     let page_count = (vend - vstart) / PAGE_SIZE;
-    let heap_offset = vstart - launch_info.virt_base as VirtAddr;
-    let pstart = launch_info.kernel_start as PhysAddr + heap_offset;
+    let heap_offset = vstart - VirtAddr::from(launch_info.virt_base);
+    let pstart = PhysAddr::from(launch_info.kernel_start) + heap_offset;
+
+    let pend = pstart + page_count * PAGE_SIZE;
+    let early_region = (pend - EARLY_ALLOC_PAGES * PAGE_SIZE, pend);
+
+    // The secrets page stage2 populated must never be handed out, should it
+    // ever fall into the heap range
+    let secrets_page = PhysAddr::from(launch_info.secrets_page);
+    let reserved = [(secrets_page, secrets_page + PAGE_SIZE), early_region];
 
-    root_mem_init(pstart, vstart, page_count);
+    root_mem_init_reserved(pstart, vstart, page_count, &reserved);
+
+    early_region
 }
 
 static CONSOLE_IO: SVSMIOPort = SVSMIOPort::new();
@@ -278,16 +318,16 @@ static mut CONSOLE_SERIAL: SerialPort = SerialPort {
 
 pub fn boot_stack_info() {
     unsafe {
-        let vaddr = (&bsp_stack_end as *const u8) as VirtAddr;
+        let vaddr = VirtAddr::from_ptr(&bsp_stack_end as *const u8);
         log::info!("Boot stack starts        @ {:#018x}", vaddr);
     }
 }
 
 fn mapping_info_init(launch_info: &KernelLaunchInfo) {
     let ksize: usize = (launch_info.kernel_end - launch_info.kernel_start) as usize;
-    let vstart: VirtAddr = launch_info.virt_base as VirtAddr;
+    let vstart: VirtAddr = VirtAddr::from(launch_info.virt_base);
     let vend: VirtAddr = vstart + ksize;
-    let pstart: PhysAddr = launch_info.kernel_start as PhysAddr;
+    let pstart: PhysAddr = PhysAddr::from(launch_info.kernel_start);
 
     init_kernel_mapping_info(vstart, vend, pstart);
 }
@@ -295,7 +335,7 @@ fn mapping_info_init(launch_info: &KernelLaunchInfo) {
 #[no_mangle]
 pub extern "C" fn svsm_start(li: &KernelLaunchInfo, vb_addr: VirtAddr) {
     let launch_info: KernelLaunchInfo = *li;
-    let vb_ptr = vb_addr as *mut u64;
+    let vb_ptr = vb_addr.as_mut_ptr::<u64>();
 
     mapping_info_init(&launch_info);
 
@@ -312,21 +352,22 @@ pub extern "C" fn svsm_start(li: &KernelLaunchInfo, vb_addr: VirtAddr) {
         LAUNCH_INFO.init(li);
     }
 
-    let cpuid_table_virt = launch_info.cpuid_page as VirtAddr;
-    unsafe { CPUID_PAGE.init(&*(cpuid_table_virt as *const SnpCpuidTable)) };
+    let cpuid_table_virt = VirtAddr::from(launch_info.cpuid_page);
+    unsafe { CPUID_PAGE.init(&*cpuid_table_virt.as_ptr::<SnpCpuidTable>()) };
     register_cpuid_table(&CPUID_PAGE);
-
-    unsafe {
-        let secrets_page_virt = launch_info.secrets_page as VirtAddr;
-        copy_secrets_page(&mut SECRETS_PAGE, secrets_page_virt);
-    }
+    init_cpu_features();
 
     cr0_init();
     cr4_init();
     efer_init();
     sev_status_init();
 
-    memory_init(&launch_info);
+    // There is no console yet to report the failure on
+    if GHCB::negotiate_version().is_err() {
+        ghcb_terminate(GHCB_TERM_SET_GENERAL, GHCB_TERM_UNSUPPORTED_PROTOCOL);
+    }
+
+    let (early_start, early_end) = memory_init(&launch_info);
     migrate_valid_bitmap().expect("Failed to migrate valid-bitmap");
 
     paging_init();
@@ -338,6 +379,7 @@ pub extern "C" fn svsm_start(li: &KernelLaunchInfo, vb_addr: VirtAddr) {
             .as_mut()
             .unwrap();
 
+        bsp_percpu.seed_early_allocator(early_start, early_end);
         bsp_percpu
             .setup()
             .expect("Failed to setup BSP per-cpu area");
@@ -346,6 +388,7 @@ pub extern "C" fn svsm_start(li: &KernelLaunchInfo, vb_addr: VirtAddr) {
             .expect("Failed to run percpu.setup_on_cpu()");
         bsp_percpu.load();
     }
+    promote_to_buddy_allocator();
     idt_init();
 
     unsafe {
@@ -356,6 +399,18 @@ pub extern "C" fn svsm_start(li: &KernelLaunchInfo, vb_addr: VirtAddr) {
 
     log::info!("COCONUT Secure Virtual Machine Service Module (SVSM)");
 
+    // Stage2 memory is still valid here and the secrets page can now be
+    // mapped through the per-cpu page table
+    let secrets_page_copy = alloc_secret_page().expect("Failed to allocate secrets page copy");
+    unsafe { SECRETS_PAGE = secrets_page_copy.as_mut_ptr::<SecretsPage>() };
+
+    let secrets_page_phys = PhysAddr::from(launch_info.secrets_page);
+    if let Err(e) = copy_secrets_page(secrets_page_mut(), secrets_page_phys) {
+        panic!("Invalid secrets page: {:?}", e);
+    }
+
+    tsc_init(secrets_page());
+
     let mem_info = memory_info();
     print_memory_info(&mem_info);
 
@@ -369,20 +424,30 @@ pub extern "C" fn svsm_start(li: &KernelLaunchInfo, vb_addr: VirtAddr) {
     unsafe {
         asm!("movq  %rax, %rsp
               jmp   svsm_main",
-              in("rax") bp,
+              in("rax") bp.as_usize(),
               options(att_syntax));
     }
 }
 
 #[no_mangle]
+#[cfg_attr(feature = "selftest", allow(unreachable_code))]
 pub extern "C" fn svsm_main() {
     invalidate_stage2().expect("Failed to invalidate Stage2 memory");
 
     let fw_cfg = FwCfg::new(&CONSOLE_IO);
 
-    init_memory_map(&fw_cfg, &LAUNCH_INFO).expect("Failed to init guest memory map");
+    let fw_meta = parse_fw_meta_data().expect("Failed to parse FW SEV meta-data");
+
+    print_fw_meta(&fw_meta);
+
+    let config = LaunchConfig::parse(&LAUNCH_INFO, &fw_cfg, &fw_meta)
+        .expect("Failed to parse launch config");
+    init_launch_config(config);
+
+    init_memory_map(launch_config()).expect("Failed to init guest memory map");
 
-    let cpus = load_acpi_cpu_info(&fw_cfg).expect("Failed to load ACPI tables");
+    let tables = load_acpi_tables(launch_config(), &fw_cfg).expect("Failed to load ACPI tables");
+    let cpus = acpi_cpu_info(&tables).expect("Failed to parse ACPI CPU information");
     let mut nr_cpus = 0;
 
     for cpu in cpus.iter() {
@@ -393,11 +458,43 @@ pub extern "C" fn svsm_main() {
 
     log::info!("{} CPU(s) present", nr_cpus);
 
-    start_secondary_cpus(&cpus);
+    let max_apic_id = cpus.iter().map(|c| c.apic_id).max().unwrap_or(0);
+    PERCPU_AREAS
+        .reserve(cpus.len(), max_apic_id)
+        .expect("Failed to allocate per-cpu registry");
 
-    let fw_meta = parse_fw_meta_data().expect("Failed to parse FW SEV meta-data");
+    if let Some(bsp) = cpus.iter().find(|c| c.apic_id == this_cpu().get_apic_id()) {
+        this_cpu_mut().set_topology(bsp.topology);
+    }
 
-    print_fw_meta(&fw_meta);
+    // The BSP per-cpu area was allocated before the SRAT was parsed
+    let bsp_apic_id = this_cpu().get_apic_id();
+    this_cpu_mut().set_proximity_domain(srat_proximity_domain(bsp_apic_id));
+
+    register_default_protocols().expect("Failed to register SVSM protocol handlers");
+
+    // The guest message channel keeps the only copy of VMPCK0
+    if attestation_init(secrets_page().vmpck_key(0)).is_err() {
+        log::warn!("Failed to set up the guest message channel, attestation is unavailable");
+    }
+    secrets_page_mut().clear_vmpck_idx(0);
+
+    // The selftests terminate the guest with their result
+    #[cfg(feature = "selftest")]
+    svsm::selftest::run_selftests(secrets_page());
+
+    // A partial SMP bring-up is acceptable, the guest can still run on the
+    // CPUs which came up. Only the APs mark themselves online.
+    let nr_aps = match start_secondary_cpus(&cpus, BringupOrder::default()) {
+        Ok(nr_aps) => nr_aps,
+        Err(e) => {
+            log::error!("AP bring-up stopped early: {:?}", e);
+            CPU_ONLINE_MASK.online_count()
+        }
+    };
+    if nr_aps + 1 < nr_cpus {
+        log::warn!("Only {} of {} CPU(s) are online", nr_aps + 1, nr_cpus);
+    }
 
     validate_fw_memory(&fw_meta).expect("Failed to validate firmware memory");
 
@@ -414,13 +511,43 @@ pub extern "C" fn svsm_main() {
     panic!("Road ends here!");
 }
 
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    log::error!("Panic: CPU[{}] {}", this_cpu().get_apic_id(), info);
+    // Only the first panic is reported, nested or concurrent ones just stop
+    if PANICKING.swap(true, Ordering::AcqRel) {
+        loop {
+            halt();
+        }
+    }
+
+    match this_cpu_index() {
+        Some(cpu_index) => {
+            // Freeze the other CPUs, so their state is kept and their output
+            // doesn't interleave with the report
+            let remaining = stop_all_other_cpus();
+            console_enter_unlocked();
+            log::error!(
+                "Panic: CPU[{}] APIC-ID {}: {}",
+                cpu_index,
+                this_cpu().get_apic_id(),
+                info
+            );
+            if remaining > 0 {
+                log::error!("{} CPU(s) did not stop", remaining);
+            }
+        }
+        None => {
+            console_enter_unlocked();
+            log::error!("Panic: {}", info);
+        }
+    }
+
+    log::error!("CR2: {:#018x} CR3: {:#018x}", read_cr2(), read_cr3().bits());
+    log::error!("Online CPUs: {}", CPU_ONLINE_MASK);
 
     print_stack(3);
 
-    loop {
-        halt();
-    }
+    terminate_svsm();
 }
@@ -6,7 +6,6 @@
 
 use crate::cpu::percpu::this_cpu_mut;
 use crate::io::IOPort;
-use crate::sev::ghcb::GHCBIOSize;
 use crate::sev::msr_protocol::request_termination_msr;
 
 pub struct SVSMIOPort {}
@@ -19,42 +18,41 @@ impl SVSMIOPort {
 
 impl IOPort for SVSMIOPort {
     fn outb(&self, port: u16, value: u8) {
-        let ret = this_cpu_mut()
-            .ghcb()
-            .ioio_out(port, GHCBIOSize::Size8, value as u64);
-        if let Err(()) = ret {
+        if this_cpu_mut().ghcb().outb(port, value).is_err() {
             request_termination_msr();
         }
     }
 
     fn inb(&self, port: u16) -> u8 {
-        let ret = this_cpu_mut().ghcb().ioio_in(port, GHCBIOSize::Size8);
-        match ret {
-            Ok(v) => (v & 0xff) as u8,
-            Err(_e) => {
-                request_termination_msr();
-                0
-            }
-        }
+        this_cpu_mut().ghcb().inb(port).unwrap_or_else(|_| {
+            request_termination_msr();
+            0
+        })
     }
 
     fn outw(&self, port: u16, value: u16) {
-        let ret = this_cpu_mut()
-            .ghcb()
-            .ioio_out(port, GHCBIOSize::Size16, value as u64);
-        if let Err(()) = ret {
+        if this_cpu_mut().ghcb().outw(port, value).is_err() {
             request_termination_msr();
         }
     }
 
     fn inw(&self, port: u16) -> u16 {
-        let ret = this_cpu_mut().ghcb().ioio_in(port, GHCBIOSize::Size16);
-        match ret {
-            Ok(v) => (v & 0xffff) as u16,
-            Err(_e) => {
-                request_termination_msr();
-                0
-            }
+        this_cpu_mut().ghcb().inw(port).unwrap_or_else(|_| {
+            request_termination_msr();
+            0
+        })
+    }
+
+    fn outl(&self, port: u16, value: u32) {
+        if this_cpu_mut().ghcb().outl(port, value).is_err() {
+            request_termination_msr();
         }
     }
+
+    fn inl(&self, port: u16) -> u32 {
+        this_cpu_mut().ghcb().inl(port).unwrap_or_else(|_| {
+            request_termination_msr();
+            0
+        })
+    }
 }
@@ -8,11 +8,11 @@ use crate::heap_start;
 use svsm::cpu::percpu::this_cpu_mut;
 use svsm::kernel_launch::KernelLaunchInfo;
 use svsm::mm;
-use svsm::mm::pagetable::{set_init_pgtable, PageTable, PageTableRef};
+use svsm::mm::pagetable::{set_code_region, set_init_pgtable, PageTable, PageTableRef};
 use svsm::mm::PerCPUPageMappingGuard;
-use svsm::sev::ghcb::PageStateChangeOp;
+use svsm::sev::ghcb::PscOp;
 use svsm::sev::pvalidate;
-use svsm::types::{PhysAddr, VirtAddr, PAGE_SIZE};
+use svsm::types::{PageSize, PhysAddr, VirtAddr, PAGE_SIZE};
 
 extern "C" {
     static stext: u8;
@@ -29,44 +29,46 @@ pub fn init_page_table(launch_info: &KernelLaunchInfo) {
     let vaddr = mm::alloc::allocate_zeroed_page().expect("Failed to allocate root page-table");
     let offset = (launch_info.virt_base - launch_info.kernel_start) as usize;
 
-    let mut pgtable = PageTableRef::new(unsafe { &mut *(vaddr as *mut PageTable) });
+    let mut pgtable = PageTableRef::new(unsafe { &mut *vaddr.as_mut_ptr::<PageTable>() });
 
     /* Text segment */
-    let start: VirtAddr = (unsafe { &stext } as *const u8) as VirtAddr;
-    let end: VirtAddr = (unsafe { &etext } as *const u8) as VirtAddr;
-    let phys: PhysAddr = start - offset;
+    let start: VirtAddr = VirtAddr::from_ptr(unsafe { &stext } as *const u8);
+    let end: VirtAddr = VirtAddr::from_ptr(unsafe { &etext } as *const u8);
+    let phys = PhysAddr::from(start.as_usize() - offset);
+    // Everything mapped from now on outside of the text segment must be NX
+    set_code_region(start, end);
     pgtable
         .map_region(start, end, phys, PageTable::exec_flags())
         .expect("Failed to map text segment");
 
     /* Writeble data */
-    let start: VirtAddr = (unsafe { &sdata } as *const u8) as VirtAddr;
-    let end: VirtAddr = (unsafe { &edata } as *const u8) as VirtAddr;
-    let phys: PhysAddr = start - offset;
+    let start: VirtAddr = VirtAddr::from_ptr(unsafe { &sdata } as *const u8);
+    let end: VirtAddr = VirtAddr::from_ptr(unsafe { &edata } as *const u8);
+    let phys = PhysAddr::from(start.as_usize() - offset);
     pgtable
         .map_region(start, end, phys, PageTable::data_flags())
         .expect("Failed to map data segment");
 
     /* Read-only data */
-    let start: VirtAddr = (unsafe { &sdataro } as *const u8) as VirtAddr;
-    let end: VirtAddr = (unsafe { &edataro } as *const u8) as VirtAddr;
-    let phys: PhysAddr = start - offset;
+    let start: VirtAddr = VirtAddr::from_ptr(unsafe { &sdataro } as *const u8);
+    let end: VirtAddr = VirtAddr::from_ptr(unsafe { &edataro } as *const u8);
+    let phys = PhysAddr::from(start.as_usize() - offset);
     pgtable
         .map_region(start, end, phys, PageTable::data_ro_flags())
         .expect("Failed to map read-only data");
 
     /* BSS */
-    let start: VirtAddr = (unsafe { &sbss } as *const u8) as VirtAddr;
-    let end: VirtAddr = (unsafe { &ebss } as *const u8) as VirtAddr;
-    let phys: PhysAddr = start - offset;
+    let start: VirtAddr = VirtAddr::from_ptr(unsafe { &sbss } as *const u8);
+    let end: VirtAddr = VirtAddr::from_ptr(unsafe { &ebss } as *const u8);
+    let phys = PhysAddr::from(start.as_usize() - offset);
     pgtable
         .map_region(start, end, phys, PageTable::data_flags())
         .expect("Failed to map bss segment");
 
     /* Heap */
-    let start: VirtAddr = (unsafe { &heap_start } as *const u8) as VirtAddr;
-    let end: VirtAddr = (launch_info.kernel_end as VirtAddr) + offset;
-    let phys: PhysAddr = start - offset;
+    let start: VirtAddr = VirtAddr::from_ptr(unsafe { &heap_start } as *const u8);
+    let end: VirtAddr = VirtAddr::from(launch_info.kernel_end) + offset;
+    let phys = PhysAddr::from(start.as_usize() - offset);
     pgtable
         .map_region(start, end, phys, PageTable::data_flags())
         .expect("Failed to map heap");
@@ -77,7 +79,7 @@ pub fn init_page_table(launch_info: &KernelLaunchInfo) {
 }
 
 pub fn invalidate_stage2() -> Result<(), ()> {
-    let pstart: PhysAddr = 0;
+    let pstart = PhysAddr::null();
     let pend = pstart + (640 * 1024);
     let mut paddr = pstart;
 
@@ -88,14 +90,15 @@ pub fn invalidate_stage2() -> Result<(), ()> {
         let guard = PerCPUPageMappingGuard::create(paddr, 0, false)?;
         let vaddr = guard.virt_addr();
 
-        pvalidate(vaddr, false, false).expect("PINVALIDATE failed");
+        let changed = pvalidate(vaddr, PageSize::Page4K, false).expect("PINVALIDATE failed");
+        assert!(changed, "Stage2 page was not validated");
 
         paddr += PAGE_SIZE;
     }
 
     this_cpu_mut()
         .ghcb()
-        .page_state_change(paddr, pend, false, PageStateChangeOp::PscShared)
+        .page_state_change_region(paddr, pend, PageSize::Page4K, PscOp::Shared)
         .expect("Failed to invalidate Stage2 memory");
 
     Ok(())
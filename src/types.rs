@@ -4,9 +4,65 @@
 //
 // Author: Joerg Roedel <jroedel@suse.de>
 
+use core::fmt;
+use core::ops::{Add, AddAssign, Sub, SubAssign};
+
 pub const PAGE_SHIFT: usize = 12;
 pub const PAGE_SIZE: usize = 1 << PAGE_SHIFT;
 pub const PAGE_SIZE_2M: usize = PAGE_SIZE * 512;
+pub const PAGE_SIZE_1G: usize = PAGE_SIZE_2M * 512;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PageSize {
+    Page4K,
+    Page2M,
+    Page1G,
+}
+
+impl PageSize {
+    pub const fn bytes(&self) -> usize {
+        1 << self.shift()
+    }
+
+    pub const fn shift(&self) -> usize {
+        match self {
+            PageSize::Page4K => PAGE_SHIFT,
+            PageSize::Page2M => PAGE_SHIFT + 9,
+            PageSize::Page1G => PAGE_SHIFT + 18,
+        }
+    }
+}
+
+pub const fn align_up(v: usize, align: usize) -> usize {
+    debug_assert!(align.is_power_of_two());
+    (v + (align - 1)) & !(align - 1)
+}
+
+pub const fn align_down(v: usize, align: usize) -> usize {
+    debug_assert!(align.is_power_of_two());
+    v & !(align - 1)
+}
+
+pub const fn is_aligned(v: usize, align: usize) -> bool {
+    debug_assert!(align.is_power_of_two());
+    (v & (align - 1)) == 0
+}
+
+pub const fn page_align_up(v: usize) -> usize {
+    align_up(v, PAGE_SIZE)
+}
+
+pub const fn page_align_down(v: usize) -> usize {
+    align_down(v, PAGE_SIZE)
+}
+
+pub const fn is_page_aligned(v: usize) -> bool {
+    is_aligned(v, PAGE_SIZE)
+}
+
+pub const fn page_offset(v: usize) -> usize {
+    v & (PAGE_SIZE - 1)
+}
 
 #[allow(clippy::identity_op)]
 pub const SVSM_CS: u16 = 1 * 8;
@@ -17,9 +73,209 @@ pub const SVSM_TSS: u16 = 6 * 8;
 
 pub const SVSM_CS_FLAGS: u16 = 0x29b;
 pub const SVSM_DS_FLAGS: u16 = 0xc93;
+pub const SVSM_USER_CS_FLAGS: u16 = 0x2fb;
+pub const SVSM_USER_DS_FLAGS: u16 = 0xcf3;
 pub const SVSM_TR_FLAGS: u16 = 0x89;
 
-pub type PhysAddr = usize;
-pub type VirtAddr = usize;
+// Physical and virtual addresses are distinct types, so that one can not be
+// passed where the other is expected. Conversions to and from usize have to
+// be explicit.
+macro_rules! define_address {
+    ($name:ident) => {
+        #[repr(transparent)]
+        #[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+        pub struct $name(usize);
+
+        impl $name {
+            pub const fn new(addr: usize) -> Self {
+                $name(addr)
+            }
+
+            pub const fn null() -> Self {
+                $name(0)
+            }
+
+            pub const fn is_null(&self) -> bool {
+                self.0 == 0
+            }
+
+            pub const fn as_usize(&self) -> usize {
+                self.0
+            }
+
+            pub const fn align_up(&self, align: usize) -> Self {
+                $name(align_up(self.0, align))
+            }
+
+            pub const fn align_down(&self, align: usize) -> Self {
+                $name(align_down(self.0, align))
+            }
+
+            pub const fn is_aligned(&self, align: usize) -> bool {
+                is_aligned(self.0, align)
+            }
+
+            pub const fn page_align_up(&self) -> Self {
+                $name(page_align_up(self.0))
+            }
+
+            pub const fn page_align_down(&self) -> Self {
+                $name(page_align_down(self.0))
+            }
+
+            pub const fn is_page_aligned(&self) -> bool {
+                is_page_aligned(self.0)
+            }
+
+            pub const fn page_offset(&self) -> usize {
+                page_offset(self.0)
+            }
+
+            pub const fn offset(&self, off: usize) -> Self {
+                $name(self.0 + off)
+            }
 
+            pub fn checked_add(&self, off: usize) -> Option<Self> {
+                self.0.checked_add(off).map($name)
+            }
+
+            pub fn checked_sub(&self, off: usize) -> Option<Self> {
+                self.0.checked_sub(off).map($name)
+            }
+
+            // Iterate over [self, end) in steps of step bytes
+            pub fn iter_to(self, end: Self, step: usize) -> impl Iterator<Item = Self> {
+                (self.0..end.0).step_by(step).map($name)
+            }
+        }
+
+        impl From<usize> for $name {
+            fn from(addr: usize) -> Self {
+                $name(addr)
+            }
+        }
+
+        impl From<u64> for $name {
+            fn from(addr: u64) -> Self {
+                $name(addr as usize)
+            }
+        }
+
+        impl From<$name> for usize {
+            fn from(addr: $name) -> usize {
+                addr.0
+            }
+        }
+
+        impl From<$name> for u64 {
+            fn from(addr: $name) -> u64 {
+                addr.0 as u64
+            }
+        }
+
+        impl Add<usize> for $name {
+            type Output = $name;
+            fn add(self, off: usize) -> $name {
+                $name(self.0 + off)
+            }
+        }
+
+        impl AddAssign<usize> for $name {
+            fn add_assign(&mut self, off: usize) {
+                self.0 += off;
+            }
+        }
+
+        impl Sub<usize> for $name {
+            type Output = $name;
+            fn sub(self, off: usize) -> $name {
+                $name(self.0 - off)
+            }
+        }
+
+        impl SubAssign<usize> for $name {
+            fn sub_assign(&mut self, off: usize) {
+                self.0 -= off;
+            }
+        }
+
+        // The distance between two addresses
+        impl Sub<$name> for $name {
+            type Output = usize;
+            fn sub(self, other: $name) -> usize {
+                self.0 - other.0
+            }
+        }
+
+        impl fmt::Debug for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "{}({:#018x})", stringify!($name), self.0)
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "{:#018x}", self.0)
+            }
+        }
+
+        impl fmt::LowerHex for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                fmt::LowerHex::fmt(&self.0, f)
+            }
+        }
+    };
+}
+
+define_address!(PhysAddr);
+define_address!(VirtAddr);
+
+impl VirtAddr {
+    pub fn from_ptr<T>(ptr: *const T) -> Self {
+        VirtAddr(ptr as usize)
+    }
+
+    pub const fn as_ptr<T>(&self) -> *const T {
+        self.0 as *const T
+    }
+
+    pub const fn as_mut_ptr<T>(&self) -> *mut T {
+        self.0 as *mut T
+    }
+}
+
+// Sanity bound for the number of CPUs reported by ACPI
 pub const MAX_CPUS: usize = 512;
+
+#[test]
+fn test_page_size() {
+    assert_eq!(PageSize::Page4K.bytes(), PAGE_SIZE);
+    assert_eq!(PageSize::Page2M.bytes(), PAGE_SIZE_2M);
+    assert_eq!(PageSize::Page1G.bytes(), PAGE_SIZE_1G);
+    assert_eq!(PageSize::Page1G.shift(), 30);
+}
+
+#[test]
+fn test_align_page_size() {
+    assert_eq!(page_align_up(0), 0);
+    assert_eq!(page_align_up(1), PAGE_SIZE);
+    assert_eq!(page_align_up(PAGE_SIZE), PAGE_SIZE);
+    assert_eq!(page_align_up(PAGE_SIZE + 1), 2 * PAGE_SIZE);
+    assert_eq!(page_align_down(PAGE_SIZE - 1), 0);
+    assert_eq!(page_align_down(PAGE_SIZE), PAGE_SIZE);
+    assert_eq!(page_align_down(2 * PAGE_SIZE - 1), PAGE_SIZE);
+    assert!(is_page_aligned(0));
+    assert!(is_page_aligned(3 * PAGE_SIZE));
+    assert!(!is_page_aligned(PAGE_SIZE + 8));
+}
+
+#[test]
+fn test_align_2m() {
+    assert_eq!(align_up(PAGE_SIZE, PAGE_SIZE_2M), PAGE_SIZE_2M);
+    assert_eq!(align_up(PAGE_SIZE_2M, PAGE_SIZE_2M), PAGE_SIZE_2M);
+    assert_eq!(align_up(PAGE_SIZE_2M + 1, PAGE_SIZE_2M), 2 * PAGE_SIZE_2M);
+    assert_eq!(align_down(PAGE_SIZE_2M - 1, PAGE_SIZE_2M), 0);
+    assert_eq!(align_down(3 * PAGE_SIZE_2M, PAGE_SIZE_2M), 3 * PAGE_SIZE_2M);
+    assert!(is_aligned(2 * PAGE_SIZE_2M, PAGE_SIZE_2M));
+    assert!(!is_aligned(PAGE_SIZE_2M + PAGE_SIZE, PAGE_SIZE_2M));
+}
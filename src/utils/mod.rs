@@ -7,7 +7,4 @@
 pub mod immut_after_init;
 pub mod util;
 
-pub use util::{
-    align_up, crosses_page, ffs, halt, is_aligned, overlap, page_align, page_align_up, page_offset,
-    zero_mem_region,
-};
+pub use util::{crosses_page, ffs, halt, idle_halt, overlap, zero_mem_region};
@@ -8,26 +8,6 @@ use crate::types::{VirtAddr, PAGE_SIZE};
 use core::arch::asm;
 use core::ptr;
 
-pub fn align_up(addr: usize, align: usize) -> usize {
-    (addr + (align - 1)) & !(align - 1)
-}
-
-pub fn page_offset(addr: usize) -> usize {
-    addr & (PAGE_SIZE - 1)
-}
-
-pub fn page_align(addr: usize) -> usize {
-    addr & !(PAGE_SIZE - 1)
-}
-
-pub fn page_align_up(addr: usize) -> usize {
-    (addr + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
-}
-
-pub fn is_aligned(addr: usize, align: usize) -> bool {
-    (addr & (align - 1)) == 0
-}
-
 #[inline(always)]
 pub fn ffs(val: u64) -> usize {
     let mut ret: usize;
@@ -51,6 +31,20 @@ pub fn halt() {
     }
 }
 
+// Halt until an interrupt arrives. Interrupts are only enabled for the HLT
+// itself: due to the STI interrupt shadow an IPI sent after the caller checked
+// for work still terminates the HLT instead of getting lost.
+pub fn idle_halt() {
+    unsafe {
+        asm!(
+            "sti
+              hlt
+              cli",
+            options(att_syntax)
+        );
+    }
+}
+
 pub fn overlap<T>(x1: T, x2: T, y1: T, y2: T) -> bool
 where
     T: core::cmp::PartialOrd,
@@ -68,7 +62,7 @@ pub fn crosses_page(start: usize, size: usize) -> bool {
 pub fn zero_mem_region(start: VirtAddr, end: VirtAddr) {
     let size = end - start;
 
-    let mut target = ptr::NonNull::new(start as *mut u8).unwrap();
+    let mut target = ptr::NonNull::new(start.as_mut_ptr::<u8>()).unwrap();
 
     // Zero region
     unsafe {